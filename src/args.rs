@@ -1,11 +1,23 @@
 use std::path::PathBuf;
-use clap::{Args, Parser, Subcommand};
+use crate::hash::HashAlgorithm;
+use crate::image::{Compression, Format};
+use crate::mass_storage::{HolePolicy, MAX_BUFFER_SIZE};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(version)]
 pub struct Arguments {
         #[command(subcommand)]
         pub command: Command,
+        /// Operate on a simulated device whose storage is the given file instead of scanning the USB bus (useful for testing), repeat it to simulate a device with multiple LUNs
+        #[arg(long, global=true)]
+        pub simulate: Vec<PathBuf>,
+        /// Comma separated list of faults injected by the simulated device (e.g. "stall-in:3,medium-error:100-120,slow:0-63:50")
+        #[arg(long, global=true, requires = "simulate")]
+        pub simulate_faults: Option<String>,
+        /// Logical block size in bytes reported by the simulated device
+        #[arg(long, global=true, default_value_t = 512, requires = "simulate")]
+        pub simulate_block_size: u32,
 }
 
 #[allow(non_camel_case_types)]
@@ -17,13 +29,23 @@ pub enum Command {
         clone(CloneOperationArgs),
         /// List all the available devices and exit
         list(ListOperationArgs),
+        /// Print the identification data, capacity and characteristics of the devices and exit
+        info(InfoOperationArgs),
+        /// Hash a range of the device's storage without writing an image
+        checksum(ChecksumOperationArgs),
 }
 
 #[derive(Args)]
 pub struct FlashOperationArgs {
-        /// Specify the input disc image
+        /// Specify the input disc image, '-' reads it from the standard input
         #[arg(short, long)]
         pub image: PathBuf,
+        /// Set the size of the (decoded) image read from the standard input, which is otherwise unknown until it ends (e.g. "8G", K, M, G and T are powers of 1000, KiB, MiB, GiB and TiB powers of 1024)
+        #[arg(long, alias = "size", global=true, value_parser = parse_size)]
+        pub input_size: Option<u64>,
+        /// Name the entry to flash when the image is a zip or tar archive, by default its single disk image (.img, .iso...) is flashed
+        #[arg(long, global=true)]
+        pub entry: Option<String>,
         /// Set the log level, it's recommended not to change this value (options: None, Info, Warning/Warn, Error, Debug)
         #[arg(short, long, global=true, default_value = "Error")]
         pub log_level: String,
@@ -36,20 +58,62 @@ pub struct FlashOperationArgs {
         /// Add the device's name (as in the string with which the device identifies itself as a product) to the filter, this information is not unique and in some cases you could still have duplicates
         #[arg(short = 'n', long, global=true)]
         pub device_name: Option<String>,
+        /// Add the logical unit number to the filter, this is needed to select a single slot of multi-slot card readers
+        #[arg(long, global=true)]
+        pub lun: Option<u8>,
         /// Skip prompts, always answer 'y'
         #[arg(short = 'y', global=true, action)]
         pub skip_prompts: bool,
-        /// Set the size of the buffer used for writing in sectors (up to 65535), higher values may achieve faster speeds
-        #[arg(long, default_value_t = 32, global=true)]
+        /// Set the size of the buffer used for writing in sectors (up to 524288), higher values may achieve faster speeds
+        #[arg(long, default_value_t = 32, global=true, value_parser = parse_buffer_size)]
         pub buffer_size: usize,
         /// Set the number of sectors to copy from the input image, this value must be less than the image's size
         #[arg(short, long, global=true)]
-        pub sector_count: Option<u32>,
+        pub sector_count: Option<u64>,
+        /// Read the written sectors back and compare them with the image, 'hash' compares sector hashes instead of keeping a second buffer
+        #[arg(long, global=true, value_enum, num_args = 0..=1, default_missing_value = "compare")]
+        pub verify: Option<VerifyMode>,
+        /// Verify the image against this checksum file (SHA256SUMS, .sha256, .md5...) before flashing, by default a checksum file listing the image is looked up next to it
+        #[arg(long, global=true)]
+        pub checksum_file: Option<PathBuf>,
+        /// Do not verify the image against a checksum file found next to it
+        #[arg(long, global=true, action, conflicts_with = "checksum_file")]
+        pub skip_checksum: bool,
+        /// Specify the minisign signature of the image, by default '<image>.minisig' is used if it exists
+        #[arg(long, global=true)]
+        pub signature: Option<PathBuf>,
+        /// Specify the minisign public key used to verify the image's signature instead of the trusted keys
+        #[arg(long, global=true)]
+        pub pubkey: Option<PathBuf>,
+        /// Set the directory holding the trusted minisign public keys ('.pub' files), by default the keys in /etc/rmsd/trusted-keys are trusted if it exists
+        #[arg(long, global=true)]
+        pub trusted_keys: Option<PathBuf>,
+        /// Flash images that have no signature (images with an invalid signature are always refused)
+        #[arg(long, global=true, action)]
+        pub allow_unsigned: bool,
+        /// Hash the image while it is written and print the digest at the end, can be repeated to compute several digests (options: sha256, sha512, blake3, md5)
+        #[arg(long = "hash", global=true, value_enum)]
+        pub hash_algorithms: Vec<HashAlgorithm>,
+        /// Set what happens to the sectors covered by the holes of sparse images and the ranges left unmapped by the bmap, which are never read (options: write-zeros, discard with UNMAP or WRITE SAME, skip), by default zeros are written unless a bmap is used
+        #[arg(long, global=true, value_enum)]
+        pub hole_policy: Option<HolePolicy>,
+        /// Specify the bmap file (as produced by bmaptool) listing the mapped ranges of the image and their checksums, by default '<image>.bmap' is used if it exists
+        #[arg(long, global=true)]
+        pub bmap: Option<PathBuf>,
+        /// Do not use the bmap file found next to the image
+        #[arg(long, global=true, action, conflicts_with = "bmap")]
+        pub no_bmap: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum VerifyMode {
+        Compare,
+        Hash,
 }
 
 #[derive(Args)]
 pub struct CloneOperationArgs {
-        /// Specify the output disc image
+        /// Specify the output disc image, '-' writes it to the standard output (messages are then printed on the standard error)
         #[arg(short, long)]
         pub image: PathBuf,
         /// Set the log level, it's recommended not to change this value
@@ -64,15 +128,70 @@ pub struct CloneOperationArgs {
         /// Add the device's name (as in the string with which the device identifies itself as a product) to the filter, this information is not unique and in some cases you could still have duplicates
         #[arg(short = 'n', long, global=true)]
         pub device_name: Option<String>,
+        /// Add the logical unit number to the filter, this is needed to select a single slot of multi-slot card readers
+        #[arg(long, global=true)]
+        pub lun: Option<u8>,
         /// Skip prompts, always answer 'y'
         #[arg(short = 'y', global=true, action)]
         pub skip_prompts: bool,
-        /// Set the size of the buffer used for writing in sectors (up to 65535), higher values may achieve faster speeds
-        #[arg(long, default_value_t = 32, global=true)]
+        /// Set the size of the buffer used for writing in sectors (up to 524288), higher values may achieve faster speeds
+        #[arg(long, default_value_t = 32, global=true, value_parser = parse_buffer_size)]
         pub buffer_size: usize,
         /// Set the number of sectors to copy from the device, this value must be less than the device's capacity
         #[arg(short, long, global=true)]
-        pub sector_count: Option<u32>,
+        pub sector_count: Option<u64>,
+        /// Hash the device's data while it is copied and print the digest at the end, can be repeated to compute several digests (options: sha256, sha512, blake3, md5)
+        #[arg(long = "hash", global=true, value_enum)]
+        pub hash_algorithms: Vec<HashAlgorithm>,
+        /// Set the layout of the output image, android-sparse stores runs of repeated words as FILL chunks while qcow2 and vhdx (dynamic) only allocate the clusters or blocks holding data, qcow2 clusters are compressed one by one with --compress gzip or zstd
+        #[arg(long, global=true, value_enum, default_value = "raw")]
+        pub format: Format,
+        /// Compress the output image (options: zstd, written in the seekable format, xz, gzip)
+        #[arg(long, global=true, value_enum)]
+        pub compress: Option<Compression>,
+        /// Set the compression level, by default the format's default level is used (zstd: 1 to 22, xz and gzip: 0 to 9)
+        #[arg(long, global=true, requires = "compress", allow_negative_numbers = true)]
+        pub compression_level: Option<i32>,
+        /// Set the number of threads compressing the output image, by default one per available CPU
+        #[arg(long, global=true, requires = "compress")]
+        pub threads: Option<usize>,
+        /// Split raw output images in parts of the given size named image.001, image.002... (e.g. "4G" to fit on FAT32, K, M, G and T are powers of 1000, KiB, MiB, GiB and TiB powers of 1024)
+        #[arg(long, global=true, value_parser = parse_size)]
+        pub split_size: Option<u64>,
+}
+
+/// Parses a buffer size in sectors, the bytes of a transfer have to fit in the 32 bit length of the command
+fn parse_buffer_size(value: &str) -> Result<usize, String> {
+        let sectors: usize = value.trim().parse().map_err(|_| format!("{:?} is not a number of sectors", value))?;
+        if sectors == 0 || sectors > MAX_BUFFER_SIZE {
+                return Err(format!("the buffer holds 1 to {} sectors", MAX_BUFFER_SIZE));
+        }
+        Ok(sectors)
+}
+
+/// Parses a number of bytes optionally followed by a decimal (K, M, G, T) or binary (KiB, MiB, GiB, TiB) unit
+fn parse_size(value: &str) -> Result<u64, String> {
+        let value = value.trim();
+        let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        let number: u64 = value[..digits].parse().map_err(|_| format!("{:?} does not start with a number", value))?;
+        let unit = value[digits..].trim();
+        let multiplier: u64 = match unit.strip_suffix(['B', 'b']).unwrap_or(unit) {
+                "" => { 1 },
+                "K" | "k" => { 1000 },
+                "M" => { 1000u64.pow(2) },
+                "G" => { 1000u64.pow(3) },
+                "T" => { 1000u64.pow(4) },
+                "Ki" => { 1 << 10 },
+                "Mi" => { 1 << 20 },
+                "Gi" => { 1 << 30 },
+                "Ti" => { 1 << 40 },
+                _ => { return Err(format!("unknown unit {:?}, the units are K, M, G, T, KiB, MiB, GiB and TiB", unit)); }
+        };
+        match number.checked_mul(multiplier) {
+                Some(0) => { Err(String::from("the size cannot be 0")) },
+                Some(size) => { Ok(size) },
+                None => { Err(format!("{:?} is too large", value)) }
+        }
 }
 
 #[derive(Args)]
@@ -89,4 +208,60 @@ pub struct ListOperationArgs {
         /// Add the device's name (as in the devices's product ID) to the filter, this information is not unique and in some cases you could still have duplicates
         #[arg(short = 'n', long, global=true)]
         pub device_name: Option<String>,
+        /// Add the logical unit number to the filter, this is needed to select a single slot of multi-slot card readers
+        #[arg(long, global=true)]
+        pub lun: Option<u8>,
+}
+
+#[derive(Args)]
+pub struct InfoOperationArgs {
+        /// Set the log level, it's recommended not to change this value
+        #[arg(short, long, global=true, default_value = "Error")]
+        pub log_level: String,
+        /// Add the device's port to the filter, this information alone should be unique and thus suffice to identify a single device
+        #[arg(short = 'p', long = "port", global=true)]
+        pub device_port: Option<u8>,
+        /// Add the device's bus (the controller to which the it is connected) to the filter, this information alone should be unique and thus suffice to identify a single device
+        #[arg(short = 'b', long = "bus", global=true)]
+        pub device_bus: Option<u8>,
+        /// Add the device's name (as in the devices's product ID) to the filter, this information is not unique and in some cases you could still have duplicates
+        #[arg(short = 'n', long, global=true)]
+        pub device_name: Option<String>,
+        /// Add the logical unit number to the filter, this is needed to select a single slot of multi-slot card readers
+        #[arg(long, global=true)]
+        pub lun: Option<u8>,
+}
+
+#[derive(Args)]
+pub struct ChecksumOperationArgs {
+        /// Set the log level, it's recommended not to change this value
+        #[arg(short, long, global=true, default_value = "Error")]
+        pub log_level: String,
+        /// Add the device's port to the filter, this information alone should be unique and thus suffice to identify a single device
+        #[arg(short = 'p', long = "port", global=true)]
+        pub device_port: Option<u8>,
+        /// Add the device's bus (the controller to which the it is connected) to the filter, this information alone should be unique and thus suffice to identify a single device
+        #[arg(short = 'b', long = "bus", global=true)]
+        pub device_bus: Option<u8>,
+        /// Add the device's name (as in the string with which the device identifies itself as a product) to the filter, this information is not unique and in some cases you could still have duplicates
+        #[arg(short = 'n', long, global=true)]
+        pub device_name: Option<String>,
+        /// Add the logical unit number to the filter, this is needed to select a single slot of multi-slot card readers
+        #[arg(long, global=true)]
+        pub lun: Option<u8>,
+        /// Skip prompts, always answer 'y'
+        #[arg(short = 'y', global=true, action)]
+        pub skip_prompts: bool,
+        /// Set the size of the buffer used for reading in sectors (up to 524288), higher values may achieve faster speeds
+        #[arg(long, default_value_t = 32, global=true, value_parser = parse_buffer_size)]
+        pub buffer_size: usize,
+        /// Set the first sector of the range to hash
+        #[arg(long, default_value_t = 0, global=true)]
+        pub start_sector: u64,
+        /// Set the number of sectors to hash, by default the range extends up to the end of the device
+        #[arg(short, long, global=true)]
+        pub sector_count: Option<u64>,
+        /// Set the hash algorithm, can be repeated to compute several digests at once (options: sha256, sha512, blake3, md5)
+        #[arg(long = "hash", global=true, value_enum, default_values_t = [HashAlgorithm::Sha256])]
+        pub hash_algorithms: Vec<HashAlgorithm>,
 }
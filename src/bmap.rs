@@ -0,0 +1,282 @@
+use clap::ValueEnum;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use crate::log;
+use crate::hash::{self, HashAlgorithm};
+
+const BMAP_EXTENSION: &str = "bmap";
+/// Extensions of compressed images, `image.wic.xz` is described by `image.wic.bmap`
+const COMPRESSED_EXTENSIONS: &[&str] = &["gz", "xz", "bz2", "zst"];
+
+/// A range of the image that holds data, with the digest of its content when the bmap records one
+#[derive(Debug, Clone)]
+pub struct MappedRange {
+        pub bytes: Range<u64>,
+        pub digest: Option<hash::Digest>,
+}
+
+/// A block map in the format of bmaptool (versions 1.x and 2.x), it lists the blocks of the image that
+/// hold data, everything else is unmapped and reads as zeros
+#[derive(Debug, Clone)]
+pub struct Bmap {
+        pub path: PathBuf,
+        pub image_size: u64,
+        pub block_size: u64,
+        pub ranges: Vec<MappedRange>,
+}
+
+impl Bmap {
+        /// The byte ranges of the image that are mapped
+        pub fn extents(&self) -> Vec<Range<u64>> {
+                self.ranges.iter().map(|r| r.bytes.clone()).collect()
+        }
+
+        pub fn mapped_size(&self) -> u64 {
+                self.ranges.iter().map(|r| r.bytes.end - r.bytes.start).sum()
+        }
+}
+
+/// Loads `bmap`, or the bmap found next to the image when none is given (`<image>.bmap`, or the name of the
+/// image without its compression extension followed by `.bmap`)
+pub fn load_image_bmap(image: &Path, bmap: Option<&PathBuf>) -> std::io::Result<Option<Bmap>> {
+        let path = match bmap {
+                Some(path) => { path.clone() },
+                None => {
+                        match find_bmap(image) {
+                                Some(path) => { path },
+                                None => {
+                                        log::debug!("load_image_bmap(): no bmap was found for {:?}", image);
+                                        return Ok(None);
+                                }
+                        }
+                }
+        };
+        let content = std::fs::read_to_string(&path)?;
+        match parse(&path, &content) {
+                Ok(bmap) => {
+                        log::debug!("load_image_bmap(): {:?} maps {} of {} bytes in {} ranges of {} byte blocks", path, bmap.mapped_size(), bmap.image_size, bmap.ranges.len(), bmap.block_size);
+                        Ok(Some(bmap))
+                },
+                Err(e) => { Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?} is not a valid bmap file: {}", path, e))) }
+        }
+}
+
+fn find_bmap(image: &Path) -> Option<PathBuf> {
+        let mut candidates = vec![];
+        let mut path = image.as_os_str().to_owned();
+        path.push(format!(".{}", BMAP_EXTENSION));
+        candidates.push(PathBuf::from(path));
+        if image.extension().is_some_and(|e| COMPRESSED_EXTENSIONS.iter().any(|c| e == *c)) {
+                candidates.push(image.with_extension(BMAP_EXTENSION));
+        }
+        candidates.into_iter().find(|p| p.is_file())
+}
+
+fn parse(path: &Path, content: &str) -> Result<Bmap, String> {
+        let document = roxmltree::Document::parse(content).map_err(|e| e.to_string())?;
+        let root = document.root_element();
+        if !root.has_tag_name("bmap") {
+                return Err(format!("unexpected root element <{}>", root.tag_name().name()));
+        }
+        let version = root.attribute("version").ok_or("the bmap version is missing")?;
+        let major = version.split('.').next().and_then(|v| v.trim().parse::<u32>().ok()).ok_or(format!("invalid version '{version}'"))?;
+        if major != 1 && major != 2 {
+                return Err(format!("unsupported version {version}"));
+        }
+        let field = |name: &str| -> Option<&str> {
+                root.children().find(|n| n.has_tag_name(name)).and_then(|n| n.text()).map(str::trim)
+        };
+        let number = |name: &str| -> Result<u64, String> {
+                field(name).ok_or(format!("<{name}> is missing"))?.parse::<u64>().map_err(|e| format!("invalid <{name}>: {e}"))
+        };
+        let image_size = number("ImageSize")?;
+        let block_size = number("BlockSize")?;
+        if block_size == 0 {
+                return Err(String::from("the block size is 0"));
+        }
+        // versions before 1.4 only use sha1
+        let checksum_type = field("ChecksumType").unwrap_or("sha1");
+        let algorithm = HashAlgorithm::from_str(checksum_type, true).ok();
+        if algorithm.is_none() {
+                log::warning!("parse(): {:?} uses {} checksums, which are not supported, the ranges are not verified", path, checksum_type);
+        }
+        if let (Some(algorithm), Some(expected)) = (algorithm, field("BmapFileChecksum")) {
+                // the checksum is computed over the file with the checksum itself replaced by zeros
+                let mut hasher = hash::Hasher::new(algorithm);
+                hasher.update(content.replacen(expected, &"0".repeat(expected.len()), 1).as_bytes());
+                if hasher.finalize().to_hex() != expected.to_ascii_lowercase() {
+                        return Err(String::from("its checksum does not match its content"));
+                }
+        }
+        let block_map = root.children().find(|n| n.has_tag_name("BlockMap")).ok_or("<BlockMap> is missing")?;
+        let mut ranges: Vec<MappedRange> = vec![];
+        for range in block_map.children().filter(|n| n.has_tag_name("Range")) {
+                let text = range.text().map(str::trim).unwrap_or("");
+                let (first, last) = text.split_once('-').unwrap_or((text, text));
+                let first = first.trim().parse::<u64>().map_err(|e| format!("invalid range '{text}': {e}"))?;
+                let last = last.trim().parse::<u64>().map_err(|e| format!("invalid range '{text}': {e}"))?;
+                let start = first * block_size;
+                let end = ((last + 1) * block_size).min(image_size);
+                if last < first || start >= image_size || ranges.last().is_some_and(|r| r.bytes.end > start) {
+                        return Err(format!("range '{text}' is out of order or outside of the image"));
+                }
+                let digest = match (algorithm, range.attribute("chksum").or(range.attribute("sha1"))) {
+                        (Some(algorithm), Some(hex)) => {
+                                Some(hash::Digest { algorithm, bytes: hash::from_hex(hex.trim()).ok_or(format!("invalid checksum of range '{text}'"))? })
+                        },
+                        _ => { None }
+                };
+                ranges.push(MappedRange { bytes: start..end, digest });
+        }
+        Ok(Bmap { path: path.to_path_buf(), image_size, block_size, ranges })
+}
+
+/// Checks the mapped ranges against their digests as the image is read
+pub struct RangeVerifier<'a> {
+        ranges: &'a [MappedRange],
+        index: usize,
+        hasher: Option<hash::Hasher>,
+}
+
+impl<'a> RangeVerifier<'a> {
+        pub fn new(bmap: &'a Bmap) -> RangeVerifier<'a> {
+                RangeVerifier { ranges: &bmap.ranges, index: 0, hasher: None }
+        }
+
+        /// Feeds `data`, read at byte `offset` of the image, returns a description of the first range whose
+        /// content does not match its digest
+        pub fn update(&mut self, mut offset: u64, mut data: &[u8]) -> Result<(), String> {
+                while !data.is_empty() {
+                        while self.ranges.get(self.index).is_some_and(|r| r.bytes.end <= offset) {
+                                self.index += 1;
+                                self.hasher = None;
+                        }
+                        let range = match self.ranges.get(self.index) {
+                                Some(r) => { r },
+                                None => { return Ok(()); }
+                        };
+                        if range.bytes.start > offset {
+                                // unmapped data is not covered by any digest
+                                let skipped = ((range.bytes.start - offset) as usize).min(data.len());
+                                offset += skipped as u64;
+                                data = &data[skipped..];
+                                continue;
+                        }
+                        let length = ((range.bytes.end - offset) as usize).min(data.len());
+                        if let Some(digest) = &range.digest {
+                                self.hasher.get_or_insert_with(|| hash::Hasher::new(digest.algorithm)).update(&data[..length]);
+                        }
+                        offset += length as u64;
+                        data = &data[length..];
+                        if offset == range.bytes.end {
+                                if let (Some(expected), Some(hasher)) = (&range.digest, self.hasher.take()) {
+                                        let digest = hasher.finalize();
+                                        if digest != *expected {
+                                                return Err(format!("bytes {} to {} hash to {} instead of {}", range.bytes.start, range.bytes.end, digest.to_hex(), expected.to_hex()));
+                                        }
+                                }
+                                self.index += 1;
+                        }
+                }
+                Ok(())
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        fn sha256(data: &[u8]) -> String {
+                let mut hasher = hash::Hasher::new(HashAlgorithm::Sha256);
+                hasher.update(data);
+                hasher.finalize().to_hex()
+        }
+
+        /// A version 2.0 bmap of an image of 10 blocks of 4096 bytes and a half, `ranges` being (text, digest) pairs,
+        /// with its BmapFileChecksum
+        fn bmap_file(ranges: &[(&str, String)]) -> String {
+                let ranges: String = ranges.iter().map(|(text, digest)| format!("        <Range chksum=\"{}\"> {} </Range>\n", digest, text)).collect();
+                let content = format!("<?xml version=\"1.0\" ?>\n<bmap version=\"2.0\">\n    <ImageSize> 43008 </ImageSize>\n    <BlockSize> 4096 </BlockSize>\n    \
+                        <BlocksCount> 11 </BlocksCount>\n    <MappedBlocksCount> 4 </MappedBlocksCount>\n    <ChecksumType> sha256 </ChecksumType>\n    \
+                        <BmapFileChecksum> {} </BmapFileChecksum>\n    <BlockMap>\n{}    </BlockMap>\n</bmap>\n", "0".repeat(64), ranges);
+                content.replacen(&"0".repeat(64), &sha256(content.as_bytes()), 1)
+        }
+
+        fn image() -> Vec<u8> {
+                (0..43008u32).map(|n| (n / 4096 + n % 13) as u8).collect()
+        }
+
+        #[test]
+        fn ranges_and_digests_are_parsed() {
+                let image = image();
+                let content = bmap_file(&[("0-1", sha256(&image[..8192])), ("5", sha256(&image[20480..24576])), ("10", sha256(&image[40960..]))]);
+                let bmap = parse(Path::new("image.bmap"), &content).unwrap();
+                assert_eq!((bmap.image_size, bmap.block_size), (43008, 4096));
+                // the last block is cut at the end of the image
+                assert_eq!(bmap.extents(), [0..8192, 20480..24576, 40960..43008]);
+                assert_eq!(bmap.mapped_size(), 8192 + 4096 + 2048);
+                assert_eq!(bmap.ranges[1].digest.as_ref().unwrap().to_hex(), sha256(&image[20480..24576]));
+                assert!(bmap.ranges.iter().all(|r| r.digest.as_ref().unwrap().algorithm == HashAlgorithm::Sha256));
+        }
+
+        #[test]
+        fn the_file_checksum_covers_the_whole_file() {
+                let content = bmap_file(&[("0-1", sha256(&image()[..8192]))]);
+                assert!(parse(Path::new("image.bmap"), &content).is_ok());
+                let tampered = content.replace("> 0-1 <", "> 0-2 <");
+                assert_eq!(parse(Path::new("image.bmap"), &tampered).unwrap_err(), "its checksum does not match its content");
+                // older versions have no file checksum
+                let content = "<bmap version=\"1.3\"><ImageSize>8192</ImageSize><BlockSize>4096</BlockSize><BlockMap>\
+                        <Range sha1=\"da39a3ee5e6b4b0d3255bfef95601890afd80709\">1</Range></BlockMap></bmap>";
+                let bmap = parse(Path::new("image.bmap"), content).unwrap();
+                // sha1 digests are not supported, the ranges are only mapped
+                assert_eq!(bmap.extents(), [4096..8192]);
+                assert!(bmap.ranges[0].digest.is_none());
+        }
+
+        #[test]
+        fn invalid_files_are_refused() {
+                let digest = sha256(b"");
+                for ranges in [&[("3-1", digest.clone())][..], &[("11", digest.clone())], &[("2-4", digest.clone()), ("4", digest.clone())], &[("x", digest.clone())], &[("1", String::from("12g4"))]] {
+                        assert!(parse(Path::new("image.bmap"), &bmap_file(ranges)).is_err(), "{:?}", ranges);
+                }
+                for content in ["<map version=\"2.0\"/>", "<bmap version=\"3.0\"/>", "<bmap/>", "<bmap version=\"2.0\"><ImageSize>1</ImageSize><BlockSize>0</BlockSize></bmap>", "<bmap"] {
+                        assert!(parse(Path::new("image.bmap"), content).is_err(), "{}", content);
+                }
+        }
+
+        #[test]
+        fn ranges_whose_digest_mismatches_are_reported() {
+                let mut image = image();
+                let content = bmap_file(&[("0-1", sha256(&image[..8192])), ("5", sha256(&image[20480..24576])), ("10", sha256(&image[40960..]))]);
+                let bmap = parse(Path::new("image.bmap"), &content).unwrap();
+                // chunks that do not line up with the ranges
+                let mut verifier = RangeVerifier::new(&bmap);
+                for (n, chunk) in image.chunks(3000).enumerate() {
+                        assert_eq!(verifier.update(n as u64 * 3000, chunk), Ok(()));
+                }
+                // unmapped data is not checked
+                image[10000] ^= 1;
+                image[22000] ^= 1;
+                let mut verifier = RangeVerifier::new(&bmap);
+                assert_eq!(verifier.update(0, &image[..20480]), Ok(()));
+                let error = verifier.update(20480, &image[20480..]).unwrap_err();
+                assert!(error.starts_with("bytes 20480 to 24576 hash to"), "{}", error);
+        }
+
+        #[test]
+        fn bmaps_are_found_next_to_the_image() {
+                let dir = std::env::temp_dir().join(format!("rmsd-bmap-{}", std::process::id()));
+                let _ = std::fs::remove_dir_all(&dir);
+                std::fs::create_dir_all(&dir).unwrap();
+                assert_eq!(find_bmap(&dir.join("image.wic.xz")), None);
+                std::fs::write(dir.join("image.wic.bmap"), "").unwrap();
+                assert_eq!(find_bmap(&dir.join("image.wic.xz")), Some(dir.join("image.wic.bmap")));
+                std::fs::write(dir.join("image.wic.xz.bmap"), "").unwrap();
+                assert_eq!(find_bmap(&dir.join("image.wic.xz")), Some(dir.join("image.wic.xz.bmap")));
+                // a bmap that cannot be parsed is an error, not a missing bmap
+                assert!(load_image_bmap(&dir.join("image.wic.xz"), None).is_err());
+                assert!(load_image_bmap(&dir.join("other.img"), None).unwrap().is_none());
+                std::fs::remove_dir_all(&dir).unwrap();
+        }
+}
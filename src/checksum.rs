@@ -0,0 +1,243 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use crate::log;
+use crate::hash::{self, HashAlgorithm};
+
+/// Sidecar files looked up next to the image, `{}` is replaced by the image's file name
+const SIDECAR_FILES: &[&str] = &["{}.sha256", "{}.sha256sum", "{}.sha512", "{}.sha512sum", "{}.md5", "{}.md5sum", "SHA256SUMS", "SHA512SUMS", "MD5SUMS"];
+
+/// A digest that a checksum file lists for the image
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectedDigest {
+        pub digest: hash::Digest,
+        pub source: PathBuf,
+}
+
+/// Verifies the image against `checksum_file`, or against the first sidecar file listing it when none is given,
+/// returns false when the digests differ or when an explicitly given checksum file does not list the image
+pub fn verify_image_checksum(image: &Path, checksum_file: Option<&PathBuf>) -> std::io::Result<bool> {
+        let expected = match checksum_file {
+                Some(path) => {
+                        match read_checksum_file(path, image)? {
+                                Some(expected) => { expected },
+                                None => {
+                                        log::error!("verify_image_checksum(): {:?} does not list a digest for {:?}", path, image);
+                                        return Ok(false);
+                                }
+                        }
+                },
+                None => {
+                        match find_sidecar(image)? {
+                                Some(expected) => { expected },
+                                None => {
+                                        log::debug!("verify_image_checksum(): no checksum file was found for {:?}", image);
+                                        return Ok(true);
+                                }
+                        }
+                }
+        };
+        let algorithm = expected.digest.algorithm;
+        println!("Checking the image's {} digest against {:?}...", algorithm.name(), expected.source);
+        let mut hasher = hash::Hasher::new(algorithm);
+        let mut file = File::open(image)?;
+        let mut buffer = vec![0u8; 1 << 20];
+        loop {
+                let bytes_read = match file.read(&mut buffer) {
+                        Ok(0) => { break; },
+                        Ok(n) => { n },
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { continue; },
+                        Err(e) => { return Err(e); }
+                };
+                hasher.update(&buffer[..bytes_read]);
+        }
+        let digest = hasher.finalize();
+        if digest != expected.digest {
+                log::error!("verify_image_checksum(): {} digest mismatch, expected {} but the image hashes to {}", algorithm.name(), expected.digest.to_hex(), digest.to_hex());
+                return Ok(false);
+        }
+        println!("The image matches its {} digest", algorithm.name());
+        Ok(true)
+}
+
+/// Looks for a checksum file listing the image in the image's directory
+fn find_sidecar(image: &Path) -> std::io::Result<Option<ExpectedDigest>> {
+        let directory = image.parent().unwrap_or(Path::new(""));
+        let file_name = match image.file_name() {
+                Some(name) => { name.to_string_lossy().into_owned() },
+                None => { return Ok(None); }
+        };
+        for pattern in SIDECAR_FILES {
+                let candidate = directory.join(pattern.replace("{}", &file_name));
+                if !candidate.is_file() {
+                        continue;
+                }
+                match read_checksum_file(&candidate, image)? {
+                        Some(expected) => { return Ok(Some(expected)); },
+                        None => { log::debug!("find_sidecar(): {:?} does not list {:?}", candidate, file_name); }
+                }
+        }
+        Ok(None)
+}
+
+/// Reads the digest of `image` from a checksum file in the GNU (`<hex>  <file>`), BSD (`SHA256 (<file>) = <hex>`)
+/// or bare (`<hex>`) format, the algorithm is taken from the BSD tag, the file's name or the length of the digest
+fn read_checksum_file(path: &Path, image: &Path) -> std::io::Result<Option<ExpectedDigest>> {
+        let content = std::fs::read_to_string(path)?;
+        let image_name = image.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let source_name = path.file_name().map(|n| n.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let lines: Vec<&str> = content.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).collect();
+        for line in &lines {
+                let (tag, name, hex) = match parse_line(line) {
+                        Some(entry) => { entry },
+                        None => { continue; }
+                };
+                // bare digests are only meaningful in a sidecar dedicated to a single image
+                let matches = match name {
+                        Some(n) => { Path::new(n.trim_start_matches("./")).file_name().map(|f| f.to_string_lossy() == image_name).unwrap_or(false) },
+                        None => { lines.len() == 1 }
+                };
+                if !matches {
+                        continue;
+                }
+                let bytes = match hash::from_hex(hex) {
+                        Some(b) => { b },
+                        None => {
+                                log::warning!("read_checksum_file(): ignoring malformed digest in {:?}", path);
+                                continue;
+                        }
+                };
+                let algorithm = match tag.and_then(algorithm_from_name).or_else(|| algorithm_from_file_name(&source_name)).or_else(|| algorithm_from_length(bytes.len())) {
+                        Some(a) => { a },
+                        None => {
+                                log::warning!("read_checksum_file(): unable to determine the hash algorithm of {:?}", path);
+                                continue;
+                        }
+                };
+                return Ok(Some(ExpectedDigest { digest: hash::Digest { algorithm, bytes }, source: path.to_path_buf() }));
+        }
+        Ok(None)
+}
+
+/// Splits a checksum line into its optional algorithm tag, optional file name and digest
+fn parse_line(line: &str) -> Option<(Option<&str>, Option<&str>, &str)> {
+        if let Some((head, hex)) = line.rsplit_once(") = ") {
+                let (tag, name) = head.split_once(" (")?;
+                return Some((Some(tag.trim()), Some(name), hex.trim()));
+        }
+        match line.split_once(char::is_whitespace) {
+                // the binary mode marker of the GNU format is not part of the name
+                Some((hex, name)) => { Some((None, Some(name.trim_start().trim_start_matches('*')), hex)) },
+                None => { Some((None, None, line)) }
+        }
+}
+
+fn algorithm_from_name(name: &str) -> Option<HashAlgorithm> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+                "sha256" => { Some(HashAlgorithm::Sha256) },
+                "sha512" => { Some(HashAlgorithm::Sha512) },
+                "blake3" | "b3" => { Some(HashAlgorithm::Blake3) },
+                "md5" => { Some(HashAlgorithm::Md5) },
+                _ => { None }
+        }
+}
+
+/// Takes the algorithm from checksum file names such as `SHA256SUMS` or `image.iso.sha256sum`
+fn algorithm_from_file_name(name: &str) -> Option<HashAlgorithm> {
+        let name = name.strip_suffix("sums").or_else(|| name.rsplit_once('.').map(|(_, extension)| extension))?;
+        algorithm_from_name(name.strip_suffix("sum").unwrap_or(name))
+}
+
+fn algorithm_from_length(length: usize) -> Option<HashAlgorithm> {
+        match length {
+                16 => { Some(HashAlgorithm::Md5) },
+                32 => { Some(HashAlgorithm::Sha256) },
+                64 => { Some(HashAlgorithm::Sha512) },
+                _ => { None }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        const ABC_MD5: &str = "900150983cd24fb0d6963f7d28e17f72";
+
+        /// A directory holding an image whose content is "abc"
+        fn directory(name: &str) -> PathBuf {
+                let path = std::env::temp_dir().join(format!("rmsd-checksum-{}-{}", name, std::process::id()));
+                let _ = std::fs::remove_dir_all(&path);
+                std::fs::create_dir_all(&path).unwrap();
+                std::fs::write(path.join("image.img"), b"abc").unwrap();
+                path
+        }
+
+        #[test]
+        fn gnu_bsd_and_bare_lines() {
+                assert_eq!(parse_line(&format!("{}  image.img", ABC_SHA256)), Some((None, Some("image.img"), ABC_SHA256)));
+                assert_eq!(parse_line(&format!("{} *image.img", ABC_SHA256)), Some((None, Some("image.img"), ABC_SHA256)));
+                assert_eq!(parse_line(&format!("{}  my image.img", ABC_SHA256)), Some((None, Some("my image.img"), ABC_SHA256)));
+                assert_eq!(parse_line(&format!("SHA256 (image.img) = {}", ABC_SHA256)), Some((Some("SHA256"), Some("image.img"), ABC_SHA256)));
+                assert_eq!(parse_line(&format!("MD5 (my (1).img) = {}", ABC_MD5)), Some((Some("MD5"), Some("my (1).img"), ABC_MD5)));
+                assert_eq!(parse_line(ABC_SHA256), Some((None, None, ABC_SHA256)));
+        }
+
+        #[test]
+        fn algorithms_are_detected() {
+                assert_eq!(algorithm_from_length(16), Some(HashAlgorithm::Md5));
+                assert_eq!(algorithm_from_length(32), Some(HashAlgorithm::Sha256));
+                assert_eq!(algorithm_from_length(64), Some(HashAlgorithm::Sha512));
+                assert_eq!(algorithm_from_length(20), None);
+                assert_eq!(algorithm_from_name("SHA-512"), Some(HashAlgorithm::Sha512));
+                assert_eq!(algorithm_from_name("B3"), Some(HashAlgorithm::Blake3));
+                assert_eq!(algorithm_from_file_name("sha256sums"), Some(HashAlgorithm::Sha256));
+                assert_eq!(algorithm_from_file_name("image.img.md5sum"), Some(HashAlgorithm::Md5));
+                assert_eq!(algorithm_from_file_name("image.img.blake3"), Some(HashAlgorithm::Blake3));
+                assert_eq!(algorithm_from_file_name("checksums.txt"), None);
+        }
+
+        #[test]
+        fn images_are_looked_up_by_file_name() {
+                let dir = directory("lookup");
+                let image = dir.join("image.img");
+                let sums = dir.join("SHA256SUMS");
+                std::fs::write(&sums, format!("# release\n{}  other.img\n{}  ./images/image.img\n", "00".repeat(32), ABC_SHA256)).unwrap();
+                let expected = read_checksum_file(&sums, &image).unwrap().unwrap();
+                assert_eq!(expected.digest.algorithm, HashAlgorithm::Sha256);
+                assert_eq!(expected.digest.to_hex(), ABC_SHA256);
+                assert_eq!(expected.source, sums);
+                assert_eq!(read_checksum_file(&sums, &dir.join("missing.img")).unwrap(), None);
+
+                // the BSD tag wins over the name of the file
+                std::fs::write(&sums, format!("MD5 (image.img) = {}\n", ABC_MD5)).unwrap();
+                assert_eq!(read_checksum_file(&sums, &image).unwrap().unwrap().digest.algorithm, HashAlgorithm::Md5);
+
+                // a bare digest is only taken from a file holding a single line
+                std::fs::write(dir.join("image.img.sha256"), format!("{}\n{}\n", ABC_SHA256, ABC_SHA256)).unwrap();
+                assert_eq!(read_checksum_file(&dir.join("image.img.sha256"), &image).unwrap(), None);
+                std::fs::write(dir.join("image.img.sha256"), format!("{}\n", ABC_SHA256)).unwrap();
+                assert_eq!(find_sidecar(&image).unwrap().unwrap().source, dir.join("image.img.sha256"));
+                std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn mismatching_images_are_refused() {
+                let dir = directory("mismatch");
+                let image = dir.join("image.img");
+                // without a checksum file there is nothing to check
+                assert!(verify_image_checksum(&image, None).unwrap());
+
+                std::fs::write(dir.join("image.img.md5"), format!("{}  image.img\n", ABC_MD5)).unwrap();
+                assert!(verify_image_checksum(&image, None).unwrap());
+                std::fs::write(&image, b"abd").unwrap();
+                assert!(!verify_image_checksum(&image, None).unwrap());
+
+                // an explicitly given checksum file has to list the image
+                let sums = dir.join("sums.txt");
+                std::fs::write(&sums, format!("{}  other.img\n", ABC_SHA256)).unwrap();
+                assert!(!verify_image_checksum(&image, Some(&sums)).unwrap());
+                assert!(verify_image_checksum(&image, Some(&dir.join("missing.txt"))).is_err());
+                std::fs::remove_dir_all(&dir).unwrap();
+        }
+}
@@ -0,0 +1,147 @@
+use clap::ValueEnum;
+use sha2::Digest as _;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+        Sha256,
+        Sha512,
+        Blake3,
+        Md5,
+}
+
+impl HashAlgorithm {
+        pub fn name(&self) -> &'static str {
+                match self {
+                        HashAlgorithm::Sha256 => { "sha256" },
+                        HashAlgorithm::Sha512 => { "sha512" },
+                        HashAlgorithm::Blake3 => { "blake3" },
+                        HashAlgorithm::Md5 => { "md5" }
+                }
+        }
+}
+
+enum State {
+        Sha256(sha2::Sha256),
+        Sha512(sha2::Sha512),
+        Blake3(Box<blake3::Hasher>),
+        Md5(md5::Md5),
+}
+
+/// Streaming hash computation, the data is fed in chunks as it is transferred
+pub struct Hasher {
+        algorithm: HashAlgorithm,
+        state: State,
+}
+
+impl Hasher {
+        pub fn new(algorithm: HashAlgorithm) -> Hasher {
+                let state = match algorithm {
+                        HashAlgorithm::Sha256 => { State::Sha256(sha2::Sha256::new()) },
+                        HashAlgorithm::Sha512 => { State::Sha512(sha2::Sha512::new()) },
+                        HashAlgorithm::Blake3 => { State::Blake3(Box::new(blake3::Hasher::new())) },
+                        HashAlgorithm::Md5 => { State::Md5(md5::Md5::new()) }
+                };
+                Hasher { algorithm, state }
+        }
+
+        pub fn update(&mut self, data: &[u8]) {
+                match &mut self.state {
+                        State::Sha256(h) => { h.update(data); },
+                        State::Sha512(h) => { h.update(data); },
+                        State::Blake3(h) => { h.update(data); },
+                        State::Md5(h) => { h.update(data); }
+                }
+        }
+
+        pub fn finalize(self) -> Digest {
+                let bytes = match self.state {
+                        State::Sha256(h) => { h.finalize().to_vec() },
+                        State::Sha512(h) => { h.finalize().to_vec() },
+                        State::Blake3(h) => { h.finalize().as_bytes().to_vec() },
+                        State::Md5(h) => { h.finalize().to_vec() }
+                };
+                Digest { algorithm: self.algorithm, bytes }
+        }
+}
+
+/// Feeds `data` to every hasher in `hashers`
+pub fn update_all(hashers: &mut [Hasher], data: &[u8]) {
+        for hasher in hashers.iter_mut() {
+                hasher.update(data);
+        }
+}
+
+/// Decodes a digest written in hexadecimal
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+        if hex.is_empty() || !hex.len().is_multiple_of(2) {
+                return None;
+        }
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+        pub algorithm: HashAlgorithm,
+        pub bytes: Vec<u8>,
+}
+
+impl Digest {
+        pub fn to_hex(&self) -> String {
+                self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+}
+
+impl fmt::Display for Digest {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}: {}", self.algorithm.name(), self.to_hex())
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        const ABC: [(HashAlgorithm, &str); 4] = [
+                (HashAlgorithm::Sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+                (HashAlgorithm::Sha512, "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"),
+                (HashAlgorithm::Blake3, "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+                (HashAlgorithm::Md5, "900150983cd24fb0d6963f7d28e17f72"),
+        ];
+
+        #[test]
+        fn known_answers() {
+                for (algorithm, expected) in ABC {
+                        let mut hasher = Hasher::new(algorithm);
+                        hasher.update(b"abc");
+                        let digest = hasher.finalize();
+                        assert_eq!(digest.to_hex(), expected);
+                        assert_eq!(digest.to_string(), format!("{}: {}", algorithm.name(), expected));
+                }
+        }
+
+        #[test]
+        fn chunks_hash_like_the_whole_data() {
+                let data: Vec<u8> = (0..100_000u32).map(|n| (n * 7 + n / 251) as u8).collect();
+                let mut hashers: Vec<Hasher> = ABC.iter().map(|(a, _)| Hasher::new(*a)).collect();
+                for chunk in data.chunks(4093) {
+                        update_all(&mut hashers, chunk);
+                }
+                for (hasher, (algorithm, _)) in hashers.into_iter().zip(ABC) {
+                        let mut whole = Hasher::new(algorithm);
+                        whole.update(&data);
+                        assert_eq!(hasher.finalize(), whole.finalize(), "{}", algorithm.name());
+                }
+        }
+
+        #[test]
+        fn hexadecimal_digests() {
+                assert_eq!(from_hex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+                assert_eq!(from_hex(""), None);
+                assert_eq!(from_hex("abc"), None);
+                assert_eq!(from_hex("zz"), None);
+                assert_eq!(from_hex("éé"), None);
+                let digest = Digest { algorithm: HashAlgorithm::Md5, bytes: from_hex(ABC[3].1).unwrap() };
+                assert_eq!(digest.to_hex(), ABC[3].1);
+        }
+}
@@ -0,0 +1,636 @@
+mod android_sparse;
+mod archive;
+mod compression;
+mod dmg;
+mod encoder;
+mod extents;
+mod lzfse;
+mod qcow2;
+mod split;
+mod vhd;
+mod vhdx;
+mod vmdk;
+
+use std::cell::Cell;
+use std::fs::File;
+use clap::ValueEnum;
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::log;
+
+pub use compression::Compression;
+
+/// Number of bytes needed to recognize every supported compression, format and archive, tar headers are the
+/// longest
+const MAGIC_LENGTH: usize = archive::TAR_BLOCK_SIZE;
+
+/// Counts the bytes read from the stored (possibly compressed) image
+struct CountingReader<R: Read> {
+        inner: R,
+        count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.inner.read(buf)?;
+                self.count.set(self.count.get() + n as u64);
+                Ok(n)
+        }
+}
+
+/// A disk image whose blocks are looked up in tables, any part of the disk can be read
+trait VirtualDisk: Read + Seek {
+        /// Size of the virtual disk
+        fn size(&self) -> u64;
+        /// Lists the byte ranges of the virtual disk that are stored in the image, everything else reads as zeros
+        fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>>;
+}
+
+/// The position of a virtual disk of `size` bytes after seeking from `current`
+fn seek_position(current: u64, size: u64, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+                SeekFrom::Start(offset) => { Some(offset) },
+                SeekFrom::Current(offset) => { current.checked_add_signed(offset) },
+                SeekFrom::End(offset) => { size.checked_add_signed(offset) }
+        };
+        position.ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the disk"))
+}
+
+/// The stream an image is read from, uncompressed images and virtual disks can seek past the parts that are
+/// not needed
+enum Source {
+        Raw(CountingReader<split::Reader>),
+        Decoded(Box<dyn Read>),
+        Virtual(Box<dyn VirtualDisk>),
+}
+
+impl Read for Source {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self {
+                        Source::Raw(reader) => { reader.read(buf) },
+                        Source::Decoded(reader) => { reader.read(buf) },
+                        Source::Virtual(reader) => { reader.read(buf) }
+                }
+        }
+}
+
+/// How the blocks of the image are laid out in the (decoded) file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+        /// Plain copy of the disk, sector by sector
+        Raw,
+        /// Android sparse image (simg), made of RAW, FILL and DONT_CARE chunks
+        AndroidSparse,
+        /// QEMU copy-on-write disk (versions 2 and 3), its clusters may be compressed or stored in backing files
+        Qcow2,
+        /// Virtual PC / Hyper-V disk, fixed or dynamic
+        #[value(skip)]
+        Vhd,
+        /// Hyper-V disk, fixed or dynamic
+        Vhdx,
+        /// VMware monolithic sparse disk (streamOptimized included)
+        #[value(skip)]
+        Vmdk,
+        /// Apple UDIF disk image, its chunks may be zlib, bzip2, LZFSE or ADC compressed
+        #[value(skip)]
+        Dmg,
+}
+
+impl Format {
+        pub fn detect(magic: &[u8]) -> Format {
+                if android_sparse::is_sparse(magic) {
+                        Format::AndroidSparse
+                } else if qcow2::is_qcow2(magic) {
+                        Format::Qcow2
+                } else if vhd::is_dynamic(magic) {
+                        Format::Vhd
+                } else if vhdx::is_vhdx(magic) {
+                        Format::Vhdx
+                } else if vmdk::is_vmdk(magic) {
+                        Format::Vmdk
+                } else {
+                        Format::Raw
+                }
+        }
+
+        pub fn name(&self) -> &'static str {
+                match self {
+                        Format::Raw => { "raw" },
+                        Format::AndroidSparse => { "Android sparse" },
+                        Format::Qcow2 => { "qcow2" },
+                        Format::Vhd => { "VHD" },
+                        Format::Vhdx => { "VHDX" },
+                        Format::Vmdk => { "VMDK" },
+                        Format::Dmg => { "DMG" }
+                }
+        }
+
+        /// Whether the blocks of the image are looked up in tables, which needs the whole file at hand
+        fn is_virtual_disk(&self) -> bool {
+                matches!(self, Format::Qcow2 | Format::Vhd | Format::Vhdx | Format::Vmdk | Format::Dmg)
+        }
+}
+
+/// The image path standing for the standard input when flashing and the standard output when cloning
+pub const STDIO_PATH: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+        path == Path::new(STDIO_PATH)
+}
+
+/// Where an image is read from, `entry` names the image inside a zip or tar archive. The size of images read
+/// from the standard input is only known when it is given as `input_size`
+#[derive(Debug, Clone, Copy)]
+pub struct Location<'a> {
+        pub path: &'a Path,
+        pub entry: Option<&'a str>,
+        pub input_size: Option<u64>,
+}
+
+/// A source image, compressed images are decoded on the fly while they are read, the chunks of Android sparse
+/// images are expanded and the blocks of virtual disks (qcow2, VHD, VHDX, VMDK and DMG) are looked up. Images stored
+/// in zip and tar archives are streamed from the archive
+pub struct Image {
+        reader: Source,
+        compression: Compression,
+        format: Format,
+        /// Size of the decoded image, if the format records it
+        size: Option<u64>,
+        /// Size of the stored image, unknown for the standard input
+        stored_size: Option<u64>,
+        consumed: Rc<Cell<u64>>,
+        /// Byte ranges holding data when the image is a sparse file, everything else reads as zeros
+        extents: Option<Vec<Range<u64>>>,
+        /// Byte ranges whose content the image leaves undefined, the DONT_CARE chunks of Android sparse images
+        undefined: Vec<Range<u64>>,
+}
+
+/// A decoded stream and what is known about the image it holds
+struct Stream {
+        reader: Box<dyn Read>,
+        compression: Compression,
+        format: Format,
+        size: Option<u64>,
+        /// Name of the archive entry the image is read from
+        entry: Option<String>,
+}
+
+/// Looks into a stream, when `probe` is set (the stream was decompressed or extracted from an archive) tar
+/// archives are searched for the image, entries compressed on their own are decoded and the format is
+/// detected again. Android sparse images are expanded
+fn inspect(mut stream: Stream, probe: bool, location: &Location) -> std::io::Result<Stream> {
+        if probe {
+                let header;
+                (header, stream.reader) = peek(stream.reader, MAGIC_LENGTH)?;
+                if archive::is_tar(&header) {
+                        let (tar, reader) = archive::tar_entry(stream.reader, location.entry)?;
+                        (stream.size, stream.reader) = (Some(tar.size), reader);
+                        stream.entry = Some(tar.name);
+                }
+                // the entry of an archive may be compressed on its own
+                if stream.entry.is_some() {
+                        let header;
+                        (header, stream.reader) = peek(stream.reader, MAGIC_LENGTH)?;
+                        let inner = Compression::detect(&header);
+                        if inner != Compression::None {
+                                (stream.compression, stream.size) = (inner, None);
+                                stream.reader = compression::decoder(inner, stream.reader)?;
+                        }
+                }
+                let header;
+                (header, stream.reader) = peek(stream.reader, android_sparse::FILE_HEADER_SIZE)?;
+                stream.format = Format::detect(&header);
+                if stream.format.is_virtual_disk() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} images have to be {} before they are flashed", stream.format.name(), if stream.entry.is_some() { "extracted" } else { "decompressed" })));
+                }
+        }
+        if stream.format == Format::AndroidSparse {
+                let expanded = android_sparse::Reader::new(stream.reader)?;
+                stream.size = Some(expanded.size());
+                stream.reader = Box::new(expanded);
+        }
+        Ok(stream)
+}
+
+/// Reads the first `length` bytes of `reader`, returning them along with a reader that still yields them
+fn peek(mut reader: Box<dyn Read>, length: usize) -> std::io::Result<(Vec<u8>, Box<dyn Read>)> {
+        let mut header = vec![0u8; length];
+        let header_length = read_full(&mut reader, &mut header)?;
+        header.truncate(header_length);
+        Ok((header.clone(), Box::new(Cursor::new(header).chain(reader))))
+}
+
+impl Image {
+        /// Opens the image and detects its compression and format from their magic bytes, Android sparse images
+        /// are also recognized once decoded. The image of a zip or tar archive (possibly compressed) is the entry
+        /// named by `location.entry`, or else its single disk image. The parts of a split image (`image.001`,
+        /// `image.002`...) are read as one file
+        pub fn open(location: &Location) -> std::io::Result<Image> {
+                if is_stdio(location.path) {
+                        return Image::open_stdin(location);
+                }
+                match Image::open_file(location) {
+                        // the single file of a tar archive is only known once the archive was read past it
+                        Err(e) if location.entry.is_none() && e.get_ref().is_some_and(|e| e.is::<archive::SingleEntry>()) => {
+                                let name = e.into_inner().unwrap().downcast::<archive::SingleEntry>().unwrap().0;
+                                log::debug!("Image::open(): {:?} holds the single entry {:?}, it is read again", location.path, name);
+                                Image::open_file(&Location { entry: Some(&name), ..*location })
+                        },
+                        result => { result }
+                }
+        }
+
+        fn open_file(location: &Location) -> std::io::Result<Image> {
+                let path = location.path;
+                let parts = split::parts(path)?;
+                let mut file = match &parts {
+                        Some(parts) => { split::Reader::open(parts)? },
+                        None => { split::Reader::open(&[path.to_path_buf()])? }
+                };
+                let mut stored_size = file.size();
+                let mut magic = [0u8; MAGIC_LENGTH];
+                let magic_length = read_full(&mut file, &mut magic)?;
+                let magic = &magic[..magic_length];
+                let mut compression = Compression::detect(magic);
+                let mut format = Format::detect(magic);
+                // the data of DMG images starts with their first chunk, which may look like a bzip2 stream
+                if dmg::has_trailer(&mut file, stored_size)? {
+                        (compression, format) = (Compression::None, Format::Dmg);
+                }
+                let is_archive = compression == Compression::None && format == Format::Raw && (archive::is_zip(magic) || archive::is_tar(magic));
+                if compression == Compression::None && format == Format::Raw && !is_archive && vhd::has_footer(&mut file, stored_size)? {
+                        format = Format::Vhd;
+                }
+                if let (Some(parts), true) = (&parts, format.is_virtual_disk()) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("split {} images are not supported, the {} parts have to be joined first", format.name(), parts.len())));
+                }
+                let mut disk: Option<Box<dyn VirtualDisk>> = match (compression, format) {
+                        (Compression::None, Format::Qcow2) => { Some(Box::new(qcow2::Reader::open(path)?)) },
+                        (Compression::None, Format::Vhd) => { Some(Box::new(vhd::Reader::open(path)?)) },
+                        (Compression::None, Format::Vhdx) => { Some(Box::new(vhdx::Reader::open(path)?)) },
+                        (Compression::None, Format::Vmdk) => { Some(Box::new(vmdk::Reader::open(path)?)) },
+                        (Compression::None, Format::Dmg) => { Some(Box::new(dmg::Reader::open(path)?)) },
+                        _ => { None }
+                };
+                let mut undefined = vec![];
+                let (mut size, extents) = match (compression, format, &mut disk) {
+                        (_, _, Some(disk)) => { (Some(disk.size()), Some(disk.data_extents()?)) },
+                        // the size of the image is recorded by the archive
+                        _ if is_archive => { (None, None) },
+                        (Compression::None, Format::Raw, None) => { (Some(stored_size), file.data_extents()?) },
+                        (Compression::None, Format::AndroidSparse, None) => {
+                                let extents = android_sparse::data_extents(&mut file)?;
+                                undefined = extents.dont_care;
+                                (None, Some(extents.data))
+                        },
+                        _ => { (compression::decoded_size(compression, &mut file)?, None) }
+                };
+                // zip entries are looked up in the central directory at the end of the file before they are read
+                let zip = match is_archive && archive::is_zip(magic) {
+                        true => { Some(archive::zip_entry(&mut file, location.entry)?) },
+                        false => {
+                                file.seek(SeekFrom::Start(0))?;
+                                None
+                        }
+                };
+                let consumed = Rc::new(Cell::new(0));
+                let counting = CountingReader { inner: file, count: consumed.clone() };
+                let mut entry = None;
+                let reader = if let Some(disk) = disk {
+                        Source::Virtual(disk)
+                } else if compression == Compression::None && format == Format::Raw && !is_archive {
+                        Source::Raw(counting)
+                } else {
+                        let decoded: Box<dyn Read> = match zip {
+                                Some(zip) => {
+                                        (stored_size, size) = (zip.stored_size, Some(zip.size));
+                                        let reader = zip.zip_reader(counting)?;
+                                        entry = Some(zip.name);
+                                        reader
+                                },
+                                None => { compression::decoder(compression, counting)? }
+                        };
+                        let stream = inspect(Stream { reader: decoded, compression, format, size, entry }, compression != Compression::None || is_archive, location)?;
+                        (compression, format, size, entry) = (stream.compression, stream.format, stream.size, stream.entry);
+                        Source::Decoded(stream.reader)
+                };
+                if let (Some(name), None) = (location.entry, &entry) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} is not a zip or tar archive, it has no entry {:?}", path, name)));
+                }
+                if let Some(parts) = &parts {
+                        log::debug!("Image::open(): {:?} is split in {} parts, from {:?} to {:?}", path, parts.len(), parts[0], parts[parts.len() - 1]);
+                }
+                log::debug!("Image::open(): {:?}{} is a {} {} image ({} bytes stored, decoded size: {:?})", path, entry.map(|e| format!(" entry {:?}", e)).unwrap_or_default(), compression.name(), format.name(), stored_size, size);
+                if let Some(extents) = &extents {
+                        log::debug!("Image::open(): {:?} is sparse, {} bytes of data in {} extents", path, extents.iter().map(|e| e.end - e.start).sum::<u64>(), extents.len());
+                }
+                Ok(Image { reader, compression, format, size, stored_size: Some(stored_size), consumed, extents, undefined })
+        }
+
+        /// Opens the image streamed on the standard input, which cannot seek: its compression, tar archives and
+        /// Android sparse images are recognized but the formats whose tables have to be looked up are refused
+        fn open_stdin(location: &Location) -> std::io::Result<Image> {
+                let consumed = Rc::new(Cell::new(0));
+                let counting = CountingReader { inner: std::io::stdin().lock(), count: consumed.clone() };
+                let (magic, reader) = peek(Box::new(counting), MAGIC_LENGTH)?;
+                let compression = Compression::detect(&magic);
+                let format = Format::detect(&magic);
+                if compression == Compression::None && (format.is_virtual_disk() || archive::is_zip(&magic)) {
+                        let name = if format.is_virtual_disk() { format!("{} images", format.name()) } else { String::from("zip archives") };
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} cannot be read from the standard input, they have to be stored in a file", name)));
+                }
+                let decoded = compression::decoder(compression, reader)?;
+                let stream = inspect(Stream { reader: decoded, compression, format, size: location.input_size, entry: None }, true, location)?;
+                if let (Some(name), None) = (location.entry, &stream.entry) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("the standard input is not a tar archive, it has no entry {:?}", name)));
+                }
+                log::debug!("Image::open_stdin(): the standard input{} is a {} {} image (decoded size: {:?})", stream.entry.as_ref().map(|e| format!(" entry {:?}", e)).unwrap_or_default(), stream.compression.name(), stream.format.name(), stream.size);
+                Ok(Image { reader: Source::Decoded(stream.reader), compression: stream.compression, format: stream.format, size: stream.size, stored_size: None, consumed, extents: None, undefined: vec![] })
+        }
+
+        pub fn compression(&self) -> Compression {
+                self.compression
+        }
+
+        pub fn format(&self) -> Format {
+                self.format
+        }
+
+        /// The size of the decoded image, `None` if it can only be known by decoding the whole image
+        pub fn size(&self) -> Option<u64> {
+                self.size
+        }
+
+        /// The byte ranges of a sparse image that hold data, `None` if the whole image has to be read
+        pub fn data_extents(&self) -> Option<&[Range<u64>]> {
+                self.extents.as_deref()
+        }
+
+        /// The byte ranges whose content the image leaves undefined, they read as zeros but are never written
+        pub fn undefined_extents(&self) -> &[Range<u64>] {
+                &self.undefined
+        }
+
+        /// Moves `length` bytes forward in the decoded image without returning them
+        pub fn skip(&mut self, length: u64) -> std::io::Result<()> {
+                match &mut self.reader {
+                        Source::Raw(reader) => {
+                                reader.inner.seek(SeekFrom::Current(length as i64))?;
+                                reader.count.set(reader.count.get() + length);
+                        },
+                        Source::Decoded(reader) => { std::io::copy(&mut reader.take(length), &mut std::io::sink())?; },
+                        Source::Virtual(reader) => { reader.seek(SeekFrom::Current(length as i64))?; }
+                }
+                Ok(())
+        }
+
+        /// The decoded size if it is known, otherwise an estimate extrapolated from the part of the stored
+        /// image consumed to produce `decoded` bytes, `None` when neither size is known
+        pub fn estimated_size(&self, decoded: u64) -> Option<u64> {
+                if let Some(size) = self.size {
+                        return Some(size);
+                }
+                let stored_size = self.stored_size?;
+                let consumed = self.consumed.get();
+                if consumed == 0 || decoded == 0 {
+                        return Some(stored_size);
+                }
+                Some((decoded as f64 * stored_size as f64 / consumed as f64) as u64)
+        }
+}
+
+impl Read for Image {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.reader.read(buf)
+        }
+}
+
+/// How clone output is stored
+#[derive(Debug, Clone, Copy)]
+pub struct OutputOptions {
+        pub format: Format,
+        pub compression: Compression,
+        /// Compression level, the format's default is used when not given
+        pub level: Option<i32>,
+        /// Number of compression worker threads
+        pub threads: usize,
+        /// Size of the parts raw images are split in, `image.001`, `image.002`...
+        pub split_size: Option<u64>,
+}
+
+/// Where a cloned image is written
+pub enum Destination {
+        Path(PathBuf),
+        /// The original standard output, taken over by `Destination::stdout`
+        Stdout(File),
+}
+
+impl Destination {
+        /// Takes the standard output over to write the image to it, what is printed afterwards goes to the
+        /// standard error so that it does not end up in the image
+        #[cfg(unix)]
+        pub fn stdout() -> std::io::Result<Destination> {
+                use std::io::IsTerminal;
+                use std::os::unix::io::FromRawFd;
+                if std::io::stdout().is_terminal() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the standard output is a terminal, redirect it to a file or a pipe"));
+                }
+                std::io::stdout().flush()?;
+                // SAFETY: dup and dup2 only create descriptors, the duplicate is owned by the returned file
+                let image = unsafe { libc::dup(libc::STDOUT_FILENO) };
+                if image < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+                        return Err(std::io::Error::last_os_error());
+                }
+                Ok(Destination::Stdout(unsafe { File::from_raw_fd(image) }))
+        }
+
+        #[cfg(not(unix))]
+        pub fn stdout() -> std::io::Result<Destination> {
+                Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "images can only be written to the standard output on unix systems"))
+        }
+
+        /// The path of the image, `-` for the standard output
+        pub fn path(&self) -> &Path {
+                match self {
+                        Destination::Path(path) => { path },
+                        Destination::Stdout(_) => { Path::new(STDIO_PATH) }
+                }
+        }
+}
+
+/// The destination of a cloned image, `finish` must be called once all the data is written
+pub trait ImageWriter: Write {
+        fn finish(self: Box<Self>) -> std::io::Result<()>;
+}
+
+/// Where raw images are written, a single file or the parts of a split image
+pub trait RawOutput: Write + Seek {
+        fn set_len(&mut self, length: u64) -> std::io::Result<()>;
+}
+
+impl RawOutput for File {
+        fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+                File::set_len(self, length)
+        }
+}
+
+impl RawOutput for split::Writer {
+        fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+                split::Writer::set_len(self, length)
+        }
+}
+
+/// Size of the blocks checked for zeros, a common filesystem block size so that skipped blocks become holes
+const SPARSE_BLOCK_SIZE: u64 = 4096;
+
+/// An uncompressed output image that seeks past all-zero blocks instead of writing them, leaving holes on
+/// filesystems that support them, the file is extended to its full length by `finish`
+pub struct SparseFile {
+        output: BufWriter<Box<dyn RawOutput>>,
+        /// Number of bytes received so far
+        length: u64,
+        /// Number of zero bytes skipped since the last write
+        skipped: u64,
+}
+
+impl SparseFile {
+        pub fn new(output: Box<dyn RawOutput>) -> SparseFile {
+                SparseFile { output: BufWriter::new(output), length: 0, skipped: 0 }
+        }
+}
+
+impl Write for SparseFile {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                // only look at the data up to the next block boundary so that whole blocks are skipped
+                let to_boundary = (SPARSE_BLOCK_SIZE - self.length % SPARSE_BLOCK_SIZE) as usize;
+                let block = &buf[..buf.len().min(to_boundary)];
+                if block.iter().all(|&b| b == 0) {
+                        self.skipped += block.len() as u64;
+                } else {
+                        if self.skipped > 0 {
+                                self.output.seek(SeekFrom::Current(self.skipped as i64))?;
+                                self.skipped = 0;
+                        }
+                        self.output.write_all(block)?;
+                }
+                self.length += block.len() as u64;
+                Ok(block.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+                self.output.flush()
+        }
+}
+
+impl ImageWriter for SparseFile {
+        fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+                self.output.flush()?;
+                // trailing zero blocks were never written
+                self.output.get_mut().set_len(self.length)
+        }
+}
+
+/// Images written to the standard output are written whole, pipes cannot seek over the zero blocks
+impl ImageWriter for BufWriter<File> {
+        fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+                self.flush()
+        }
+}
+
+impl ImageWriter for encoder::ParallelEncoder {
+        fn finish(self: Box<Self>) -> std::io::Result<()> {
+                encoder::ParallelEncoder::finish(*self)
+        }
+}
+
+impl ImageWriter for android_sparse::Writer {
+        fn finish(self: Box<Self>) -> std::io::Result<()> {
+                android_sparse::Writer::finish(*self)
+        }
+}
+
+impl ImageWriter for qcow2::Writer {
+        fn finish(self: Box<Self>) -> std::io::Result<()> {
+                qcow2::Writer::finish(*self)
+        }
+}
+
+impl ImageWriter for vhdx::Writer {
+        fn finish(self: Box<Self>) -> std::io::Result<()> {
+                vhdx::Writer::finish(*self)
+        }
+}
+
+/// Creates the output image of `size` bytes made of sectors of `sector_size` bytes, compressing it on
+/// `options.threads` worker threads if requested, uncompressed raw images are written as sparse files. Raw
+/// images, compressed or not, can be split in parts of `options.split_size` bytes or written to the standard
+/// output
+pub fn create(destination: Destination, options: &OutputOptions, size: u64, sector_size: u32) -> std::io::Result<Box<dyn ImageWriter>> {
+        if options.format != Format::Raw && options.format != Format::Qcow2 && options.compression != Compression::None {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} images cannot be compressed", options.format.name())));
+        }
+        if options.format == Format::Qcow2 && !matches!(options.compression, Compression::None | Compression::Gzip | Compression::Zstd) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "qcow2 clusters can only be compressed with gzip (deflate) or zstd"));
+        }
+        let level = match (options.compression.level_range(), options.level) {
+                (Some(range), Some(level)) if !range.contains(&level) => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} compression levels range from {} to {}", options.compression.name(), range.start(), range.end())));
+                },
+                (Some(_), level) => { level.unwrap_or(options.compression.default_level()) },
+                (None, _) => { 0 }
+        };
+        if options.format != Format::Raw && options.split_size.is_some() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} images cannot be split", options.format.name())));
+        }
+        let path = destination.path().to_path_buf();
+        let output: Box<dyn Write> = match (destination, options.split_size) {
+                (Destination::Stdout(_), Some(_)) => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "images written to the standard output cannot be split"));
+                },
+                (Destination::Stdout(_), None) if options.format != Format::Raw => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} images cannot be written to the standard output, their tables are written last", options.format.name())));
+                },
+                (Destination::Stdout(file), None) if options.compression == Compression::None => { return Ok(Box::new(BufWriter::new(file))); },
+                (Destination::Stdout(file), None) => { Box::new(file) },
+                (Destination::Path(path), split_size) => {
+                        let output: Box<dyn RawOutput> = match split_size {
+                                Some(part_size) => {
+                                        log::debug!("create(): {:?} is split in parts of {} bytes, from {:?}", path, part_size, split::part_path(&path, 0));
+                                        Box::new(split::Writer::create(&path, part_size)?)
+                                },
+                                None => {
+                                        let file = File::create(&path)?;
+                                        match options.format {
+                                                Format::AndroidSparse => { return Ok(Box::new(android_sparse::Writer::new(file, size)?)); },
+                                                Format::Qcow2 => { return Ok(Box::new(qcow2::Writer::new(file, size, options.compression, level)?)); },
+                                                Format::Vhdx => { return Ok(Box::new(vhdx::Writer::new(file, size, sector_size)?)); },
+                                                Format::Vhd | Format::Vmdk | Format::Dmg => { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} images cannot be written", options.format.name()))); },
+                                                Format::Raw => { Box::new(file) }
+                                        }
+                                }
+                        };
+                        if options.compression == Compression::None {
+                                return Ok(Box::new(SparseFile::new(output)));
+                        }
+                        output
+                }
+        };
+        log::debug!("create(): {:?} is {}, level {} on {} threads", path, options.compression.name(), level, options.threads);
+        Ok(Box::new(encoder::ParallelEncoder::new(output, options.compression, level, options.threads)))
+}
+
+/// Reads until `buf` is full or the end of the stream is reached, returning the number of bytes read
+pub fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+                match reader.read(&mut buf[filled..]) {
+                        Ok(0) => { break; },
+                        Ok(n) => { filled += n; },
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { },
+                        Err(e) => { return Err(e); }
+                }
+        }
+        Ok(filled)
+}
@@ -0,0 +1,340 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+const MAGIC: u32 = 0xED26FF3A;
+const MAJOR_VERSION: u16 = 1;
+pub const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+const DEFAULT_BLOCK_SIZE: u64 = 4096;
+/// Largest RAW chunk emitted when writing, in blocks
+const MAX_RAW_CHUNK_BLOCKS: usize = 4096;
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid Android sparse image, {}", message))
+}
+
+pub fn is_sparse(magic: &[u8]) -> bool {
+        magic.len() >= 4 && u32::from_le_bytes(magic[..4].try_into().unwrap()) == MAGIC
+}
+
+/// The file header of an Android sparse image
+#[derive(Debug, Clone, Copy)]
+struct Header {
+        file_header_size: usize,
+        chunk_header_size: usize,
+        block_size: u32,
+        total_blocks: u32,
+        total_chunks: u32,
+        checksum: u32,
+}
+
+impl Header {
+        fn read(reader: &mut impl Read) -> std::io::Result<Header> {
+                let mut data = [0u8; FILE_HEADER_SIZE];
+                reader.read_exact(&mut data)?;
+                let u16_at = |offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+                let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                if !is_sparse(&data) || u16_at(4) != MAJOR_VERSION {
+                        return Err(invalid(format!("unsupported version {}.{}", u16_at(4), u16_at(6))));
+                }
+                let header = Header {
+                        file_header_size: usize::from(u16_at(8)),
+                        chunk_header_size: usize::from(u16_at(10)),
+                        block_size: u32_at(12),
+                        total_blocks: u32_at(16),
+                        total_chunks: u32_at(20),
+                        checksum: u32_at(24),
+                };
+                if header.file_header_size < FILE_HEADER_SIZE || header.chunk_header_size < CHUNK_HEADER_SIZE || header.block_size == 0 || !header.block_size.is_multiple_of(4) {
+                        return Err(invalid(format!("malformed header {:?}", header)));
+                }
+                // headers may be extended by later versions
+                std::io::copy(&mut reader.take((header.file_header_size - FILE_HEADER_SIZE) as u64), &mut std::io::sink())?;
+                Ok(header)
+        }
+
+        fn size(&self) -> u64 {
+                u64::from(self.total_blocks) * u64::from(self.block_size)
+        }
+
+        /// Reads a chunk header, returning its type, its size in blocks and the size of its data
+        fn read_chunk(&self, reader: &mut impl Read) -> std::io::Result<(u16, u64, u64)> {
+                let mut data = [0u8; CHUNK_HEADER_SIZE];
+                reader.read_exact(&mut data)?;
+                std::io::copy(&mut reader.take((self.chunk_header_size - CHUNK_HEADER_SIZE) as u64), &mut std::io::sink())?;
+                let chunk_type = u16::from_le_bytes([data[0], data[1]]);
+                let blocks = u64::from(u32::from_le_bytes(data[4..8].try_into().unwrap()));
+                let total_size = u64::from(u32::from_le_bytes(data[8..12].try_into().unwrap()));
+                let data_size = total_size.checked_sub(self.chunk_header_size as u64).ok_or(invalid(format!("chunk of {} bytes is smaller than its header", total_size)))?;
+                let expected = match chunk_type {
+                        CHUNK_RAW => { blocks * u64::from(self.block_size) },
+                        CHUNK_FILL | CHUNK_CRC32 => { 4 },
+                        CHUNK_DONT_CARE => { 0 },
+                        _ => { return Err(invalid(format!("unknown chunk type {:#06x}", chunk_type))); }
+                };
+                if data_size != expected {
+                        return Err(invalid(format!("chunk {:#06x} of {} blocks carries {} bytes of data", chunk_type, blocks, data_size)));
+                }
+                Ok((chunk_type, blocks, data_size))
+        }
+}
+
+/// Byte ranges of the expanded image, by kind of chunk
+pub struct Extents {
+        /// Covered by RAW and FILL chunks, FILL chunks of zeros included as the blocks they cover are meant to be written
+        pub data: Vec<Range<u64>>,
+        /// Covered by DONT_CARE chunks, whose content the image leaves undefined
+        pub dont_care: Vec<Range<u64>>,
+}
+
+/// Walks the chunk headers of a seekable image and lists the byte ranges of the expanded image covered by
+/// each kind of chunk
+pub fn data_extents<R: Read + Seek>(reader: &mut R) -> std::io::Result<Extents> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = Header::read(reader)?;
+        let mut extents: Vec<Range<u64>> = vec![];
+        let mut dont_care: Vec<Range<u64>> = vec![];
+        let mut offset: u64 = 0;
+        for _ in 0..header.total_chunks {
+                let (chunk_type, blocks, data_size) = header.read_chunk(reader)?;
+                reader.seek(SeekFrom::Current(data_size as i64))?;
+                let length = blocks * u64::from(header.block_size);
+                match chunk_type {
+                        CHUNK_RAW | CHUNK_FILL => { super::extents::push(&mut extents, offset..offset + length); },
+                        CHUNK_DONT_CARE => { super::extents::push(&mut dont_care, offset..offset + length); },
+                        _ => {}
+                };
+                offset += length;
+        }
+        Ok(Extents { data: extents, dont_care })
+}
+
+/// The chunk being expanded and the number of bytes of it left
+enum Chunk {
+        Raw(u64),
+        Fill([u8; 4], u64),
+        DontCare(u64),
+}
+
+/// Expands an Android sparse image on the fly, DONT_CARE chunks read as zeros and the CRC32 chunks (as well
+/// as the checksum of the header, when set) are verified
+pub struct Reader<R: Read> {
+        inner: R,
+        header: Header,
+        chunks_left: u32,
+        chunk: Chunk,
+        offset: u64,
+        crc: crc32fast::Hasher,
+}
+
+impl<R: Read> Reader<R> {
+        pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+                let header = Header::read(&mut inner)?;
+                Ok(Reader { inner, header, chunks_left: header.total_chunks, chunk: Chunk::DontCare(0), offset: 0, crc: crc32fast::Hasher::new() })
+        }
+
+        /// Size of the expanded image
+        pub fn size(&self) -> u64 {
+                self.header.size()
+        }
+
+        /// Moves to the next chunk holding data, returns false at the end of the image
+        fn next_chunk(&mut self) -> std::io::Result<bool> {
+                loop {
+                        if self.chunks_left == 0 {
+                                if self.offset != self.header.size() {
+                                        return Err(invalid(format!("the chunks describe {} of {} bytes", self.offset, self.header.size())));
+                                }
+                                if self.header.checksum != 0 && self.crc.clone().finalize() != self.header.checksum {
+                                        return Err(invalid(String::from("the image does not match the checksum of its header")));
+                                }
+                                return Ok(false);
+                        }
+                        self.chunks_left -= 1;
+                        let (chunk_type, blocks, _) = self.header.read_chunk(&mut self.inner)?;
+                        let length = blocks * u64::from(self.header.block_size);
+                        if self.offset + length > self.header.size() {
+                                return Err(invalid(format!("chunk at byte {} extends past the end of the image", self.offset)));
+                        }
+                        let mut value = [0u8; 4];
+                        self.chunk = match chunk_type {
+                                CHUNK_RAW => { Chunk::Raw(length) },
+                                CHUNK_FILL => {
+                                        self.inner.read_exact(&mut value)?;
+                                        Chunk::Fill(value, length)
+                                },
+                                CHUNK_DONT_CARE => { Chunk::DontCare(length) },
+                                _ => {
+                                        self.inner.read_exact(&mut value)?;
+                                        if self.crc.clone().finalize() != u32::from_le_bytes(value) {
+                                                return Err(invalid(format!("CRC32 mismatch at byte {}", self.offset)));
+                                        }
+                                        continue;
+                                }
+                        };
+                        if length > 0 {
+                                return Ok(true);
+                        }
+                }
+        }
+}
+
+impl<R: Read> Read for Reader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let left = match self.chunk { Chunk::Raw(n) | Chunk::Fill(_, n) | Chunk::DontCare(n) => { n } };
+                if left == 0 && !self.next_chunk()? {
+                        return Ok(0);
+                }
+                let count = match &mut self.chunk {
+                        Chunk::Raw(left) => {
+                                let length = (*left).min(buf.len() as u64) as usize;
+                                let count = self.inner.read(&mut buf[..length])?;
+                                if count == 0 {
+                                        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the Android sparse image is truncated"));
+                                }
+                                *left -= count as u64;
+                                count
+                        },
+                        Chunk::Fill(value, left) => {
+                                let count = (*left).min(buf.len() as u64) as usize;
+                                // chunks start on block boundaries, which are multiples of the pattern's length
+                                let phase = (self.offset % 4) as usize;
+                                for (i, byte) in buf[..count].iter_mut().enumerate() {
+                                        *byte = value[(phase + i) % 4];
+                                }
+                                *left -= count as u64;
+                                count
+                        },
+                        Chunk::DontCare(left) => {
+                                let count = (*left).min(buf.len() as u64) as usize;
+                                buf[..count].fill(0);
+                                *left -= count as u64;
+                                count
+                        }
+                };
+                self.crc.update(&buf[..count]);
+                self.offset += count as u64;
+                if self.offset == self.header.size() {
+                        // readers stop at the expanded size, the trailing CRC32 chunks are checked now
+                        self.next_chunk()?;
+                }
+                Ok(count)
+        }
+}
+
+/// The chunk being accumulated by the writer
+enum Pending {
+        None,
+        Raw(Vec<u8>),
+        Fill([u8; 4], u32),
+}
+
+/// Writes an Android sparse image, blocks repeating a 4 byte pattern (zeros included) become FILL chunks and
+/// the others RAW chunks. The header is rewritten by `finish` once the number of chunks is known
+pub struct Writer {
+        output: BufWriter<File>,
+        block_size: usize,
+        block: Vec<u8>,
+        pending: Pending,
+        blocks: u32,
+        chunks: u32,
+}
+
+impl Writer {
+        /// Creates a writer for an image of `size` bytes, the block size is the largest power of two up to 4KiB
+        /// that divides it
+        pub fn new(file: File, size: u64) -> std::io::Result<Writer> {
+                let block_size = (2..=DEFAULT_BLOCK_SIZE.trailing_zeros()).rev().map(|shift| 1u64 << shift).find(|b| size.is_multiple_of(*b))
+                        .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the image size is not a multiple of 4 bytes"))?;
+                if size / block_size > u64::from(u32::MAX) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the image is too large for the Android sparse format"));
+                }
+                let mut writer = Writer { output: BufWriter::new(file), block_size: block_size as usize, block: Vec::with_capacity(block_size as usize), pending: Pending::None, blocks: 0, chunks: 0 };
+                writer.write_header()?;
+                Ok(writer)
+        }
+
+        fn write_header(&mut self) -> std::io::Result<()> {
+                let mut header = [0u8; FILE_HEADER_SIZE];
+                header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+                header[4..6].copy_from_slice(&MAJOR_VERSION.to_le_bytes());
+                header[8..10].copy_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+                header[10..12].copy_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+                header[12..16].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+                header[16..20].copy_from_slice(&self.blocks.to_le_bytes());
+                header[20..24].copy_from_slice(&self.chunks.to_le_bytes());
+                self.output.write_all(&header)
+        }
+
+        fn write_chunk(&mut self, chunk_type: u16, blocks: u32, data: &[u8]) -> std::io::Result<()> {
+                let mut header = [0u8; CHUNK_HEADER_SIZE];
+                header[0..2].copy_from_slice(&chunk_type.to_le_bytes());
+                header[4..8].copy_from_slice(&blocks.to_le_bytes());
+                header[8..12].copy_from_slice(&((CHUNK_HEADER_SIZE + data.len()) as u32).to_le_bytes());
+                self.output.write_all(&header)?;
+                self.output.write_all(data)?;
+                self.chunks += 1;
+                Ok(())
+        }
+
+        fn flush_pending(&mut self) -> std::io::Result<()> {
+                match std::mem::replace(&mut self.pending, Pending::None) {
+                        Pending::None => { Ok(()) },
+                        Pending::Raw(data) => { self.write_chunk(CHUNK_RAW, (data.len() / self.block_size) as u32, &data) },
+                        Pending::Fill(value, blocks) => { self.write_chunk(CHUNK_FILL, blocks, &value) }
+                }
+        }
+
+        fn push_block(&mut self) -> std::io::Result<()> {
+                let value: [u8; 4] = self.block[..4].try_into().unwrap();
+                let is_fill = self.block.chunks_exact(4).all(|word| word == value);
+                match (&mut self.pending, is_fill) {
+                        (Pending::Fill(pending_value, blocks), true) if *pending_value == value => { *blocks += 1; },
+                        (Pending::Raw(data), false) if data.len() < MAX_RAW_CHUNK_BLOCKS * self.block_size => { data.extend_from_slice(&self.block); },
+                        (_, true) => {
+                                self.flush_pending()?;
+                                self.pending = Pending::Fill(value, 1);
+                        },
+                        (_, false) => {
+                                self.flush_pending()?;
+                                let mut data = Vec::with_capacity(MAX_RAW_CHUNK_BLOCKS * self.block_size);
+                                data.extend_from_slice(&self.block);
+                                self.pending = Pending::Raw(data);
+                        }
+                }
+                self.block.clear();
+                self.blocks += 1;
+                Ok(())
+        }
+
+        /// Writes the last chunk and the final header
+        pub fn finish(mut self) -> std::io::Result<()> {
+                if !self.block.is_empty() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the image does not end on a block boundary"));
+                }
+                self.flush_pending()?;
+                self.output.seek(SeekFrom::Start(0))?;
+                self.write_header()?;
+                self.output.flush()
+        }
+}
+
+impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let accepted = buf.len().min(self.block_size - self.block.len());
+                self.block.extend_from_slice(&buf[..accepted]);
+                if self.block.len() == self.block_size {
+                        self.push_block()?;
+                }
+                Ok(accepted)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+                self.output.flush()
+        }
+}
@@ -0,0 +1,354 @@
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use crate::log;
+
+const ZIP_LOCAL_HEADER_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_CENTRAL_HEADER_MAGIC: &[u8] = b"PK\x01\x02";
+const ZIP_END_MAGIC: &[u8] = b"PK\x05\x06";
+const ZIP64_LOCATOR_MAGIC: &[u8] = b"PK\x06\x07";
+const ZIP64_END_MAGIC: &[u8] = b"PK\x06\x06";
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+const ZIP_CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP_END_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const ZIP64_END_SIZE: usize = 56;
+/// The end of central directory record is followed by a comment of up to 64KiB
+const ZIP_MAX_COMMENT_SIZE: usize = 0xFFFF;
+/// Sizes and offsets set to this value are stored in the zip64 extra field
+const ZIP64_MARKER: u32 = 0xFFFF_FFFF;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+const ZIP_FLAG_ENCRYPTED: u16 = 1 << 0;
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+const ZIP_METHOD_BZIP2: u16 = 12;
+const ZIP_METHOD_ZSTD: u16 = 93;
+const ZIP_METHOD_XZ: u16 = 95;
+pub const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+/// POSIX archives split long names between the name and this prefix, GNU archives use the space differently
+const TAR_POSIX_MAGIC: &[u8] = b"ustar\0";
+const TAR_TYPE_REGULAR: u8 = b'0';
+const TAR_TYPE_REGULAR_OLD: u8 = 0;
+const TAR_TYPE_CONTIGUOUS: u8 = b'7';
+const TAR_TYPE_GNU_LONG_NAME: u8 = b'L';
+const TAR_TYPE_PAX: u8 = b'x';
+/// Largest GNU long name or pax header read
+const TAR_MAX_METADATA_SIZE: u64 = 1 << 20;
+/// Extensions of the entries picked when the archive holds several files, compressed images included
+const IMAGE_EXTENSIONS: &[&str] = &["img", "iso", "raw", "bin", "dd", "simg"];
+const COMPRESSED_EXTENSIONS: &[&str] = &["gz", "xz", "bz2", "zst"];
+
+fn invalid(kind: &str, message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid {} archive, {}", kind, message))
+}
+
+pub fn is_zip(magic: &[u8]) -> bool {
+        magic.starts_with(ZIP_LOCAL_HEADER_MAGIC)
+}
+
+/// Recognizes the first header of ustar (POSIX and GNU) archives, the checksum has to match as the magic sits
+/// in the middle of the block
+pub fn is_tar(magic: &[u8]) -> bool {
+        magic.len() >= TAR_BLOCK_SIZE && magic[257..262] == *TAR_MAGIC && tar_checksum_matches(&magic[..TAR_BLOCK_SIZE])
+}
+
+/// Whether the name of an entry looks like a disk image, possibly compressed
+fn is_disk_image(name: &str) -> bool {
+        let mut parts = name.rsplit('/').next().unwrap_or(name).rsplit('.');
+        let mut extension = parts.next();
+        if extension.is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str())) {
+                extension = parts.next();
+        }
+        // a name without a dot yields no extension once its single part is consumed
+        parts.next().is_some() && extension.is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Error of tar archives read without an entry name that hold a single file not named like a disk image: zip
+/// archives pick their single file, but tar archives are read in order and it was skipped when it was seen, so
+/// the archive has to be read again with the entry named
+#[derive(Debug)]
+pub struct SingleEntry(pub String);
+
+impl std::fmt::Display for SingleEntry {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "the tar archive holds the single entry {:?}, which is not named like a disk image, name it with --entry", self.0)
+        }
+}
+
+impl std::error::Error for SingleEntry {}
+
+/// An entry of an archive
+pub struct Entry {
+        pub name: String,
+        /// Size of the entry once extracted
+        pub size: u64,
+        /// Size of the entry in the archive
+        pub stored_size: u64,
+        method: u16,
+        encrypted: bool,
+        /// Offset of the local header of zip entries
+        offset: u64,
+}
+
+impl Entry {
+        /// Reads the data of a zip entry from `reader`, positioned at its start, decompressing it on the fly
+        pub fn zip_reader<R: Read + 'static>(&self, reader: R) -> std::io::Result<Box<dyn Read>> {
+                let data = reader.take(self.stored_size);
+                Ok(match self.method {
+                        ZIP_METHOD_STORED => { Box::new(data) },
+                        ZIP_METHOD_DEFLATE => { Box::new(flate2::read::DeflateDecoder::new(data)) },
+                        ZIP_METHOD_BZIP2 => { Box::new(bzip2::read::BzDecoder::new(BufReader::new(data))) },
+                        ZIP_METHOD_ZSTD => { Box::new(zstd::stream::read::Decoder::new(data)?) },
+                        ZIP_METHOD_XZ => { Box::new(xz2::read::XzDecoder::new(BufReader::new(data))) },
+                        method => { return Err(invalid("zip", format!("{:?} uses the unsupported compression method {}", self.name, method))); }
+                })
+        }
+}
+
+/// Picks the entry named `name`, or else the single disk image of the archive (or its single file)
+fn select(kind: &str, entries: Vec<Entry>, name: Option<&str>) -> std::io::Result<Entry> {
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.as_str()).collect::<Vec<&str>>().join(", ");
+        if let Some(name) = name {
+                let listed = names(&entries);
+                return entries.into_iter().find(|e| e.name == name).ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, format!("the {} archive has no entry {:?} (entries: {})", kind, name, listed)));
+        }
+        if entries.len() == 1 {
+                return Ok(entries.into_iter().next().unwrap());
+        }
+        let mut images: Vec<Entry> = entries.into_iter().filter(|e| is_disk_image(&e.name)).collect();
+        match images.len() {
+                1 => { Ok(images.remove(0)) },
+                0 => { Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("the {} archive holds no disk image, name the entry to flash with --entry", kind))) },
+                _ => { Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("the {} archive holds several disk images ({}), name the one to flash with --entry", kind, names(&images)))) }
+        }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Finds the number of entries of the central directory of a zip archive, its size and its offset, from the
+/// end of central directory record (or its zip64 version)
+fn zip_central_directory<R: Read + Seek>(file: &mut R, file_size: u64) -> std::io::Result<(u64, u64, u64)> {
+        let tail_size = file_size.min((ZIP_END_SIZE + ZIP_MAX_COMMENT_SIZE) as u64);
+        let mut tail = vec![0u8; tail_size as usize];
+        file.seek(SeekFrom::Start(file_size - tail_size))?;
+        file.read_exact(&mut tail)?;
+        let end = (0..tail.len().saturating_sub(ZIP_END_SIZE - 1)).rev().find(|&i| tail[i..].starts_with(ZIP_END_MAGIC)).ok_or(invalid("zip", String::from("the end of central directory record is missing")))?;
+        let record = &tail[end..end + ZIP_END_SIZE];
+        let (entries, size, offset) = (u64::from(u16_at(record, 10)), u64::from(u32_at(record, 12)), u32_at(record, 16));
+        if offset != ZIP64_MARKER && entries != 0xFFFF {
+                return Ok((entries, size, u64::from(offset)));
+        }
+        let end_offset = file_size - tail_size + end as u64;
+        let mut locator = [0u8; ZIP64_LOCATOR_SIZE];
+        file.seek(SeekFrom::Start(end_offset.checked_sub(ZIP64_LOCATOR_SIZE as u64).ok_or(invalid("zip", String::from("the zip64 locator is missing")))?))?;
+        file.read_exact(&mut locator)?;
+        if !locator.starts_with(ZIP64_LOCATOR_MAGIC) {
+                return Err(invalid("zip", String::from("the zip64 locator is missing")));
+        }
+        let mut record = [0u8; ZIP64_END_SIZE];
+        file.seek(SeekFrom::Start(u64_at(&locator, 8)))?;
+        file.read_exact(&mut record)?;
+        if !record.starts_with(ZIP64_END_MAGIC) {
+                return Err(invalid("zip", String::from("the zip64 end of central directory record is missing")));
+        }
+        Ok((u64_at(&record, 32), u64_at(&record, 40), u64_at(&record, 48)))
+}
+
+/// Looks the entry to flash up in the central directory of the zip archive, leaving `file` at the start of
+/// its data
+pub fn zip_entry<R: Read + Seek>(file: &mut R, name: Option<&str>) -> std::io::Result<Entry> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+        let (count, size, offset) = zip_central_directory(file, file_size)?;
+        if offset.checked_add(size).is_none_or(|end| end > file_size) {
+                return Err(invalid("zip", format!("the central directory of {} bytes at byte {} does not fit in the file", size, offset)));
+        }
+        let mut directory = vec![0u8; size as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut directory)?;
+        let mut entries = vec![];
+        let mut position = 0;
+        for _ in 0..count {
+                let header = directory.get(position..position + ZIP_CENTRAL_HEADER_SIZE).filter(|h| h.starts_with(ZIP_CENTRAL_HEADER_MAGIC)).ok_or(invalid("zip", String::from("the central directory is truncated")))?;
+                let (flags, method) = (u16_at(header, 8), u16_at(header, 10));
+                let mut stored_size = u64::from(u32_at(header, 20));
+                let mut size = u64::from(u32_at(header, 24));
+                let mut local_offset = u64::from(u32_at(header, 42));
+                let name_length = usize::from(u16_at(header, 28));
+                let extra_length = usize::from(u16_at(header, 30));
+                let comment_length = usize::from(u16_at(header, 32));
+                let variable = directory.get(position + ZIP_CENTRAL_HEADER_SIZE..position + ZIP_CENTRAL_HEADER_SIZE + name_length + extra_length).ok_or(invalid("zip", String::from("the central directory is truncated")))?;
+                let entry_name = String::from_utf8_lossy(&variable[..name_length]).into_owned();
+                // the zip64 extra field only holds the values that did not fit, in this order
+                let mut extra = &variable[name_length..];
+                while extra.len() >= 4 {
+                        let (id, length) = (u16_at(extra, 0), usize::from(u16_at(extra, 2)));
+                        let mut field = extra.get(4..4 + length).unwrap_or(&[]);
+                        if id == ZIP64_EXTRA_FIELD {
+                                for value in [&mut size, &mut stored_size, &mut local_offset] {
+                                        if *value == u64::from(ZIP64_MARKER) && field.len() >= 8 {
+                                                *value = u64_at(field, 0);
+                                                field = &field[8..];
+                                        }
+                                }
+                        }
+                        extra = &extra[(4 + length).min(extra.len())..];
+                }
+                position += ZIP_CENTRAL_HEADER_SIZE + name_length + extra_length + comment_length;
+                if entry_name.ends_with('/') {
+                        continue;
+                }
+                entries.push(Entry { name: entry_name, size, stored_size, method, encrypted: flags & ZIP_FLAG_ENCRYPTED != 0, offset: local_offset });
+        }
+        let entry = select("zip", entries, name)?;
+        if entry.encrypted {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("the zip entry {:?} is encrypted", entry.name)));
+        }
+        let mut header = [0u8; ZIP_LOCAL_HEADER_SIZE];
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.read_exact(&mut header)?;
+        if !header.starts_with(ZIP_LOCAL_HEADER_MAGIC) {
+                return Err(invalid("zip", format!("the local header of {:?} is missing", entry.name)));
+        }
+        // the local extra field may differ from the central directory's
+        let data_offset = entry.offset + (ZIP_LOCAL_HEADER_SIZE + usize::from(u16_at(&header, 26)) + usize::from(u16_at(&header, 28))) as u64;
+        if data_offset.checked_add(entry.stored_size).is_none_or(|end| end > file_size) {
+                return Err(invalid("zip", format!("the data of {:?} does not fit in the file", entry.name)));
+        }
+        file.seek(SeekFrom::Start(data_offset))?;
+        log::debug!("zip_entry(): {:?} is {} bytes, {} bytes stored with method {}", entry.name, entry.size, entry.stored_size, entry.method);
+        Ok(entry)
+}
+
+/// Parses a numeric field of a tar header, made of octal digits or of a big-endian base-256 number for the
+/// values that do not fit
+fn tar_number(field: &[u8]) -> Option<u64> {
+        if field.first().is_some_and(|b| b & 0x80 != 0) {
+                return field[1..].iter().try_fold(u64::from(field[0] & 0x7F), |value, &b| value.checked_mul(256).map(|v| v | u64::from(b)));
+        }
+        let digits = std::str::from_utf8(field).ok()?.trim_matches(|c| c == ' ' || c == '\0');
+        u64::from_str_radix(digits, 8).ok()
+}
+
+/// The checksum is the sum of the bytes of the header, its own field counting as spaces
+fn tar_checksum_matches(header: &[u8]) -> bool {
+        let sum: u64 = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) }).sum();
+        tar_number(&header[148..156]) == Some(sum)
+}
+
+fn tar_string(field: &[u8]) -> String {
+        String::from_utf8_lossy(field.split(|&b| b == 0).next().unwrap_or(field)).into_owned()
+}
+
+/// Entries are often stored as "./name"
+fn tar_normalized(name: &str) -> &str {
+        name.trim_start_matches("./")
+}
+
+/// Moves past `size` bytes of entry data and the padding that completes their last block
+fn tar_skip(reader: &mut dyn Read, size: u64) -> std::io::Result<()> {
+        let padded = size.next_multiple_of(TAR_BLOCK_SIZE as u64);
+        if std::io::copy(&mut reader.take(padded), &mut std::io::sink())? != padded {
+                return Err(invalid("tar", String::from("the archive is truncated")));
+        }
+        Ok(())
+}
+
+/// Reads the data of a GNU long name or pax header entry
+fn tar_metadata(reader: &mut dyn Read, size: u64) -> std::io::Result<Vec<u8>> {
+        if size > TAR_MAX_METADATA_SIZE {
+                return Err(invalid("tar", format!("metadata entry of {} bytes", size)));
+        }
+        let mut data = vec![0u8; size.next_multiple_of(TAR_BLOCK_SIZE as u64) as usize];
+        reader.read_exact(&mut data)?;
+        data.truncate(size as usize);
+        Ok(data)
+}
+
+/// Extracts the path and the size from the "<length> <key>=<value>\n" records of a pax header
+fn pax_records(mut data: &[u8]) -> (Option<String>, Option<u64>) {
+        let (mut path, mut size) = (None, None);
+        while let Some(space) = data.iter().position(|&b| b == b' ') {
+                let length = match std::str::from_utf8(&data[..space]).ok().and_then(|l| l.parse::<usize>().ok()) {
+                        Some(length) if length > space && length <= data.len() => { length },
+                        _ => { break; }
+                };
+                let record = String::from_utf8_lossy(&data[space + 1..length]);
+                if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+                        match key {
+                                "path" => { path = Some(String::from(value)); },
+                                "size" => { size = value.parse().ok(); },
+                                _ => {}
+                        }
+                }
+                data = &data[length..];
+        }
+        (path, size)
+}
+
+/// Walks the headers of the tar archive read from `reader` up to the entry named `name`, or else the first disk
+/// image, and returns it with a reader of its data. Archives are read in order, so unlike zip archives the other
+/// entries are not looked at, and a single file not named like a disk image is reported as a `SingleEntry` error
+pub fn tar_entry(mut reader: Box<dyn Read>, name: Option<&str>) -> std::io::Result<(Entry, Box<dyn Read>)> {
+        let mut seen = vec![];
+        let (mut long_name, mut pax_path, mut pax_size) = (None, None, None);
+        loop {
+                let mut header = [0u8; TAR_BLOCK_SIZE];
+                if super::read_full(&mut reader, &mut header)? < TAR_BLOCK_SIZE || header.iter().all(|&b| b == 0) {
+                        break;
+                }
+                if !tar_checksum_matches(&header) {
+                        return Err(invalid("tar", format!("the checksum of the header following {:?} does not match", seen.last().map(String::as_str).unwrap_or(""))));
+                }
+                let size = match pax_size.take().or(tar_number(&header[124..136])) {
+                        Some(size) => { size },
+                        None => { return Err(invalid("tar", String::from("malformed entry size"))); }
+                };
+                match header[156] {
+                        TAR_TYPE_GNU_LONG_NAME => {
+                                long_name = Some(tar_string(&tar_metadata(&mut reader, size)?));
+                                continue;
+                        },
+                        TAR_TYPE_PAX => {
+                                (pax_path, pax_size) = pax_records(&tar_metadata(&mut reader, size)?);
+                                continue;
+                        },
+                        _ => {}
+                }
+                let entry_name = long_name.take().or(pax_path.take()).unwrap_or_else(|| {
+                        let base = tar_string(&header[..100]);
+                        match header[257..263] == *TAR_POSIX_MAGIC && header[345] != 0 {
+                                true => { format!("{}/{}", tar_string(&header[345..500]), base) },
+                                false => { base }
+                        }
+                });
+                if matches!(header[156], TAR_TYPE_REGULAR | TAR_TYPE_REGULAR_OLD | TAR_TYPE_CONTIGUOUS) {
+                        let selected = match name {
+                                Some(name) => { tar_normalized(&entry_name) == tar_normalized(name) },
+                                None => { is_disk_image(&entry_name) }
+                        };
+                        if selected {
+                                log::debug!("tar_entry(): {:?} is {} bytes", entry_name, size);
+                                let entry = Entry { name: entry_name, size, stored_size: size, method: ZIP_METHOD_STORED, encrypted: false, offset: 0 };
+                                return Ok((entry, Box::new(reader.take(size))));
+                        }
+                        seen.push(entry_name);
+                }
+                tar_skip(&mut reader, size)?;
+        }
+        if let (None, [single]) = (name, seen.as_slice()) {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, SingleEntry(single.clone())));
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::NotFound, match name {
+                Some(name) => { format!("the tar archive has no entry {:?} (entries: {})", name, seen.join(", ")) },
+                None => { format!("the tar archive holds no disk image (entries: {}), name the entry to flash with --entry", seen.join(", ")) }
+        }))
+}
@@ -0,0 +1,323 @@
+use clap::ValueEnum;
+use std::ops::RangeInclusive;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const ZSTD_MAGIC: u32 = 0xFD2FB528;
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+const ZSTD_SKIPPABLE_MAGIC_MASK: u32 = 0xFFFFFFF0;
+const XZ_FOOTER_MAGIC: &[u8] = b"YZ";
+const XZ_HEADER_SIZE: u64 = 12;
+const XZ_FOOTER_SIZE: u64 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+        #[value(skip)]
+        None,
+        Gzip,
+        Xz,
+        #[value(skip)]
+        Bzip2,
+        Zstd,
+}
+
+impl Compression {
+        pub fn detect(magic: &[u8]) -> Compression {
+                if magic.starts_with(XZ_MAGIC) {
+                        Compression::Xz
+                } else if magic.starts_with(GZIP_MAGIC) {
+                        Compression::Gzip
+                } else if magic.starts_with(BZIP2_MAGIC) {
+                        Compression::Bzip2
+                } else if magic.len() >= 4 && u32::from_le_bytes(magic[..4].try_into().unwrap()) == ZSTD_MAGIC {
+                        Compression::Zstd
+                } else {
+                        Compression::None
+                }
+        }
+
+        /// The compression levels accepted when encoding, `None` if the format is not encoded
+        pub fn level_range(&self) -> Option<RangeInclusive<i32>> {
+                match self {
+                        Compression::Gzip | Compression::Xz => { Some(0..=9) },
+                        Compression::Zstd => { Some(zstd::compression_level_range()) },
+                        Compression::None | Compression::Bzip2 => { None }
+                }
+        }
+
+        pub fn default_level(&self) -> i32 {
+                match self {
+                        Compression::Gzip | Compression::Xz => { 6 },
+                        Compression::Zstd => { zstd::DEFAULT_COMPRESSION_LEVEL },
+                        Compression::None | Compression::Bzip2 => { 0 }
+                }
+        }
+
+        pub fn name(&self) -> &'static str {
+                match self {
+                        Compression::None => { "uncompressed" },
+                        Compression::Gzip => { "gzip compressed" },
+                        Compression::Xz => { "xz compressed" },
+                        Compression::Bzip2 => { "bzip2 compressed" },
+                        Compression::Zstd => { "zstd compressed" }
+                }
+        }
+}
+
+/// Wraps `reader` in the streaming decoder of `compression`, concatenated streams are decoded as a whole
+pub fn decoder<R: Read + 'static>(compression: Compression, reader: R) -> std::io::Result<Box<dyn Read>> {
+        Ok(match compression {
+                Compression::None => { Box::new(reader) },
+                Compression::Gzip => { Box::new(flate2::read::MultiGzDecoder::new(BufReader::new(reader))) },
+                Compression::Xz => { Box::new(xz2::read::XzDecoder::new_multi_decoder(BufReader::new(reader))) },
+                Compression::Bzip2 => { Box::new(bzip2::read::MultiBzDecoder::new(BufReader::new(reader))) },
+                Compression::Zstd => { Box::new(zstd::stream::read::Decoder::new(reader)?) }
+        })
+}
+
+/// Reads the decoded size recorded by the format, xz records it in the index of every stream and zstd in the
+/// header of every frame (when the encoder knew it). The gzip trailer only records the size modulo 4GiB and
+/// bzip2 does not record it at all, so their size is unknown
+pub fn decoded_size<R: Read + Seek>(compression: Compression, file: &mut R) -> std::io::Result<Option<u64>> {
+        match compression {
+                Compression::None => { Ok(Some(file.seek(SeekFrom::End(0))?)) },
+                Compression::Xz => { xz_decoded_size(file) },
+                Compression::Zstd => { zstd_decoded_size(file) },
+                Compression::Gzip | Compression::Bzip2 => { Ok(None) }
+        }
+}
+
+fn read_at<R: Read + Seek>(file: &mut R, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+}
+
+/// Decodes a variable length integer of the xz format, returning it along with its length
+fn xz_varint(data: &[u8]) -> Option<(u64, usize)> {
+        let mut value: u64 = 0;
+        for (i, byte) in data.iter().take(9).enumerate() {
+                value |= u64::from(byte & 0x7F) << (7 * i);
+                if byte & 0x80 == 0 {
+                        return Some((value, i + 1));
+                }
+        }
+        None
+}
+
+/// Walks the streams of the file backwards from the end, summing the uncompressed sizes listed in their indexes
+fn xz_decoded_size<R: Read + Seek>(file: &mut R) -> std::io::Result<Option<u64>> {
+        let mut position = file.seek(SeekFrom::End(0))?;
+        let mut total: u64 = 0;
+        while position > 0 {
+                // streams may be followed by padding made of null 4 byte words
+                let mut word = [0u8; 4];
+                if position < XZ_HEADER_SIZE + XZ_FOOTER_SIZE {
+                        return Ok(None);
+                }
+                read_at(file, position - 4, &mut word)?;
+                if word == [0u8; 4] {
+                        position -= 4;
+                        continue;
+                }
+                let mut footer = [0u8; XZ_FOOTER_SIZE as usize];
+                read_at(file, position - XZ_FOOTER_SIZE, &mut footer)?;
+                if &footer[10..12] != XZ_FOOTER_MAGIC {
+                        return Ok(None);
+                }
+                let index_size = (u64::from(u32::from_le_bytes(footer[4..8].try_into().unwrap())) + 1) * 4;
+                let index_start = match (position - XZ_FOOTER_SIZE).checked_sub(index_size) {
+                        Some(start) => { start },
+                        None => { return Ok(None); }
+                };
+                let mut index = vec![0u8; index_size as usize];
+                read_at(file, index_start, &mut index)?;
+                if index[0] != 0x00 {
+                        return Ok(None);
+                }
+                let mut offset = 1;
+                let (records, length) = match xz_varint(&index[offset..]) { Some(v) => { v }, None => { return Ok(None); } };
+                offset += length;
+                let mut blocks_size: u64 = 0;
+                for _ in 0..records {
+                        let (unpadded, length) = match xz_varint(&index[offset..]) { Some(v) => { v }, None => { return Ok(None); } };
+                        offset += length;
+                        let (uncompressed, length) = match xz_varint(&index[offset..]) { Some(v) => { v }, None => { return Ok(None); } };
+                        offset += length;
+                        blocks_size += unpadded.next_multiple_of(4);
+                        total += uncompressed;
+                }
+                position = match index_start.checked_sub(blocks_size + XZ_HEADER_SIZE) {
+                        Some(start) => { start },
+                        None => { return Ok(None); }
+                };
+        }
+        Ok(Some(total))
+}
+
+/// Walks the frames of the file summing their content sizes, the block headers are followed to find the end
+/// of each frame so nothing is decompressed
+fn zstd_decoded_size<R: Read + Seek>(file: &mut R) -> std::io::Result<Option<u64>> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut position: u64 = 0;
+        let mut total: u64 = 0;
+        while position < file_size {
+                let mut magic = [0u8; 4];
+                reader.read_exact(&mut magic)?;
+                let magic = u32::from_le_bytes(magic);
+                if magic & ZSTD_SKIPPABLE_MAGIC_MASK == ZSTD_SKIPPABLE_MAGIC {
+                        let mut size = [0u8; 4];
+                        reader.read_exact(&mut size)?;
+                        let size = u32::from_le_bytes(size);
+                        reader.seek_relative(i64::from(size))?;
+                        position += 8 + u64::from(size);
+                        continue;
+                }
+                if magic != ZSTD_MAGIC {
+                        return Ok(None);
+                }
+                let mut descriptor = [0u8; 1];
+                reader.read_exact(&mut descriptor)?;
+                let descriptor = descriptor[0];
+                let single_segment = descriptor & 0x20 != 0;
+                let has_checksum = descriptor & 0x04 != 0;
+                let dictionary_id_size = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+                let content_size_size = match descriptor >> 6 {
+                        0 => { if single_segment { 1 } else { 0 } },
+                        1 => { 2 },
+                        2 => { 4 },
+                        _ => { 8 }
+                };
+                if content_size_size == 0 {
+                        return Ok(None);
+                }
+                let window_descriptor_size = if single_segment { 0 } else { 1 };
+                reader.seek_relative(window_descriptor_size + dictionary_id_size)?;
+                let mut content_size = [0u8; 8];
+                reader.read_exact(&mut content_size[..content_size_size])?;
+                total += match content_size_size {
+                        2 => { u64::from_le_bytes(content_size) + 256 },
+                        _ => { u64::from_le_bytes(content_size) }
+                };
+                position += 5 + (window_descriptor_size + dictionary_id_size) as u64 + content_size_size as u64;
+                loop {
+                        let mut header = [0u8; 4];
+                        reader.read_exact(&mut header[..3])?;
+                        let header = u32::from_le_bytes(header);
+                        let block_size = match (header >> 1) & 0x3 {
+                                // RLE blocks store a single byte
+                                1 => { 1 },
+                                3 => { return Ok(None); },
+                                _ => { header >> 3 }
+                        };
+                        reader.seek_relative(i64::from(block_size))?;
+                        position += 3 + u64::from(block_size);
+                        if header & 0x1 != 0 {
+                                break;
+                        }
+                }
+                if has_checksum {
+                        reader.seek_relative(4)?;
+                        position += 4;
+                }
+        }
+        Ok(Some(total))
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+        use std::io::{Cursor, Write};
+
+        fn data(length: usize) -> Vec<u8> {
+                (0..length).map(|n| (n / 1000 + n % 7) as u8).collect()
+        }
+
+        fn xz(data: &[u8]) -> Vec<u8> {
+                let mut encoder = xz2::write::XzEncoder::new(vec![], 1);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+        }
+
+        fn decode(compression: Compression, encoded: Vec<u8>) -> Vec<u8> {
+                let mut decoded = vec![];
+                decoder(compression, Cursor::new(encoded)).unwrap().read_to_end(&mut decoded).unwrap();
+                decoded
+        }
+
+        #[test]
+        fn formats_are_detected_from_their_magic() {
+                assert_eq!(Compression::detect(&xz(b"abc")), Compression::Xz);
+                assert_eq!(Compression::detect(&[0x1F, 0x8B, 0x08, 0x00]), Compression::Gzip);
+                assert_eq!(Compression::detect(b"BZh91AY&SY"), Compression::Bzip2);
+                assert_eq!(Compression::detect(&zstd::bulk::compress(b"abc", 1).unwrap()), Compression::Zstd);
+                assert_eq!(Compression::detect(&[0x28, 0xB5, 0x2F]), Compression::None);
+                assert_eq!(Compression::detect(&[0xEB, 0x3C, 0x90, 0x4D]), Compression::None);
+                assert_eq!(Compression::detect(&[]), Compression::None);
+        }
+
+        #[test]
+        fn concatenated_streams_are_decoded_as_a_whole() {
+                let (first, second) = (data(70000), data(30000));
+                let whole = [first.clone(), second.clone()].concat();
+
+                let mut gzip = vec![];
+                for part in [&first, &second] {
+                        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+                        encoder.write_all(part).unwrap();
+                        gzip.extend(encoder.finish().unwrap());
+                }
+                assert!(decode(Compression::Gzip, gzip) == whole);
+
+                let mut bzip2 = vec![];
+                for part in [&first, &second] {
+                        let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::fast());
+                        encoder.write_all(part).unwrap();
+                        bzip2.extend(encoder.finish().unwrap());
+                }
+                assert!(decode(Compression::Bzip2, bzip2) == whole);
+
+                assert!(decode(Compression::Xz, [xz(&first), xz(&second)].concat()) == whole);
+                let zstd = [zstd::bulk::compress(&first, 1).unwrap(), zstd::bulk::compress(&second, 1).unwrap()].concat();
+                assert!(decode(Compression::Zstd, zstd) == whole);
+        }
+
+        #[test]
+        fn xz_sizes_are_read_from_the_indexes() {
+                assert_eq!(decoded_size(Compression::Xz, &mut Cursor::new(xz(&data(100000)))).unwrap(), Some(100000));
+                // several streams separated and followed by stream padding
+                let mut streams = [xz(&data(5000)), vec![0; 8], xz(&[]), xz(&data(300000))].concat();
+                streams.extend([0; 4]);
+                assert_eq!(decoded_size(Compression::Xz, &mut Cursor::new(streams)).unwrap(), Some(305000));
+                // a truncated file has no footer
+                let truncated = xz(&data(100000));
+                assert_eq!(decoded_size(Compression::Xz, &mut Cursor::new(&truncated[..truncated.len() - 6])).unwrap(), None);
+        }
+
+        #[test]
+        fn zstd_sizes_are_read_from_the_frame_headers() {
+                // frames of different content size lengths, separated by a skippable frame
+                let mut frames = [zstd::bulk::compress(&data(200), 3).unwrap(), zstd::bulk::compress(&data(1000), 3).unwrap()].concat();
+                frames.extend(0x184D2A53u32.to_le_bytes());
+                frames.extend(3u32.to_le_bytes());
+                frames.extend([1, 2, 3]);
+                frames.extend(zstd::bulk::compress(&data(300000), 3).unwrap());
+                assert_eq!(decoded_size(Compression::Zstd, &mut Cursor::new(frames)).unwrap(), Some(301200));
+                // a streaming encoder does not know the size when it writes the header
+                let mut encoder = zstd::stream::write::Encoder::new(vec![], 3).unwrap();
+                encoder.write_all(&data(100000)).unwrap();
+                assert_eq!(decoded_size(Compression::Zstd, &mut Cursor::new(encoder.finish().unwrap())).unwrap(), None);
+        }
+
+        #[test]
+        fn gzip_and_bzip2_sizes_are_unknown() {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+                encoder.write_all(&data(1000)).unwrap();
+                assert_eq!(decoded_size(Compression::Gzip, &mut Cursor::new(encoder.finish().unwrap())).unwrap(), None);
+                assert_eq!(decoded_size(Compression::Bzip2, &mut Cursor::new(b"BZh9".to_vec())).unwrap(), None);
+                assert_eq!(decoded_size(Compression::None, &mut Cursor::new(data(1234))).unwrap(), Some(1234));
+        }
+}
@@ -0,0 +1,290 @@
+use base64::Engine;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use crate::log;
+use super::{extents, lzfse, VirtualDisk};
+
+const TRAILER_MAGIC: &[u8] = b"koly";
+const TRAILER_SIZE: u64 = 512;
+const BLOCK_TABLE_MAGIC: &[u8] = b"mish";
+const BLOCK_TABLE_HEADER_SIZE: usize = 204;
+const CHUNK_ENTRY_SIZE: usize = 40;
+const SECTOR_SIZE: u64 = 512;
+const CHUNK_ZERO: u32 = 0x0000_0000;
+const CHUNK_RAW: u32 = 0x0000_0001;
+const CHUNK_IGNORED: u32 = 0x0000_0002;
+const CHUNK_ADC: u32 = 0x8000_0004;
+const CHUNK_ZLIB: u32 = 0x8000_0005;
+const CHUNK_BZIP2: u32 = 0x8000_0006;
+const CHUNK_LZFSE: u32 = 0x8000_0007;
+const CHUNK_COMMENT: u32 = 0x7FFF_FFFE;
+const CHUNK_TERMINATOR: u32 = 0xFFFF_FFFF;
+/// Largest property list read, it holds the block tables of the partitions
+const MAX_PLIST_SIZE: u64 = 64 << 20;
+/// Largest compressed chunk accepted (32MiB decoded), hdiutil writes chunks of 1MiB
+const MAX_COMPRESSED_CHUNK_SECTORS: u64 = 1 << 16;
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid DMG image, {}", message))
+}
+
+/// UDIF images are recognized by the trailer in their last 512 bytes
+pub fn has_trailer<R: Read + Seek>(file: &mut R, size: u64) -> std::io::Result<bool> {
+        if size < TRAILER_SIZE {
+                return Ok(false);
+        }
+        let mut magic = [0u8; 4];
+        file.seek(SeekFrom::Start(size - TRAILER_SIZE))?;
+        file.read_exact(&mut magic)?;
+        Ok(magic == TRAILER_MAGIC)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// A run of sectors of the disk and where its data is stored
+#[derive(Debug, Clone)]
+struct Chunk {
+        kind: u32,
+        sectors: Range<u64>,
+        /// Offset of the data in the file and its length
+        offset: u64,
+        length: u64,
+}
+
+impl Chunk {
+        fn bytes(&self) -> Range<u64> {
+                self.sectors.start * SECTOR_SIZE..self.sectors.end * SECTOR_SIZE
+        }
+
+        fn is_hole(&self) -> bool {
+                matches!(self.kind, CHUNK_ZERO | CHUNK_IGNORED)
+        }
+}
+
+/// The element following `<key>key</key>` in a dictionary of the property list
+fn dict_value<'a, 'input>(dict: roxmltree::Node<'a, 'input>, key: &str) -> Option<roxmltree::Node<'a, 'input>> {
+        dict.children().filter(|n| n.is_element()).skip_while(|n| !(n.has_tag_name("key") && n.text() == Some(key))).nth(1)
+}
+
+/// Extracts the block tables ("mish" blocks) of the partitions from the "blkx" array of the property list
+fn block_tables(plist: &str) -> std::io::Result<Vec<Vec<u8>>> {
+        // property lists declare their document type
+        let options = roxmltree::ParsingOptions { allow_dtd: true, ..roxmltree::ParsingOptions::default() };
+        let document = roxmltree::Document::parse_with_options(plist, options).map_err(|e| invalid(format!("malformed property list, {}", e)))?;
+        let blkx = document.descendants().filter(|n| n.has_tag_name("dict")).find_map(|dict| dict_value(dict, "blkx").filter(|n| n.has_tag_name("array"))).ok_or(invalid(String::from("the property list has no blkx array")))?;
+        let mut tables = vec![];
+        for partition in blkx.children().filter(|n| n.has_tag_name("dict")) {
+                let data = dict_value(partition, "Data").and_then(|n| n.text()).ok_or(invalid(String::from("a blkx entry has no data")))?;
+                let data: String = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+                let table = base64::engine::general_purpose::STANDARD.decode(data).map_err(|e| invalid(format!("malformed blkx data, {}", e)))?;
+                log::debug!("dmg::block_tables(): partition {:?}, {} bytes of block table", dict_value(partition, "Name").and_then(|n| n.text()).unwrap_or(""), table.len());
+                tables.push(table);
+        }
+        Ok(tables)
+}
+
+/// Decodes an ADC (Apple Data Compression) chunk, made of literal runs and matches at most 64KiB back
+fn adc_decompress(input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+        let truncated = || invalid(String::from("truncated ADC chunk"));
+        let mut position = 0;
+        while position < input.len() {
+                let opcode = input[position];
+                let (length, distance) = if opcode & 0x80 != 0 {
+                        let length = usize::from(opcode & 0x7F) + 1;
+                        output.extend_from_slice(input.get(position + 1..position + 1 + length).ok_or_else(truncated)?);
+                        position += 1 + length;
+                        continue;
+                } else if opcode & 0x40 != 0 {
+                        let operand = input.get(position + 1..position + 3).ok_or_else(truncated)?;
+                        position += 3;
+                        (usize::from(opcode & 0x3F) + 4, usize::from(u16::from_be_bytes([operand[0], operand[1]])))
+                } else {
+                        let operand = *input.get(position + 1).ok_or_else(truncated)?;
+                        position += 2;
+                        (usize::from((opcode & 0x3F) >> 2) + 3, usize::from(opcode & 3) << 8 | usize::from(operand))
+                };
+                lzfse::copy_match(output, distance + 1, length)?;
+        }
+        Ok(())
+}
+
+/// Reads the disk of a UDIF image (.dmg), its chunks are listed in the block tables of the property list
+/// pointed to by the trailer. Compressed chunks (zlib, bzip2, LZFSE and ADC) are decoded whole when they
+/// are read, zero and ignored chunks read as zeros
+pub struct Reader {
+        file: File,
+        size: u64,
+        /// The chunks sorted by sector
+        chunks: Vec<Chunk>,
+        /// The index of the last compressed chunk decoded and its data
+        decoded: Option<(usize, Vec<u8>)>,
+        position: u64,
+}
+
+impl Reader {
+        pub fn open(path: &Path) -> std::io::Result<Reader> {
+                let mut file = File::open(path)?;
+                let stored_size = file.metadata()?.len();
+                let mut trailer = [0u8; TRAILER_SIZE as usize];
+                file.seek(SeekFrom::Start(stored_size - TRAILER_SIZE))?;
+                file.read_exact(&mut trailer)?;
+                let (data_fork_offset, plist_offset, plist_length, sectors) = (u64_at(&trailer, 24), u64_at(&trailer, 216), u64_at(&trailer, 224), u64_at(&trailer, 492));
+                if sectors > u64::MAX / SECTOR_SIZE {
+                        return Err(invalid(format!("disk of {} sectors", sectors)));
+                }
+                if plist_length == 0 || plist_length > MAX_PLIST_SIZE || plist_offset.saturating_add(plist_length) > stored_size {
+                        return Err(invalid(format!("the property list of {} bytes at byte {} is missing", plist_length, plist_offset)));
+                }
+                let mut plist = vec![0u8; plist_length as usize];
+                file.seek(SeekFrom::Start(plist_offset))?;
+                file.read_exact(&mut plist)?;
+                let mut chunks = vec![];
+                for table in block_tables(&String::from_utf8_lossy(&plist))? {
+                        if table.len() < BLOCK_TABLE_HEADER_SIZE || !table.starts_with(BLOCK_TABLE_MAGIC) {
+                                return Err(invalid(String::from("malformed block table")));
+                        }
+                        let (first_sector, data_offset, count) = (u64_at(&table, 8), u64_at(&table, 24), u32_at(&table, 200) as usize);
+                        let entries = table.get(BLOCK_TABLE_HEADER_SIZE..BLOCK_TABLE_HEADER_SIZE + count * CHUNK_ENTRY_SIZE).ok_or(invalid(String::from("the block table is truncated")))?;
+                        for entry in entries.chunks_exact(CHUNK_ENTRY_SIZE) {
+                                let kind = u32_at(entry, 0);
+                                let start = first_sector.saturating_add(u64_at(entry, 8));
+                                let chunk = Chunk { kind, sectors: start..start.saturating_add(u64_at(entry, 16)), offset: data_fork_offset.saturating_add(data_offset).saturating_add(u64_at(entry, 24)), length: u64_at(entry, 32) };
+                                match kind {
+                                        CHUNK_COMMENT | CHUNK_TERMINATOR => { continue; },
+                                        CHUNK_ZERO | CHUNK_IGNORED | CHUNK_RAW => {},
+                                        CHUNK_ADC | CHUNK_ZLIB | CHUNK_BZIP2 | CHUNK_LZFSE if chunk.sectors.end - chunk.sectors.start <= MAX_COMPRESSED_CHUNK_SECTORS => {},
+                                        CHUNK_ADC | CHUNK_ZLIB | CHUNK_BZIP2 | CHUNK_LZFSE => { return Err(invalid(format!("compressed chunk of {} sectors", chunk.sectors.end - chunk.sectors.start))); },
+                                        _ => { return Err(invalid(format!("unsupported chunk type {:#010x}", kind))); }
+                                }
+                                if chunk.sectors.end > sectors || (!chunk.is_hole() && chunk.offset.saturating_add(chunk.length) > stored_size) {
+                                        return Err(invalid(format!("the chunk of sectors {:?} does not fit in the image", chunk.sectors)));
+                                }
+                                chunks.push(chunk);
+                        }
+                }
+                chunks.sort_by_key(|c| c.sectors.start);
+                if chunks.windows(2).any(|pair| pair[0].sectors.end > pair[1].sectors.start) {
+                        return Err(invalid(String::from("the chunks overlap")));
+                }
+                log::debug!("dmg::Reader::open(): {:?} is a disk of {} sectors in {} chunks", path, sectors, chunks.len());
+                Ok(Reader { file, size: sectors * SECTOR_SIZE, chunks, decoded: None, position: 0 })
+        }
+
+        /// Decodes the compressed chunk `index`, keeping it for the following reads
+        fn decode(&mut self, index: usize) -> std::io::Result<&[u8]> {
+                if self.decoded.as_ref().is_none_or(|(cached, _)| *cached != index) {
+                        let chunk = &self.chunks[index];
+                        let expected = (chunk.bytes().end - chunk.bytes().start) as usize;
+                        let mut data = vec![0u8; chunk.length as usize];
+                        self.file.seek(SeekFrom::Start(chunk.offset))?;
+                        self.file.read_exact(&mut data)?;
+                        let mut decoded = Vec::with_capacity(expected);
+                        let result = match chunk.kind {
+                                CHUNK_ZLIB => { flate2::read::ZlibDecoder::new(&data[..]).take(expected as u64).read_to_end(&mut decoded).map(|_| ()) },
+                                CHUNK_BZIP2 => { bzip2::read::BzDecoder::new(&data[..]).take(expected as u64).read_to_end(&mut decoded).map(|_| ()) },
+                                CHUNK_LZFSE => { lzfse::decompress(&data, &mut decoded) },
+                                _ => { adc_decompress(&data, &mut decoded) }
+                        };
+                        if let Err(e) = result {
+                                return Err(invalid(format!("failed to decompress the chunk of sectors {:?}, {}", chunk.sectors, e)));
+                        }
+                        if decoded.len() < expected {
+                                return Err(invalid(format!("the chunk of sectors {:?} decodes to {} bytes instead of {}", chunk.sectors, decoded.len(), expected)));
+                        }
+                        decoded.truncate(expected);
+                        self.decoded = Some((index, decoded));
+                }
+                Ok(&self.decoded.as_ref().unwrap().1)
+        }
+}
+
+impl VirtualDisk for Reader {
+        /// Size of the disk
+        fn size(&self) -> u64 {
+                self.size
+        }
+
+        /// Lists the byte ranges of the disk stored in chunks, zero and ignored chunks are holes
+        fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+                let mut data = vec![];
+                for chunk in self.chunks.iter().filter(|c| !c.is_hole()) {
+                        extents::push(&mut data, chunk.bytes());
+                }
+                Ok(data)
+        }
+}
+
+impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.position >= self.size || buf.is_empty() {
+                        return Ok(0);
+                }
+                let index = self.chunks.partition_point(|c| c.bytes().end <= self.position);
+                // the sectors listed in no chunk read as zeros
+                let chunk = match self.chunks.get(index) {
+                        Some(chunk) if chunk.bytes().start <= self.position => { chunk.clone() },
+                        next => {
+                                let end = next.map(|c| c.bytes().start).unwrap_or(self.size);
+                                let length = (end - self.position).min(buf.len() as u64) as usize;
+                                buf[..length].fill(0);
+                                self.position += length as u64;
+                                return Ok(length);
+                        }
+                };
+                let within = self.position - chunk.bytes().start;
+                let length = (chunk.bytes().end - self.position).min(buf.len() as u64) as usize;
+                let buf = &mut buf[..length];
+                match chunk.kind {
+                        CHUNK_ZERO | CHUNK_IGNORED => { buf.fill(0); },
+                        CHUNK_RAW => {
+                                // raw chunks shorter than their sectors are completed with zeros
+                                let stored = chunk.length.saturating_sub(within).min(length as u64) as usize;
+                                self.file.seek(SeekFrom::Start(chunk.offset + within))?;
+                                self.file.read_exact(&mut buf[..stored])?;
+                                buf[stored..].fill(0);
+                        },
+                        _ => {
+                                let decoded = self.decode(index)?;
+                                buf.copy_from_slice(&decoded[within as usize..within as usize + length]);
+                        }
+                }
+                self.position += length as u64;
+                Ok(length)
+        }
+}
+
+impl Seek for Reader {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.position = super::seek_position(self.position, self.size, position)?;
+                Ok(self.position)
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        #[test]
+        fn adc_opcodes() {
+                let chunk = [
+                        0x83, b'a', b'b', b'c', b'd',   // literals: "abcd"
+                        0x14, 0x03,                     // short match: 8 bytes from 4 back
+                        0x46, 0x00, 0x0B,               // long match: 10 bytes from 12 back
+                        0x81, b'x', b'y',               // literals: "xy"
+                        0x08, 0x01,                     // short match: 5 bytes from 2 back
+                ];
+                let mut output = vec![];
+                adc_decompress(&chunk, &mut output).unwrap();
+                assert_eq!(output, b"abcdabcdabcdabcdabcdabxyxyxyx");
+                assert!(adc_decompress(&chunk[..chunk.len() - 1], &mut vec![]).is_err());
+                assert!(adc_decompress(&[0x80, b'a', 0x00, 0x01], &mut vec![]).is_err());
+        }
+}
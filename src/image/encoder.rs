@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use super::Compression;
+
+const ZSTD_SEEKABLE_FRAME_SIZE: usize = 4 << 20;
+const GZIP_MEMBER_SIZE: usize = 4 << 20;
+const XZ_STREAM_SIZE: usize = 16 << 20;
+const ZSTD_SEEK_TABLE_MAGIC: u32 = 0x184D2A5E;
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const ZSTD_SEEK_TABLE_FOOTER_SIZE: u32 = 9;
+
+/// A chunk of the image waiting to be compressed by a worker
+struct Job {
+        index: u64,
+        data: Vec<u8>,
+}
+
+/// A compressed chunk, `decoded_length` is the size of the chunk before compression
+struct Encoded {
+        index: u64,
+        decoded_length: usize,
+        data: std::io::Result<Vec<u8>>,
+}
+
+/// Compresses the image on worker threads, the image is split in chunks that are compressed independently and
+/// written in order as zstd frames (followed by the seek table of the seekable format), gzip members or xz
+/// streams, every decoder handles such concatenations as a single stream
+pub struct ParallelEncoder {
+        output: BufWriter<Box<dyn Write>>,
+        compression: Compression,
+        chunk_size: usize,
+        pending: Vec<u8>,
+        jobs: Option<mpsc::Sender<Job>>,
+        results: mpsc::Receiver<Encoded>,
+        workers: Vec<thread::JoinHandle<()>>,
+        max_in_flight: usize,
+        in_flight: usize,
+        next_index: u64,
+        next_to_write: u64,
+        completed: BTreeMap<u64, (usize, Vec<u8>)>,
+        /// (compressed size, decompressed size) of every zstd frame written so far
+        seek_table: Vec<(u32, u32)>,
+}
+
+impl ParallelEncoder {
+        pub fn new(output: Box<dyn Write>, compression: Compression, level: i32, threads: usize) -> ParallelEncoder {
+                let chunk_size = match compression {
+                        Compression::Xz => { XZ_STREAM_SIZE },
+                        Compression::Gzip => { GZIP_MEMBER_SIZE },
+                        _ => { ZSTD_SEEKABLE_FRAME_SIZE }
+                };
+                let threads = threads.max(1);
+                let (jobs, job_receiver) = mpsc::channel::<Job>();
+                let (result_sender, results) = mpsc::channel::<Encoded>();
+                let job_receiver = Arc::new(Mutex::new(job_receiver));
+                let workers = (0..threads).map(|_| {
+                        let job_receiver = job_receiver.clone();
+                        let result_sender = result_sender.clone();
+                        thread::spawn(move || {
+                                loop {
+                                        let job = match job_receiver.lock().unwrap().recv() {
+                                                Ok(job) => { job },
+                                                Err(_) => { break; }
+                                        };
+                                        let data = encode_chunk(compression, level, &job.data);
+                                        if result_sender.send(Encoded { index: job.index, decoded_length: job.data.len(), data }).is_err() {
+                                                break;
+                                        }
+                                }
+                        })
+                }).collect();
+                ParallelEncoder {
+                        output: BufWriter::new(output),
+                        compression,
+                        chunk_size,
+                        pending: Vec::with_capacity(chunk_size),
+                        jobs: Some(jobs),
+                        results,
+                        workers,
+                        max_in_flight: threads * 2,
+                        in_flight: 0,
+                        next_index: 0,
+                        next_to_write: 0,
+                        completed: BTreeMap::new(),
+                        seek_table: vec![],
+                }
+        }
+
+        fn submit(&mut self) -> std::io::Result<()> {
+                while self.in_flight >= self.max_in_flight {
+                        self.collect(true)?;
+                }
+                let data = std::mem::replace(&mut self.pending, Vec::with_capacity(self.chunk_size));
+                self.jobs.as_ref().unwrap().send(Job { index: self.next_index, data }).map_err(|_| std::io::Error::other("the compression workers have stopped"))?;
+                self.next_index += 1;
+                self.in_flight += 1;
+                self.collect(false)
+        }
+
+        /// Gathers the chunks compressed so far (waiting for one if `block` is set) and writes those that are next in order
+        fn collect(&mut self, block: bool) -> std::io::Result<()> {
+                let mut received: Vec<Encoded> = vec![];
+                if block {
+                        received.push(self.results.recv().map_err(|_| std::io::Error::other("the compression workers have stopped"))?);
+                }
+                received.extend(self.results.try_iter());
+                for encoded in received {
+                        self.in_flight -= 1;
+                        self.completed.insert(encoded.index, (encoded.decoded_length, encoded.data?));
+                }
+                while let Some((decoded_length, data)) = self.completed.remove(&self.next_to_write) {
+                        self.output.write_all(&data)?;
+                        if self.compression == Compression::Zstd {
+                                self.seek_table.push((data.len() as u32, decoded_length as u32));
+                        }
+                        self.next_to_write += 1;
+                }
+                Ok(())
+        }
+
+        /// Compresses the remaining data, waits for the workers and writes the zstd seek table
+        pub fn finish(mut self) -> std::io::Result<()> {
+                if !self.pending.is_empty() {
+                        self.submit()?;
+                }
+                self.jobs = None;
+                while self.in_flight > 0 {
+                        self.collect(true)?;
+                }
+                for worker in self.workers.drain(..) {
+                        worker.join().map_err(|_| std::io::Error::other("a compression worker panicked"))?;
+                }
+                if self.compression == Compression::Zstd {
+                        let entries = self.seek_table.len() as u32;
+                        self.output.write_all(&ZSTD_SEEK_TABLE_MAGIC.to_le_bytes())?;
+                        self.output.write_all(&(entries * 8 + ZSTD_SEEK_TABLE_FOOTER_SIZE).to_le_bytes())?;
+                        for (compressed, decompressed) in &self.seek_table {
+                                self.output.write_all(&compressed.to_le_bytes())?;
+                                self.output.write_all(&decompressed.to_le_bytes())?;
+                        }
+                        self.output.write_all(&entries.to_le_bytes())?;
+                        // no per-frame checksums
+                        self.output.write_all(&[0u8])?;
+                        self.output.write_all(&ZSTD_SEEKABLE_MAGIC.to_le_bytes())?;
+                }
+                self.output.flush()
+        }
+}
+
+impl Write for ParallelEncoder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let accepted = buf.len().min(self.chunk_size - self.pending.len());
+                self.pending.extend_from_slice(&buf[..accepted]);
+                if self.pending.len() == self.chunk_size {
+                        self.submit()?;
+                }
+                Ok(accepted)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+                self.output.flush()
+        }
+}
+
+fn encode_chunk(compression: Compression, level: i32, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match compression {
+                Compression::Gzip => {
+                        let mut encoder = flate2::write::GzEncoder::new(Vec::with_capacity(data.len() / 2), flate2::Compression::new(level as u32));
+                        encoder.write_all(data)?;
+                        encoder.finish()
+                },
+                Compression::Xz => {
+                        let mut encoder = xz2::write::XzEncoder::new(Vec::with_capacity(data.len() / 2), level as u32);
+                        encoder.write_all(data)?;
+                        encoder.finish()
+                },
+                // the content size is recorded in every frame header
+                Compression::Zstd => { zstd::bulk::compress(data, level) },
+                _ => { Ok(data.to_vec()) }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+        use std::io::Read;
+        use std::sync::Arc;
+
+        /// A `Write` whose content is still reachable once the encoder owning it is finished
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+                fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                        self.0.lock().unwrap().extend_from_slice(buf);
+                        Ok(buf.len())
+                }
+
+                fn flush(&mut self) -> std::io::Result<()> {
+                        Ok(())
+                }
+        }
+
+        fn data(length: usize) -> Vec<u8> {
+                let mut state: u32 = 0x12345678;
+                (0..length).map(|n| {
+                        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                        // compressible runs every other 64KiB
+                        if (n >> 16) % 2 == 0 { (state >> 24) as u8 } else { (n >> 16) as u8 }
+                }).collect()
+        }
+
+        fn encode(compression: Compression, level: i32, threads: usize, data: &[u8]) -> Vec<u8> {
+                let output = Shared::default();
+                let mut encoder = ParallelEncoder::new(Box::new(output.clone()), compression, level, threads);
+                // writes that do not line up with the chunks
+                for part in data.chunks(1_000_003) {
+                        encoder.write_all(part).unwrap();
+                }
+                encoder.finish().unwrap();
+                let encoded = output.0.lock().unwrap().clone();
+                encoded
+        }
+
+        fn u32_at(data: &[u8], offset: usize) -> u32 {
+                u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+        }
+
+        #[test]
+        fn zstd_frames_are_listed_in_the_seek_table() {
+                let data = data(2 * ZSTD_SEEKABLE_FRAME_SIZE + 12345);
+                let encoded = encode(Compression::Zstd, 1, 4, &data);
+                let footer = encoded.len() - ZSTD_SEEK_TABLE_FOOTER_SIZE as usize;
+                assert_eq!(u32_at(&encoded, footer + 5), ZSTD_SEEKABLE_MAGIC);
+                assert_eq!(encoded[footer + 4], 0);
+                let entries = u32_at(&encoded, footer) as usize;
+                assert_eq!(entries, 3);
+                let table = footer - entries * 8;
+                // the seek table is a skippable frame
+                assert_eq!(u32_at(&encoded, table - 8), ZSTD_SEEK_TABLE_MAGIC);
+                assert_eq!(u32_at(&encoded, table - 4) as usize, entries * 8 + ZSTD_SEEK_TABLE_FOOTER_SIZE as usize);
+
+                // every frame is decoded on its own, in the order of the image
+                let (mut stored, mut decoded) = (0usize, 0usize);
+                for entry in 0..entries {
+                        let compressed_size = u32_at(&encoded, table + entry * 8) as usize;
+                        let decompressed_size = u32_at(&encoded, table + entry * 8 + 4) as usize;
+                        let frame = zstd::bulk::decompress(&encoded[stored..stored + compressed_size], decompressed_size).unwrap();
+                        assert!(frame == data[decoded..decoded + decompressed_size], "frame {}", entry);
+                        stored += compressed_size;
+                        decoded += decompressed_size;
+                }
+                assert_eq!((stored, decoded), (table - 8, data.len()));
+
+                let mut whole = vec![];
+                zstd::stream::read::Decoder::new(&encoded[..]).unwrap().read_to_end(&mut whole).unwrap();
+                assert!(whole == data);
+        }
+
+        #[test]
+        fn gzip_members_and_xz_streams_are_written_in_order() {
+                let data = data(3 * GZIP_MEMBER_SIZE + 1000);
+                for threads in [1, 3] {
+                        let encoded = encode(Compression::Gzip, 1, threads, &data);
+                        let mut decoded = vec![];
+                        flate2::read::MultiGzDecoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+                        assert!(decoded == data, "{} threads", threads);
+                        // the first member only holds the first chunk
+                        let mut first = vec![];
+                        flate2::read::GzDecoder::new(&encoded[..]).read_to_end(&mut first).unwrap();
+                        assert!(first == data[..GZIP_MEMBER_SIZE]);
+                }
+
+                let data = self::data(XZ_STREAM_SIZE + 5000);
+                let encoded = encode(Compression::Xz, 0, 2, &data);
+                let mut decoded = vec![];
+                xz2::read::XzDecoder::new_multi_decoder(&encoded[..]).read_to_end(&mut decoded).unwrap();
+                assert!(decoded == data);
+                // the size is found by walking the index of every stream
+                assert_eq!(super::super::compression::decoded_size(Compression::Xz, &mut std::io::Cursor::new(encoded)).unwrap(), Some(data.len() as u64));
+        }
+
+        #[test]
+        fn empty_images_are_valid_streams() {
+                let encoded = encode(Compression::Zstd, 3, 2, &[]);
+                // only the seek table, without any entry
+                assert_eq!(encoded.len(), 8 + ZSTD_SEEK_TABLE_FOOTER_SIZE as usize);
+                assert!(encode(Compression::Gzip, 6, 2, &[]).is_empty());
+        }
+}
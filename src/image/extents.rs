@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::ops::Range;
+
+/// Lists the byte ranges of the first `size` bytes of `file` that hold data, the holes of sparse files are
+/// found with `lseek(SEEK_DATA/SEEK_HOLE)`. `None` is returned when the file has no hole or when the
+/// filesystem cannot report them. The file's offset is moved
+#[cfg(target_os = "linux")]
+pub fn data_extents(file: &File, size: u64) -> std::io::Result<Option<Vec<Range<u64>>>> {
+        use std::os::unix::io::AsRawFd;
+        let fd = file.as_raw_fd();
+        let mut extents: Vec<Range<u64>> = vec![];
+        let mut position: u64 = 0;
+        while position < size {
+                // SAFETY: lseek only repositions the offset of the descriptor owned by `file`
+                let start = unsafe { libc::lseek(fd, position as libc::off_t, libc::SEEK_DATA) };
+                if start < 0 {
+                        let error = std::io::Error::last_os_error();
+                        match error.raw_os_error() {
+                                // there is no data past `position`
+                                Some(libc::ENXIO) => { break; },
+                                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => { return Ok(None); },
+                                _ => { return Err(error); }
+                        }
+                }
+                // SAFETY: as above
+                let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+                if end < 0 {
+                        return Err(std::io::Error::last_os_error());
+                }
+                let (start, end) = (start as u64, (end as u64).min(size));
+                if start >= end {
+                        break;
+                }
+                extents.push(start..end);
+                position = end;
+        }
+        if extents.len() == 1 && extents[0] == (0..size) {
+                return Ok(None);
+        }
+        Ok(Some(extents))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_extents(_file: &File, _size: u64) -> std::io::Result<Option<Vec<Range<u64>>>> {
+        Ok(None)
+}
+
+/// Appends `range` to sorted extents, merging it with the last one when they touch
+pub fn push(extents: &mut Vec<Range<u64>>, range: Range<u64>) {
+        if range.is_empty() {
+                return;
+        }
+        match extents.last_mut() {
+                Some(last) if last.end >= range.start => { last.end = last.end.max(range.end); },
+                _ => { extents.push(range); }
+        }
+}
+
+/// The ranges covered by both `a` and `b`, which are sorted and do not overlap
+pub fn intersect(a: &[Range<u64>], b: &[Range<u64>]) -> Vec<Range<u64>> {
+        let mut result = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+                push(&mut result, a[i].start.max(b[j].start)..a[i].end.min(b[j].end));
+                if a[i].end < b[j].end {
+                        i += 1;
+                } else {
+                        j += 1;
+                }
+        }
+        result
+}
+
+/// Merges two lists of sorted extents into one
+pub fn union(a: &[Range<u64>], b: &[Range<u64>]) -> Vec<Range<u64>> {
+        let mut all: Vec<Range<u64>> = a.iter().chain(b).cloned().collect();
+        all.sort_by_key(|r| r.start);
+        let mut result = vec![];
+        for range in all {
+                push(&mut result, range);
+        }
+        result
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+        use std::io::{Seek, SeekFrom, Write};
+
+        const MIB: u64 = 1 << 20;
+
+        #[test]
+        fn extents_are_merged_and_combined() {
+                let mut extents = vec![];
+                push(&mut extents, 0..10);
+                push(&mut extents, 10..20);
+                push(&mut extents, 15..18);
+                push(&mut extents, 30..30);
+                push(&mut extents, 40..50);
+                assert_eq!(extents, [0..20, 40..50]);
+                assert_eq!(intersect(&extents, &[5..45, 48..60]), [5..20, 40..45, 48..50]);
+                assert_eq!(intersect(&extents, &[20..40]), []);
+                assert_eq!(union(&extents, &[18..25, 50..55, 60..70]), [0..25, 40..55, 60..70]);
+                assert_eq!(union(&[], &extents), extents);
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn holes_are_found_with_seek_data_and_seek_hole() {
+                let path = std::env::temp_dir().join(format!("rmsd-extents-{}", std::process::id()));
+                let mut file = File::create(&path).unwrap();
+                // data at 1MiB and from 3MiB to 4MiB + 1 byte, then a hole up to 8MiB
+                file.seek(SeekFrom::Start(MIB)).unwrap();
+                file.write_all(&[1; 4096]).unwrap();
+                file.seek(SeekFrom::Start(3 * MIB)).unwrap();
+                file.write_all(&vec![2; MIB as usize + 1]).unwrap();
+                file.set_len(8 * MIB).unwrap();
+                file.sync_all().unwrap();
+                let file = File::open(&path).unwrap();
+                let extents = data_extents(&file, 8 * MIB).unwrap();
+                std::fs::remove_file(&path).unwrap();
+                let extents = match extents {
+                        Some(extents) => { extents },
+                        // the filesystem of the temporary directory does not report holes
+                        None => { return; }
+                };
+                // filesystems allocate whole blocks around the data
+                assert_eq!(extents.len(), 2, "{:?}", extents);
+                assert!(extents[0].start <= MIB && extents[0].end >= MIB + 4096 && extents[0].end < 3 * MIB, "{:?}", extents);
+                assert!(extents[1].start <= 3 * MIB && extents[1].start >= extents[0].end, "{:?}", extents);
+                assert!(extents[1].end > 4 * MIB && extents[1].end < 8 * MIB, "{:?}", extents);
+                // files without holes are not worth listing
+                let file = std::fs::File::open(std::env::current_exe().unwrap()).unwrap();
+                assert_eq!(data_extents(&file, 4096).unwrap(), None);
+        }
+}
@@ -0,0 +1,449 @@
+const END_OF_STREAM_MAGIC: &[u8] = b"bvx$";
+const UNCOMPRESSED_MAGIC: &[u8] = b"bvx-";
+const COMPRESSED_V1_MAGIC: &[u8] = b"bvx1";
+const COMPRESSED_V2_MAGIC: &[u8] = b"bvx2";
+const LZVN_MAGIC: &[u8] = b"bvxn";
+/// Size of the fixed part of version 2 headers, the frequency tables follow
+const V2_HEADER_SIZE: usize = 32;
+const LITERALS_PER_BLOCK: u32 = 4 * 10000;
+const MATCHES_PER_BLOCK: u32 = 10000;
+const L_SYMBOLS: usize = 20;
+const M_SYMBOLS: usize = 20;
+const D_SYMBOLS: usize = 64;
+const LITERAL_SYMBOLS: usize = 256;
+const L_STATES: usize = 64;
+const M_STATES: usize = 64;
+const D_STATES: usize = 256;
+const LITERAL_STATES: usize = 1024;
+/// Number of extra bits read after the symbol of each value, and the value of each symbol
+const L_EXTRA_BITS: [u8; L_SYMBOLS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 3, 5, 8];
+const L_BASE_VALUE: [u32; L_SYMBOLS] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 20, 28, 60];
+const M_EXTRA_BITS: [u8; M_SYMBOLS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 5, 8, 11];
+const M_BASE_VALUE: [u32; M_SYMBOLS] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 24, 56, 312];
+const D_EXTRA_BITS: [u8; D_SYMBOLS] = [
+        0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7,
+        8, 8, 8, 8, 9, 9, 9, 9, 10, 10, 10, 10, 11, 11, 11, 11, 12, 12, 12, 12, 13, 13, 13, 13, 14, 14, 14, 14, 15, 15, 15, 15,
+];
+const D_BASE_VALUE: [u32; D_SYMBOLS] = [
+        0, 1, 2, 3, 4, 6, 8, 10, 12, 16, 20, 24, 28, 36, 44, 52, 60, 76, 92, 108, 124, 156, 188, 220, 252, 316, 380, 444,
+        508, 636, 764, 892, 1020, 1276, 1532, 1788, 2044, 2556, 3068, 3580, 4092, 5116, 6140, 7164, 8188, 10236, 12284,
+        14332, 16380, 20476, 24572, 28668, 32764, 40956, 49148, 57340, 65532, 81916, 98300, 114684, 131068, 163836,
+        196604, 229372,
+];
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid LZFSE data, {}", message))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// `length` bytes of `data` from `offset`, or an error if the block is truncated
+fn slice(data: &[u8], offset: usize, length: usize) -> std::io::Result<&[u8]> {
+        offset.checked_add(length).and_then(|end| data.get(offset..end)).ok_or(invalid(format!("the block at byte {} is truncated", offset)))
+}
+
+/// Appends `length` bytes copied from `distance` bytes back in `output`, the copy may overlap the bytes it
+/// produces
+pub fn copy_match(output: &mut Vec<u8>, distance: usize, length: usize) -> std::io::Result<()> {
+        if distance == 0 || distance > output.len() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("match distance {} out of the {} bytes decoded", distance, output.len())));
+        }
+        let start = output.len() - distance;
+        if distance >= length {
+                output.extend_from_within(start..start + length);
+        } else {
+                for i in 0..length {
+                        output.push(output[start + i]);
+                }
+        }
+        Ok(())
+}
+
+/// Decodes an LZFSE stream, made of LZFSE, LZVN and uncompressed blocks up to the end of stream marker, and
+/// appends it to `output`
+pub fn decompress(input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+        let mut position = 0;
+        loop {
+                let magic = input.get(position..position + 4).ok_or(invalid(String::from("the end of stream marker is missing")))?;
+                match magic {
+                        END_OF_STREAM_MAGIC => { return Ok(()); },
+                        UNCOMPRESSED_MAGIC => {
+                                let length = u32_at(slice(input, position, 8)?, 4) as usize;
+                                output.extend_from_slice(slice(input, position + 8, length)?);
+                                position += 8 + length;
+                        },
+                        LZVN_MAGIC => {
+                                let header = slice(input, position, 12)?;
+                                let (raw_length, payload_length) = (u32_at(header, 4) as usize, u32_at(header, 8) as usize);
+                                lzvn_decompress(slice(input, position + 12, payload_length)?, output, raw_length)?;
+                                position += 12 + payload_length;
+                        },
+                        COMPRESSED_V2_MAGIC => {
+                                let header = BlockHeader::read(input, position)?;
+                                position = header.decode(input, output)?;
+                        },
+                        COMPRESSED_V1_MAGIC => { return Err(invalid(String::from("version 1 blocks are not supported"))); },
+                        _ => { return Err(invalid(format!("unknown block magic {:02x?} at byte {}", magic, position))); }
+                }
+        }
+}
+
+/// One state of an FSE decoding table: the symbol it decodes, and the next state made of `delta` plus the
+/// next `bits` bits of the stream
+#[derive(Debug, Clone, Copy, Default)]
+struct TableEntry {
+        symbol: u8,
+        bits: u32,
+        delta: usize,
+}
+
+/// Builds the decoding table of `states` states from the frequency of each symbol
+fn decoding_table(frequencies: &[u16], states: usize) -> std::io::Result<Vec<TableEntry>> {
+        if frequencies.iter().map(|&f| usize::from(f)).sum::<usize>() > states {
+                return Err(invalid(format!("the frequencies exceed {} states", states)));
+        }
+        let mut table = Vec::with_capacity(states);
+        for (symbol, &frequency) in frequencies.iter().enumerate().filter(|(_, &f)| f != 0) {
+                let frequency = usize::from(frequency);
+                // shift needed so that states <= frequency << k < 2 * states
+                let k = (frequency as u32).leading_zeros() - (states as u32).leading_zeros();
+                let j0 = ((2 * states) >> k) - frequency;
+                for j in 0..frequency {
+                        table.push(match j < j0 {
+                                true => { TableEntry { symbol: symbol as u8, bits: k, delta: ((frequency + j) << k) - states } },
+                                false => { TableEntry { symbol: symbol as u8, bits: k - 1, delta: (j - j0) << (k - 1) } }
+                        });
+                }
+        }
+        // the tables of streams that hold no symbol are empty
+        table.resize(states, TableEntry::default());
+        Ok(table)
+}
+
+/// Reads the bits of an FSE stream backwards from its end, the first bits read are the last written
+struct BitReader<'a> {
+        data: &'a [u8],
+        /// The bytes in front of this offset have not been read yet
+        position: usize,
+        accumulator: u64,
+        bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+        /// Starts reading at `end`, the stream was padded with `-padding` bits to complete its last byte
+        fn new(data: &'a [u8], end: usize, padding: i32) -> std::io::Result<BitReader<'a>> {
+                let (length, bits) = match padding {
+                        0 => { (7, 56) },
+                        -7..=-1 => { (8, (64 + padding) as u32) },
+                        _ => { return Err(invalid(format!("{} bits of padding", padding))); }
+                };
+                let position = end.checked_sub(length).ok_or(invalid(String::from("the bit stream is truncated")))?;
+                let accumulator = data[position..end].iter().rev().fold(0u64, |accumulator, &b| accumulator << 8 | u64::from(b));
+                if accumulator >> bits != 0 {
+                        return Err(invalid(String::from("the padding of the bit stream is not made of zeros")));
+                }
+                Ok(BitReader { data, position, accumulator, bits })
+        }
+
+        /// Loads whole bytes until at least 56 bits are available
+        fn refill(&mut self) -> std::io::Result<()> {
+                let length = ((63 - self.bits) / 8) as usize;
+                self.position = self.position.checked_sub(length).ok_or(invalid(String::from("the bit stream is truncated")))?;
+                for &b in self.data[self.position..self.position + length].iter().rev() {
+                        self.accumulator = self.accumulator << 8 | u64::from(b);
+                }
+                self.bits += 8 * length as u32;
+                Ok(())
+        }
+
+        fn pull(&mut self, count: u32) -> std::io::Result<u64> {
+                if count > self.bits {
+                        return Err(invalid(String::from("the bit stream is exhausted")));
+                }
+                self.bits -= count;
+                let value = self.accumulator >> self.bits;
+                self.accumulator &= (1u64 << self.bits) - 1;
+                Ok(value)
+        }
+
+        /// Decodes the next symbol of the stream whose state is `state`
+        fn symbol(&mut self, table: &[TableEntry], state: &mut usize) -> std::io::Result<u8> {
+                let entry = table[*state];
+                *state = entry.delta + self.pull(entry.bits)? as usize;
+                Ok(entry.symbol)
+        }
+
+        /// Decodes the next value, the bits of the next state are followed by the extra bits added to the
+        /// base value of the symbol
+        fn value(&mut self, table: &[TableEntry], state: &mut usize, extra_bits: &[u8], base_value: &[u32]) -> std::io::Result<u32> {
+                let entry = table[*state];
+                let extra = u32::from(extra_bits[usize::from(entry.symbol)]);
+                let bits = self.pull(entry.bits + extra)?;
+                *state = entry.delta + (bits >> extra) as usize;
+                Ok(base_value[usize::from(entry.symbol)] + (bits & ((1 << extra) - 1)) as u32)
+        }
+}
+
+/// The header of a compressed LZFSE block, version 2 headers pack the fields and the frequency tables
+struct BlockHeader {
+        /// Offset of the payload of the block, the literals followed by the matches
+        payload: usize,
+        raw_length: usize,
+        literals: u32,
+        literal_payload_length: usize,
+        literal_padding: i32,
+        literal_states: [usize; 4],
+        matches: u32,
+        lmd_payload_length: usize,
+        lmd_padding: i32,
+        l_state: usize,
+        m_state: usize,
+        d_state: usize,
+        /// The frequencies of the L, M, D and literal symbols, in that order
+        frequencies: Vec<u16>,
+}
+
+impl BlockHeader {
+        fn read(input: &[u8], position: usize) -> std::io::Result<BlockHeader> {
+                let fixed = slice(input, position, V2_HEADER_SIZE)?;
+                let (v0, v1, v2) = (u64_at(fixed, 8), u64_at(fixed, 16), u64_at(fixed, 24));
+                let field = |value: u64, offset: u32, bits: u32| ((value >> offset) & ((1 << bits) - 1)) as usize;
+                let header_length = field(v2, 0, 32);
+                let header = BlockHeader {
+                        payload: position + header_length,
+                        raw_length: u32_at(fixed, 4) as usize,
+                        literals: field(v0, 0, 20) as u32,
+                        literal_payload_length: field(v0, 20, 20),
+                        literal_padding: field(v0, 60, 3) as i32 - 7,
+                        literal_states: [field(v1, 0, 10), field(v1, 10, 10), field(v1, 20, 10), field(v1, 30, 10)],
+                        matches: field(v0, 40, 20) as u32,
+                        lmd_payload_length: field(v1, 40, 20),
+                        lmd_padding: field(v1, 60, 3) as i32 - 7,
+                        l_state: field(v2, 32, 10),
+                        m_state: field(v2, 42, 10),
+                        d_state: field(v2, 52, 10),
+                        frequencies: read_frequencies(slice(input, position + V2_HEADER_SIZE, header_length.saturating_sub(V2_HEADER_SIZE))?)?,
+                };
+                if header_length < V2_HEADER_SIZE || header.literals > LITERALS_PER_BLOCK || header.matches > MATCHES_PER_BLOCK || header.literal_states.iter().any(|&s| s >= LITERAL_STATES) || header.l_state >= L_STATES || header.m_state >= M_STATES || header.d_state >= D_STATES {
+                        return Err(invalid(format!("malformed block header at byte {}", position)));
+                }
+                Ok(header)
+        }
+
+        /// Decodes the literals, then the L, M, D triplets that interleave them with matches, returning the
+        /// offset of the next block
+        fn decode(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<usize> {
+                let (l_frequencies, rest) = self.frequencies.split_at(L_SYMBOLS);
+                let (m_frequencies, rest) = rest.split_at(M_SYMBOLS);
+                let (d_frequencies, literal_frequencies) = rest.split_at(D_SYMBOLS);
+                let literal_end = self.payload + slice(input, self.payload, self.literal_payload_length)?.len();
+                let lmd_end = literal_end + slice(input, literal_end, self.lmd_payload_length)?.len();
+                // literals are decoded 4 at a time, each of the 4 interleaved streams having its own state
+                let table = decoding_table(literal_frequencies, LITERAL_STATES)?;
+                let mut literals = vec![0u8; (self.literals as usize).next_multiple_of(4)];
+                let mut states = self.literal_states;
+                let mut stream = BitReader::new(input, literal_end, self.literal_padding)?;
+                for group in literals.chunks_exact_mut(4) {
+                        stream.refill()?;
+                        for (literal, state) in group.iter_mut().zip(states.iter_mut()) {
+                                *literal = stream.symbol(&table, state)?;
+                        }
+                }
+                let (l_table, m_table, d_table) = (decoding_table(l_frequencies, L_STATES)?, decoding_table(m_frequencies, M_STATES)?, decoding_table(d_frequencies, D_STATES)?);
+                let (mut l_state, mut m_state, mut d_state) = (self.l_state, self.m_state, self.d_state);
+                let mut stream = BitReader::new(input, lmd_end, self.lmd_padding)?;
+                let (start, mut literal, mut distance) = (output.len(), 0, 0);
+                for _ in 0..self.matches {
+                        stream.refill()?;
+                        let l = stream.value(&l_table, &mut l_state, &L_EXTRA_BITS, &L_BASE_VALUE)? as usize;
+                        let m = stream.value(&m_table, &mut m_state, &M_EXTRA_BITS, &M_BASE_VALUE)? as usize;
+                        // a distance of 0 repeats the previous one
+                        let d = stream.value(&d_table, &mut d_state, &D_EXTRA_BITS, &D_BASE_VALUE)? as usize;
+                        if d != 0 {
+                                distance = d;
+                        }
+                        output.extend_from_slice(literals.get(literal..literal + l).ok_or(invalid(String::from("the matches use more literals than decoded")))?);
+                        literal += l;
+                        // the trailing literals of a block come with an empty match
+                        if m > 0 {
+                                copy_match(output, distance, m)?;
+                        }
+                        if output.len() - start > self.raw_length {
+                                return Err(invalid(format!("the block decodes to more than {} bytes", self.raw_length)));
+                        }
+                }
+                if output.len() - start != self.raw_length {
+                        return Err(invalid(format!("the block decodes to {} bytes instead of {}", output.len() - start, self.raw_length)));
+                }
+                Ok(lmd_end)
+        }
+}
+
+/// Decodes the frequency tables of a version 2 header, each frequency is a variable length code read from
+/// the least significant bits
+fn read_frequencies(data: &[u8]) -> std::io::Result<Vec<u16>> {
+        const CODE_BITS: [u32; 32] = [2, 3, 2, 5, 2, 3, 2, 8, 2, 3, 2, 5, 2, 3, 2, 14, 2, 3, 2, 5, 2, 3, 2, 8, 2, 3, 2, 5, 2, 3, 2, 14];
+        const CODE_VALUE: [u16; 32] = [0, 2, 1, 4, 0, 3, 1, 0, 0, 2, 1, 5, 0, 3, 1, 0, 0, 2, 1, 6, 0, 3, 1, 0, 0, 2, 1, 7, 0, 3, 1, 0];
+        let mut frequencies = vec![0u16; L_SYMBOLS + M_SYMBOLS + D_SYMBOLS + LITERAL_SYMBOLS];
+        // the tables may be omitted when the block holds no symbol
+        if data.is_empty() {
+                return Ok(frequencies);
+        }
+        let (mut accumulator, mut bits, mut bytes) = (0u32, 0, data.iter());
+        for frequency in frequencies.iter_mut() {
+                while bits + 8 <= 32 {
+                        match bytes.next() {
+                                Some(&b) => { accumulator |= u32::from(b) << bits; },
+                                None => { break; }
+                        }
+                        bits += 8;
+                }
+                let code = (accumulator & 31) as usize;
+                let length = CODE_BITS[code];
+                *frequency = match length {
+                        8 => { 8 + ((accumulator >> 4) & 0xF) as u16 },
+                        14 => { 24 + ((accumulator >> 4) & 0x3FF) as u16 },
+                        _ => { CODE_VALUE[code] }
+                };
+                if length > bits {
+                        return Err(invalid(String::from("the frequency tables are truncated")));
+                }
+                accumulator >>= length;
+                bits -= length;
+        }
+        if bits >= 8 || bytes.next().is_some() {
+                return Err(invalid(String::from("the frequency tables do not fill the header")));
+        }
+        Ok(frequencies)
+}
+
+/// Decodes the payload of an LZVN block, whose opcodes copy literals from the payload and matches from the
+/// output, into `raw_length` bytes appended to `output`
+fn lzvn_decompress(input: &[u8], output: &mut Vec<u8>, raw_length: usize) -> std::io::Result<()> {
+        let lzvn_invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid LZVN data, {}", message));
+        let byte = |offset: usize| input.get(offset).copied().ok_or(lzvn_invalid(String::from("the payload is truncated")));
+        let (start, mut position, mut distance) = (output.len(), 0, 0);
+        loop {
+                let opcode = byte(position)?;
+                // the length of the opcode, the number of literals following it, the length of the match and its
+                // distance when the opcode sets a new one
+                let (length, literals, match_length, new_distance) = match opcode {
+                        0x06 => { break; },
+                        0x0E | 0x16 => {
+                                position += 1;
+                                continue;
+                        },
+                        0xE0 => { (2, usize::from(byte(position + 1)?) + 16, 0, None) },
+                        0xE1..=0xEF => { (1, usize::from(opcode & 0xF), 0, None) },
+                        0xF0 => { (2, 0, usize::from(byte(position + 1)?) + 16, None) },
+                        0xF1..=0xFF => { (1, 0, usize::from(opcode & 0xF), None) },
+                        0xA0..=0xBF => {
+                                let operand = usize::from(byte(position + 1)?) | usize::from(byte(position + 2)?) << 8;
+                                (3, usize::from((opcode >> 3) & 3), ((usize::from(opcode & 7) << 2) | (operand & 3)) + 3, Some(operand >> 2))
+                        },
+                        0x1E | 0x26 | 0x2E | 0x36 | 0x3E | 0x70..=0x7F | 0xD0..=0xDF => {
+                                return Err(lzvn_invalid(format!("undefined opcode {:#04x} at byte {}", opcode, position)));
+                        },
+                        _ => {
+                                let (literals, match_length) = (usize::from(opcode >> 6), usize::from((opcode >> 3) & 7) + 3);
+                                match opcode & 7 {
+                                        6 => { (1, literals, match_length, None) },
+                                        7 => { (3, literals, match_length, Some(usize::from(byte(position + 1)?) | usize::from(byte(position + 2)?) << 8)) },
+                                        _ => { (2, literals, match_length, Some(usize::from(opcode & 7) << 8 | usize::from(byte(position + 1)?))) }
+                                }
+                        }
+                };
+                position += length;
+                output.extend_from_slice(input.get(position..position + literals).ok_or(lzvn_invalid(String::from("the payload is truncated")))?);
+                position += literals;
+                if let Some(new_distance) = new_distance {
+                        distance = new_distance;
+                }
+                if match_length > 0 {
+                        copy_match(output, distance, match_length)?;
+                }
+                if output.len() - start > raw_length {
+                        return Err(lzvn_invalid(format!("the block decodes to more than {} bytes", raw_length)));
+                }
+        }
+        if output.len() - start != raw_length {
+                return Err(lzvn_invalid(format!("the block decodes to {} bytes instead of {}", output.len() - start, raw_length)));
+        }
+        Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        /// A version 2 block of the 128 bytes of `V2_DECODED`, made by a separate encoder written from the
+        /// reference implementation's description of the format, the frequency tables are not trivial
+        const V2_BLOCK: &str = "62767832800000003800f001000400404131eae50f050070a400000032a8c0068f0070080000c02187c023000000c0218f02c0a3008f0200008f020000000000000000000000ef0100000000000000f085000000a79c0200000000c0e92900a700707a0ac029007c0ccfc033f00cfc10a73c03f00c9cf231bc07f00c3c03ef013c03000000000000000000000000000000000000000000000000000000000000000000000000549a70fd6e6a3a18be645d5a541a9f43fe3ebef9bc2975b6e969e0860e40422c202a";
+        const V2_DECODED: &[u8] = b"The LZFSE block of the known answer test, abracadabra, abracadabra, abracadabra, abracadabra, abracadabra, abracadabra, the end.";
+
+        /// An LZVN payload using every kind of opcode, assembled by hand
+        const LZVN_PAYLOAD: &[u8] = &[
+                0xE4, b'a', b'b', b'c', b'd',   // small literal: "abcd"
+                0x28, 0x04,                     // small distance: 8 bytes from 4 back
+                0xF2,                           // small match: 2 bytes from the previous distance
+                0x80, 0x03, b'x', b'y',         // small distance with 2 literals: "xy" then 3 bytes from 3 back
+                0x4E, b'z',                     // previous distance with 1 literal: "z" then 4 bytes
+                0x17, 0x18, 0x00,               // large distance: 5 bytes from 24 back
+                0xA8, 0x2B, 0x00, b'q',         // medium distance with 1 literal: "q" then 6 bytes from 10 back
+                0x0E,                           // nop
+                0xE0, 0x01, b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'A', b'B', b'C', b'D', b'E', b'F', b'G',
+                0xF0, 0x02,                     // large match: 18 bytes from the previous distance
+                0x06, 0, 0, 0, 0, 0, 0, 0,      // end of stream
+        ];
+        const LZVN_DECODED: &[u8] = b"abcdabcdabcdabxybxyzxyzxabcdaqxyzxab0123456789ABCDEFG789ABCDEFG789ABCDE";
+
+        fn hex(data: &str) -> Vec<u8> {
+                (0..data.len()).step_by(2).map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap()).collect()
+        }
+
+        fn block(magic: &[u8], fields: &[u32], payload: &[u8]) -> Vec<u8> {
+                let mut block = magic.to_vec();
+                fields.iter().for_each(|f| block.extend_from_slice(&f.to_le_bytes()));
+                block.extend_from_slice(payload);
+                block
+        }
+
+        #[test]
+        fn lzvn_opcodes() {
+                let mut output = b"prefix".to_vec();
+                lzvn_decompress(LZVN_PAYLOAD, &mut output, LZVN_DECODED.len()).unwrap();
+                assert_eq!(&output[6..], LZVN_DECODED);
+                assert!(lzvn_decompress(LZVN_PAYLOAD, &mut vec![], LZVN_DECODED.len() - 1).is_err());
+                assert!(lzvn_decompress(&LZVN_PAYLOAD[..LZVN_PAYLOAD.len() - 10], &mut vec![], LZVN_DECODED.len()).is_err());
+                // the first match cannot reach before the start of the output
+                assert!(lzvn_decompress(&[0x28, 0x04, 0x06], &mut vec![], 8).is_err());
+        }
+
+        #[test]
+        fn stream_of_every_kind_of_block() {
+                let mut stream = hex(V2_BLOCK);
+                stream.extend(block(LZVN_MAGIC, &[LZVN_DECODED.len() as u32, LZVN_PAYLOAD.len() as u32], LZVN_PAYLOAD));
+                stream.extend(block(UNCOMPRESSED_MAGIC, &[5], b"hello"));
+                stream.extend_from_slice(END_OF_STREAM_MAGIC);
+                let mut output = vec![];
+                decompress(&stream, &mut output).unwrap();
+                assert_eq!(output, [V2_DECODED, LZVN_DECODED, b"hello"].concat());
+        }
+
+        #[test]
+        fn malformed_streams() {
+                let mut v2 = hex(V2_BLOCK);
+                assert!(decompress(&v2, &mut vec![]).is_err(), "missing end of stream");
+                v2.truncate(v2.len() - 1);
+                v2.extend_from_slice(END_OF_STREAM_MAGIC);
+                assert!(decompress(&v2, &mut vec![]).is_err(), "truncated block");
+                assert!(decompress(&block(UNCOMPRESSED_MAGIC, &[6], b"hello"), &mut vec![]).is_err());
+                assert!(decompress(b"bvx1\0\0\0\0bvx$", &mut vec![]).is_err());
+        }
+}
@@ -0,0 +1,556 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use crate::log;
+use super::{extents, Compression, VirtualDisk};
+
+const MAGIC: &[u8] = b"QFI\xfb";
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+/// Longest chain of backing files followed, deeper chains are most likely loops
+const MAX_BACKING_DEPTH: usize = 16;
+/// Host offset stored in L1 and standard L2 entries
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+/// Standard clusters that read as zeros (version 3)
+const ZERO_FLAG: u64 = 1;
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+const INCOMPATIBLE_COMPRESSION_TYPE: u64 = 1 << 3;
+/// Incompatible features that do not prevent reading the image
+const SUPPORTED_INCOMPATIBLE: u64 = INCOMPATIBLE_DIRTY | INCOMPATIBLE_CORRUPT | INCOMPATIBLE_COMPRESSION_TYPE;
+const COMPRESSION_ZLIB: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+/// Cluster size of the images written, the default of qemu-img
+const OUTPUT_CLUSTER_BITS: u32 = 16;
+/// Refcounts of the images written are 16 bits wide
+const OUTPUT_REFCOUNT_ORDER: u32 = 4;
+/// Header of the images written with zstd compressed clusters, the compression type padded to 8 bytes
+const ZSTD_HEADER_SIZE: usize = V3_HEADER_SIZE + 8;
+/// qemu inflates compressed clusters with a 4KiB window
+const DEFLATE_WINDOW: usize = 4096;
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid qcow2 image, {}", message))
+}
+
+pub fn is_qcow2(magic: &[u8]) -> bool {
+        magic.starts_with(MAGIC)
+}
+
+/// Where a cluster of the guest disk is stored
+enum Mapping {
+        Unallocated,
+        Zero,
+        Data(u64),
+        /// Host offset and maximum length of the compressed data
+        Compressed(u64, u64),
+}
+
+/// The image the unallocated clusters are read from
+enum Backing {
+        Raw { file: File, size: u64 },
+        Qcow2(Box<Reader>),
+}
+
+/// Reads the guest disk of a qcow2 image (versions 2 and 3), the clusters are looked up in the L1/L2 tables
+/// and the unallocated ones are read from the backing file, if any
+pub struct Reader {
+        file: File,
+        cluster_bits: u32,
+        size: u64,
+        compression_type: u8,
+        l1: Vec<u64>,
+        /// The last L2 table read and its host offset
+        l2: Option<(u64, Vec<u64>)>,
+        /// The last compressed cluster decoded and its host offset
+        cluster: Option<(u64, Vec<u8>)>,
+        backing: Option<Backing>,
+        position: u64,
+}
+
+impl Reader {
+        pub fn open(path: &Path) -> std::io::Result<Reader> {
+                Reader::open_chain(path, 0)
+        }
+
+        fn open_chain(path: &Path, depth: usize) -> std::io::Result<Reader> {
+                let mut file = File::open(path)?;
+                let mut data = [0u8; V3_HEADER_SIZE + 8];
+                let length = super::read_full(&mut file, &mut data)?;
+                let u32_at = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+                let u64_at = |offset: usize| u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap());
+                if length < V2_HEADER_SIZE || !is_qcow2(&data) {
+                        return Err(invalid(String::from("the header is missing")));
+                }
+                let version = u32_at(4);
+                let cluster_bits = u32_at(20);
+                if version != 2 && version != 3 {
+                        return Err(invalid(format!("unsupported version {}", version)));
+                }
+                if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+                        return Err(invalid(format!("unsupported cluster size 2^{}", cluster_bits)));
+                }
+                if u32_at(32) != 0 {
+                        return Err(invalid(String::from("encrypted images are not supported")));
+                }
+                let mut compression_type = COMPRESSION_ZLIB;
+                if version == 3 {
+                        if length < V3_HEADER_SIZE {
+                                return Err(invalid(String::from("the header is truncated")));
+                        }
+                        let incompatible = u64_at(72);
+                        if incompatible & !SUPPORTED_INCOMPATIBLE != 0 {
+                                return Err(invalid(format!("unsupported incompatible features {:#x}", incompatible & !SUPPORTED_INCOMPATIBLE)));
+                        }
+                        if incompatible & INCOMPATIBLE_CORRUPT != 0 {
+                                log::warning!("qcow2::Reader::open(): {:?} is marked as corrupt, its content may be wrong", path);
+                        }
+                        if incompatible & INCOMPATIBLE_COMPRESSION_TYPE != 0 {
+                                if u32_at(100) as usize <= V3_HEADER_SIZE {
+                                        return Err(invalid(String::from("the compression type is missing")));
+                                }
+                                compression_type = data[V3_HEADER_SIZE];
+                                if compression_type != COMPRESSION_ZLIB && compression_type != COMPRESSION_ZSTD {
+                                        return Err(invalid(format!("unsupported compression type {}", compression_type)));
+                                }
+                        }
+                }
+                let size = u64_at(24);
+                let l1_size = u32_at(36) as usize;
+                let l1_offset = u64_at(40);
+                if (l1_size as u64) < size.div_ceil(1 << (2 * cluster_bits - 3)) {
+                        return Err(invalid(format!("the L1 table of {} entries does not cover {} bytes", l1_size, size)));
+                }
+                let mut table = vec![0u8; l1_size * 8];
+                file.seek(SeekFrom::Start(l1_offset))?;
+                file.read_exact(&mut table)?;
+                let l1 = table.chunks_exact(8).map(|e| u64::from_be_bytes(e.try_into().unwrap())).collect();
+                let backing = match (u64_at(8), u32_at(16)) {
+                        (0, _) => { None },
+                        (_, name_size) if name_size > MAX_BACKING_FILE_NAME => {
+                                return Err(invalid(format!("the backing file name is {} bytes long", name_size)));
+                        },
+                        (name_offset, name_size) => {
+                                if depth >= MAX_BACKING_DEPTH {
+                                        return Err(invalid(format!("more than {} backing files are chained", MAX_BACKING_DEPTH)));
+                                }
+                                let mut name = vec![0u8; name_size as usize];
+                                file.seek(SeekFrom::Start(name_offset))?;
+                                file.read_exact(&mut name)?;
+                                let name = PathBuf::from(String::from_utf8(name).map_err(|_| invalid(String::from("the backing file name is not UTF-8")))?);
+                                // relative names are relative to the directory of the image
+                                let backing_path = path.parent().unwrap_or(Path::new("")).join(name);
+                                Some(Backing::open(&backing_path, depth + 1)?)
+                        }
+                };
+                log::debug!("qcow2::Reader::open(): {:?} is a version {} image of {} bytes, {} byte clusters, compression type {}", path, version, size, 1u64 << cluster_bits, compression_type);
+                Ok(Reader { file, cluster_bits, size, compression_type, l1, l2: None, cluster: None, backing, position: 0 })
+        }
+
+        fn cluster_size(&self) -> u64 {
+                1 << self.cluster_bits
+        }
+
+        fn mapping(&mut self, cluster: u64) -> std::io::Result<Mapping> {
+                let entries = 1u64 << (self.cluster_bits - 3);
+                let table_offset = self.l1.get((cluster / entries) as usize).copied().unwrap_or(0) & OFFSET_MASK;
+                if table_offset == 0 {
+                        return Ok(Mapping::Unallocated);
+                }
+                if self.l2.as_ref().is_none_or(|(offset, _)| *offset != table_offset) {
+                        let mut table = vec![0u8; self.cluster_size() as usize];
+                        self.file.seek(SeekFrom::Start(table_offset))?;
+                        self.file.read_exact(&mut table)?;
+                        self.l2 = Some((table_offset, table.chunks_exact(8).map(|e| u64::from_be_bytes(e.try_into().unwrap())).collect()));
+                }
+                let entry = self.l2.as_ref().unwrap().1[(cluster % entries) as usize];
+                if entry & COMPRESSED_FLAG != 0 {
+                        // the offset takes the low bits, followed by the number of additional 512 byte sectors
+                        let offset_bits = 62 - (self.cluster_bits - 8);
+                        let offset = entry & ((1 << offset_bits) - 1);
+                        let sectors = ((entry & !(COPIED_FLAG | COMPRESSED_FLAG)) >> offset_bits) + 1;
+                        return Ok(Mapping::Compressed(offset, sectors * 512 - (offset & 511)));
+                }
+                Ok(match entry & OFFSET_MASK {
+                        _ if entry & ZERO_FLAG != 0 => { Mapping::Zero },
+                        0 => { Mapping::Unallocated },
+                        offset => { Mapping::Data(offset) }
+                })
+        }
+
+        fn decompress(&mut self, offset: u64, length: u64) -> std::io::Result<&[u8]> {
+                if self.cluster.as_ref().is_none_or(|(cached, _)| *cached != offset) {
+                        // the length is rounded up to whole sectors and may go past the end of the file
+                        let mut compressed = vec![0u8; length as usize];
+                        self.file.seek(SeekFrom::Start(offset))?;
+                        let compressed_length = super::read_full(&mut self.file, &mut compressed)?;
+                        compressed.truncate(compressed_length);
+                        let mut cluster = vec![0u8; self.cluster_size() as usize];
+                        let result = match self.compression_type {
+                                COMPRESSION_ZSTD => { zstd::stream::read::Decoder::with_buffer(&compressed[..])?.single_frame().read_exact(&mut cluster) },
+                                _ => { flate2::read::DeflateDecoder::new(&compressed[..]).read_exact(&mut cluster) }
+                        };
+                        if let Err(e) = result {
+                                return Err(invalid(format!("failed to decompress the cluster at {:#x}, {}", offset, e)));
+                        }
+                        self.cluster = Some((offset, cluster));
+                }
+                Ok(&self.cluster.as_ref().unwrap().1)
+        }
+
+        /// Reads the unallocated part of the guest disk at `offset` from the backing file, past its end the disk
+        /// reads as zeros
+        fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+                let count = match &mut self.backing {
+                        Some(Backing::Raw { file, size }) if offset < *size => {
+                                file.seek(SeekFrom::Start(offset))?;
+                                let length = (*size - offset).min(buf.len() as u64) as usize;
+                                file.read(&mut buf[..length])?
+                        },
+                        Some(Backing::Qcow2(reader)) => {
+                                reader.seek(SeekFrom::Start(offset))?;
+                                reader.read(buf)?
+                        },
+                        _ => { 0 }
+                };
+                if count == 0 {
+                        buf.fill(0);
+                        return Ok(buf.len());
+                }
+                Ok(count)
+        }
+}
+
+impl Backing {
+        fn open(path: &Path, depth: usize) -> std::io::Result<Backing> {
+                let mut file = File::open(path).map_err(|e| std::io::Error::new(e.kind(), format!("failed to open the backing file {:?}, {}", path, e)))?;
+                let mut magic = [0u8; 4];
+                let length = super::read_full(&mut file, &mut magic)?;
+                if is_qcow2(&magic[..length]) {
+                        return Ok(Backing::Qcow2(Box::new(Reader::open_chain(path, depth)?)));
+                }
+                let size = file.metadata()?.len();
+                log::debug!("qcow2::Backing::open(): {:?} is a raw backing file of {} bytes", path, size);
+                Ok(Backing::Raw { file, size })
+        }
+}
+
+impl VirtualDisk for Reader {
+        /// Size of the guest disk
+        fn size(&self) -> u64 {
+                self.size
+        }
+
+        /// Lists the byte ranges of the guest disk that are stored in the image or in its backing files, clusters
+        /// marked as zeros are holes
+        fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+                let cluster_size = self.cluster_size();
+                let entries = 1u64 << (self.cluster_bits - 3);
+                let clusters = self.size.div_ceil(cluster_size);
+                let mut data: Vec<Range<u64>> = vec![];
+                let mut unallocated: Vec<Range<u64>> = vec![];
+                for table in 0..clusters.div_ceil(entries) {
+                        let first = table * entries;
+                        let last = (first + entries).min(clusters);
+                        if self.l1[table as usize] & OFFSET_MASK == 0 {
+                                extents::push(&mut unallocated, first * cluster_size..(last * cluster_size).min(self.size));
+                                continue;
+                        }
+                        for cluster in first..last {
+                                let range = cluster * cluster_size..((cluster + 1) * cluster_size).min(self.size);
+                                match self.mapping(cluster)? {
+                                        Mapping::Data(_) | Mapping::Compressed(_, _) => { extents::push(&mut data, range); },
+                                        Mapping::Unallocated => { extents::push(&mut unallocated, range); },
+                                        Mapping::Zero => {}
+                                }
+                        }
+                }
+                let backing = match &mut self.backing {
+                        Some(Backing::Raw { file, size }) => {
+                                extents::data_extents(file, *size)?.unwrap_or_else(|| std::iter::once(0..*size).collect())
+                        },
+                        Some(Backing::Qcow2(reader)) => { reader.data_extents()? },
+                        None => { vec![] }
+                };
+                Ok(extents::union(&data, &extents::intersect(&unallocated, &backing)))
+        }
+}
+
+impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.position >= self.size || buf.is_empty() {
+                        return Ok(0);
+                }
+                let cluster_size = self.cluster_size();
+                let within = self.position % cluster_size;
+                let length = (cluster_size - within).min(self.size - self.position).min(buf.len() as u64) as usize;
+                let buf = &mut buf[..length];
+                let count = match self.mapping(self.position / cluster_size)? {
+                        Mapping::Unallocated => { self.read_backing(self.position, buf)? },
+                        Mapping::Zero => {
+                                buf.fill(0);
+                                length
+                        },
+                        Mapping::Data(offset) => {
+                                // clusters past the end of the file read as zeros
+                                self.file.seek(SeekFrom::Start(offset + within))?;
+                                let count = super::read_full(&mut self.file, buf)?;
+                                buf[count..].fill(0);
+                                length
+                        },
+                        Mapping::Compressed(offset, compressed_length) => {
+                                let cluster = self.decompress(offset, compressed_length)?;
+                                buf.copy_from_slice(&cluster[within as usize..within as usize + length]);
+                                length
+                        }
+                };
+                self.position += count as u64;
+                Ok(count)
+        }
+}
+
+impl Seek for Reader {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.position = super::seek_position(self.position, self.size, position)?;
+                Ok(self.position)
+        }
+}
+
+/// Writes a version 3 qcow2 image, only the clusters holding data are allocated. Data clusters follow each other
+/// in the file, each L2 table is allocated in front of the first cluster it maps, the header, the L1 table and
+/// the refcounts are written by `finish`
+pub struct Writer {
+        output: BufWriter<File>,
+        size: u64,
+        /// The compression type of the clusters and its level, `None` when they are stored as they are
+        compression: Option<(u8, i32)>,
+        /// The guest cluster being received
+        cluster: Vec<u8>,
+        guest_cluster: u64,
+        l1: Vec<u64>,
+        /// The L2 table being filled and its index in the L1 table
+        l2: Option<(usize, Vec<u64>)>,
+        /// Number of clusters in the file
+        host_clusters: u64,
+        /// End of the compressed clusters packed in the last cluster of the file, 0 when it is not shared
+        packed_end: u64,
+        /// Number of compressed clusters stored in the clusters of the file that hold more than one
+        references: BTreeMap<u64, u16>,
+}
+
+impl Writer {
+        /// Creates a writer for a guest disk of `size` bytes, its clusters are compressed with `compression`
+        /// (deflate or zstd) when it makes them smaller
+        pub fn new(file: File, size: u64, compression: Compression, level: i32) -> std::io::Result<Writer> {
+                let compression = match compression {
+                        Compression::None => { None },
+                        Compression::Gzip => { Some((COMPRESSION_ZLIB, level)) },
+                        Compression::Zstd => { Some((COMPRESSION_ZSTD, level)) },
+                        _ => { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("qcow2 clusters cannot be compressed with {}, only gzip (deflate) and zstd are supported", compression.name()))); }
+                };
+                let cluster_size = 1u64 << OUTPUT_CLUSTER_BITS;
+                let l1_size = size.div_ceil(cluster_size).div_ceil(cluster_size / 8);
+                if l1_size > u64::from(u32::MAX) / 8 {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the image is too large for the qcow2 format"));
+                }
+                let mut writer = Writer {
+                        output: BufWriter::new(file),
+                        size,
+                        compression,
+                        cluster: Vec::with_capacity(cluster_size as usize),
+                        guest_cluster: 0,
+                        l1: vec![0; l1_size as usize],
+                        l2: None,
+                        // the header and the L1 table are written once the file is complete
+                        host_clusters: 1 + (l1_size * 8).div_ceil(cluster_size),
+                        packed_end: 0,
+                        references: BTreeMap::new(),
+                };
+                writer.output.seek(SeekFrom::Start(writer.host_clusters * cluster_size))?;
+                Ok(writer)
+        }
+
+        fn cluster_size(&self) -> u64 {
+                1 << OUTPUT_CLUSTER_BITS
+        }
+
+        /// Reserves the next cluster at the end of the file, after the compressed clusters packed in the last one
+        fn allocate(&mut self) -> std::io::Result<u64> {
+                let offset = self.host_clusters * self.cluster_size();
+                if self.packed_end != 0 {
+                        self.output.write_all(&vec![0u8; (offset - self.packed_end) as usize])?;
+                        self.packed_end = 0;
+                }
+                self.host_clusters += 1;
+                Ok(offset)
+        }
+
+        /// Compresses the cluster being received, `None` when it is not compressed or does not get smaller
+        fn compress_cluster(&self) -> std::io::Result<Option<Vec<u8>>> {
+                let compressed = match self.compression {
+                        Some((COMPRESSION_ZSTD, level)) => { zstd::bulk::compress(&self.cluster, level)? },
+                        Some((_, level)) => { deflate(&self.cluster, level)? },
+                        None => { return Ok(None); }
+                };
+                Ok((compressed.len() < self.cluster.len()).then_some(compressed))
+        }
+
+        /// Appends a compressed cluster right after the previous one, returning its L2 entry
+        fn write_compressed(&mut self, data: &[u8]) -> std::io::Result<u64> {
+                let cluster_size = self.cluster_size();
+                let offset = if self.packed_end != 0 { self.packed_end } else { self.host_clusters * cluster_size };
+                let end = offset + data.len() as u64;
+                self.output.write_all(data)?;
+                for cluster in offset / cluster_size..end.div_ceil(cluster_size) {
+                        *self.references.entry(cluster).or_insert(0) += 1;
+                }
+                self.host_clusters = end.div_ceil(cluster_size);
+                self.packed_end = if end.is_multiple_of(cluster_size) { 0 } else { end };
+                // the number of 512 byte sectors holding the data, past the one it starts in
+                let sectors = (end - 1) / 512 - offset / 512;
+                Ok(COMPRESSED_FLAG | sectors << (62 - (OUTPUT_CLUSTER_BITS - 8)) | offset)
+        }
+
+        /// Writes the L2 table being filled over the cluster it was allocated
+        fn flush_table(&mut self) -> std::io::Result<()> {
+                if let Some((index, table)) = self.l2.take() {
+                        let end = self.output.stream_position()?;
+                        self.output.seek(SeekFrom::Start(self.l1[index] & OFFSET_MASK))?;
+                        self.output.write_all(&table.iter().flat_map(|e| e.to_be_bytes()).collect::<Vec<u8>>())?;
+                        self.output.seek(SeekFrom::Start(end))?;
+                }
+                Ok(())
+        }
+
+        fn push_cluster(&mut self) -> std::io::Result<()> {
+                let cluster_size = self.cluster_size();
+                self.cluster.resize(cluster_size as usize, 0);
+                if self.cluster.iter().any(|&b| b != 0) {
+                        let entries = cluster_size / 8;
+                        let index = (self.guest_cluster / entries) as usize;
+                        if self.l2.as_ref().is_none_or(|(current, _)| *current != index) {
+                                self.flush_table()?;
+                                let offset = self.allocate()?;
+                                self.output.write_all(&vec![0u8; cluster_size as usize])?;
+                                self.l1[index] = offset | COPIED_FLAG;
+                                self.l2 = Some((index, vec![0; entries as usize]));
+                        }
+                        let entry = match self.compress_cluster()? {
+                                Some(compressed) => { self.write_compressed(&compressed)? },
+                                None => {
+                                        let offset = self.allocate()?;
+                                        self.output.write_all(&self.cluster)?;
+                                        offset | COPIED_FLAG
+                                }
+                        };
+                        self.l2.as_mut().unwrap().1[(self.guest_cluster % entries) as usize] = entry;
+                }
+                self.cluster.clear();
+                self.guest_cluster += 1;
+                Ok(())
+        }
+
+        /// Writes the refcounts, the L1 table and the header
+        pub fn finish(mut self) -> std::io::Result<()> {
+                if !self.cluster.is_empty() {
+                        self.push_cluster()?;
+                }
+                self.flush_table()?;
+                let cluster_size = self.cluster_size();
+                // the refcount blocks and table have to count themselves
+                let per_block = cluster_size * 8 / (1 << OUTPUT_REFCOUNT_ORDER);
+                let (mut blocks, mut table_clusters) = (0, 0);
+                loop {
+                        let needed_blocks = (self.host_clusters + blocks + table_clusters).div_ceil(per_block);
+                        let needed_table_clusters = (needed_blocks * 8).div_ceil(cluster_size);
+                        if (needed_blocks, needed_table_clusters) == (blocks, table_clusters) {
+                                break;
+                        }
+                        (blocks, table_clusters) = (needed_blocks, needed_table_clusters);
+                }
+                let table_offset = self.allocate()?;
+                self.host_clusters += table_clusters - 1;
+                let first_block = self.host_clusters;
+                self.host_clusters += blocks;
+                let mut table = vec![0u8; (table_clusters * cluster_size) as usize];
+                for block in 0..blocks {
+                        table[block as usize * 8..][..8].copy_from_slice(&((first_block + block) * cluster_size).to_be_bytes());
+                }
+                self.output.write_all(&table)?;
+                for block in 0..blocks {
+                        let used = self.host_clusters.saturating_sub(block * per_block).min(per_block);
+                        let mut refcounts = vec![0u8; cluster_size as usize];
+                        for (n, refcount) in refcounts.chunks_exact_mut(2).take(used as usize).enumerate() {
+                                let count = self.references.get(&(block * per_block + n as u64)).copied().unwrap_or(1);
+                                refcount.copy_from_slice(&count.to_be_bytes());
+                        }
+                        self.output.write_all(&refcounts)?;
+                }
+                let mut header = [0u8; ZSTD_HEADER_SIZE];
+                header[0..4].copy_from_slice(MAGIC);
+                header[4..8].copy_from_slice(&3u32.to_be_bytes());
+                header[20..24].copy_from_slice(&OUTPUT_CLUSTER_BITS.to_be_bytes());
+                header[24..32].copy_from_slice(&self.size.to_be_bytes());
+                header[36..40].copy_from_slice(&(self.l1.len() as u32).to_be_bytes());
+                header[40..48].copy_from_slice(&cluster_size.to_be_bytes());
+                header[48..56].copy_from_slice(&table_offset.to_be_bytes());
+                header[56..60].copy_from_slice(&(table_clusters as u32).to_be_bytes());
+                header[96..100].copy_from_slice(&OUTPUT_REFCOUNT_ORDER.to_be_bytes());
+                let header_size = match self.compression {
+                        Some((COMPRESSION_ZSTD, _)) => {
+                                header[72..80].copy_from_slice(&INCOMPATIBLE_COMPRESSION_TYPE.to_be_bytes());
+                                header[V3_HEADER_SIZE] = COMPRESSION_ZSTD;
+                                ZSTD_HEADER_SIZE
+                        },
+                        _ => { V3_HEADER_SIZE }
+                };
+                header[100..104].copy_from_slice(&(header_size as u32).to_be_bytes());
+                self.output.seek(SeekFrom::Start(0))?;
+                self.output.write_all(&header[..header_size])?;
+                self.output.seek(SeekFrom::Start(cluster_size))?;
+                self.output.write_all(&self.l1.iter().flat_map(|e| e.to_be_bytes()).collect::<Vec<u8>>())?;
+                self.output.flush()
+        }
+}
+
+impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let length = (self.cluster_size() as usize - self.cluster.len()).min(buf.len());
+                self.cluster.extend_from_slice(&buf[..length]);
+                if self.cluster.len() == self.cluster_size() as usize {
+                        self.push_cluster()?;
+                }
+                Ok(length)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+                self.output.flush()
+        }
+}
+
+/// Compresses a cluster as raw deflate data made of independent pieces of `DEFLATE_WINDOW` bytes, so that none
+/// of its matches reaches further back than the window qemu inflates compressed clusters with
+fn deflate(data: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(data.len());
+        let pieces = data.len().div_ceil(DEFLATE_WINDOW);
+        for (n, piece) in data.chunks(DEFLATE_WINDOW).enumerate() {
+                let mut compress = flate2::Compress::new(flate2::Compression::new(level as u32), false);
+                // the pieces but the last end with a sync flush, on a byte boundary without ending the stream
+                let flush = if n + 1 == pieces { flate2::FlushCompress::Finish } else { flate2::FlushCompress::Sync };
+                loop {
+                        output.reserve(DEFLATE_WINDOW);
+                        let status = compress.compress_vec(&piece[compress.total_in() as usize..], &mut output, flush).map_err(std::io::Error::other)?;
+                        if compress.total_in() as usize == piece.len() && (status == flate2::Status::StreamEnd || (n + 1 < pieces && output.len() < output.capacity())) {
+                                break;
+                        }
+                }
+        }
+        Ok(output)
+}
@@ -0,0 +1,205 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use super::{extents, seek_position};
+use crate::log;
+
+/// Suffix of the first part of a split image
+const FIRST_PART_SUFFIX: &str = "001";
+
+/// The path of the part `index` (counted from 0) of the split image `path`, `image.001`, `image.002`...
+pub fn part_path(path: &Path, index: u64) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{:03}", index + 1));
+        PathBuf::from(name)
+}
+
+/// Lists the parts of the split image `path` points to, either its first part (`image.001`) or the name the
+/// parts were made from (`image`) when no such file exists. `None` is returned for other files, sets whose parts
+/// before the last one differ in size are rejected since parts are missing or left over from another image
+pub fn parts(path: &Path) -> std::io::Result<Option<Vec<PathBuf>>> {
+        let base = if path.extension().is_some_and(|e| e == FIRST_PART_SUFFIX) {
+                path.with_extension("")
+        } else if !path.exists() && part_path(path, 0).exists() {
+                path.to_path_buf()
+        } else {
+                return Ok(None);
+        };
+        let mut parts = vec![];
+        let mut sizes = vec![];
+        while part_path(&base, parts.len() as u64).is_file() {
+                let part = part_path(&base, parts.len() as u64);
+                sizes.push(part.metadata()?.len());
+                parts.push(part);
+        }
+        if let Some(index) = sizes.iter().take(sizes.len().saturating_sub(1)).position(|&size| size != sizes[0]) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                        format!("{:?} is {} bytes while the parts before the last one of a split image must all be {} bytes like {:?}", parts[index], sizes[index], sizes[0], parts[0])));
+        }
+        if sizes.last().is_some_and(|&size| size > sizes[0]) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                        format!("the last part of the split image, {:?}, is larger than {:?}", parts[parts.len() - 1], parts[0])));
+        }
+        Ok((!parts.is_empty()).then_some(parts))
+}
+
+/// The parts of a split image read as a single file
+pub struct Reader {
+        parts: Vec<File>,
+        /// Offset of the start of every part followed by the total size
+        starts: Vec<u64>,
+        /// Index of the part holding `position`
+        index: usize,
+        position: u64,
+}
+
+impl Reader {
+        pub fn open(paths: &[PathBuf]) -> std::io::Result<Reader> {
+                let mut parts = vec![];
+                let mut starts = vec![0];
+                for path in paths {
+                        let file = File::open(path)?;
+                        starts.push(starts[starts.len() - 1] + file.metadata()?.len());
+                        parts.push(file);
+                }
+                Ok(Reader { parts, starts, index: 0, position: 0 })
+        }
+
+        pub fn size(&self) -> u64 {
+                self.starts[self.parts.len()]
+        }
+
+        /// The byte ranges holding data of the parts that are sparse files, `None` if none of them has a hole
+        pub fn data_extents(&mut self) -> std::io::Result<Option<Vec<Range<u64>>>> {
+                let mut data: Vec<Range<u64>> = vec![];
+                let mut sparse = false;
+                for (part, start) in self.parts.iter().zip(self.starts.iter()) {
+                        let length = part.metadata()?.len();
+                        match extents::data_extents(part, length)? {
+                                Some(part_extents) => {
+                                        sparse = true;
+                                        for extent in part_extents {
+                                                extents::push(&mut data, start + extent.start..start + extent.end);
+                                        }
+                                },
+                                None => { extents::push(&mut data, *start..start + length); }
+                        }
+                }
+                self.seek(SeekFrom::Start(self.position))?;
+                Ok(sparse.then_some(data))
+        }
+}
+
+impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                while self.index < self.parts.len() {
+                        let n = self.parts[self.index].read(buf)?;
+                        if n > 0 || buf.is_empty() {
+                                self.position += n as u64;
+                                return Ok(n);
+                        }
+                        // the part may have been shorter than when the set was opened
+                        self.index += 1;
+                        if let Some(part) = self.parts.get_mut(self.index) {
+                                part.seek(SeekFrom::Start(0))?;
+                                self.position = self.starts[self.index];
+                        }
+                }
+                Ok(0)
+        }
+}
+
+impl Seek for Reader {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.position = seek_position(self.position, self.size(), position)?;
+                // the part starting after `position`, less one, also for positions past the end
+                self.index = self.starts[1..].partition_point(|&end| end <= self.position).min(self.parts.len().saturating_sub(1));
+                if let Some(part) = self.parts.get_mut(self.index) {
+                        part.seek(SeekFrom::Start(self.position - self.starts[self.index]))?;
+                }
+                Ok(self.position)
+        }
+}
+
+/// Writes an image in parts of `part_size` bytes named after it, parts are created as the position moves to
+/// them so the parts holes were seeked over are created too
+pub struct Writer {
+        path: PathBuf,
+        part_size: u64,
+        part: File,
+        /// Index of the part `part` is, the number of parts created so far less one is `created`
+        index: u64,
+        created: u64,
+        position: u64,
+}
+
+impl Writer {
+        pub fn create(path: &Path, part_size: u64) -> std::io::Result<Writer> {
+                if part_size == 0 {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the parts of a split image cannot be empty"));
+                }
+                let part = File::create(part_path(path, 0))?;
+                // parts left over from a larger image would be read as part of this one
+                let mut index = 1;
+                while part_path(path, index).is_file() {
+                        log::warning!("split::Writer::create(): removing {:?}, a part of a previous image", part_path(path, index));
+                        std::fs::remove_file(part_path(path, index))?;
+                        index += 1;
+                }
+                Ok(Writer { path: path.to_path_buf(), part_size, part, index: 0, created: 0, position: 0 })
+        }
+
+        /// Opens the part `index`, creating it and the parts before it that do not exist yet
+        fn open_part(&mut self, index: u64) -> std::io::Result<File> {
+                while self.created < index {
+                        self.created += 1;
+                        File::create(part_path(&self.path, self.created))?;
+                }
+                OpenOptions::new().write(true).open(part_path(&self.path, index))
+        }
+
+        /// Sets the length of the whole image, resizing the parts and creating the missing ones
+        pub fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+                let count = length.div_ceil(self.part_size).max(1);
+                for index in 0..count {
+                        let part = self.open_part(index)?;
+                        part.set_len((length - index * self.part_size).min(self.part_size))?;
+                }
+                Ok(())
+        }
+}
+
+impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let index = self.position / self.part_size;
+                let offset = self.position % self.part_size;
+                if index != self.index {
+                        self.part = self.open_part(index)?;
+                        self.part.seek(SeekFrom::Start(offset))?;
+                        self.index = index;
+                }
+                let length = buf.len().min((self.part_size - offset) as usize);
+                let n = self.part.write(&buf[..length])?;
+                self.position += n as u64;
+                Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+                self.part.flush()
+        }
+}
+
+impl Seek for Writer {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                if let SeekFrom::End(_) = position {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "the end of a split image being written is unknown"));
+                }
+                self.position = seek_position(self.position, 0, position)?;
+                // other parts are positioned when they are opened by the next write
+                if self.position / self.part_size == self.index {
+                        self.part.seek(SeekFrom::Start(self.position % self.part_size))?;
+                }
+                Ok(self.position)
+        }
+}
@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use crate::log;
+use super::{extents, VirtualDisk};
+
+const FOOTER_COOKIE: &[u8] = b"conectix";
+const DYNAMIC_HEADER_COOKIE: &[u8] = b"cxsparse";
+const FOOTER_SIZE: u64 = 512;
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+const SECTOR_SIZE: u64 = 512;
+const DISK_TYPE_FIXED: u32 = 2;
+const DISK_TYPE_DYNAMIC: u32 = 3;
+const DISK_TYPE_DIFFERENCING: u32 = 4;
+const UNALLOCATED_BLOCK: u32 = 0xFFFF_FFFF;
+/// Largest block size accepted, the format's default is 2MiB
+const MAX_BLOCK_SIZE: u64 = 256 << 20;
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid VHD image, {}", message))
+}
+
+pub fn is_dynamic(magic: &[u8]) -> bool {
+        magic.starts_with(FOOTER_COOKIE)
+}
+
+/// Fixed disks are raw images followed by a footer, they can only be recognized from their last sector: its
+/// checksum has to match and the disk it describes has to fit before it, raw images ending with the cookie are
+/// not taken for VHDs
+pub fn has_footer<R: Read + Seek>(file: &mut R, size: u64) -> std::io::Result<bool> {
+        if size < FOOTER_SIZE {
+                return Ok(false);
+        }
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        if !footer.starts_with(FOOTER_COOKIE) {
+                return Ok(false);
+        }
+        match parse_footer(&footer) {
+                Ok((DISK_TYPE_FIXED, disk_size)) if disk_size <= size - FOOTER_SIZE => { Ok(true) },
+                Ok((disk_type, disk_size)) => {
+                        log::debug!("has_footer(): the last sector holds the footer of a disk of type {} and {} bytes, the image is raw", disk_type, disk_size);
+                        Ok(false)
+                },
+                Err(e) => {
+                        log::warning!("has_footer(): the image ends with a VHD cookie but is read as a raw image, {}", e);
+                        Ok(false)
+                }
+        }
+}
+
+/// Checks the footer (or its copy at the start of dynamic disks), returns its disk type and the size of the
+/// disk
+fn parse_footer(footer: &[u8; FOOTER_SIZE as usize]) -> std::io::Result<(u32, u64)> {
+        if !footer.starts_with(FOOTER_COOKIE) {
+                return Err(invalid(String::from("the footer is missing")));
+        }
+        let expected = u32::from_be_bytes(footer[64..68].try_into().unwrap());
+        // one's complement of the sum of the bytes of the footer, checksum excluded
+        let sum = footer.iter().enumerate().filter(|(i, _)| !(64..68).contains(i)).fold(0u32, |sum, (_, &b)| sum.wrapping_add(u32::from(b)));
+        if !sum != expected {
+                return Err(invalid(String::from("the checksum of the footer does not match")));
+        }
+        Ok((u32::from_be_bytes(footer[60..64].try_into().unwrap()), u64::from_be_bytes(footer[48..56].try_into().unwrap())))
+}
+
+/// The allocation table of a dynamic disk
+struct Dynamic {
+        block_size: u64,
+        /// Size of the sector bitmap in front of each block
+        bitmap_size: u64,
+        /// Sector of the file where each block starts
+        bat: Vec<u32>,
+        /// The bitmap of the last block read
+        bitmap: Option<(u32, Vec<u8>)>,
+}
+
+/// Reads the virtual disk of a fixed or dynamic VHD image, the unallocated blocks of dynamic disks read as
+/// zeros
+pub struct Reader {
+        file: File,
+        size: u64,
+        dynamic: Option<Dynamic>,
+        position: u64,
+}
+
+impl Reader {
+        pub fn open(path: &Path) -> std::io::Result<Reader> {
+                let mut file = File::open(path)?;
+                let stored_size = file.metadata()?.len();
+                let mut footer = [0u8; FOOTER_SIZE as usize];
+                file.read_exact(&mut footer)?;
+                if !is_dynamic(&footer) {
+                        file.seek(SeekFrom::Start(stored_size - FOOTER_SIZE))?;
+                        file.read_exact(&mut footer)?;
+                }
+                let (disk_type, size) = parse_footer(&footer)?;
+                let dynamic = match disk_type {
+                        DISK_TYPE_FIXED => {
+                                if size > stored_size - FOOTER_SIZE {
+                                        return Err(invalid(format!("the disk of {} bytes does not fit in the file", size)));
+                                }
+                                None
+                        },
+                        DISK_TYPE_DYNAMIC => {
+                                let mut header = [0u8; DYNAMIC_HEADER_SIZE];
+                                file.seek(SeekFrom::Start(u64::from_be_bytes(footer[16..24].try_into().unwrap())))?;
+                                file.read_exact(&mut header)?;
+                                if !header.starts_with(DYNAMIC_HEADER_COOKIE) {
+                                        return Err(invalid(String::from("the dynamic disk header is missing")));
+                                }
+                                let table_offset = u64::from_be_bytes(header[16..24].try_into().unwrap());
+                                let entries = u32::from_be_bytes(header[28..32].try_into().unwrap()) as usize;
+                                let block_size = u64::from(u32::from_be_bytes(header[32..36].try_into().unwrap()));
+                                if block_size == 0 || block_size > MAX_BLOCK_SIZE || !block_size.is_multiple_of(SECTOR_SIZE) || (entries as u64) < size.div_ceil(block_size) {
+                                        return Err(invalid(format!("{} blocks of {} bytes do not describe a disk of {} bytes", entries, block_size, size)));
+                                }
+                                let mut table = vec![0u8; entries * 4];
+                                file.seek(SeekFrom::Start(table_offset))?;
+                                file.read_exact(&mut table)?;
+                                let bat = table.chunks_exact(4).map(|e| u32::from_be_bytes(e.try_into().unwrap())).collect();
+                                let bitmap_size = (block_size / SECTOR_SIZE).div_ceil(8).next_multiple_of(SECTOR_SIZE);
+                                Some(Dynamic { block_size, bitmap_size, bat, bitmap: None })
+                        },
+                        DISK_TYPE_DIFFERENCING => { return Err(invalid(String::from("differencing disks are not supported"))); },
+                        _ => { return Err(invalid(format!("unknown disk type {}", disk_type))); }
+                };
+                log::debug!("vhd::Reader::open(): {:?} is a {} disk of {} bytes", path, if dynamic.is_some() { "dynamic" } else { "fixed" }, size);
+                Ok(Reader { file, size, dynamic, position: 0 })
+        }
+}
+
+impl VirtualDisk for Reader {
+        /// Size of the virtual disk
+        fn size(&self) -> u64 {
+                self.size
+        }
+
+        /// Lists the byte ranges of the virtual disk that are allocated, the holes of fixed disks stored in sparse
+        /// files are not
+        fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+                let dynamic = match &self.dynamic {
+                        Some(dynamic) => { dynamic },
+                        None => { return Ok(extents::data_extents(&self.file, self.size)?.unwrap_or_else(|| std::iter::once(0..self.size).collect())); }
+                };
+                let mut data = vec![];
+                for (block, &sector) in dynamic.bat.iter().enumerate() {
+                        let start = block as u64 * dynamic.block_size;
+                        if sector != UNALLOCATED_BLOCK && start < self.size {
+                                extents::push(&mut data, start..(start + dynamic.block_size).min(self.size));
+                        }
+                }
+                Ok(data)
+        }
+}
+
+impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.position >= self.size || buf.is_empty() {
+                        return Ok(0);
+                }
+                let length = (self.size - self.position).min(buf.len() as u64) as usize;
+                let buf = &mut buf[..length];
+                let dynamic = match &mut self.dynamic {
+                        Some(dynamic) => { dynamic },
+                        None => {
+                                self.file.seek(SeekFrom::Start(self.position))?;
+                                let count = self.file.read(buf)?;
+                                self.position += count as u64;
+                                return Ok(count);
+                        }
+                };
+                let block = (self.position / dynamic.block_size) as usize;
+                let within = self.position % dynamic.block_size;
+                let sector = dynamic.bat[block];
+                let buf = &mut buf[..(dynamic.block_size - within).min(length as u64) as usize];
+                if sector == UNALLOCATED_BLOCK {
+                        buf.fill(0);
+                        self.position += buf.len() as u64;
+                        return Ok(buf.len());
+                }
+                let block_offset = u64::from(sector) * SECTOR_SIZE;
+                if dynamic.bitmap.as_ref().is_none_or(|(cached, _)| *cached != sector) {
+                        let mut bitmap = vec![0u8; dynamic.bitmap_size as usize];
+                        self.file.seek(SeekFrom::Start(block_offset))?;
+                        self.file.read_exact(&mut bitmap)?;
+                        dynamic.bitmap = Some((sector, bitmap));
+                }
+                // the sectors of the block that are not marked in its bitmap read as zeros
+                let bitmap = &dynamic.bitmap.as_ref().unwrap().1;
+                let is_set = |sector: u64| bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0;
+                let first = within / SECTOR_SIZE;
+                let mut end = (first + 1) * SECTOR_SIZE;
+                while end < within + buf.len() as u64 && is_set(end / SECTOR_SIZE) == is_set(first) {
+                        end += SECTOR_SIZE;
+                }
+                let count = (end.min(within + buf.len() as u64) - within) as usize;
+                let buf = &mut buf[..count];
+                if is_set(first) {
+                        self.file.seek(SeekFrom::Start(block_offset + dynamic.bitmap_size + within))?;
+                        self.file.read_exact(buf)?;
+                } else {
+                        buf.fill(0);
+                }
+                self.position += buf.len() as u64;
+                Ok(buf.len())
+        }
+}
+
+impl Seek for Reader {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.position = super::seek_position(self.position, self.size, position)?;
+                Ok(self.position)
+        }
+}
@@ -0,0 +1,374 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use crate::log;
+use super::{extents, VirtualDisk};
+
+const FILE_IDENTIFIER: &[u8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8] = b"head";
+const REGION_TABLE_SIGNATURE: &[u8] = b"regi";
+const METADATA_SIGNATURE: &[u8] = b"metadata";
+const HEADER_OFFSETS: [u64; 2] = [64 << 10, 128 << 10];
+const HEADER_SIZE: usize = 4 << 10;
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 << 10, 256 << 10];
+const REGION_TABLE_SIZE: usize = 64 << 10;
+const MAX_REGION_ENTRIES: usize = 2047;
+const METADATA_TABLE_HEADER_SIZE: usize = 32;
+const METADATA_ENTRY_SIZE: usize = 32;
+/// The metadata items follow the metadata table
+const METADATA_ITEMS_OFFSET: u32 = 64 << 10;
+const BAT_REGION: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA_REGION: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+const FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const VIRTUAL_DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const PAGE_83_DATA: &str = "BECA12AB-B2E6-4523-93EF-C309E000C746";
+const LOGICAL_SECTOR_SIZE: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+const PHYSICAL_SECTOR_SIZE: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
+const REQUIRED_FLAG: u32 = 1;
+const METADATA_IS_VIRTUAL_DISK: u32 = 1 << 1;
+const METADATA_IS_REQUIRED: u32 = 1 << 2;
+const HAS_PARENT: u32 = 1 << 1;
+const BLOCK_NOT_PRESENT: u64 = 0;
+const BLOCK_FULLY_PRESENT: u64 = 6;
+const BLOCK_PARTIALLY_PRESENT: u64 = 7;
+const BAT_STATE_MASK: u64 = 7;
+/// Offsets in the file are stored in MiB, in the high bits of the BAT entries
+const MIB: u64 = 1 << 20;
+const MIN_BLOCK_SIZE: u64 = MIB;
+const MAX_BLOCK_SIZE: u64 = 256 * MIB;
+/// Every chunk of 2^23 sectors has a sector bitmap block, interleaved with the payload blocks in the BAT
+const SECTORS_PER_CHUNK: u64 = 1 << 23;
+/// Block size of the images written, small blocks allocate less space for mostly empty drives
+const OUTPUT_BLOCK_SIZE: u64 = 2 * MIB;
+const OUTPUT_LOG_OFFSET: u64 = MIB;
+const OUTPUT_LOG_LENGTH: u64 = MIB;
+const OUTPUT_METADATA_OFFSET: u64 = 2 * MIB;
+const OUTPUT_METADATA_LENGTH: u64 = MIB;
+const OUTPUT_BAT_OFFSET: u64 = 3 * MIB;
+const CREATOR: &str = "rmsd";
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid VHDX image, {}", message))
+}
+
+pub fn is_vhdx(magic: &[u8]) -> bool {
+        magic.starts_with(FILE_IDENTIFIER)
+}
+
+/// The on-disk form of a GUID, its first three fields are little-endian
+fn guid(text: &str) -> [u8; 16] {
+        let hex = crate::hash::from_hex(&text.replace('-', "")).unwrap();
+        let mut bytes: [u8; 16] = hex.try_into().unwrap();
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+}
+
+/// A random (version 4) GUID, derived from the time and the process since no source of randomness is at hand
+fn new_guid(salt: u8) -> [u8; 16] {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let seed = format!("{}:{}:{}", now.as_nanos(), std::process::id(), salt);
+        let mut bytes: [u8; 16] = blake3::hash(seed.as_bytes()).as_bytes()[..16].try_into().unwrap();
+        bytes[7] = (bytes[7] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        bytes
+}
+
+/// Checks the CRC-32C of a structure, computed with its checksum field (at byte 4) set to zero
+fn checksum_matches(data: &[u8]) -> bool {
+        let expected = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let mut copy = data.to_vec();
+        copy[4..8].fill(0);
+        crc32c::crc32c(&copy) == expected
+}
+
+fn set_checksum(data: &mut [u8]) {
+        data[4..8].fill(0);
+        let checksum = crc32c::crc32c(data);
+        data[4..8].copy_from_slice(&checksum.to_le_bytes());
+}
+
+fn read_at(file: &mut File, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(data)
+}
+
+/// Number of payload blocks covered by each sector bitmap block
+fn chunk_ratio(block_size: u64, logical_sector_size: u32) -> u64 {
+        SECTORS_PER_CHUNK * u64::from(logical_sector_size) / block_size
+}
+
+/// Reads the virtual disk of a fixed or dynamic VHDX image, the blocks that are not present read as zeros
+pub struct Reader {
+        file: File,
+        size: u64,
+        block_size: u64,
+        chunk_ratio: u64,
+        bat: Vec<u64>,
+        position: u64,
+}
+
+impl Reader {
+        pub fn open(path: &Path) -> std::io::Result<Reader> {
+                let mut file = File::open(path)?;
+                if !is_vhdx(&read_at(&mut file, 0, FILE_IDENTIFIER.len())?) {
+                        return Err(invalid(String::from("the file identifier is missing")));
+                }
+                // the current header is the valid one with the highest sequence number
+                let mut header: Option<Vec<u8>> = None;
+                for offset in HEADER_OFFSETS {
+                        let candidate = read_at(&mut file, offset, HEADER_SIZE)?;
+                        let sequence = |h: &[u8]| u64::from_le_bytes(h[8..16].try_into().unwrap());
+                        if candidate.starts_with(HEADER_SIGNATURE) && checksum_matches(&candidate) && header.as_ref().is_none_or(|h| sequence(h) < sequence(&candidate)) {
+                                header = Some(candidate);
+                        }
+                }
+                let header = header.ok_or(invalid(String::from("both headers are corrupted")))?;
+                let version = u16::from_le_bytes([header[66], header[67]]);
+                if version != 1 {
+                        return Err(invalid(format!("unsupported version {}", version)));
+                }
+                if header[48..64].iter().any(|&b| b != 0) {
+                        return Err(invalid(String::from("its log has to be replayed first, attach it once to Hyper-V or convert it with qemu-img")));
+                }
+                let table = REGION_TABLE_OFFSETS.iter()
+                        .map(|&offset| read_at(&mut file, offset, REGION_TABLE_SIZE))
+                        .collect::<std::io::Result<Vec<Vec<u8>>>>()?
+                        .into_iter()
+                        .find(|t| t.starts_with(REGION_TABLE_SIGNATURE) && checksum_matches(t))
+                        .ok_or(invalid(String::from("both region tables are corrupted")))?;
+                let entry_count = (u32::from_le_bytes(table[8..12].try_into().unwrap()) as usize).min(MAX_REGION_ENTRIES);
+                let (mut bat_region, mut metadata_region) = (None, None);
+                for entry in table[16..].chunks_exact(32).take(entry_count) {
+                        let region = (u64::from_le_bytes(entry[16..24].try_into().unwrap()), u32::from_le_bytes(entry[24..28].try_into().unwrap()) as usize);
+                        if entry[..16] == guid(BAT_REGION) {
+                                bat_region = Some(region);
+                        } else if entry[..16] == guid(METADATA_REGION) {
+                                metadata_region = Some(region);
+                        } else if u32::from_le_bytes(entry[28..32].try_into().unwrap()) & REQUIRED_FLAG != 0 {
+                                return Err(invalid(String::from("it requires an unknown region")));
+                        }
+                }
+                let (bat_offset, bat_length) = bat_region.ok_or(invalid(String::from("the BAT region is missing")))?;
+                let (metadata_offset, metadata_length) = metadata_region.ok_or(invalid(String::from("the metadata region is missing")))?;
+                let metadata = read_at(&mut file, metadata_offset, metadata_length)?;
+                if !metadata.starts_with(METADATA_SIGNATURE) {
+                        return Err(invalid(String::from("the metadata table is missing")));
+                }
+                let item = |id: &str| -> Option<&[u8]> {
+                        let count = u16::from_le_bytes([metadata[10], metadata[11]]) as usize;
+                        let entry = metadata[METADATA_TABLE_HEADER_SIZE..].chunks_exact(METADATA_ENTRY_SIZE).take(count).find(|e| e[..16] == guid(id))?;
+                        let offset = u32::from_le_bytes(entry[16..20].try_into().unwrap()) as usize;
+                        let length = u32::from_le_bytes(entry[20..24].try_into().unwrap()) as usize;
+                        metadata.get(offset..offset + length)
+                };
+                let parameters = item(FILE_PARAMETERS).filter(|p| p.len() >= 8).ok_or(invalid(String::from("the file parameters are missing")))?;
+                let block_size = u64::from(u32::from_le_bytes(parameters[0..4].try_into().unwrap()));
+                if u32::from_le_bytes(parameters[4..8].try_into().unwrap()) & HAS_PARENT != 0 {
+                        return Err(invalid(String::from("differencing disks are not supported")));
+                }
+                let size = item(VIRTUAL_DISK_SIZE).filter(|s| s.len() >= 8).map(|s| u64::from_le_bytes(s[0..8].try_into().unwrap()))
+                        .ok_or(invalid(String::from("the virtual disk size is missing")))?;
+                let logical_sector_size = item(LOGICAL_SECTOR_SIZE).filter(|s| s.len() >= 4).map(|s| u32::from_le_bytes(s[0..4].try_into().unwrap()))
+                        .ok_or(invalid(String::from("the logical sector size is missing")))?;
+                if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || (logical_sector_size != 512 && logical_sector_size != 4096) {
+                        return Err(invalid(format!("unsupported block size {} or sector size {}", block_size, logical_sector_size)));
+                }
+                let chunk_ratio = chunk_ratio(block_size, logical_sector_size);
+                let blocks = size.div_ceil(block_size);
+                let entries = blocks + blocks.saturating_sub(1) / chunk_ratio;
+                if entries * 8 > bat_length as u64 {
+                        return Err(invalid(format!("the BAT of {} bytes does not cover {} blocks", bat_length, blocks)));
+                }
+                let bat = read_at(&mut file, bat_offset, entries as usize * 8)?.chunks_exact(8).map(|e| u64::from_le_bytes(e.try_into().unwrap())).collect();
+                log::debug!("vhdx::Reader::open(): {:?} is a disk of {} bytes, {} byte blocks, {} byte sectors", path, size, block_size, logical_sector_size);
+                Ok(Reader { file, size, block_size, chunk_ratio, bat, position: 0 })
+        }
+
+        /// The BAT entry of a payload block, the sector bitmap entries are skipped
+        fn entry(&self, block: u64) -> u64 {
+                self.bat[(block + block / self.chunk_ratio) as usize]
+        }
+}
+
+impl VirtualDisk for Reader {
+        /// Size of the virtual disk
+        fn size(&self) -> u64 {
+                self.size
+        }
+
+        /// Lists the byte ranges of the virtual disk stored in the image
+        fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+                let mut data = vec![];
+                for block in 0..self.size.div_ceil(self.block_size) {
+                        if self.entry(block) & BAT_STATE_MASK == BLOCK_FULLY_PRESENT {
+                                extents::push(&mut data, block * self.block_size..((block + 1) * self.block_size).min(self.size));
+                        }
+                }
+                Ok(data)
+        }
+}
+
+impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.position >= self.size || buf.is_empty() {
+                        return Ok(0);
+                }
+                let within = self.position % self.block_size;
+                let length = (self.block_size - within).min(self.size - self.position).min(buf.len() as u64) as usize;
+                let buf = &mut buf[..length];
+                let entry = self.entry(self.position / self.block_size);
+                match entry & BAT_STATE_MASK {
+                        BLOCK_FULLY_PRESENT => {
+                                self.file.seek(SeekFrom::Start((entry & !(MIB - 1)) + within))?;
+                                self.file.read_exact(buf)?;
+                        },
+                        BLOCK_PARTIALLY_PRESENT => { return Err(invalid(String::from("partially present blocks are only valid in differencing disks"))); },
+                        // blocks that are not present, zero, unmapped or undefined
+                        _ => { buf.fill(0); }
+                }
+                self.position += length as u64;
+                Ok(length)
+        }
+}
+
+impl Seek for Reader {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.position = super::seek_position(self.position, self.size, position)?;
+                Ok(self.position)
+        }
+}
+
+/// Writes a dynamic VHDX image, only the blocks holding data are allocated. The blocks follow the BAT in the
+/// file, the headers, the region tables, the metadata and the BAT are written by `finish`
+pub struct Writer {
+        output: BufWriter<File>,
+        size: u64,
+        logical_sector_size: u32,
+        /// The block being received and its index
+        block: Vec<u8>,
+        block_index: u64,
+        bat: Vec<u64>,
+        /// Offset of the next block in the file
+        next_offset: u64,
+}
+
+impl Writer {
+        /// Creates a writer for a virtual disk of `size` bytes made of sectors of `sector_size` bytes (512 or 4096)
+        pub fn new(file: File, size: u64, sector_size: u32) -> std::io::Result<Writer> {
+                let logical_sector_size = if sector_size == 4096 { 4096 } else { 512 };
+                if !size.is_multiple_of(u64::from(logical_sector_size)) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the image size is not a multiple of the sector size"));
+                }
+                let blocks = size.div_ceil(OUTPUT_BLOCK_SIZE);
+                let entries = blocks + blocks.saturating_sub(1) / chunk_ratio(OUTPUT_BLOCK_SIZE, logical_sector_size);
+                let next_offset = OUTPUT_BAT_OFFSET + (entries * 8).next_multiple_of(MIB);
+                let mut output = BufWriter::new(file);
+                output.seek(SeekFrom::Start(next_offset))?;
+                Ok(Writer { output, size, logical_sector_size, block: Vec::with_capacity(OUTPUT_BLOCK_SIZE as usize), block_index: 0, bat: vec![BLOCK_NOT_PRESENT; entries as usize], next_offset })
+        }
+
+        fn push_block(&mut self) -> std::io::Result<()> {
+                self.block.resize(OUTPUT_BLOCK_SIZE as usize, 0);
+                if self.block.iter().any(|&b| b != 0) {
+                        self.output.write_all(&self.block)?;
+                        let ratio = chunk_ratio(OUTPUT_BLOCK_SIZE, self.logical_sector_size);
+                        self.bat[(self.block_index + self.block_index / ratio) as usize] = self.next_offset | BLOCK_FULLY_PRESENT;
+                        self.next_offset += OUTPUT_BLOCK_SIZE;
+                }
+                self.block.clear();
+                self.block_index += 1;
+                Ok(())
+        }
+
+        fn metadata(&self) -> Vec<u8> {
+                let mut metadata = vec![0u8; OUTPUT_METADATA_LENGTH as usize];
+                let items: [(&str, u32, Vec<u8>); 5] = [
+                        (FILE_PARAMETERS, METADATA_IS_REQUIRED, [(OUTPUT_BLOCK_SIZE as u32).to_le_bytes(), 0u32.to_le_bytes()].concat()),
+                        (VIRTUAL_DISK_SIZE, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, self.size.to_le_bytes().to_vec()),
+                        (PAGE_83_DATA, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, new_guid(3).to_vec()),
+                        (LOGICAL_SECTOR_SIZE, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, self.logical_sector_size.to_le_bytes().to_vec()),
+                        (PHYSICAL_SECTOR_SIZE, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, self.logical_sector_size.to_le_bytes().to_vec()),
+                ];
+                metadata[..8].copy_from_slice(METADATA_SIGNATURE);
+                metadata[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+                let mut offset = METADATA_ITEMS_OFFSET;
+                for (index, (id, flags, data)) in items.into_iter().enumerate() {
+                        let entry = &mut metadata[METADATA_TABLE_HEADER_SIZE + index * METADATA_ENTRY_SIZE..][..METADATA_ENTRY_SIZE];
+                        entry[..16].copy_from_slice(&guid(id));
+                        entry[16..20].copy_from_slice(&offset.to_le_bytes());
+                        entry[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                        entry[24..28].copy_from_slice(&flags.to_le_bytes());
+                        metadata[offset as usize..][..data.len()].copy_from_slice(&data);
+                        offset += data.len() as u32;
+                }
+                metadata
+        }
+
+        /// Writes the file identifier, the headers, the region tables, the metadata and the BAT
+        pub fn finish(mut self) -> std::io::Result<()> {
+                if !self.block.is_empty() {
+                        self.push_block()?;
+                }
+                let mut identifier = vec![0u8; REGION_TABLE_OFFSETS[0] as usize];
+                identifier[..8].copy_from_slice(FILE_IDENTIFIER);
+                for (i, unit) in CREATOR.encode_utf16().enumerate() {
+                        identifier[8 + 2 * i..][..2].copy_from_slice(&unit.to_le_bytes());
+                }
+                let (file_write_guid, data_write_guid) = (new_guid(1), new_guid(2));
+                for (sequence, offset) in HEADER_OFFSETS.iter().enumerate() {
+                        let header = &mut identifier[*offset as usize..][..HEADER_SIZE];
+                        header[..4].copy_from_slice(HEADER_SIGNATURE);
+                        header[8..16].copy_from_slice(&(sequence as u64).to_le_bytes());
+                        header[16..32].copy_from_slice(&file_write_guid);
+                        header[32..48].copy_from_slice(&data_write_guid);
+                        header[66..68].copy_from_slice(&1u16.to_le_bytes());
+                        header[68..72].copy_from_slice(&(OUTPUT_LOG_LENGTH as u32).to_le_bytes());
+                        header[72..80].copy_from_slice(&OUTPUT_LOG_OFFSET.to_le_bytes());
+                        set_checksum(header);
+                }
+                let mut table = vec![0u8; REGION_TABLE_SIZE];
+                table[..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+                table[8..12].copy_from_slice(&2u32.to_le_bytes());
+                let regions = [(BAT_REGION, OUTPUT_BAT_OFFSET, (self.bat.len() as u64 * 8).next_multiple_of(MIB)), (METADATA_REGION, OUTPUT_METADATA_OFFSET, OUTPUT_METADATA_LENGTH)];
+                for (entry, (id, offset, length)) in table[16..].chunks_exact_mut(32).zip(regions) {
+                        entry[..16].copy_from_slice(&guid(id));
+                        entry[16..24].copy_from_slice(&offset.to_le_bytes());
+                        entry[24..28].copy_from_slice(&(length as u32).to_le_bytes());
+                        entry[28..32].copy_from_slice(&REQUIRED_FLAG.to_le_bytes());
+                }
+                set_checksum(&mut table);
+                self.output.seek(SeekFrom::Start(0))?;
+                self.output.write_all(&identifier)?;
+                for _ in REGION_TABLE_OFFSETS {
+                        self.output.write_all(&table)?;
+                }
+                // the log region stays empty
+                self.output.seek(SeekFrom::Start(OUTPUT_METADATA_OFFSET))?;
+                let metadata = self.metadata();
+                self.output.write_all(&metadata)?;
+                let mut bat: Vec<u8> = self.bat.iter().flat_map(|e| e.to_le_bytes()).collect();
+                bat.resize(bat.len().next_multiple_of(MIB as usize), 0);
+                self.output.write_all(&bat)?;
+                self.output.flush()
+        }
+}
+
+impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let length = (OUTPUT_BLOCK_SIZE as usize - self.block.len()).min(buf.len());
+                self.block.extend_from_slice(&buf[..length]);
+                if self.block.len() == OUTPUT_BLOCK_SIZE as usize {
+                        self.push_block()?;
+                }
+                Ok(length)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+                self.output.flush()
+        }
+}
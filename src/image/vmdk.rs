@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use crate::log;
+use super::{extents, VirtualDisk};
+
+const MAGIC: &[u8] = b"KDMV";
+const HEADER_SIZE: usize = 512;
+const SECTOR_SIZE: u64 = 512;
+const FLAG_ZEROED_GRAIN_ENTRIES: u32 = 1 << 2;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const COMPRESSION_DEFLATE: u16 = 1;
+/// Grain directory offset of streamOptimized images, the real offset is in the footer
+const GD_AT_END: u64 = u64::MAX;
+/// The footer takes the second to last sector, in front of the end-of-stream marker
+const FOOTER_FROM_END: u64 = 2 * SECTOR_SIZE;
+/// Grain table entry of grains that read as zeros
+const ZERO_GRAIN: u32 = 1;
+/// Compressed grains start with their LBA and the size of their data
+const GRAIN_MARKER_SIZE: usize = 12;
+/// Largest grain accepted (64MiB), the usual size is 64KiB
+const MAX_GRAIN_SECTORS: u64 = 1 << 17;
+
+fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid VMDK image, {}", message))
+}
+
+pub fn is_vmdk(magic: &[u8]) -> bool {
+        magic.starts_with(MAGIC)
+}
+
+/// The fields of the sparse extent header
+#[derive(Debug, Clone, Copy)]
+struct Header {
+        version: u32,
+        flags: u32,
+        capacity: u64,
+        grain_size: u64,
+        descriptor_offset: u64,
+        descriptor_size: u64,
+        entries_per_table: u32,
+        gd_offset: u64,
+        compression: u16,
+}
+
+impl Header {
+        fn read(file: &mut File, offset: u64) -> std::io::Result<Header> {
+                let mut data = [0u8; HEADER_SIZE];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut data)?;
+                if !is_vmdk(&data) {
+                        return Err(invalid(format!("the header at byte {} is missing", offset)));
+                }
+                let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+                Ok(Header {
+                        version: u32_at(4),
+                        flags: u32_at(8),
+                        capacity: u64_at(12),
+                        grain_size: u64_at(20),
+                        descriptor_offset: u64_at(28),
+                        descriptor_size: u64_at(36),
+                        entries_per_table: u32_at(44),
+                        gd_offset: u64_at(56),
+                        compression: u16::from_le_bytes([data[77], data[78]]),
+                })
+        }
+}
+
+/// Where a grain of the virtual disk is stored
+enum Grain {
+        Unallocated,
+        Zero,
+        /// Sector of the file where the grain starts
+        Stored(u64),
+}
+
+/// Reads the virtual disk of a monolithic sparse VMDK image (streamOptimized images included), the grains are
+/// looked up in the grain directory and tables and the unallocated ones read as zeros
+pub struct Reader {
+        file: File,
+        header: Header,
+        directory: Vec<u32>,
+        /// The last grain table read and the sector it starts at
+        table: Option<(u32, Vec<u32>)>,
+        /// The last compressed grain decoded and the sector it starts at
+        grain: Option<(u64, Vec<u8>)>,
+        position: u64,
+}
+
+impl Reader {
+        pub fn open(path: &Path) -> std::io::Result<Reader> {
+                let mut file = File::open(path)?;
+                let mut header = Header::read(&mut file, 0)?;
+                if header.gd_offset == GD_AT_END {
+                        let stored_size = file.metadata()?.len();
+                        header = Header::read(&mut file, stored_size.checked_sub(FOOTER_FROM_END).ok_or(invalid(String::from("the footer is missing")))?)?;
+                }
+                if !(1..=3).contains(&header.version) {
+                        return Err(invalid(format!("unsupported version {}", header.version)));
+                }
+                if header.flags & FLAG_COMPRESSED != 0 && header.compression != COMPRESSION_DEFLATE {
+                        return Err(invalid(format!("unsupported compression algorithm {}", header.compression)));
+                }
+                if header.grain_size == 0 || header.grain_size > MAX_GRAIN_SECTORS || header.entries_per_table == 0 || header.gd_offset == GD_AT_END {
+                        return Err(invalid(format!("malformed header {:?}", header)));
+                }
+                if header.descriptor_offset != 0 {
+                        let mut descriptor = vec![0u8; (header.descriptor_size * SECTOR_SIZE) as usize];
+                        file.seek(SeekFrom::Start(header.descriptor_offset * SECTOR_SIZE))?;
+                        file.read_exact(&mut descriptor)?;
+                        if String::from_utf8_lossy(&descriptor).lines().any(|l| l.trim_start().starts_with("parentFileNameHint")) {
+                                return Err(invalid(String::from("delta links of a parent disk are not supported")));
+                        }
+                }
+                let grains_per_table = header.grain_size * u64::from(header.entries_per_table);
+                let tables = header.capacity.div_ceil(grains_per_table) as usize;
+                let mut directory = vec![0u8; tables * 4];
+                file.seek(SeekFrom::Start(header.gd_offset * SECTOR_SIZE))?;
+                file.read_exact(&mut directory)?;
+                let directory = directory.chunks_exact(4).map(|e| u32::from_le_bytes(e.try_into().unwrap())).collect();
+                log::debug!("vmdk::Reader::open(): {:?} is a version {} sparse extent of {} sectors, {} sector grains, flags {:#x}", path, header.version, header.capacity, header.grain_size, header.flags);
+                Ok(Reader { file, header, directory, table: None, grain: None, position: 0 })
+        }
+
+        fn grain_bytes(&self) -> u64 {
+                self.header.grain_size * SECTOR_SIZE
+        }
+
+        fn grain(&mut self, grain: u64) -> std::io::Result<Grain> {
+                let entries = u64::from(self.header.entries_per_table);
+                let table_sector = self.directory[(grain / entries) as usize];
+                if table_sector == 0 {
+                        return Ok(Grain::Unallocated);
+                }
+                if self.table.as_ref().is_none_or(|(cached, _)| *cached != table_sector) {
+                        let mut table = vec![0u8; entries as usize * 4];
+                        self.file.seek(SeekFrom::Start(u64::from(table_sector) * SECTOR_SIZE))?;
+                        self.file.read_exact(&mut table)?;
+                        self.table = Some((table_sector, table.chunks_exact(4).map(|e| u32::from_le_bytes(e.try_into().unwrap())).collect()));
+                }
+                Ok(match self.table.as_ref().unwrap().1[(grain % entries) as usize] {
+                        0 => { Grain::Unallocated },
+                        ZERO_GRAIN if self.header.flags & FLAG_ZEROED_GRAIN_ENTRIES != 0 => { Grain::Zero },
+                        sector => { Grain::Stored(u64::from(sector)) }
+                })
+        }
+
+        fn decompress(&mut self, sector: u64) -> std::io::Result<&[u8]> {
+                if self.grain.as_ref().is_none_or(|(cached, _)| *cached != sector) {
+                        let mut marker = [0u8; GRAIN_MARKER_SIZE];
+                        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                        self.file.read_exact(&mut marker)?;
+                        let compressed_size = u32::from_le_bytes(marker[8..12].try_into().unwrap());
+                        let mut grain = vec![0u8; self.grain_bytes() as usize];
+                        // the last grain of the disk may be shorter
+                        let decoded = super::read_full(&mut flate2::read::ZlibDecoder::new((&mut self.file).take(u64::from(compressed_size))), &mut grain);
+                        if let Err(e) = decoded {
+                                return Err(invalid(format!("failed to decompress the grain at sector {}, {}", sector, e)));
+                        }
+                        self.grain = Some((sector, grain));
+                }
+                Ok(&self.grain.as_ref().unwrap().1)
+        }
+}
+
+impl VirtualDisk for Reader {
+        /// Size of the virtual disk
+        fn size(&self) -> u64 {
+                self.header.capacity * SECTOR_SIZE
+        }
+
+        /// Lists the byte ranges of the virtual disk stored in the image, zero grains are holes
+        fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+                let grain_bytes = self.grain_bytes();
+                let size = self.size();
+                let mut data = vec![];
+                for grain in 0..size.div_ceil(grain_bytes) {
+                        if let Grain::Stored(_) = self.grain(grain)? {
+                                extents::push(&mut data, grain * grain_bytes..((grain + 1) * grain_bytes).min(size));
+                        }
+                }
+                Ok(data)
+        }
+}
+
+impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let size = self.size();
+                if self.position >= size || buf.is_empty() {
+                        return Ok(0);
+                }
+                let grain_bytes = self.grain_bytes();
+                let within = self.position % grain_bytes;
+                let length = (grain_bytes - within).min(size - self.position).min(buf.len() as u64) as usize;
+                let buf = &mut buf[..length];
+                match self.grain(self.position / grain_bytes)? {
+                        Grain::Unallocated | Grain::Zero => { buf.fill(0); },
+                        Grain::Stored(sector) if self.header.flags & FLAG_COMPRESSED != 0 => {
+                                let grain = self.decompress(sector)?;
+                                buf.copy_from_slice(&grain[within as usize..within as usize + length]);
+                        },
+                        Grain::Stored(sector) => {
+                                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE + within))?;
+                                self.file.read_exact(buf)?;
+                        }
+                }
+                self.position += length as u64;
+                Ok(length)
+        }
+}
+
+impl Seek for Reader {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.position = super::seek_position(self.position, self.size(), position)?;
+                Ok(self.position)
+        }
+}
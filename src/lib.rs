@@ -0,0 +1,13 @@
+#[macro_use]
+pub mod log;
+pub mod args;
+pub mod bmap;
+pub mod checksum;
+pub mod hash;
+pub mod image;
+pub mod mass_storage;
+pub mod scsi;
+pub mod signature;
+pub mod simulator;
+pub mod transport;
+pub mod util;
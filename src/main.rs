@@ -1,16 +1,25 @@
-mod mass_storage;
-#[macro_use]
-mod log;
 use clap::Parser;
-mod args;
-mod util;
-use util::{ acquire_target, filter_devices, do_progress_bar };
+use std::rc::Rc;
+use rmsd::{args, bmap, checksum, hash, image, log, mass_storage, signature, simulator};
+use rmsd::util::{ acquire_target, filter_devices, do_progress_bar, print_device_info, print_device_summary, print_digests };
 
 fn main() {
-        rusb::set_log_level(rusb::LogLevel::Error);
         log::set_level(log::Level::Error);
         let arguments = args::Arguments::parse();
-        let mut list = mass_storage::list_devices();
+        let mut list = if !arguments.simulate.is_empty() {
+                let faults = simulator::Faults::parse(arguments.simulate_faults.as_deref().unwrap_or("")).unwrap_or_else(|e| {
+                        println!("Invalid simulated fault list: {}", e);
+                        std::process::exit(1);
+                });
+                let sim = simulator::SimulatedDevice::open(&arguments.simulate, arguments.simulate_block_size, faults).unwrap_or_else(|e| {
+                        println!("Unable to open the simulated device's storage {:?}: {}", arguments.simulate, e);
+                        std::process::exit(1);
+                });
+                mass_storage::list_transport_devices(Rc::new(sim), simulator::SIMULATED_OUT_ENDPOINT, simulator::SIMULATED_IN_ENDPOINT)
+        } else {
+                rusb::set_log_level(rusb::LogLevel::Error);
+                mass_storage::list_devices()
+        };
         if list.len() == 0 {
                 println!("No Mass Storage Class devices detected");
                 std::process::exit(1);
@@ -19,22 +28,131 @@ fn main() {
         match arguments.command {
                 args::Command::flash(args) => {
                         log::set_level(log::level_from(&args.log_level));
-                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port);
-                        let target: &mut mass_storage::Device = acquire_target(&mut list, args.skip_prompts, args.buffer_size);
-                        target.flash_image_from_file(&args.image, args.buffer_size, args.sector_count, do_progress_bar).expect("Flashing operation failed, please retry");
+                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port, args.lun);
+                        // the standard input can only be read once, while the image is flashed
+                        let from_stdin = image::is_stdio(&args.image);
+                        if from_stdin && !args.skip_prompts {
+                                println!("The image is read from the standard input, -y is required since the prompts would read it too");
+                                std::process::exit(1);
+                        }
+                        if from_stdin && (args.verify.is_some() || args.signature.is_some() || args.checksum_file.is_some()) {
+                                println!("The image read from the standard input can only be read once, it cannot be checked against a signature or a checksum file nor verified, use --hash to print its digest");
+                                std::process::exit(1);
+                        }
+                        if !from_stdin && args.input_size.is_some() {
+                                println!("--input-size only applies to images read from the standard input");
+                                std::process::exit(1);
+                        }
+                        let signed = signature::verify_image_signature(&args.image, args.signature.as_ref(), args.pubkey.as_ref(), args.trusted_keys.as_ref(), args.allow_unsigned).unwrap_or_else(|e| {
+                                println!("Unable to verify the image's signature: {}", e);
+                                std::process::exit(1);
+                        });
+                        if !signed {
+                                println!("The image's signature could not be verified, refusing to flash it");
+                                std::process::exit(1);
+                        }
+                        if !args.skip_checksum && !from_stdin {
+                                let matches = checksum::verify_image_checksum(&args.image, args.checksum_file.as_ref()).unwrap_or_else(|e| {
+                                        println!("Unable to verify the image's checksum: {}", e);
+                                        std::process::exit(1);
+                                });
+                                if !matches {
+                                        println!("The image does not match its checksum, refusing to flash it");
+                                        std::process::exit(1);
+                                }
+                        }
+                        let bmap = match args.no_bmap || (from_stdin && args.bmap.is_none()) {
+                                true => { None },
+                                false => {
+                                        bmap::load_image_bmap(&args.image, args.bmap.as_ref()).unwrap_or_else(|e| {
+                                                println!("Unable to use the image's bmap: {}", e);
+                                                std::process::exit(1);
+                                        })
+                                }
+                        };
+                        // like bmaptool, the ranges left unmapped by a bmap are not written by default
+                        let sparse = mass_storage::SparseOptions {
+                                hole_policy: args.hole_policy.unwrap_or(if bmap.is_some() { mass_storage::HolePolicy::Skip } else { mass_storage::HolePolicy::WriteZeros }),
+                                bmap: bmap.as_ref(),
+                        };
+                        let location = image::Location { path: &args.image, entry: args.entry.as_deref(), input_size: args.input_size };
+                        let target: &mut mass_storage::Device = acquire_target(&mut list, args.skip_prompts);
+                        let mut hashers: Vec<hash::Hasher> = args.hash_algorithms.iter().map(|a| hash::Hasher::new(*a)).collect();
+                        if !target.flash_image_from_file(&location, args.buffer_size, args.sector_count, &sparse, &mut hashers, do_progress_bar).expect("Flashing operation failed, please retry") {
+                                println!("Flashing operation failed, please retry");
+                                std::process::exit(1);
+                        }
+                        print_digests(hashers);
+                        if let Some(mode) = args.verify {
+                                println!("Verifying...");
+                                if !target.verify_image_on_device(&location, args.buffer_size, args.sector_count, mode == args::VerifyMode::Hash, &sparse, do_progress_bar).expect("Verification failed") {
+                                        println!("Verification failed, the device does not hold the image");
+                                        std::process::exit(1);
+                                }
+                                println!("Verification succeeded");
+                        }
                 },
                 args::Command::clone(args) => { 
                         log::set_level(log::level_from(&args.log_level));
-                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port);
-                        let target: &mut mass_storage::Device = acquire_target(&mut list, args.skip_prompts, args.buffer_size);
-                        target.clone_drive_to_file(&args.image, args.buffer_size, args.sector_count, do_progress_bar).expect("Flashing operation failed, please retry");
+                        let destination = match image::is_stdio(&args.image) {
+                                true => {
+                                        image::Destination::stdout().unwrap_or_else(|e| {
+                                                println!("Unable to write the image to the standard output: {}", e);
+                                                std::process::exit(1);
+                                        })
+                                },
+                                false => { image::Destination::Path(args.image.clone()) }
+                        };
+                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port, args.lun);
+                        let target: &mut mass_storage::Device = acquire_target(&mut list, args.skip_prompts);
+                        let mut hashers: Vec<hash::Hasher> = args.hash_algorithms.iter().map(|a| hash::Hasher::new(*a)).collect();
+                        let output = image::OutputOptions {
+                                format: args.format,
+                                compression: args.compress.unwrap_or(image::Compression::None),
+                                level: args.compression_level,
+                                threads: args.threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+                                split_size: args.split_size,
+                        };
+                        if !target.clone_drive_to_file(destination, args.buffer_size, args.sector_count, &output, &mut hashers, do_progress_bar).expect("Cloning operation failed, please retry") {
+                                println!("Cloning operation failed, please retry");
+                                std::process::exit(1);
+                        }
+                        print_digests(hashers);
                 
                 },
                 args::Command::list(args) => {
                         log::set_level(log::level_from(&args.log_level));
-                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port);
-                        for (n, d) in list.iter().enumerate() {
-                                println!("{}. '{}' at bus {}, port {}", n, d.name().unwrap(), d.generic_device.bus_number(), d.generic_device.port_number());
+                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port, args.lun);
+                        for (n, d) in list.iter_mut().enumerate() {
+                                println!("{}. '{}' at bus {}, port {}, LUN {}", n, d.name().unwrap(), d.bus_number(), d.port_number(), d.lun());
+                                match d.open() {
+                                        Ok(readiness) => { print_device_summary(d, readiness); },
+                                        Err(e) => { println!("\tUnable to open the device: {}", e); }
+                                };
+                                d.close();
+                        }
+                },
+                args::Command::checksum(args) => {
+                        log::set_level(log::level_from(&args.log_level));
+                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port, args.lun);
+                        let target: &mut mass_storage::Device = acquire_target(&mut list, args.skip_prompts);
+                        let mut hashers: Vec<hash::Hasher> = args.hash_algorithms.iter().map(|a| hash::Hasher::new(*a)).collect();
+                        if !target.hash_range(args.start_sector, args.sector_count, args.buffer_size, &mut hashers, do_progress_bar).expect("Checksum operation failed, please retry") {
+                                println!("Checksum operation failed, please retry");
+                                std::process::exit(1);
+                        }
+                        print_digests(hashers);
+                },
+                args::Command::info(args) => {
+                        log::set_level(log::level_from(&args.log_level));
+                        filter_devices(&mut list, args.device_name, args.device_bus, args.device_port, args.lun);
+                        for (n, d) in list.iter_mut().enumerate() {
+                                println!("{}. '{}' at bus {}, port {}, LUN {}", n, d.name().unwrap(), d.bus_number(), d.port_number(), d.lun());
+                                match d.open() {
+                                        Ok(readiness) => { print_device_info(d, readiness); },
+                                        Err(e) => { println!("\tUnable to open the device: {}", e); }
+                                };
+                                d.close();
                         }
                 }
         };
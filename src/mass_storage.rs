@@ -1,17 +1,40 @@
+use clap::ValueEnum;
 use rusb::{self as usb, GlobalContext};
 use serde::{Serialize, Deserialize};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::hash::{Hash, Hasher};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::ops::Range;
 use std::time::Duration;
-use std::fs::File;
 use crate::log;
+use crate::scsi;
+use crate::hash;
+use crate::image;
+use crate::bmap;
+use crate::transport::BulkTransport;
 
 const MASS_STORAGE_CLASS_ID: u8 = 0x8;
 const MASS_STORAGE_SUBCLASS_ID: u8 = 0x6;
 const MASS_STORAGE_PROTOCOL_ID: u8 = 0x50;
 const MASS_STORAGE_CBW_EXPECTED_SIZE: usize = 31;
+const MASS_STORAGE_CSW_EXPECTED_SIZE: usize = 13;
+const MASS_STORAGE_CBW_SIGNATURE: u32 = 0x43425355;
+const MASS_STORAGE_CSW_SIGNATURE: u32 = 0x53425355;
+const MASS_STORAGE_RESET_REQUEST: u8 = 0xFF;
+const MASS_STORAGE_GET_MAX_LUN_REQUEST: u8 = 0xFE;
+const MASS_STORAGE_MAX_LUN: u8 = 15;
+/// Largest buffer in sectors, transfers of sectors of up to 4KiB then fit in the 32 bit length of the CBW
+pub const MAX_BUFFER_SIZE: usize = 1 << 19;
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+const TRANSFER_ATTEMPTS: usize = 3;
+const DEFAULT_BLOCK_SIZE: u32 = 512;
+const READY_ATTEMPTS: usize = 10;
+const READY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const READY_MAX_BACKOFF: Duration = Duration::from_secs(2);
+const VPD_ALLOCATION_LENGTH: usize = 255;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum Direction {
         HostToDevice = 0x00,
@@ -39,6 +62,53 @@ pub enum CommandStatus {
         Reserved   = 0xFF
 }
 
+/// Outcome of the readiness handshake performed when a device is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+        Ready,
+        NoMedium,
+        /// The unit kept rejecting TEST UNIT READY, with the sense data of the last attempt if any
+        NotReady(Option<scsi::SenseData>),
+}
+
+impl std::fmt::Display for Readiness {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                match self {
+                        Readiness::Ready => { write!(f, "ready") },
+                        Readiness::NoMedium => { write!(f, "no medium inserted") },
+                        Readiness::NotReady(Some(sense)) => { write!(f, "not ready ({})", sense) },
+                        Readiness::NotReady(None) => { write!(f, "not responding") }
+                }
+        }
+}
+
+/// What happens to the sectors covered by the holes of a sparse image, which are never read from the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HolePolicy {
+        /// Write zeros, the device ends up holding exactly the image
+        WriteZeros,
+        /// Deallocate the sectors with UNMAP or WRITE SAME, zeros are written if the device supports neither
+        Discard,
+        /// Leave the sectors untouched
+        Skip,
+}
+
+/// How the parts of a sparse image that hold no data are found and handled
+#[derive(Debug, Clone, Copy)]
+pub struct SparseOptions<'a> {
+        pub hole_policy: HolePolicy,
+        /// Lists the mapped ranges of the image, replacing the holes reported by the filesystem
+        pub bmap: Option<&'a bmap::Bmap>,
+}
+
+/// The command used to deallocate sectors and the largest number of sectors it accepts
+#[derive(Debug, Clone, Copy)]
+enum DiscardMethod {
+        Unmap(u64),
+        WriteSame16(u64),
+        WriteSame10(u64),
+}
+
 #[derive(Deserialize)]
 #[repr(C)]
 pub struct CommandStatusWrapper {
@@ -48,255 +118,1146 @@ pub struct CommandStatusWrapper {
         status: u8
 }
 
+/// Bookkeeping of the command in flight, used to validate its CSW
+#[derive(Debug, Clone, Copy)]
+struct Transaction {
+        tag: u32,
+        direction: Direction,
+        length: u32,
+        transferred: usize
+}
+
 #[derive(Debug)]
 pub struct Device {
-        pub generic_device: usb::Device<GlobalContext>,
-        handle: Option<usb::DeviceHandle<GlobalContext>>,
+        pub generic_device: Option<usb::Device<GlobalContext>>,
+        handle: Option<Rc<dyn BulkTransport>>,
         in_endpoint: u8,
         out_endpoint: u8, 
-        selected_interface: u8
+        selected_interface: u8,
+        lun: u8,
+        block_size: u32,
+        last_sense: Cell<Option<scsi::SenseData>>,
+        next_tag: Cell<u32>,
+        transaction: Cell<Option<Transaction>>
 }
 
 #[allow(dead_code)]
 impl Device {
+        /// Creates a device (addressing logical unit `lun`) that is already open and talks through
+        /// `transport` instead of a USB device
+        pub fn with_transport(transport: Rc<dyn BulkTransport>, in_endpoint: u8, out_endpoint: u8, lun: u8) -> Device {
+                Device::new(None, Some(transport), in_endpoint, out_endpoint, 0, lun)
+        }
+
+        fn new(generic_device: Option<usb::Device<GlobalContext>>, handle: Option<Rc<dyn BulkTransport>>, in_endpoint: u8, out_endpoint: u8, selected_interface: u8, lun: u8) -> Device {
+                Device {
+                        generic_device,
+                        handle,
+                        in_endpoint,
+                        out_endpoint,
+                        selected_interface,
+                        lun,
+                        block_size: DEFAULT_BLOCK_SIZE,
+                        last_sense: Cell::new(None),
+                        next_tag: Cell::new(1),
+                        transaction: Cell::new(None)
+                }
+        }
+
         pub fn name(&self) -> usb::Result<String> {
-                let handle = match self.handle.as_ref() {
-                        Some(h) => { h },
-                        None => { 
-                                let res = self.generic_device.open();
-                                if res.is_err() {
-                                        let e = res.unwrap_err();
-                                        log::error!("name(): failed to open generic_device, cause: {}", e);
-                                        if e == rusb::Error::Access {
-                                                println!("Not enough privileges, aborting...");
-                                                std::process::exit(1);
-                                        }
-                                        return Ok(String::from(""));
+                let generic_device = match self.generic_device.as_ref() {
+                        Some(d) => { d },
+                        None => { return Ok(String::from("Simulated Device")); }
+                };
+                let handle = match generic_device.open() {
+                        Ok(h) => { h },
+                        Err(e) => {
+                                log::error!("name(): failed to open generic_device, cause: {}", e);
+                                if e == rusb::Error::Access {
+                                        println!("Not enough privileges, aborting...");
+                                        std::process::exit(1);
                                 }
-                                &res.unwrap()
+                                return Ok(String::from(""));
                         }
                 };
-                let dev_descriptor = self.generic_device.device_descriptor();
-                if dev_descriptor.is_err() {
-                        log::error!("name(): failed to get device descriptor, cause: {}", dev_descriptor.unwrap_err());
-                        return Ok(String::from(""));
-                }
-                handle.read_product_string_ascii(&dev_descriptor.unwrap())
+                let dev_descriptor = match generic_device.device_descriptor() {
+                        Ok(desc) => { desc },
+                        Err(e) => {
+                                log::error!("name(): failed to get device descriptor, cause: {}", e);
+                                return Ok(String::from(""));
+                        }
+                };
+                handle.read_product_string_ascii(&dev_descriptor)
+        }
+
+        /// The logical unit addressed by every command sent to this device
+        pub fn lun(&self) -> u8 {
+                self.lun
+        }
+
+        pub fn bus_number(&self) -> u8 {
+                self.generic_device.as_ref().map_or(0, |d| d.bus_number())
+        }
+
+        pub fn port_number(&self) -> u8 {
+                self.generic_device.as_ref().map_or(0, |d| d.port_number())
         }
 
-        pub fn open(&mut self) -> usb::Result<()> {
-                self.handle = match self.generic_device.open() {
-                        Ok(dev) => { 
-                                if usb::supports_detach_kernel_driver() {
-                                        dev.set_auto_detach_kernel_driver(true).unwrap();
+        /// The size in bytes of a logical block, as reported by the device when it was opened
+        pub fn block_size(&self) -> u32 {
+                self.block_size
+        }
+
+        /// Opens the device and performs the initialization sequence (INQUIRY, then TEST UNIT READY until the
+        /// unit is ready), the logical block size is only queried once the medium is ready for transfers
+        pub fn open(&mut self) -> usb::Result<Readiness> {
+                if let Some(generic_device) = self.generic_device.as_ref() {
+                        self.handle = match generic_device.open() {
+                                Ok(dev) => { 
+                                        if usb::supports_detach_kernel_driver() {
+                                                dev.set_auto_detach_kernel_driver(true).unwrap();
+                                        }
+                                        dev.claim_interface(self.selected_interface).unwrap_or_else(|e| log::error!("open(): failed to claim interface, cause: {}", e));
+                                        Some(Rc::new(dev)) 
+                                },
+                                Err(e) => {
+                                        log::error!("open(): failed to open generic_device, cause: {}", e); 
+                                        return Err(e);
                                 }
-                                dev.claim_interface(self.selected_interface).unwrap_or_else(|e| log::error!("open(): failed to claim interface, cause: {}", e));
-                                Some(dev) 
+                        };
+                }
+                match self.inquiry()? {
+                        Some(inquiry) => { log::debug!("open(): '{} {} {}' ({})", inquiry.vendor, inquiry.product, inquiry.revision, inquiry.peripheral_type_name()); },
+                        None => { log::warning!("open(): the device did not answer INQUIRY"); }
+                };
+                let readiness = self.ready()?;
+                if readiness != Readiness::Ready {
+                        log::debug!("open(): LUN {} is {}", self.lun, readiness);
+                        return Ok(readiness);
+                }
+                let mut block_size: u32 = 0;
+                match self.query_capacity(None, Some(&mut block_size)) {
+                        Ok(Some(CommandStatus::Success)) if block_size != 0 => {
+                                self.block_size = block_size;
                         },
-                        Err(e) => {
-                                log::error!("open(): failed to open generic_device, cause: {}", e); 
-                                None 
+                        result => {
+                                log::warning!("open(): failed to query the logical block size ({:?}), assuming {} bytes", result, DEFAULT_BLOCK_SIZE);
                         }
                 };
-                Ok(())
+                log::debug!("open(): logical block size is {} bytes", self.block_size);
+                Ok(Readiness::Ready)
+        }
+
+        /// Releases the interface of a USB device so that another logical unit of the same interface can be opened
+        pub fn close(&mut self) {
+                if self.generic_device.is_some() {
+                        self.handle = None;
+                }
         }
 
         pub fn send_command(&self, command_block: &[u8], direction: Direction, outcoming_bytes: u32) -> usb::Result<bool> {
                 assert!(self.handle.is_some());
-                let mut cbw: CommandBlockWrapper = CommandBlockWrapper { signature: 0x43425355,  transaction_id: 0, length: outcoming_bytes, logical_unit_number: 0, direction: direction as u8, command_length: command_block.len() as u8, command_data: [0; 16]};
+                self.last_sense.set(None);
+                let tag = self.next_tag.get();
+                self.next_tag.set(tag.wrapping_add(1));
+                let mut cbw: CommandBlockWrapper = CommandBlockWrapper { signature: MASS_STORAGE_CBW_SIGNATURE, transaction_id: tag, length: outcoming_bytes, logical_unit_number: self.lun, direction: direction as u8, command_length: command_block.len() as u8, command_data: [0; 16]};
                 let handle = self.handle.as_ref().unwrap();
                 cbw.command_data[..command_block.len()].copy_from_slice(command_block);
-                let bytes_written = handle.write_bulk(self.in_endpoint, bincode::serialize(&cbw).unwrap().as_slice(), Duration::from_millis(0)).unwrap_or_else(|e| { log::error!("send_command(): failed to perform bulk write, cause: {}", e); 0});
-                log::debug!("send_command(): sending CBW (31 bytes), {} bytes were written to the device endpoint (address = {})", bytes_written, self.in_endpoint);
+                let bytes_written = handle.write_bulk(self.in_endpoint, bincode::serialize(&cbw).unwrap().as_slice(), TRANSFER_TIMEOUT).unwrap_or_else(|e| { log::error!("send_command(): failed to perform bulk write, cause: {}", e); 0});
+                log::debug!("send_command(): sending CBW (31 bytes, tag {}), {} bytes were written to the device endpoint (address = {})", tag, bytes_written, self.in_endpoint);
                 if bytes_written == MASS_STORAGE_CBW_EXPECTED_SIZE {
+                        self.transaction.set(Some(Transaction { tag, direction, length: outcoming_bytes, transferred: 0 }));
                         Ok(true)
                 } else {
+                        // the device is in an unknown state after rejecting a CBW
+                        self.reset_recovery()?;
                         Ok(false)
                 }
         }
 
+        /// Bulk-Only Mass Storage Reset followed by clearing the halt condition on both bulk endpoints,
+        /// this brings the device back to a state where it accepts a new CBW
+        pub fn reset_recovery(&self) -> usb::Result<()> {
+                assert!(self.handle.is_some());
+                log::warning!("reset_recovery(): resetting interface {}", self.selected_interface);
+                self.transaction.set(None);
+                let handle = self.handle.as_ref().unwrap();
+                let request_type = usb::request_type(usb::Direction::Out, usb::RequestType::Class, usb::Recipient::Interface);
+                handle.write_control(request_type, MASS_STORAGE_RESET_REQUEST, 0, u16::from(self.selected_interface), &[], TRANSFER_TIMEOUT)?;
+                handle.clear_halt(self.out_endpoint)?;
+                handle.clear_halt(self.in_endpoint)
+        }
+
+        fn clear_halt(&self, endpoint: u8) {
+                log::warning!("clear_halt(): endpoint {:#04x} is stalled, clearing the halt condition", endpoint);
+                let handle = self.handle.as_ref().unwrap();
+                handle.clear_halt(endpoint).unwrap_or_else(|e| log::error!("clear_halt(): failed to clear endpoint {:#04x}, cause: {}", endpoint, e));
+        }
+
+        fn record_transferred(&self, bytes: usize) {
+                if let Some(mut transaction) = self.transaction.get() {
+                        transaction.transferred += bytes;
+                        self.transaction.set(Some(transaction));
+                }
+        }
+
+        /// Performs the data phase of the current command from the device to the host, a stalled
+        /// endpoint is cleared so that the CSW can still be received
+        fn data_in(&self, buf: &mut [u8]) -> usize {
+                assert!(self.handle.is_some());
+                let handle = self.handle.as_ref().unwrap();
+                let bytes_read = match handle.read_bulk(self.out_endpoint, buf, TRANSFER_TIMEOUT) {
+                        Ok(n) => { n },
+                        Err(usb::Error::Pipe) => {
+                                self.clear_halt(self.out_endpoint);
+                                0
+                        },
+                        Err(e) => {
+                                log::error!("data_in(): failed to perform bulk read, cause: {}", e);
+                                0
+                        }
+                };
+                self.record_transferred(bytes_read);
+                bytes_read
+        }
+
+        /// Performs the data phase of the current command from the host to the device, a stalled
+        /// endpoint is cleared so that the CSW can still be received
+        fn data_out(&self, data: &[u8]) -> usize {
+                assert!(self.handle.is_some());
+                let handle = self.handle.as_ref().unwrap();
+                let bytes_written = match handle.write_bulk(self.in_endpoint, data, TRANSFER_TIMEOUT) {
+                        Ok(n) => { n },
+                        Err(usb::Error::Pipe) => {
+                                self.clear_halt(self.in_endpoint);
+                                0
+                        },
+                        Err(e) => {
+                                log::error!("data_out(): failed to perform bulk write, cause: {}", e);
+                                0
+                        }
+                };
+                self.record_transferred(bytes_written);
+                bytes_written
+        }
+
+        /// Reads the CSW of the last command, when the command failed the reason is retrieved with
+        /// REQUEST SENSE and made available through `sense()`
         pub fn status(&self, residue: Option<&mut u32>) -> usb::Result<Option<CommandStatus>> {
+                let status = self.read_status(residue)?;
+                if status == Some(CommandStatus::Error) {
+                        let sense = self.request_sense()?;
+                        match sense {
+                                Some(s) if s.key == scsi::SENSE_KEY_UNIT_ATTENTION || s.key == scsi::SENSE_KEY_NOT_READY => {
+                                        // expected while a unit is initializing, the caller decides whether it is an error
+                                        log::debug!("status(): command failed, sense data: {}", s);
+                                },
+                                Some(s) => { log::warning!("status(): command failed, sense data: {}", s); },
+                                None => { log::warning!("status(): command failed and no sense data is available"); }
+                        };
+                        self.last_sense.set(sense);
+                }
+                Ok(status)
+        }
+
+        /// The sense data describing why the last command failed, if it did
+        pub fn sense(&self) -> Option<scsi::SenseData> {
+                self.last_sense.get()
+        }
+
+        /// Sends REQUEST SENSE and decodes the sense data it returns
+        pub fn request_sense(&self) -> usb::Result<Option<scsi::SenseData>> {
+                let mut command_block = [0u8; 6];
+                command_block[0] = scsi::REQUEST_SENSE;
+                command_block[4] = scsi::FIXED_SENSE_LENGTH as u8;
+                if !self.send_command(&command_block, Direction::DeviceToHost, scsi::FIXED_SENSE_LENGTH as u32)? {
+                        return Ok(None);
+                }
+                let mut buf = [0u8; scsi::FIXED_SENSE_LENGTH];
+                let bytes_read = self.data_in(&mut buf);
+                match self.read_status(None)? {
+                        Some(CommandStatus::Success) => { Ok(scsi::SenseData::parse(&buf[..bytes_read])) },
+                        status => {
+                                log::warning!("request_sense(): command failed with status {:?}", status);
+                                Ok(None)
+                        }
+                }
+        }
+
+        /// Reads and validates the CSW, a stalled endpoint is cleared and the read retried once; a CSW that
+        /// cannot be read, is not valid or is not meaningful triggers a reset recovery and yields `None`
+        fn read_status(&self, residue: Option<&mut u32>) -> usb::Result<Option<CommandStatus>> {
                 assert!(self.handle.is_some());
-                let csw: CommandStatusWrapper;
                 let handle = self.handle.as_ref().unwrap();
+                let transaction = self.transaction.take();
                 let mut buf = [0u8; size_of::<CommandStatusWrapper>()];
-                
-                let bytes_read = handle.read_bulk(self.out_endpoint, &mut buf, Duration::from_millis(0)).unwrap_or_else(|e| { log::error!("status(): failed to perform bulk read, cause: {}", e); 0 });
-                if bytes_read < 13 {
+                let mut result = handle.read_bulk(self.out_endpoint, &mut buf[..MASS_STORAGE_CSW_EXPECTED_SIZE], TRANSFER_TIMEOUT);
+                if result == Err(usb::Error::Pipe) {
+                        self.clear_halt(self.out_endpoint);
+                        result = handle.read_bulk(self.out_endpoint, &mut buf[..MASS_STORAGE_CSW_EXPECTED_SIZE], TRANSFER_TIMEOUT);
+                }
+                let bytes_read = match result {
+                        Ok(n) => { n },
+                        Err(e) => {
+                                log::error!("status(): failed to perform bulk read, cause: {}", e);
+                                self.reset_recovery()?;
+                                return Ok(None);
+                        }
+                };
+                if bytes_read < MASS_STORAGE_CSW_EXPECTED_SIZE {
                         log::warning!("status(): Device returned only {} bytes instead of 13", bytes_read);
+                        self.reset_recovery()?;
                         return Ok(None)
                 }
 
-                csw = bincode::deserialize(&buf).unwrap();
-                log::debug!("status(): CSW ({bytes_read} bytes) successfully received, residue is {} bytes of data", csw.residue);
-                match residue {
-                        Some(r) => { *r = csw.residue; }
-                        None => {  }
-                };
-                Ok(Some(match csw.status {
+                let csw: CommandStatusWrapper = bincode::deserialize(&buf).unwrap();
+                if csw.signature != MASS_STORAGE_CSW_SIGNATURE {
+                        log::warning!("status(): invalid CSW signature {:#010x}", csw.signature);
+                        self.reset_recovery()?;
+                        return Ok(None);
+                }
+                if let Some(t) = transaction.filter(|t| t.tag != csw.transaction_id) {
+                        log::warning!("status(): CSW tag {} does not match the CBW tag {}", csw.transaction_id, t.tag);
+                        self.reset_recovery()?;
+                        return Ok(None);
+                }
+                log::debug!("status(): CSW ({bytes_read} bytes, tag {}) successfully received, residue is {} bytes of data", csw.transaction_id, csw.residue);
+                let status = match csw.status {
                         0 => { CommandStatus::Success },
                         1 => { CommandStatus::Error },
                         2 => { CommandStatus::PhaseError },
                         _ => { CommandStatus::Reserved }
-                }))
+                };
+                if let Some(t) = transaction {
+                        if csw.residue > t.length && status != CommandStatus::PhaseError {
+                                log::warning!("status(): CSW is not meaningful, residue ({} bytes) exceeds the expected transfer length ({} bytes)", csw.residue, t.length);
+                                self.reset_recovery()?;
+                                return Ok(None);
+                        }
+                        if let Some(mismatch) = data_mismatch(&t, csw.residue, &status) {
+                                log::warning!("status(): host/device data mismatch, {}", mismatch);
+                        }
+                }
+                if let Some(r) = residue {
+                        *r = csw.residue;
+                }
+                if status == CommandStatus::PhaseError || status == CommandStatus::Reserved {
+                        log::warning!("status(): device reported status {:?}", status);
+                        self.reset_recovery()?;
+                }
+                Ok(Some(status))
         }
 
-        pub fn query_capacity(&self, sector_count: Option<&mut u32>, sector_size: Option<&mut u32>) -> usb::Result<Option<CommandStatus>> {
-                assert!(self.handle.is_some());
-                let mut command_block: [u8; 10] = [0; 10];
-                command_block[0] = 0x25;
-                self.send_command(&command_block, Direction::DeviceToHost, 8)?;
-                let handle = self.handle.as_ref().unwrap();
-                let mut buf = [0u8; size_of::<u64>()];
-                let bytes_read = handle.read_bulk(self.out_endpoint, &mut buf, Duration::from_millis(0)).unwrap_or_else(|e| { log::error!("query_capacity(): failed to perform bulk read, cause: {}", e); 0 });
+        /// Queries the number of addressable sectors and their size, devices with more than 2^32 sectors
+        /// are detected through READ CAPACITY(16)
+        pub fn query_capacity(&self, sector_count: Option<&mut u64>, sector_size: Option<&mut u32>) -> usb::Result<Option<CommandStatus>> {
+                let mut command_block = [0u8; 10];
+                command_block[0] = scsi::READ_CAPACITY_10;
+                let mut buf = [0u8; 8];
+                let mut status = self.read_capacity(&command_block, &mut buf)?;
+                let mut last_lba = u64::from(u32::from_be_bytes(buf[..4].try_into().unwrap()));
+                let mut block_size = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+                if status.is_some() && last_lba == u64::from(u32::MAX) {
+                        log::debug!("query_capacity(): device has more than 2^32 sectors, falling back to READ CAPACITY(16)");
+                        let mut command_block = [0u8; 16];
+                        command_block[0] = scsi::SERVICE_ACTION_IN_16;
+                        command_block[1] = scsi::READ_CAPACITY_16_SERVICE_ACTION;
+                        command_block[10..14].copy_from_slice(&32u32.to_be_bytes());
+                        let mut buf = [0u8; 32];
+                        status = self.read_capacity(&command_block, &mut buf)?;
+                        last_lba = u64::from_be_bytes(buf[..8].try_into().unwrap());
+                        block_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+                }
+                if status.is_none() {
+                        return Ok(None);
+                }
+                if let Some(count) = sector_count {
+                        *count = last_lba + 1;
+                }
+                if let Some(size) = sector_size {
+                        *size = block_size;
+                }
+                Ok(status)
+        }
+
+        fn read_capacity(&self, command_block: &[u8], buf: &mut [u8]) -> usb::Result<Option<CommandStatus>> {
+                let mut bytes_read: usize = 0;
+                let status = self.command_with_data_in(command_block, buf, &mut bytes_read)?;
                 if bytes_read < buf.len() {
                         log::warning!("query_capacity(): Device returned only {} bytes instead of {}", bytes_read, buf.len());
                         return Ok(None)
                 }
-                if sector_count.is_some() {
-                        *sector_count.unwrap() = u32::from_be_bytes(buf[..4].try_into().unwrap());
+                Ok(status)
+        }
+
+        /// Sends a command whose data phase goes from the device to the host, `data_size` receives the
+        /// number of bytes that were actually read into `buf`
+        fn command_with_data_in(&self, command_block: &[u8], buf: &mut [u8], data_size: &mut usize) -> usb::Result<Option<CommandStatus>> {
+                assert!(self.handle.is_some());
+                let mut status = None;
+                for attempt in 1..=TRANSFER_ATTEMPTS {
+                        *data_size = 0;
+                        if self.send_command(command_block, Direction::DeviceToHost, buf.len() as u32)? {
+                                *data_size = self.data_in(buf);
+                                status = self.status(None)?;
+                        }
+                        match status {
+                                // no data at all is never a valid response, the data phase was most likely aborted
+                                Some(CommandStatus::Success) if *data_size > 0 || buf.is_empty() => { break; },
+                                Some(CommandStatus::Error) => { break; },
+                                _ => {
+                                        log::warning!("command_with_data_in(): command {:#04x} did not complete (status {:?}), attempt {}/{}", command_block[0], status, attempt, TRANSFER_ATTEMPTS);
+                                }
+                        }
                 }
-                if sector_size.is_some() {
-                        *sector_size.unwrap() = u32::from_be_bytes(buf[4..].try_into().unwrap());
+                Ok(status)
+        }
+
+        /// Sends a standard INQUIRY command and decodes its response
+        pub fn inquiry(&self) -> usb::Result<Option<scsi::InquiryData>> {
+                let mut command_block = [0u8; 6];
+                command_block[0] = scsi::INQUIRY;
+                command_block[3..5].copy_from_slice(&(scsi::INQUIRY_STANDARD_LENGTH as u16).to_be_bytes());
+                let mut buf = [0u8; scsi::INQUIRY_STANDARD_LENGTH];
+                let mut bytes_read: usize = 0;
+                match self.command_with_data_in(&command_block, &mut buf, &mut bytes_read)? {
+                        Some(CommandStatus::Success) => { Ok(scsi::InquiryData::parse(&buf[..bytes_read])) },
+                        status => {
+                                log::warning!("inquiry(): command failed with status {:?}", status);
+                                Ok(None)
+                        }
                 }
-                self.status(None)
         }
 
-        pub fn ready(&self) -> usb::Result<bool> {
-                let cb = [0u8; 6];
-                self.send_command(&cb, Direction::DeviceToHost, 0)?;
-                let status = self.status(None).unwrap_or_else(|e| { log::error!("ready(): failed to get status, cause: {}", e); None });
-                if status.is_none() {
-                        return Ok(false);
+        /// Reads the raw Vital Product Data page `page_code` (header included) through INQUIRY
+        pub fn vital_product_data(&self, page_code: u8) -> usb::Result<Option<Vec<u8>>> {
+                let mut command_block = [0u8; 6];
+                command_block[0] = scsi::INQUIRY;
+                command_block[1] = 0x01;
+                command_block[2] = page_code;
+                command_block[3..5].copy_from_slice(&(VPD_ALLOCATION_LENGTH as u16).to_be_bytes());
+                let mut buf = vec![0u8; VPD_ALLOCATION_LENGTH];
+                let mut bytes_read: usize = 0;
+                match self.command_with_data_in(&command_block, &mut buf, &mut bytes_read)? {
+                        Some(CommandStatus::Success) if bytes_read >= 4 && buf[1] == page_code => {
+                                buf.truncate(bytes_read);
+                                Ok(Some(buf))
+                        },
+                        status => {
+                                log::warning!("vital_product_data(): failed to read page {:#04x}, status: {:?}", page_code, status);
+                                Ok(None)
+                        }
                 }
-                Ok(status.unwrap() == CommandStatus::Success)
         }
 
-        pub fn storage_read(&self, data: &mut [u8], start: u32, data_size: &mut usize) -> usb::Result<Option<CommandStatus>> {
-                assert!((data.len() % 512) == 0);
-                let success = self.initiate_storage_transfer(Direction::DeviceToHost, start, (data.len() / 512) as u16);
-                log::debug!("storage_write(): starting transfer, result: {:?}", success);
-                if success {
-                        assert!(self.handle.is_some());
-                        let handle = self.handle.as_ref().unwrap();
-                        *data_size = handle.read_bulk(self.out_endpoint, data, Duration::from_millis(0)).unwrap_or_else(|e| { log::error!("storage_read(): bulk read failed, cause: {}", e); 0 });
-                } else {
+        /// Sends TEST UNIT READY once
+        pub fn test_unit_ready(&self) -> usb::Result<Option<CommandStatus>> {
+                let mut command_block = [0u8; 6];
+                command_block[0] = scsi::TEST_UNIT_READY;
+                if !self.send_command(&command_block, Direction::DeviceToHost, 0)? {
                         return Ok(None);
                 }
-
                 self.status(None)
         }
 
-        pub fn storage_write(&self, data: &[u8], start: u32) -> usb::Result<Option<CommandStatus>> {
-                assert!((data.len() % 512) == 0);
-                let success = self.initiate_storage_transfer(Direction::HostToDevice, start, (data.len() / 512) as u16);
-                log::debug!("storage_write(): starting transfer, result: {:?}", success);
-                if success {
-                        assert!(self.handle.is_some());
-                        let handle = self.handle.as_ref().unwrap();
-                        handle.write_bulk(self.in_endpoint, data, Duration::from_millis(0)).unwrap_or_else(|e| { log::error!("storage_write(): bulk write failed, cause: {}", e); 0 });
-                } else {
-                        return Ok(None);
+        /// Repeats TEST UNIT READY until the unit reports it is ready, UNIT ATTENTION conditions (medium changed,
+        /// power on reset) are retried right away while a unit that is becoming ready is given increasing delays
+        pub fn ready(&self) -> usb::Result<Readiness> {
+                let mut backoff = READY_INITIAL_BACKOFF;
+                let mut last_sense: Option<scsi::SenseData> = None;
+                for attempt in 1..=READY_ATTEMPTS {
+                        let status = self.test_unit_ready()?;
+                        let sense = self.sense();
+                        match (&status, sense) {
+                                (Some(CommandStatus::Success), _) => { return Ok(Readiness::Ready); },
+                                (Some(CommandStatus::Error), Some(s)) if s.key == scsi::SENSE_KEY_UNIT_ATTENTION => {
+                                        log::debug!("ready(): unit attention reported ({}), attempt {}/{}", s, attempt, READY_ATTEMPTS);
+                                        last_sense = sense;
+                                        continue;
+                                },
+                                (Some(CommandStatus::Error), Some(s)) if s.key == scsi::SENSE_KEY_NOT_READY && s.asc == scsi::ASC_MEDIUM_NOT_PRESENT => {
+                                        return Ok(Readiness::NoMedium);
+                                },
+                                (Some(CommandStatus::Error), Some(s)) if s.key == scsi::SENSE_KEY_NOT_READY && s.asc == scsi::ASC_LOGICAL_UNIT_NOT_READY && scsi::ASCQ_TRANSIENT_NOT_READY.contains(&s.ascq) => {
+                                        log::debug!("ready(): {}, waiting {:?}, attempt {}/{}", s, backoff, attempt, READY_ATTEMPTS);
+                                        last_sense = sense;
+                                },
+                                (Some(CommandStatus::Error), Some(_)) => {
+                                        // the unit will not become ready by itself (write protection, unsupported medium...)
+                                        return Ok(Readiness::NotReady(sense));
+                                },
+                                _ => {
+                                        log::debug!("ready(): TEST UNIT READY did not complete (status {:?}), waiting {:?}, attempt {}/{}", status, backoff, attempt, READY_ATTEMPTS);
+                                        last_sense = sense;
+                                }
+                        }
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(READY_MAX_BACKOFF);
                 }
+                Ok(Readiness::NotReady(last_sense))
+        }
 
-                self.status(None)
+        /// The number of sectors of a transfer of `length` bytes, which have to fit in the 32 bit length of the CBW
+        fn transfer_sectors(&self, length: usize) -> usb::Result<u32> {
+                if u32::try_from(length).is_err() {
+                        log::error!("transfer_sectors(): {} bytes do not fit in a single transfer", length);
+                        return Err(usb::Error::InvalidParam);
+                }
+                Ok((length / self.block_size as usize) as u32)
         }
 
-        pub fn flash_image_from_file(&self, filename: &PathBuf, buffer_size: usize, preferred_size: Option<u32>, progress_cb: fn(u32, u32) -> ()) -> std::io::Result<bool> {
-                let file_handle = File::open(filename);
-                if file_handle.is_err() {
-                        let error = file_handle.unwrap_err();
-                        log::error!("flash_from_file(): failed to open file {:?}, cause: {}", filename, error);
-                        return Ok(false);
+        /// Reads `data.len()` bytes starting at sector `start`, incomplete transfers are retried and reported
+        /// as `None` if they keep failing
+        pub fn storage_read(&self, data: &mut [u8], start: u64, data_size: &mut usize) -> usb::Result<Option<CommandStatus>> {
+                assert!(data.len().is_multiple_of(self.block_size as usize));
+                let count = self.transfer_sectors(data.len())?;
+                for attempt in 1..=TRANSFER_ATTEMPTS {
+                        let mut residue: u32 = 0;
+                        *data_size = 0;
+                        let success = self.initiate_storage_transfer(Direction::DeviceToHost, start, count);
+                        log::debug!("storage_read(): starting transfer, result: {:?}", success);
+                        let status = if success {
+                                *data_size = self.data_in(data);
+                                self.status(Some(&mut residue))?
+                        } else {
+                                None
+                        };
+                        match status {
+                                Some(CommandStatus::Success) if *data_size == data.len() && residue == 0 => { return Ok(status); },
+                                Some(CommandStatus::Error) => { return Ok(status); },
+                                _ => {
+                                        log::warning!("storage_read(): reading {} sectors at {} did not complete ({} bytes received, status {:?}), attempt {}/{}", count, start, data_size, status, attempt, TRANSFER_ATTEMPTS);
+                                }
+                        }
                 }
-                let mut file = file_handle.unwrap();
-                let file_size = std::fs::metadata(filename)?.len();
-                if file_size % 512 != 0 {
-                        log::error!("flash_from_file(): image size is not divisible by 512, therefore it is invalid");
-                        return Ok(false);
+                Ok(None)
+        }
+
+        /// Writes `data` starting at sector `start`, incomplete transfers are retried and reported as `None`
+        /// if they keep failing
+        pub fn storage_write(&self, data: &[u8], start: u64) -> usb::Result<Option<CommandStatus>> {
+                assert!(data.len().is_multiple_of(self.block_size as usize));
+                let count = self.transfer_sectors(data.len())?;
+                for attempt in 1..=TRANSFER_ATTEMPTS {
+                        let mut residue: u32 = 0;
+                        let mut bytes_written: usize = 0;
+                        let success = self.initiate_storage_transfer(Direction::HostToDevice, start, count);
+                        log::debug!("storage_write(): starting transfer, result: {:?}", success);
+                        let status = if success {
+                                bytes_written = self.data_out(data);
+                                self.status(Some(&mut residue))?
+                        } else {
+                                None
+                        };
+                        match status {
+                                Some(CommandStatus::Success) if bytes_written == data.len() && residue == 0 => { return Ok(status); },
+                                Some(CommandStatus::Error) => { return Ok(status); },
+                                _ => {
+                                        log::warning!("storage_write(): writing {} sectors at {} did not complete ({} bytes sent, residue {}, status {:?}), attempt {}/{}", count, start, bytes_written, residue, status, attempt, TRANSFER_ATTEMPTS);
+                                }
+                        }
+                }
+                Ok(None)
+        }
+
+        /// Finds out how the device deallocates sectors from its Logical Block Provisioning and Block Limits VPD pages
+        fn discard_method(&self) -> usb::Result<Option<DiscardMethod>> {
+                let supported_pages = self.vital_product_data(scsi::VPD_SUPPORTED_PAGES)?.map(|page| scsi::parse_supported_pages(&page)).unwrap_or_default();
+                if !supported_pages.contains(&scsi::VPD_LOGICAL_BLOCK_PROVISIONING) {
+                        return Ok(None);
+                }
+                let provisioning = match self.vital_product_data(scsi::VPD_LOGICAL_BLOCK_PROVISIONING)?.and_then(|page| scsi::LogicalBlockProvisioning::parse(&page)) {
+                        Some(p) => { p },
+                        None => { return Ok(None); }
+                };
+                let limits = match supported_pages.contains(&scsi::VPD_BLOCK_LIMITS) {
+                        true => { self.vital_product_data(scsi::VPD_BLOCK_LIMITS)?.and_then(|page| scsi::BlockLimits::parse(&page)).unwrap_or_default() },
+                        false => { scsi::BlockLimits::default() }
+                };
+                log::debug!("discard_method(): {:?}, {:?}", provisioning, limits);
+                // a maximum write same length of 0 means the device does not report a limit
+                let write_same_length = |max: u64| if limits.maximum_write_same_length == 0 { max } else { limits.maximum_write_same_length.min(max) };
+                Ok(if provisioning.unmap && limits.maximum_unmap_lba_count > 0 && limits.maximum_unmap_descriptor_count > 0 {
+                        Some(DiscardMethod::Unmap(u64::from(limits.maximum_unmap_lba_count)))
+                } else if provisioning.write_same_16 {
+                        Some(DiscardMethod::WriteSame16(write_same_length(u64::from(u32::MAX))))
+                } else if provisioning.write_same_10 {
+                        Some(DiscardMethod::WriteSame10(write_same_length(u64::from(u16::MAX))))
+                } else {
+                        None
+                })
+        }
+
+        /// Deallocates `count` sectors starting at `start`, in as many commands as the device's limits require
+        fn discard(&self, method: DiscardMethod, start: u64, count: u64) -> usb::Result<Option<CommandStatus>> {
+                let mut done: u64 = 0;
+                while done < count {
+                        let lba = start + done;
+                        let (blocks, command_block, data) = match method {
+                                DiscardMethod::Unmap(max) => {
+                                        let blocks = (count - done).min(max);
+                                        // parameter list header followed by a single block descriptor
+                                        let mut parameters = vec![0u8; 24];
+                                        parameters[0..2].copy_from_slice(&22u16.to_be_bytes());
+                                        parameters[2..4].copy_from_slice(&16u16.to_be_bytes());
+                                        parameters[8..16].copy_from_slice(&lba.to_be_bytes());
+                                        parameters[16..20].copy_from_slice(&(blocks as u32).to_be_bytes());
+                                        let mut cb = vec![0u8; 10];
+                                        cb[0] = scsi::UNMAP;
+                                        cb[7..9].copy_from_slice(&(parameters.len() as u16).to_be_bytes());
+                                        (blocks, cb, parameters)
+                                },
+                                DiscardMethod::WriteSame16(max) => {
+                                        let blocks = (count - done).min(max);
+                                        let mut cb = vec![0u8; 16];
+                                        cb[0] = scsi::WRITE_SAME_16;
+                                        cb[1] = 0x08;
+                                        cb[2..10].copy_from_slice(&lba.to_be_bytes());
+                                        cb[10..14].copy_from_slice(&(blocks as u32).to_be_bytes());
+                                        (blocks, cb, vec![0u8; self.block_size as usize])
+                                },
+                                DiscardMethod::WriteSame10(max) => {
+                                        let blocks = (count - done).min(max);
+                                        if lba + blocks > u64::from(u32::MAX) {
+                                                log::error!("discard(): sector {} cannot be addressed by WRITE SAME(10)", lba + blocks);
+                                                return Ok(None);
+                                        }
+                                        let mut cb = vec![0u8; 10];
+                                        cb[0] = scsi::WRITE_SAME_10;
+                                        cb[1] = 0x08;
+                                        cb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+                                        cb[7..9].copy_from_slice(&(blocks as u16).to_be_bytes());
+                                        (blocks, cb, vec![0u8; self.block_size as usize])
+                                }
+                        };
+                        log::debug!("discard(): deallocating {} sectors at {} with {:?}", blocks, lba, method);
+                        if !self.send_command(&command_block, Direction::HostToDevice, data.len() as u32)? {
+                                return Ok(None);
+                        }
+                        let mut residue: u32 = 0;
+                        let bytes_written = self.data_out(&data);
+                        match self.status(Some(&mut residue))? {
+                                Some(CommandStatus::Success) if bytes_written == data.len() && residue == 0 => {},
+                                Some(CommandStatus::Success) => { return Ok(None); },
+                                status => { return Ok(status); }
+                        }
+                        done += blocks;
                 }
-                let mut device_capacity = 0;
+                Ok(Some(CommandStatus::Success))
+        }
+
+        /// Writes `count` sectors of zeros starting at `start` in chunks of `buffer_size` sectors
+        fn write_zeros(&self, start: u64, count: u64, buffer_size: usize, progress: impl Fn(u64)) -> usb::Result<Option<CommandStatus>> {
+                let zeros = vec![0u8; buffer_size * self.block_size as usize];
+                let mut done: u64 = 0;
+                while done < count {
+                        let chunk = (count - done).min(buffer_size as u64) as usize;
+                        let status = self.storage_write(&zeros[..chunk * self.block_size as usize], start + done)?;
+                        if status != Some(CommandStatus::Success) {
+                                return Ok(status);
+                        }
+                        done += chunk as u64;
+                        progress(start + done);
+                }
+                Ok(Some(CommandStatus::Success))
+        }
+
+        /// Writes the image to the device, compressed images are decoded on the fly and the decoded data is fed
+        /// to `hashers` as it is written. The holes of sparse images and the ranges left unmapped by `bmap` are
+        /// not read, the sectors they cover are handled according to `sparse.hole_policy` (the DONT_CARE chunks of
+        /// Android sparse images are always skipped), while the mapped ranges are checked against their digests
+        pub fn flash_image_from_file(&self, location: &image::Location, buffer_size: usize, preferred_size: Option<u64>, sparse: &SparseOptions, hashers: &mut [hash::Hasher], progress_cb: fn(u64, u64)) -> std::io::Result<bool> {
+                let (hole_policy, bmap) = (sparse.hole_policy, sparse.bmap);
+                let mut image = match image::Image::open(location) {
+                        Ok(img) => { img },
+                        Err(e) => {
+                                log::error!("flash_from_file(): failed to open file {:?}, cause: {}", location.path, e);
+                                return Ok(false);
+                        }
+                };
+                let image_size = match (image.size(), bmap) {
+                        (Some(size), Some(map)) if size != map.image_size => {
+                                log::error!("flash_from_file(): the bmap {:?} describes an image of {} bytes but the image is {} bytes long", map.path, map.image_size, size);
+                                return Ok(false);
+                        },
+                        (size, map) => { size.or(map.map(|m| m.image_size)) }
+                };
+                let block_size = self.block_size as usize;
+                let image_sectors = image_size.map(|size| size.div_ceil(block_size as u64));
+                match image_size {
+                        Some(size) if !size.is_multiple_of(block_size as u64) => {
+                                log::warning!("flash_from_file(): image size is not a multiple of the device's block size ({} bytes), the last block will be padded with zeros", block_size);
+                        },
+                        Some(_) => {},
+                        None if image::is_stdio(location.path) => { log::warning!("flash_from_file(): the size of the image read from the standard input is unknown, use --input-size to show the progress"); },
+                        None => { log::warning!("flash_from_file(): the size of the {} image is unknown until it is decoded, the progress is estimated", image.compression().name()); }
+                };
+                let mut device_capacity: u64 = 0;
                 self.query_capacity(Some(&mut device_capacity), None).unwrap_or_else(|e| { log::warning!("flash_from_file(): failed to determine device capacity, flashing process may fail due to the device not being big enough, cause: {}", e); None});
-                if file_size > u64::from(device_capacity * 512) {
+                if image_sectors.is_some_and(|sectors| sectors > device_capacity) {
                         log::error!("flash_from_file(): Device has not enough space, unable to flash image");
                         return Ok(false);
                 }
-                let output_size = match preferred_size {
-                        Some(sz) => {
-                                if sz > (file_size / 512) as u32 {
-                                        log::error!("preferred size ({sz} sectors) is greater than the size of the input image ({} sectors)", file_size / 512);
-                                        std::process::exit(1);
-                                };
-                                sz
+                let output_size = match (preferred_size, image_sectors) {
+                        (Some(sz), Some(sectors)) if sz > sectors => {
+                                log::error!("preferred size ({sz} sectors) is greater than the size of the input image ({} sectors)", sectors);
+                                std::process::exit(1);
                         },
-                        None => {
-                                (file_size / 512) as u32
-                        }
+                        (Some(sz), _) => { Some(sz) },
+                        (None, sectors) => { sectors }
                 };
-                log::debug!("beginning to write image {:?} to device...", filename);
-                let mut write_buffer = vec![0u8; buffer_size * 512];
-                let mut current_sector: u32 = 0;
-                let mut bytes_read: usize;
+                let holes = plan_holes(bmap.map(|m| m.extents()).as_deref().or(image.data_extents()), image.undefined_extents(), image_size, block_size as u64, hole_policy);
+                let mut range_verifier = bmap.map(bmap::RangeVerifier::new);
+                let discard_method = match hole_policy {
+                        HolePolicy::Discard if holes.iter().any(|(_, policy)| *policy == HolePolicy::Discard) => {
+                                let method = self.discard_method().unwrap_or_else(|e| { log::warning!("flash_from_file(): failed to query the device's provisioning support, cause: {}", e); None });
+                                if method.is_none() {
+                                        log::warning!("flash_from_file(): the device cannot deallocate sectors, zeros are written over the holes of the image instead");
+                                }
+                                method
+                        },
+                        _ => { None }
+                };
+                log::debug!("beginning to write {} {} image {:?} to device ({} holes, policy {:?})...", image.compression().name(), image.format().name(), location.path, holes.len(), hole_policy);
+                let mut write_buffer = vec![0u8; buffer_size * block_size];
+                let mut current_sector: u64 = 0;
                 'write_image: loop {
-                        progress_cb(current_sector, output_size);
-                        bytes_read = file.read(&mut write_buffer)?;
-                        if bytes_read == 0 || current_sector > output_size {
+                        let total = progress_total(&image, output_size, current_sector, block_size);
+                        progress_cb(current_sector, total);
+                        let mut count = output_size.map_or(buffer_size as u64, |size| (size - current_sector).min(buffer_size as u64)) as usize;
+                        match holes.get(holes.partition_point(|(h, _)| h.end <= current_sector)) {
+                                Some((hole, policy)) if hole.start <= current_sector && count > 0 => {
+                                        let end = output_size.map_or(hole.end, |size| hole.end.min(size));
+                                        let hole_bytes = (end - current_sector) * block_size as u64;
+                                        if let Err(e) = image.skip(hole_bytes) {
+                                                log::error!("flash_from_file(): failed to read the image, cause: {}", e);
+                                                return Ok(false);
+                                        }
+                                        if !hashers.is_empty() {
+                                                // the padding of the last sector is not part of the image
+                                                let image_bytes = hole_bytes.min(image_size.unwrap_or(u64::MAX).saturating_sub(current_sector * block_size as u64));
+                                                hash_zeros(hashers, image_bytes);
+                                        }
+                                        let status = match (policy, discard_method) {
+                                                (HolePolicy::Skip, _) => { Ok(Some(CommandStatus::Success)) },
+                                                (HolePolicy::Discard, Some(method)) => { self.discard(method, current_sector, end - current_sector) },
+                                                _ => { self.write_zeros(current_sector, end - current_sector, buffer_size, |sector| progress_cb(sector, total)) }
+                                        };
+                                        let status = status.unwrap_or_else(|e| { log::error!("flash_from_file(): failed to write to the device, cause: {}", e); None });
+                                        if status != Some(CommandStatus::Success) {
+                                                println!();
+                                                self.log_failure("flash_from_file(): failed to fill the hole starting at sector", current_sector, status);
+                                                return Ok(false);
+                                        }
+                                        current_sector = end;
+                                        continue 'write_image;
+                                },
+                                Some((hole, _)) if hole.start > current_sector => { count = count.min((hole.start - current_sector) as usize); },
+                                _ => {}
+                        };
+                        let buffer = &mut write_buffer[..count * block_size];
+                        let bytes_read = match image::read_full(&mut image, buffer) {
+                                Ok(n) => { n },
+                                Err(e) => {
+                                        log::error!("flash_from_file(): failed to read the image, cause: {}", e);
+                                        return Ok(false);
+                                }
+                        };
+                        if bytes_read == 0 {
                                 break 'write_image;
                         }
-                        self.storage_write(&write_buffer[..bytes_read], current_sector).unwrap();
-                        current_sector += (bytes_read / 512) as u32;
+                        hash::update_all(hashers, &buffer[..bytes_read]);
+                        if let Some(Err(e)) = range_verifier.as_mut().map(|v| v.update(current_sector * block_size as u64, &buffer[..bytes_read])) {
+                                println!();
+                                log::error!("flash_from_file(): the image does not match its bmap, {}", e);
+                                return Ok(false);
+                        }
+                        let padded_size = bytes_read.next_multiple_of(block_size);
+                        buffer[bytes_read..padded_size].fill(0);
+                        if current_sector + (padded_size / block_size) as u64 > device_capacity {
+                                println!();
+                                log::error!("flash_from_file(): the decoded image is larger than the device ({} sectors), unable to flash image", device_capacity);
+                                return Ok(false);
+                        }
+                        let status = self.storage_write(&buffer[..padded_size], current_sector).unwrap_or_else(|e| { log::error!("flash_from_file(): failed to write to the device, cause: {}", e); None });
+                        if status != Some(CommandStatus::Success) {
+                                println!();
+                                self.log_failure("flash_from_file(): failed to write sector", current_sector, status);
+                                return Ok(false);
+                        }
+                        current_sector += (padded_size / block_size) as u64;
                 } 
+                if output_size.is_none_or(|size| current_sector < size) {
+                        progress_cb(current_sector, current_sector);
+                }
+                println!();
                 Ok(true)
         }
 
-        pub fn clone_drive_to_file(&self, filename: &PathBuf, buffer_size: usize, preferred_size: Option<u32>, progress_cb: fn(u32, u32) -> ()) -> std::io::Result<bool> {
-                let file_handle = File::create(filename);
-                if file_handle.is_err() {
-                        let error = file_handle.unwrap_err();
-                        log::error!("clone_drive_to_file(): failed to create file {:?}, cause {}", filename, error);
-                        return Ok(false);
+        /// Reads back the sectors written by `flash_image_from_file` and compares them with the image, the
+        /// sectors are compared byte by byte unless `fast` is set, in which case only the hash of each sector of
+        /// the image is kept while the device is read into the same buffer. The holes of sparse images (and the
+        /// ranges left unmapped by the bmap) are only compared when zeros were written over them, as their
+        /// content is undefined once discarded or skipped
+        pub fn verify_image_on_device(&self, location: &image::Location, buffer_size: usize, preferred_size: Option<u64>, fast: bool, sparse: &SparseOptions, progress_cb: fn(u64, u64)) -> std::io::Result<bool> {
+                let bmap = sparse.bmap;
+                let mut image = match image::Image::open(location) {
+                        Ok(img) => { img },
+                        Err(e) => {
+                                log::error!("verify_image_on_device(): failed to open file {:?}, cause: {}", location.path, e);
+                                return Ok(false);
+                        }
+                };
+                let block_size = self.block_size as usize;
+                let image_size = image.size().or(bmap.map(|m| m.image_size));
+                let output_size = match (preferred_size, image_size.map(|size| size.div_ceil(block_size as u64))) {
+                        (Some(sz), Some(sectors)) => { Some(sz.min(sectors)) },
+                        (sz, sectors) => { sz.or(sectors) }
+                };
+                // the holes are planned as flash does, zeros were written over those following WriteZeros
+                let holes = plan_holes(bmap.map(|m| m.extents()).as_deref().or(image.data_extents()), image.undefined_extents(), image_size, block_size as u64, sparse.hole_policy);
+                log::debug!("verifying {:?} sectors of image {:?}{}...", output_size, location.path, if fast { " through sector hashes" } else { "" });
+                let mut image_buffer = vec![0u8; buffer_size * block_size];
+                let mut device_buffer = if fast { vec![] } else { vec![0u8; buffer_size * block_size] };
+                let mut sector_hashes: Vec<u64> = Vec::with_capacity(buffer_size);
+                let mut first_mismatch: Option<u64> = None;
+                let mut mismatched_sectors: u64 = 0;
+                let mut compared_sectors: u64 = 0;
+                let mut bytes_read: usize = 0;
+                let mut current_sector: u64 = 0;
+                loop {
+                        progress_cb(current_sector, progress_total(&image, output_size, current_sector, block_size));
+                        let mut limit = output_size.map_or(buffer_size as u64, |size| size.saturating_sub(current_sector).min(buffer_size as u64)) as usize;
+                        let mut zeros = false;
+                        match holes.get(holes.partition_point(|(h, _)| h.end <= current_sector)) {
+                                Some((hole, policy)) if hole.start <= current_sector && limit > 0 => {
+                                        let end = output_size.map_or(hole.end, |size| hole.end.min(size));
+                                        if *policy == HolePolicy::WriteZeros {
+                                                // whatever the image holds there, the device is compared against zeros
+                                                limit = limit.min((end - current_sector) as usize);
+                                                zeros = true;
+                                        }
+                                        let skipped = if zeros { limit as u64 } else { end - current_sector };
+                                        if let Err(e) = image.skip(skipped * block_size as u64) {
+                                                log::error!("verify_image_on_device(): failed to read the image, cause: {}", e);
+                                                return Ok(false);
+                                        }
+                                        if !zeros {
+                                                current_sector = end;
+                                                continue;
+                                        }
+                                },
+                                Some((hole, _)) if hole.start > current_sector => { limit = limit.min((hole.start - current_sector) as usize); },
+                                _ => {}
+                        };
+                        let image_bytes = match zeros {
+                                true => {
+                                        image_buffer[..limit * block_size].fill(0);
+                                        limit * block_size
+                                },
+                                false => {
+                                        match image::read_full(&mut image, &mut image_buffer[..limit * block_size]) {
+                                                Ok(n) => { n },
+                                                Err(e) => {
+                                                        log::error!("verify_image_on_device(): failed to read the image, cause: {}", e);
+                                                        return Ok(false);
+                                                }
+                                        }
+                                }
+                        };
+                        if image_bytes == 0 {
+                                break;
+                        }
+                        let count = image_bytes.div_ceil(block_size);
+                        let length = count * block_size;
+                        image_buffer[image_bytes..length].fill(0);
+                        if fast {
+                                sector_hashes.clear();
+                                sector_hashes.extend(image_buffer[..length].chunks(block_size).map(hash_sector));
+                        }
+                        let device_chunk = if fast { &mut image_buffer[..length] } else { &mut device_buffer[..length] };
+                        let status = self.storage_read(device_chunk, current_sector, &mut bytes_read).unwrap_or_else(|e| { log::error!("verify_image_on_device(): failed to read from the device, cause: {}", e); None });
+                        if status != Some(CommandStatus::Success) {
+                                println!();
+                                self.log_failure("verify_image_on_device(): failed to read sector", current_sector, status);
+                                return Ok(false);
+                        }
+                        let mismatches = (0..count).filter(|&n| {
+                                let sector = n * block_size..(n + 1) * block_size;
+                                if fast {
+                                        hash_sector(&image_buffer[sector]) != sector_hashes[n]
+                                } else {
+                                        device_buffer[sector.clone()] != image_buffer[sector]
+                                }
+                        });
+                        for n in mismatches {
+                                first_mismatch.get_or_insert(current_sector + n as u64);
+                                mismatched_sectors += 1;
+                        }
+                        compared_sectors += count as u64;
+                        current_sector += count as u64;
                 }
-                let mut file = file_handle.unwrap();
-                let mut device_capacity: u32 = 0;
-                self.query_capacity(Some(&mut device_capacity), None).unwrap();
-                let mut read_buffer = vec![0u8; buffer_size * 512];
-                let output_size = match preferred_size {
-                        Some(sz) => {
-                                if sz > device_capacity {
-                                        log::error!("preferred size ({sz} sectors) is greater than the target device's capacity ({device_capacity} sectors)");
-                                        std::process::exit(1);
-                                };
-                                sz
+                println!();
+                match first_mismatch {
+                        Some(lba) => {
+                                log::error!("verify_image_on_device(): {} of {} sectors do not match the image, the first mismatch is at LBA {}", mismatched_sectors, compared_sectors, lba);
+                                Ok(false)
                         },
-                        None => {
-                                device_capacity
+                        None => { Ok(true) }
+                }
+        }
+
+        /// Copies the device's storage to the file, stored as described by `output`, the data is fed to `hashers`
+        /// as it is written
+        pub fn clone_drive_to_file(&self, destination: image::Destination, buffer_size: usize, preferred_size: Option<u64>, output: &image::OutputOptions, hashers: &mut [hash::Hasher], progress_cb: fn(u64, u64)) -> std::io::Result<bool> {
+                let output_size = match self.range_size(0, preferred_size) {
+                        Some(sz) => { sz },
+                        None => { return Ok(false); }
+                };
+                let filename = destination.path().to_path_buf();
+                let mut writer = match image::create(destination, output, output_size * self.block_size as u64, self.block_size) {
+                        Ok(w) => { w },
+                        Err(e) => {
+                                log::error!("clone_drive_to_file(): failed to create file {:?}, cause {}", filename, e);
+                                return Ok(false);
                         }
                 };
-                log::debug!("cloning drive of {device_capacity} sectors ({output_size} sectors will be copied)...");
+                log::debug!("cloning drive ({output_size} sectors will be copied)...");
+                let completed = self.read_sectors(0, output_size, buffer_size, progress_cb, |data| {
+                        hash::update_all(hashers, data);
+                        writer.write_all(data)
+                })?;
+                writer.finish()?;
+                Ok(completed)
+        }
+
+        /// Hashes `preferred_size` sectors (or up to the end of the device) starting at `start` without storing them
+        pub fn hash_range(&self, start: u64, preferred_size: Option<u64>, buffer_size: usize, hashers: &mut [hash::Hasher], progress_cb: fn(u64, u64)) -> std::io::Result<bool> {
+                let size = match self.range_size(start, preferred_size) {
+                        Some(sz) => { sz },
+                        None => { return Ok(false); }
+                };
+                log::debug!("hashing {size} sectors starting at {start}...");
+                self.read_sectors(start, size, buffer_size, progress_cb, |data| {
+                        hash::update_all(hashers, data);
+                        Ok(())
+                })
+        }
+
+        /// Checks that the range of `preferred_size` sectors starting at `start` lies within the device and returns
+        /// its size, the range extends up to the end of the device when no size is given
+        fn range_size(&self, start: u64, preferred_size: Option<u64>) -> Option<u64> {
+                let mut device_capacity: u64 = 0;
+                match self.query_capacity(Some(&mut device_capacity), None) {
+                        Ok(Some(CommandStatus::Success)) => {},
+                        status => {
+                                log::error!("range_size(): failed to determine the device's capacity ({:?})", status);
+                                return None;
+                        }
+                };
+                if start > device_capacity {
+                        log::error!("start sector {start} is beyond the end of the device ({device_capacity} sectors)");
+                        return None;
+                }
+                match preferred_size {
+                        Some(sz) if sz > device_capacity - start => {
+                                log::error!("preferred size ({sz} sectors) is greater than the target device's capacity ({device_capacity} sectors, {} after sector {start})", device_capacity - start);
+                                None
+                        },
+                        Some(sz) => { Some(sz) },
+                        None => { Some(device_capacity - start) }
+                }
+        }
+
+        /// Reads `count` sectors starting at `start` in chunks of `buffer_size` sectors and passes each chunk to `consumer`
+        fn read_sectors(&self, start: u64, count: u64, buffer_size: usize, progress_cb: fn(u64, u64), mut consumer: impl FnMut(&[u8]) -> std::io::Result<()>) -> std::io::Result<bool> {
+                let block_size = self.block_size as usize;
+                let mut read_buffer = vec![0u8; buffer_size * block_size];
                 let mut bytes_read: usize = 0;
-                let mut current_sector: u32 = 0;
-                for i in 0..u32::div_ceil(output_size, buffer_size as u32) {
-                        current_sector = i * buffer_size as u32;
-                        self.storage_read(&mut read_buffer, current_sector, &mut bytes_read).unwrap();
-                        file.write(&read_buffer[..bytes_read])?;
-                        progress_cb(current_sector, output_size);
-                }
-                progress_cb(current_sector + (bytes_read / 512) as u32, output_size);
-                print!("\n");
+                let mut done: u64 = 0;
+                while done < count {
+                        let chunk = (count - done).min(buffer_size as u64) as usize;
+                        let status = self.storage_read(&mut read_buffer[..chunk * block_size], start + done, &mut bytes_read).unwrap_or_else(|e| { log::error!("read_sectors(): failed to read from the device, cause: {}", e); None });
+                        if status != Some(CommandStatus::Success) {
+                                println!();
+                                self.log_failure("read_sectors(): failed to read sector", start + done, status);
+                                return Ok(false);
+                        }
+                        consumer(&read_buffer[..bytes_read])?;
+                        done += chunk as u64;
+                        progress_cb(done, count);
+                }
+                println!();
                 Ok(true)
         }
 
-        fn initiate_storage_transfer(&self, direction: Direction, start_sector: u32, count: u16) -> bool {
-                let mut cb = [0u8; 10];
-                cb[0] = match direction {
-                        Direction::HostToDevice => { 0x2A },
-                        Direction::DeviceToHost => { 0x28 }
+        /// Logs why the transfer starting at `sector` failed, preferring the device's sense data over the raw status
+        fn log_failure(&self, context: &str, sector: u64, status: Option<CommandStatus>) {
+                match self.sense() {
+                        Some(sense) => { log::error!("{} {}: {}", context, sector, sense); },
+                        None => { log::error!("{} {}: command status is {:?}", context, sector, status); }
+                };
+        }
+
+        /// Sends the READ or WRITE command for `count` sectors starting at `start_sector`, the 16 byte
+        /// variant is used only when the addressed range does not fit in 32 bits or the count in 16
+        fn initiate_storage_transfer(&self, direction: Direction, start_sector: u64, count: u32) -> bool {
+                let end_sector = start_sector + u64::from(count);
+                let length = count * self.block_size;
+                let result = if end_sector > u64::from(u32::MAX) || count > u32::from(u16::MAX) {
+                        let mut cb = [0u8; 16];
+                        cb[0] = match direction {
+                                Direction::HostToDevice => { scsi::WRITE_16 },
+                                Direction::DeviceToHost => { scsi::READ_16 }
+                        };
+                        cb[2..10].copy_from_slice(&start_sector.to_be_bytes());
+                        cb[10..14].copy_from_slice(&count.to_be_bytes());
+                        self.send_command(&cb, direction, length)
+                } else {
+                        let mut cb = [0u8; 10];
+                        cb[0] = match direction {
+                                Direction::HostToDevice => { scsi::WRITE_10 },
+                                Direction::DeviceToHost => { scsi::READ_10 }
+                        };
+                        cb[2..6].copy_from_slice(&(start_sector as u32).to_be_bytes());
+                        cb[7..9].copy_from_slice(&(count as u16).to_be_bytes());
+                        self.send_command(&cb, direction, length)
+                };
+                result.unwrap_or_else(|e| { log::error!("initiate_storage_transfer(): failed to send command, cause: {}", e); false })
+        }
+}
+
+/// Describes the host/device data mismatches among the "thirteen cases" of the Bulk-Only specification,
+/// given the transaction as seen by the host and the CSW returned by the device
+fn data_mismatch(transaction: &Transaction, residue: u32, status: &CommandStatus) -> Option<String> {
+        let expected = transaction.length as usize;
+        let transferred = transaction.transferred;
+        if expected == 0 {
+                return match status {
+                        CommandStatus::PhaseError => { Some(String::from("case 2/3 (Hn < Di, Hn < Do): the device expected a data phase")) },
+                        _ => { None }
                 };
-                cb[2..6].copy_from_slice(&start_sector.to_be_bytes());
-                cb[7..9].copy_from_slice(&count.to_be_bytes());
-                let success = self.send_command(&cb, direction, u32::from(count) * 512).unwrap_or_else(|e| { log::error!("storage_write(): failed to send command, cause: {}", e); false });
-                success
         }
+        match (transaction.direction, status) {
+                (Direction::DeviceToHost, CommandStatus::PhaseError) => {
+                        Some(format!("case 7/8 (Hi < Di, Hi <> Do): the device wanted to send more than {} bytes or to receive data", expected))
+                },
+                (Direction::HostToDevice, CommandStatus::PhaseError) => {
+                        Some(format!("case 10/13 (Ho <> Di, Ho < Do): the device wanted to send data or to receive more than {} bytes", expected))
+                },
+                (Direction::DeviceToHost, _) if transferred < expected => {
+                        Some(format!("case 4/5 (Hi > Dn, Hi > Di): the device sent {} of {} bytes, reported residue is {}", transferred, expected, residue))
+                },
+                (Direction::HostToDevice, _) if residue > 0 || transferred < expected => {
+                        Some(format!("case 9/11 (Ho > Dn, Ho > Do): the device processed {} of {} bytes ({} were sent)", expected - residue as usize, expected, transferred))
+                },
+                _ => { None }
+        }
+}
+
+/// Total number of sectors reported to the progress callback, estimated from the part of the image consumed so
+/// far when its decoded size is unknown, 0 when the size of the stored image is not known either
+fn progress_total(image: &image::Image, output_size: Option<u64>, current_sector: u64, block_size: usize) -> u64 {
+        output_size.unwrap_or_else(|| image.estimated_size(current_sector * block_size as u64).map_or(0, |size| size.div_ceil(block_size as u64).max(current_sector)))
+}
+
+/// The ranges of sectors of an image of `size` bytes that are entirely outside of the data `extents`, sectors
+/// partially holding data are part of the data
+fn hole_sectors(extents: Option<&[Range<u64>]>, size: Option<u64>, block_size: u64) -> Vec<Range<u64>> {
+        let (extents, size) = match (extents, size) {
+                (Some(extents), Some(size)) => { (extents, size) },
+                _ => { return vec![]; }
+        };
+        let mut holes = vec![];
+        let mut position: u64 = 0;
+        for extent in extents.iter().map(|e| e.start / block_size..e.end.div_ceil(block_size)) {
+                if extent.start > position {
+                        holes.push(position..extent.start);
+                }
+                position = position.max(extent.end);
+        }
+        let image_sectors = size.div_ceil(block_size);
+        if image_sectors > position {
+                holes.push(position..image_sectors);
+        }
+        holes
+}
+
+/// The holes of an image of `size` bytes by ranges of sectors and what happens to each of them, the sectors
+/// outside of the data `extents` follow `policy` except those the image leaves `undefined`, which are skipped
+fn plan_holes(extents: Option<&[Range<u64>]>, undefined: &[Range<u64>], size: Option<u64>, block_size: u64, policy: HolePolicy) -> Vec<(Range<u64>, HolePolicy)> {
+        let undefined: Vec<Range<u64>> = undefined.iter().map(|e| e.start.div_ceil(block_size)..e.end / block_size).filter(|r| r.start < r.end).collect();
+        let mut holes = vec![];
+        for hole in hole_sectors(extents, size, block_size) {
+                let mut start = hole.start;
+                for skipped in undefined.iter().map(|u| u.start.max(hole.start)..u.end.min(hole.end)).filter(|u| u.start < u.end) {
+                        if skipped.start > start {
+                                holes.push((start..skipped.start, policy));
+                        }
+                        start = skipped.end;
+                        holes.push((skipped, HolePolicy::Skip));
+                }
+                if start < hole.end {
+                        holes.push((start..hole.end, policy));
+                }
+        }
+        holes
+}
+
+/// Feeds `length` zeros to `hashers`
+fn hash_zeros(hashers: &mut [hash::Hasher], length: u64) {
+        let zeros = [0u8; 1 << 16];
+        let mut remaining = length;
+        while remaining > 0 {
+                let chunk = remaining.min(zeros.len() as u64) as usize;
+                hash::update_all(hashers, &zeros[..chunk]);
+                remaining -= chunk as u64;
+        }
+}
+
+fn hash_sector(sector: &[u8]) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        sector.hash(&mut hasher);
+        hasher.finish()
+}
+
+/// Issues the GET MAX LUN class request, devices with a single logical unit are allowed to stall it
+fn query_max_lun(transport: &dyn BulkTransport, interface: u8) -> u8 {
+        let request_type = usb::request_type(usb::Direction::In, usb::RequestType::Class, usb::Recipient::Interface);
+        let mut buf = [0u8; 1];
+        match transport.read_control(request_type, MASS_STORAGE_GET_MAX_LUN_REQUEST, 0, u16::from(interface), &mut buf, TRANSFER_TIMEOUT) {
+                Ok(1) => { buf[0].min(MASS_STORAGE_MAX_LUN) },
+                Ok(_) => { 0 },
+                Err(e) => {
+                        log::debug!("query_max_lun(): GET MAX LUN failed, assuming a single LUN, cause: {}", e);
+                        0
+                }
+        }
+}
+
+/// Opens `dev` just long enough to query the highest LUN of its interface `interface`
+fn query_usb_max_lun(dev: &usb::Device<GlobalContext>, interface: u8) -> u8 {
+        let handle = match dev.open() {
+                Ok(h) => { h },
+                Err(e) => {
+                        log::debug!("query_usb_max_lun(): failed to open {:?}, assuming a single LUN, cause: {}", dev, e);
+                        return 0;
+                }
+        };
+        if usb::supports_detach_kernel_driver() {
+                handle.set_auto_detach_kernel_driver(true).unwrap_or_else(|e| log::debug!("query_usb_max_lun(): failed to enable kernel driver auto-detach, cause: {}", e));
+        }
+        if let Err(e) = handle.claim_interface(interface) {
+                log::debug!("query_usb_max_lun(): failed to claim interface {}, assuming a single LUN, cause: {}", interface, e);
+                return 0;
+        }
+        query_max_lun(&handle, interface)
+}
+
+/// Lists one device for each logical unit reachable through `transport`
+pub fn list_transport_devices(transport: Rc<dyn BulkTransport>, in_endpoint: u8, out_endpoint: u8) -> Vec<Device> {
+        let max_lun = query_max_lun(transport.as_ref(), 0);
+        (0..=max_lun).map(|lun| Device::with_transport(transport.clone(), in_endpoint, out_endpoint, lun)).collect()
 }
 
 #[allow(dead_code)]
@@ -314,19 +1275,104 @@ pub fn list_devices() -> Vec<Device> {
                         if if_desc.class_code() == MASS_STORAGE_CLASS_ID 
                         && if_desc.sub_class_code() == MASS_STORAGE_SUBCLASS_ID 
                         && if_desc.protocol_code() == MASS_STORAGE_PROTOCOL_ID {
-                                let mut d = Device { generic_device: dev.clone(), handle: None, in_endpoint: 0, out_endpoint: 0, selected_interface: interface.number() };
+                                let (mut in_endpoint, mut out_endpoint) = (0, 0);
                                 for e in if_desc.endpoint_descriptors() {
                                         if e.address() & (Direction::DeviceToHost as u8) != 0 {
-                                                d.out_endpoint = e.address();
+                                                out_endpoint = e.address();
                                         } else {
-                                                d.in_endpoint = e.address();
+                                                in_endpoint = e.address();
                                         }
                                 };
-                                let device_name = d.name().unwrap_or(String::from("Unknown Device"));
-                                log::debug!("list_devices(): [{} at {:#?}] Mass Storage Class interface found (input at endpoint {}, output at endpoint {})", device_name, dev, d.in_endpoint, d.out_endpoint);
-                                list.push(d);
+                                let max_lun = query_usb_max_lun(&dev, interface.number());
+                                for lun in 0..=max_lun {
+                                        let d = Device::new(Some(dev.clone()), None, in_endpoint, out_endpoint, interface.number(), lun);
+                                        let device_name = d.name().unwrap_or(String::from("Unknown Device"));
+                                        log::debug!("list_devices(): [{} at {:#?}] Mass Storage Class interface found (LUN {} of {}, input at endpoint {}, output at endpoint {})", device_name, dev, lun, max_lun + 1, in_endpoint, out_endpoint);
+                                        list.push(d);
+                                }
                         }
                 }
         };
         list
 }
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        fn transaction(direction: Direction, length: u32, transferred: usize) -> Transaction {
+                Transaction { tag: 1, direction, length, transferred }
+        }
+
+        #[test]
+        fn thirteen_cases() {
+                let case = |t: Transaction, residue: u32, status: CommandStatus| data_mismatch(&t, residue, &status).map(|m| m.split(" (").next().unwrap().to_string());
+                // cases 1, 6 and 12: host and device agree
+                assert_eq!(case(transaction(Direction::DeviceToHost, 0, 0), 0, CommandStatus::Success), None);
+                assert_eq!(case(transaction(Direction::DeviceToHost, 512, 512), 0, CommandStatus::Success), None);
+                assert_eq!(case(transaction(Direction::HostToDevice, 512, 512), 0, CommandStatus::Success), None);
+                // a command without data phase failing is not a mismatch
+                assert_eq!(case(transaction(Direction::HostToDevice, 0, 0), 0, CommandStatus::Error), None);
+                assert_eq!(case(transaction(Direction::DeviceToHost, 0, 0), 0, CommandStatus::PhaseError).as_deref(), Some("case 2/3"));
+                assert_eq!(case(transaction(Direction::DeviceToHost, 512, 100), 412, CommandStatus::Success).as_deref(), Some("case 4/5"));
+                assert_eq!(case(transaction(Direction::DeviceToHost, 512, 0), 512, CommandStatus::Error).as_deref(), Some("case 4/5"));
+                assert_eq!(case(transaction(Direction::DeviceToHost, 512, 512), 0, CommandStatus::PhaseError).as_deref(), Some("case 7/8"));
+                // the host sent everything but the device processed less
+                assert_eq!(case(transaction(Direction::HostToDevice, 512, 512), 12, CommandStatus::Success).as_deref(), Some("case 9/11"));
+                assert_eq!(case(transaction(Direction::HostToDevice, 512, 256), 0, CommandStatus::Success).as_deref(), Some("case 9/11"));
+                assert_eq!(case(transaction(Direction::HostToDevice, 512, 0), 0, CommandStatus::PhaseError).as_deref(), Some("case 10/13"));
+        }
+
+        #[test]
+        fn mismatches_report_the_bytes_transferred() {
+                let message = data_mismatch(&transaction(Direction::HostToDevice, 4096, 4096), 1024, &CommandStatus::Success).unwrap();
+                assert!(message.ends_with("the device processed 3072 of 4096 bytes (4096 were sent)"), "{}", message);
+                let message = data_mismatch(&transaction(Direction::DeviceToHost, 4096, 512), 3584, &CommandStatus::Success).unwrap();
+                assert!(message.ends_with("the device sent 512 of 4096 bytes, reported residue is 3584"), "{}", message);
+        }
+
+        /// A simulated device of 64 sectors filled with 0xA5, opened
+        fn simulated_device(backing: &std::path::Path, faults: &str) -> (Device, Rc<crate::simulator::SimulatedDevice>) {
+                std::fs::write(backing, vec![0xA5; 64 * 512]).unwrap();
+                let faults = crate::simulator::Faults::parse(faults).unwrap();
+                let simulated = Rc::new(crate::simulator::SimulatedDevice::open(&[backing.to_path_buf()], 512, faults).unwrap());
+                let mut device = Device::with_transport(simulated.clone(), crate::simulator::SIMULATED_OUT_ENDPOINT, crate::simulator::SIMULATED_IN_ENDPOINT, 0);
+                assert_eq!(device.open().unwrap(), Readiness::Ready);
+                (device, simulated)
+        }
+
+        #[test]
+        fn discard_methods() {
+                let backing = std::env::temp_dir().join(format!("rmsd-discard-{}", std::process::id()));
+                let (device, _) = simulated_device(&backing, "");
+                assert!(matches!(device.discard_method().unwrap(), Some(DiscardMethod::Unmap(0x400000))));
+                let (device, _) = simulated_device(&backing, "no-provisioning:0");
+                assert!(matches!(device.discard_method().unwrap(), None));
+                std::fs::remove_file(&backing).unwrap();
+        }
+
+        #[test]
+        fn discards_are_split_by_the_limits_of_their_command() {
+                let backing = std::env::temp_dir().join(format!("rmsd-discard-limits-{}", std::process::id()));
+                for method in [DiscardMethod::Unmap(8), DiscardMethod::WriteSame16(8), DiscardMethod::WriteSame10(8)] {
+                        let (device, simulated) = simulated_device(&backing, "");
+                        let commands = simulated.commands();
+                        assert_eq!(device.discard(method, 10, 20).unwrap(), Some(CommandStatus::Success), "{:?}", method);
+                        assert_eq!(simulated.commands() - commands, 3, "{:?}", method);
+                        let content = std::fs::read(&backing).unwrap();
+                        assert!(content[..10 * 512].iter().all(|&b| b == 0xA5), "{:?}", method);
+                        assert!(content[10 * 512..30 * 512].iter().all(|&b| b == 0), "{:?}", method);
+                        assert!(content[30 * 512..].iter().all(|&b| b == 0xA5), "{:?}", method);
+                }
+                // WRITE SAME(10) cannot address sectors past 2^32
+                let (device, simulated) = simulated_device(&backing, "");
+                let commands = simulated.commands();
+                assert_eq!(device.discard(DiscardMethod::WriteSame10(8), u64::from(u32::MAX) - 4, 8).unwrap(), None);
+                assert_eq!(simulated.commands(), commands);
+                // the device refuses the commands when it does not support them
+                let (device, _) = simulated_device(&backing, "no-provisioning:0");
+                assert_eq!(device.discard(DiscardMethod::Unmap(8), 0, 8).unwrap(), Some(CommandStatus::Error));
+                assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5));
+                std::fs::remove_file(&backing).unwrap();
+        }
+}
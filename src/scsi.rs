@@ -0,0 +1,557 @@
+use std::fmt;
+
+pub const TEST_UNIT_READY: u8 = 0x00;
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const INQUIRY: u8 = 0x12;
+pub const READ_CAPACITY_10: u8 = 0x25;
+pub const READ_10: u8 = 0x28;
+pub const WRITE_10: u8 = 0x2A;
+pub const WRITE_SAME_10: u8 = 0x41;
+pub const UNMAP: u8 = 0x42;
+pub const READ_16: u8 = 0x88;
+pub const WRITE_16: u8 = 0x8A;
+pub const WRITE_SAME_16: u8 = 0x93;
+pub const SERVICE_ACTION_IN_16: u8 = 0x9E;
+pub const READ_CAPACITY_16_SERVICE_ACTION: u8 = 0x10;
+
+pub const INQUIRY_STANDARD_LENGTH: usize = 36;
+pub const FIXED_SENSE_LENGTH: usize = 18;
+pub const VPD_SUPPORTED_PAGES: u8 = 0x00;
+pub const VPD_UNIT_SERIAL_NUMBER: u8 = 0x80;
+pub const VPD_DEVICE_IDENTIFICATION: u8 = 0x83;
+pub const VPD_BLOCK_LIMITS: u8 = 0xB0;
+pub const VPD_BLOCK_DEVICE_CHARACTERISTICS: u8 = 0xB1;
+pub const VPD_LOGICAL_BLOCK_PROVISIONING: u8 = 0xB2;
+
+fn ascii_field(data: &[u8]) -> String {
+        String::from_utf8_lossy(data).trim().to_string()
+}
+
+fn hex_field(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Decoded standard INQUIRY data
+#[derive(Debug, Clone)]
+pub struct InquiryData {
+        pub peripheral_qualifier: u8,
+        pub peripheral_type: u8,
+        pub removable: bool,
+        pub version: u8,
+        pub vendor: String,
+        pub product: String,
+        pub revision: String,
+}
+
+impl InquiryData {
+        pub fn parse(data: &[u8]) -> Option<InquiryData> {
+                if data.len() < INQUIRY_STANDARD_LENGTH {
+                        return None;
+                }
+                Some(InquiryData {
+                        peripheral_qualifier: data[0] >> 5,
+                        peripheral_type: data[0] & 0x1F,
+                        removable: data[1] & 0x80 != 0,
+                        version: data[2],
+                        vendor: ascii_field(&data[8..16]),
+                        product: ascii_field(&data[16..32]),
+                        revision: ascii_field(&data[32..36]),
+                })
+        }
+
+        pub fn peripheral_type_name(&self) -> &'static str {
+                match self.peripheral_type {
+                        0x00 => { "direct access block device" },
+                        0x01 => { "sequential access device" },
+                        0x04 => { "write-once device" },
+                        0x05 => { "CD/DVD device" },
+                        0x07 => { "optical memory device" },
+                        0x0E => { "simplified direct access device" },
+                        0x1F => { "unknown or no device type" },
+                        _ => { "other device type" }
+                }
+        }
+}
+
+/// A designator from the Device Identification VPD page (0x83)
+#[derive(Debug, Clone)]
+pub struct Designator {
+        pub association: u8,
+        pub designator_type: u8,
+        pub code_set: u8,
+        pub value: Vec<u8>,
+}
+
+impl Designator {
+        pub fn type_name(&self) -> &'static str {
+                match self.designator_type {
+                        0x0 => { "vendor specific" },
+                        0x1 => { "T10 vendor ID" },
+                        0x2 => { "EUI-64" },
+                        0x3 => { "NAA" },
+                        0x4 => { "relative target port" },
+                        0x5 => { "target port group" },
+                        0x6 => { "logical unit group" },
+                        0x7 => { "MD5 logical unit identifier" },
+                        0x8 => { "SCSI name string" },
+                        _ => { "reserved" }
+                }
+        }
+
+        pub fn association_name(&self) -> &'static str {
+                match self.association {
+                        0 => { "logical unit" },
+                        1 => { "target port" },
+                        2 => { "target device" },
+                        _ => { "reserved" }
+                }
+        }
+}
+
+impl fmt::Display for Designator {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let value = match self.code_set {
+                        // ASCII and UTF-8
+                        2 | 3 => { ascii_field(&self.value) },
+                        _ => { hex_field(&self.value) }
+                };
+                write!(f, "{} ({}): {}", self.type_name(), self.association_name(), value)
+        }
+}
+
+/// Decodes the designator list of the Device Identification VPD page
+pub fn parse_device_identification(page: &[u8]) -> Vec<Designator> {
+        let mut designators = vec![];
+        let mut offset = 4;
+        while offset + 4 <= page.len() {
+                let length = usize::from(page[offset + 3]);
+                let end = (offset + 4 + length).min(page.len());
+                designators.push(Designator {
+                        association: (page[offset + 1] >> 4) & 0x3,
+                        designator_type: page[offset + 1] & 0xF,
+                        code_set: page[offset] & 0xF,
+                        value: page[offset + 4..end].to_vec(),
+                });
+                offset += 4 + length;
+        }
+        designators
+}
+
+/// Decodes the serial number from the Unit Serial Number VPD page
+pub fn parse_unit_serial_number(page: &[u8]) -> Option<String> {
+        if page.len() < 4 {
+                return None;
+        }
+        let length = usize::from(u16::from_be_bytes([page[2], page[3]]));
+        Some(ascii_field(&page[4..(4 + length).min(page.len())]))
+}
+
+/// Decodes the list of page codes from the Supported VPD Pages page
+pub fn parse_supported_pages(page: &[u8]) -> Vec<u8> {
+        if page.len() < 4 {
+                return vec![];
+        }
+        let length = usize::from(u16::from_be_bytes([page[2], page[3]]));
+        page[4..(4 + length).min(page.len())].to_vec()
+}
+
+/// Decoded Block Limits VPD page (0xB0), transfer lengths are in logical blocks and 0 means "not reported"
+#[derive(Debug, Clone, Default)]
+pub struct BlockLimits {
+        pub maximum_transfer_length: u32,
+        pub optimal_transfer_length: u32,
+        pub maximum_unmap_lba_count: u32,
+        pub maximum_unmap_descriptor_count: u32,
+        pub optimal_unmap_granularity: u32,
+        pub maximum_write_same_length: u64,
+}
+
+impl BlockLimits {
+        pub fn parse(page: &[u8]) -> Option<BlockLimits> {
+                if page.len() < 16 {
+                        return None;
+                }
+                let u32_at = |offset: usize| -> u32 {
+                        page.get(offset..offset + 4).map_or(0, |b| u32::from_be_bytes(b.try_into().unwrap()))
+                };
+                Some(BlockLimits {
+                        maximum_transfer_length: u32_at(8),
+                        optimal_transfer_length: u32_at(12),
+                        maximum_unmap_lba_count: u32_at(20),
+                        maximum_unmap_descriptor_count: u32_at(24),
+                        optimal_unmap_granularity: u32_at(28),
+                        maximum_write_same_length: page.get(36..44).map_or(0, |b| u64::from_be_bytes(b.try_into().unwrap())),
+                })
+        }
+}
+
+/// Decoded Block Device Characteristics VPD page (0xB1)
+#[derive(Debug, Clone)]
+pub struct BlockDeviceCharacteristics {
+        pub rotation_rate: u16,
+        pub form_factor: u8,
+}
+
+impl BlockDeviceCharacteristics {
+        pub fn parse(page: &[u8]) -> Option<BlockDeviceCharacteristics> {
+                if page.len() < 8 {
+                        return None;
+                }
+                Some(BlockDeviceCharacteristics {
+                        rotation_rate: u16::from_be_bytes([page[4], page[5]]),
+                        form_factor: page[7] & 0xF,
+                })
+        }
+
+        pub fn rotation_rate_description(&self) -> String {
+                match self.rotation_rate {
+                        0x0000 => { String::from("not reported") },
+                        0x0001 => { String::from("non-rotating medium") },
+                        0xFFFF | 0x0002..=0x0400 => { String::from("reserved") },
+                        rpm => { format!("{} rpm", rpm) }
+                }
+        }
+
+        pub fn form_factor_description(&self) -> &'static str {
+                match self.form_factor {
+                        0x0 => { "not reported" },
+                        0x1 => { "5.25 inch" },
+                        0x2 => { "3.5 inch" },
+                        0x3 => { "2.5 inch" },
+                        0x4 => { "1.8 inch" },
+                        0x5 => { "less than 1.8 inch" },
+                        _ => { "reserved" }
+                }
+        }
+}
+
+/// Decoded Logical Block Provisioning VPD page (0xB2), tells which commands deallocate logical blocks
+#[derive(Debug, Clone)]
+pub struct LogicalBlockProvisioning {
+        /// UNMAP is supported
+        pub unmap: bool,
+        /// WRITE SAME(16) with the UNMAP bit is supported
+        pub write_same_16: bool,
+        /// WRITE SAME(10) with the UNMAP bit is supported
+        pub write_same_10: bool,
+        /// Deallocated blocks read back as zeros
+        pub reads_zeros: bool,
+}
+
+impl LogicalBlockProvisioning {
+        pub fn parse(page: &[u8]) -> Option<LogicalBlockProvisioning> {
+                if page.len() < 6 {
+                        return None;
+                }
+                Some(LogicalBlockProvisioning {
+                        unmap: page[5] & 0x80 != 0,
+                        write_same_16: page[5] & 0x40 != 0,
+                        write_same_10: page[5] & 0x20 != 0,
+                        reads_zeros: (page[5] >> 2) & 0x7 == 0x1,
+                })
+        }
+}
+
+pub const SENSE_KEY_RECOVERED_ERROR: u8 = 0x1;
+pub const SENSE_KEY_NOT_READY: u8 = 0x2;
+pub const SENSE_KEY_MEDIUM_ERROR: u8 = 0x3;
+pub const SENSE_KEY_HARDWARE_ERROR: u8 = 0x4;
+pub const SENSE_KEY_UNIT_ATTENTION: u8 = 0x6;
+
+pub const ASC_LOGICAL_UNIT_NOT_READY: u8 = 0x04;
+pub const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3A;
+/// ASCQs of `ASC_LOGICAL_UNIT_NOT_READY` after which the unit is expected to become ready by itself
+pub const ASCQ_TRANSIENT_NOT_READY: &[u8] = &[0x00, 0x01, 0x07, 0x08, 0x0A];
+
+/// Additional sense code descriptions from the SPC tables, as (ASC, ASCQ, description)
+const ADDITIONAL_SENSE_CODES: &[(u8, u8, &str)] = &[
+        (0x00, 0x00, "no additional sense information"),
+        (0x04, 0x00, "logical unit not ready, cause not reportable"),
+        (0x04, 0x01, "logical unit is in process of becoming ready"),
+        (0x04, 0x02, "logical unit not ready, initializing command required"),
+        (0x04, 0x03, "logical unit not ready, manual intervention required"),
+        (0x04, 0x04, "logical unit not ready, format in progress"),
+        (0x04, 0x07, "logical unit not ready, operation in progress"),
+        (0x04, 0x08, "logical unit not ready, long write in progress"),
+        (0x04, 0x0A, "logical unit not accessible, asymmetric access state transition"),
+        (0x08, 0x00, "logical unit communication failure"),
+        (0x0C, 0x00, "write error"),
+        (0x0C, 0x02, "write error, auto reallocation failed"),
+        (0x10, 0x00, "ID CRC or ECC error"),
+        (0x11, 0x00, "unrecovered read error"),
+        (0x11, 0x01, "read retries exhausted"),
+        (0x11, 0x02, "error too long to correct"),
+        (0x14, 0x01, "record not found"),
+        (0x15, 0x00, "random positioning error"),
+        (0x1A, 0x00, "parameter list length error"),
+        (0x20, 0x00, "invalid command operation code"),
+        (0x21, 0x00, "logical block address out of range"),
+        (0x24, 0x00, "invalid field in CDB"),
+        (0x25, 0x00, "logical unit not supported"),
+        (0x26, 0x00, "invalid field in parameter list"),
+        (0x27, 0x00, "write protected"),
+        (0x27, 0x01, "hardware write protected"),
+        (0x27, 0x02, "logical unit software write protected"),
+        (0x28, 0x00, "not ready to ready change, medium may have changed"),
+        (0x29, 0x00, "power on, reset, or bus device reset occurred"),
+        (0x29, 0x01, "power on occurred"),
+        (0x29, 0x02, "SCSI bus reset occurred"),
+        (0x29, 0x04, "device internal reset"),
+        (0x2A, 0x01, "mode parameters changed"),
+        (0x30, 0x00, "incompatible medium installed"),
+        (0x30, 0x01, "cannot read medium, unknown format"),
+        (0x31, 0x00, "medium format corrupted"),
+        (0x3A, 0x00, "medium not present"),
+        (0x3A, 0x01, "medium not present, tray closed"),
+        (0x3A, 0x02, "medium not present, tray open"),
+        (0x3F, 0x01, "microcode has been changed"),
+        (0x44, 0x00, "internal target failure"),
+        (0x4B, 0x00, "data phase error"),
+        (0x5D, 0x00, "failure prediction threshold exceeded"),
+];
+
+pub fn sense_key_name(key: u8) -> &'static str {
+        match key {
+                0x0 => { "NO SENSE" },
+                0x1 => { "RECOVERED ERROR" },
+                0x2 => { "NOT READY" },
+                0x3 => { "MEDIUM ERROR" },
+                0x4 => { "HARDWARE ERROR" },
+                0x5 => { "ILLEGAL REQUEST" },
+                0x6 => { "UNIT ATTENTION" },
+                0x7 => { "DATA PROTECT" },
+                0x8 => { "BLANK CHECK" },
+                0x9 => { "VENDOR SPECIFIC" },
+                0xA => { "COPY ABORTED" },
+                0xB => { "ABORTED COMMAND" },
+                0xD => { "VOLUME OVERFLOW" },
+                0xE => { "MISCOMPARE" },
+                _ => { "RESERVED" }
+        }
+}
+
+pub fn additional_sense_description(asc: u8, ascq: u8) -> Option<&'static str> {
+        ADDITIONAL_SENSE_CODES.iter().find(|(c, q, _)| *c == asc && *q == ascq).map(|(_, _, d)| *d)
+}
+
+/// Decoded sense data, as returned by REQUEST SENSE in either fixed or descriptor format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenseData {
+        pub key: u8,
+        pub asc: u8,
+        pub ascq: u8,
+        /// The information field, for medium errors this is the first failing LBA
+        pub information: Option<u64>,
+}
+
+impl SenseData {
+        pub fn parse(data: &[u8]) -> Option<SenseData> {
+                if data.is_empty() {
+                        return None;
+                }
+                match data[0] & 0x7F {
+                        0x70 | 0x71 => {
+                                if data.len() < 14 {
+                                        return None;
+                                }
+                                let information = if data[0] & 0x80 != 0 {
+                                        Some(u64::from(u32::from_be_bytes(data[3..7].try_into().unwrap())))
+                                } else {
+                                        None
+                                };
+                                Some(SenseData { key: data[2] & 0xF, asc: data[12], ascq: data[13], information })
+                        },
+                        0x72 | 0x73 => {
+                                if data.len() < 8 {
+                                        return None;
+                                }
+                                let mut information = None;
+                                let end = (8 + usize::from(data[7])).min(data.len());
+                                let mut offset = 8;
+                                while offset + 2 <= end {
+                                        let length = usize::from(data[offset + 1]);
+                                        // information descriptor with the VALID bit set
+                                        if data[offset] == 0x00 && length >= 0xA && offset + 12 <= end && data[offset + 2] & 0x80 != 0 {
+                                                information = Some(u64::from_be_bytes(data[offset + 4..offset + 12].try_into().unwrap()));
+                                        }
+                                        offset += 2 + length;
+                                }
+                                Some(SenseData { key: data[1] & 0xF, asc: data[2], ascq: data[3], information })
+                        },
+                        _ => { None }
+                }
+        }
+
+        pub fn description(&self) -> String {
+                match additional_sense_description(self.asc, self.ascq) {
+                        Some(d) => { String::from(d) },
+                        None => { format!("additional sense code {:#04x}/{:#04x}", self.asc, self.ascq) }
+                }
+        }
+}
+
+impl fmt::Display for SenseData {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}: {}", sense_key_name(self.key), self.description())?;
+                match (self.key, self.information) {
+                        (SENSE_KEY_RECOVERED_ERROR | SENSE_KEY_MEDIUM_ERROR | SENSE_KEY_HARDWARE_ERROR, Some(lba)) => { write!(f, " at LBA {}", lba) },
+                        _ => { Ok(()) }
+                }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        #[test]
+        fn standard_inquiry_data() {
+                let mut data = vec![0x00, 0x80, 0x06, 0x02, 0x1F, 0x00, 0x00, 0x00];
+                data.extend_from_slice(b"SanDisk Ultra           1.00");
+                let inquiry = InquiryData::parse(&data).unwrap();
+                assert_eq!((inquiry.peripheral_qualifier, inquiry.peripheral_type, inquiry.removable, inquiry.version), (0, 0, true, 6));
+                assert_eq!((inquiry.vendor.as_str(), inquiry.product.as_str(), inquiry.revision.as_str()), ("SanDisk", "Ultra", "1.00"));
+                assert_eq!(inquiry.peripheral_type_name(), "direct access block device");
+                // a CD-ROM drive whose logical unit is not connected
+                data[0] = 0x25;
+                data[1] = 0x00;
+                let inquiry = InquiryData::parse(&data).unwrap();
+                assert_eq!((inquiry.peripheral_qualifier, inquiry.peripheral_type, inquiry.removable), (1, 5, false));
+                assert_eq!(inquiry.peripheral_type_name(), "CD/DVD device");
+                assert!(InquiryData::parse(&data[..INQUIRY_STANDARD_LENGTH - 1]).is_none());
+        }
+
+        #[test]
+        fn supported_pages_and_serial_number() {
+                let page = [0x00, VPD_SUPPORTED_PAGES, 0x00, 0x04, 0x00, 0x80, 0x83, 0xB0];
+                assert_eq!(parse_supported_pages(&page), [0x00, 0x80, 0x83, 0xB0]);
+                // the page length goes past the data received
+                assert_eq!(parse_supported_pages(&page[..6]), [0x00, 0x80]);
+                assert!(parse_supported_pages(&page[..3]).is_empty());
+
+                let mut page = vec![0x00, VPD_UNIT_SERIAL_NUMBER, 0x00, 0x0C];
+                page.extend_from_slice(b"  4C530001  ");
+                assert_eq!(parse_unit_serial_number(&page).as_deref(), Some("4C530001"));
+                assert_eq!(parse_unit_serial_number(&page[..8]).as_deref(), Some("4C"));
+                assert_eq!(parse_unit_serial_number(&page[..3]), None);
+        }
+
+        #[test]
+        fn device_identification_designators() {
+                let mut page = vec![0x00, VPD_DEVICE_IDENTIFICATION, 0x00, 0x00];
+                // T10 vendor ID in ASCII, then an NAA identifier of the target port in binary
+                page.extend_from_slice(&[0x02, 0x01, 0x00, 0x08]);
+                page.extend_from_slice(b"Generic ");
+                page.extend_from_slice(&[0x01, 0x13, 0x00, 0x08, 0x50, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD]);
+                page[3] = (page.len() - 4) as u8;
+                let designators = parse_device_identification(&page);
+                assert_eq!(designators.len(), 2);
+                assert_eq!(designators[0].to_string(), "T10 vendor ID (logical unit): Generic");
+                assert_eq!((designators[1].association, designators[1].designator_type, designators[1].code_set), (1, 3, 1));
+                assert_eq!(designators[1].to_string(), "NAA (target port): 500123456789ABCD");
+                // the value of a designator cut short is kept, a header cut short is dropped
+                let designators = parse_device_identification(&page[..page.len() - 4]);
+                assert_eq!(designators[1].value, [0x50, 0x01, 0x23, 0x45]);
+                assert_eq!(parse_device_identification(&page[..14]).len(), 1);
+                assert!(parse_device_identification(&page[..4]).is_empty());
+        }
+
+        #[test]
+        fn block_limits() {
+                let mut page = vec![0u8; 64];
+                page[1] = VPD_BLOCK_LIMITS;
+                page[3] = 0x3C;
+                page[8..12].copy_from_slice(&0xFFFFu32.to_be_bytes());
+                page[12..16].copy_from_slice(&128u32.to_be_bytes());
+                page[20..24].copy_from_slice(&0x0040_0000u32.to_be_bytes());
+                page[24..28].copy_from_slice(&1u32.to_be_bytes());
+                page[28..32].copy_from_slice(&8u32.to_be_bytes());
+                page[36..44].copy_from_slice(&(1u64 << 33).to_be_bytes());
+                let limits = BlockLimits::parse(&page).unwrap();
+                assert_eq!((limits.maximum_transfer_length, limits.optimal_transfer_length), (0xFFFF, 128));
+                assert_eq!((limits.maximum_unmap_lba_count, limits.maximum_unmap_descriptor_count, limits.optimal_unmap_granularity), (0x0040_0000, 1, 8));
+                assert_eq!(limits.maximum_write_same_length, 1 << 33);
+                // SBC-2 devices return the first 16 bytes only, the later fields are not reported
+                let limits = BlockLimits::parse(&page[..16]).unwrap();
+                assert_eq!((limits.maximum_transfer_length, limits.maximum_unmap_lba_count, limits.maximum_write_same_length), (0xFFFF, 0, 0));
+                assert!(BlockLimits::parse(&page[..15]).is_none());
+        }
+
+        #[test]
+        fn block_device_characteristics() {
+                let page = [0x00, VPD_BLOCK_DEVICE_CHARACTERISTICS, 0x00, 0x3C, 0x00, 0x01, 0x00, 0x03];
+                let characteristics = BlockDeviceCharacteristics::parse(&page).unwrap();
+                assert_eq!(characteristics.rotation_rate_description(), "non-rotating medium");
+                assert_eq!(characteristics.form_factor_description(), "2.5 inch");
+                let page = [0x00, VPD_BLOCK_DEVICE_CHARACTERISTICS, 0x00, 0x3C, 0x1C, 0x20, 0x00, 0xF2];
+                let characteristics = BlockDeviceCharacteristics::parse(&page).unwrap();
+                assert_eq!(characteristics.rotation_rate_description(), "7200 rpm");
+                assert_eq!(characteristics.form_factor_description(), "3.5 inch");
+                assert_eq!(BlockDeviceCharacteristics { rotation_rate: 0x0200, form_factor: 0x9 }.rotation_rate_description(), "reserved");
+                assert!(BlockDeviceCharacteristics::parse(&page[..7]).is_none());
+        }
+
+        #[test]
+        fn logical_block_provisioning() {
+                // UNMAP and WRITE SAME(16), deallocated blocks read as zeros (LBPRZ)
+                let page = [0x00, VPD_LOGICAL_BLOCK_PROVISIONING, 0x00, 0x04, 0x00, 0xC4, 0x02, 0x00];
+                let provisioning = LogicalBlockProvisioning::parse(&page).unwrap();
+                assert!(provisioning.unmap && provisioning.write_same_16 && !provisioning.write_same_10 && provisioning.reads_zeros);
+                let provisioning = LogicalBlockProvisioning::parse(&[0x00, VPD_LOGICAL_BLOCK_PROVISIONING, 0x00, 0x04, 0x00, 0x20]).unwrap();
+                assert!(!provisioning.unmap && !provisioning.write_same_16 && provisioning.write_same_10 && !provisioning.reads_zeros);
+                assert!(LogicalBlockProvisioning::parse(&page[..5]).is_none());
+        }
+
+        #[test]
+        fn fixed_format_sense_data() {
+                // current error with the VALID bit set, the information field holds the failing LBA
+                let mut data = [0u8; FIXED_SENSE_LENGTH];
+                data[..8].copy_from_slice(&[0xF0, 0x00, 0x03, 0x00, 0x01, 0x23, 0x45, 0x0A]);
+                data[12..14].copy_from_slice(&[0x11, 0x00]);
+                let sense = SenseData::parse(&data).unwrap();
+                assert_eq!(sense, SenseData { key: SENSE_KEY_MEDIUM_ERROR, asc: 0x11, ascq: 0x00, information: Some(0x12345) });
+                assert_eq!(sense.to_string(), "MEDIUM ERROR: unrecovered read error at LBA 74565");
+                // deferred error without the VALID bit
+                data[0] = 0x71;
+                data[2] = 0x06;
+                data[12..14].copy_from_slice(&[0x29, 0x00]);
+                let sense = SenseData::parse(&data).unwrap();
+                assert_eq!((sense.key, sense.information), (SENSE_KEY_UNIT_ATTENTION, None));
+                assert_eq!(sense.to_string(), "UNIT ATTENTION: power on, reset, or bus device reset occurred");
+                // the ASC and ASCQ are needed, the rest of the 18 bytes is not
+                assert!(SenseData::parse(&data[..14]).is_some());
+                assert!(SenseData::parse(&data[..13]).is_none());
+        }
+
+        #[test]
+        fn descriptor_format_sense_data() {
+                // an information descriptor with the VALID bit set holding a 64 bit LBA
+                let mut data = vec![0x72, 0x03, 0x11, 0x01, 0x00, 0x00, 0x00, 0x0C];
+                data.extend_from_slice(&[0x00, 0x0A, 0x80, 0x00]);
+                data.extend_from_slice(&(5u64 << 32).to_be_bytes());
+                let sense = SenseData::parse(&data).unwrap();
+                assert_eq!(sense, SenseData { key: SENSE_KEY_MEDIUM_ERROR, asc: 0x11, ascq: 0x01, information: Some(5 << 32) });
+                assert_eq!(sense.to_string(), "MEDIUM ERROR: read retries exhausted at LBA 21474836480");
+                // deferred errors use 0x73, the information is ignored without the VALID bit
+                data[0] = 0x73;
+                data[10] = 0x00;
+                assert_eq!(SenseData::parse(&data).unwrap().information, None);
+                // a descriptor cut by the additional length or by the buffer is not read
+                data[10] = 0x80;
+                data[7] = 0x0B;
+                assert_eq!(SenseData::parse(&data).unwrap().information, None);
+                data[7] = 0x0C;
+                let sense = SenseData::parse(&data[..19]).unwrap();
+                assert_eq!((sense.key, sense.asc, sense.information), (SENSE_KEY_MEDIUM_ERROR, 0x11, None));
+                assert!(SenseData::parse(&data[..7]).is_none());
+        }
+
+        #[test]
+        fn unknown_sense_data() {
+                assert!(SenseData::parse(&[]).is_none());
+                // the response code of neither format
+                assert!(SenseData::parse(&[0x00; FIXED_SENSE_LENGTH]).is_none());
+                let sense = SenseData { key: 0xC, asc: 0x99, ascq: 0x01, information: Some(7) };
+                assert_eq!(sense.to_string(), "RESERVED: additional sense code 0x99/0x01");
+        }
+}
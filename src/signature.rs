@@ -0,0 +1,117 @@
+use minisign_verify::{PublicKey, Signature};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use crate::log;
+
+pub const DEFAULT_TRUSTED_KEYS_DIRECTORY: &str = "/etc/rmsd/trusted-keys";
+const SIGNATURE_EXTENSION: &str = "minisig";
+const PUBLIC_KEY_EXTENSION: &str = "pub";
+
+/// Verifies the minisign (ed25519) signature of the image against `public_key`, or against the keys found in
+/// `trusted_keys` (`DEFAULT_TRUSTED_KEYS_DIRECTORY` when none is given). Images without a signature are refused
+/// unless `allow_unsigned` is set, invalid signatures and signatures without a key to check them are always refused
+pub fn verify_image_signature(image: &Path, signature: Option<&PathBuf>, public_key: Option<&PathBuf>, trusted_keys: Option<&PathBuf>, allow_unsigned: bool) -> std::io::Result<bool> {
+        let keys = match public_key {
+                Some(path) => {
+                        match PublicKey::from_file(path) {
+                                Ok(key) => { vec![(path.clone(), key)] },
+                                Err(e) => {
+                                        log::error!("verify_image_signature(): unable to load the public key {:?}, cause: {}", path, e);
+                                        return Ok(false);
+                                }
+                        }
+                },
+                None => { load_trusted_keys(trusted_keys)? }
+        };
+        let signature_path = match signature {
+                Some(path) => { Some(path.clone()) },
+                None => {
+                        let mut path = image.as_os_str().to_owned();
+                        path.push(format!(".{}", SIGNATURE_EXTENSION));
+                        Some(PathBuf::from(path)).filter(|p| p.is_file())
+                }
+        };
+        let signature_path = match signature_path {
+                Some(path) => { path },
+                None if allow_unsigned => {
+                        log::warning!("verify_image_signature(): {:?} is not signed, flashing it anyway", image);
+                        return Ok(true);
+                },
+                None => {
+                        log::error!("verify_image_signature(): {:?} is not signed (no .{} signature was found), use --signature or --allow-unsigned", image, SIGNATURE_EXTENSION);
+                        return Ok(false);
+                }
+        };
+        if keys.is_empty() {
+                log::error!("verify_image_signature(): no public key is available to verify {:?}, use --pubkey or install the key in {:?}", signature_path, trusted_keys.map_or(Path::new(DEFAULT_TRUSTED_KEYS_DIRECTORY), |p| p.as_path()));
+                return Ok(false);
+        }
+        let minisig = match Signature::from_file(&signature_path) {
+                Ok(s) => { s },
+                Err(e) => {
+                        log::error!("verify_image_signature(): unable to decode the signature {:?}, cause: {}", signature_path, e);
+                        return Ok(false);
+                }
+        };
+        // the key id embedded in the signature selects the key
+        let (key_path, mut verifier) = match keys.iter().find_map(|(path, key)| key.verify_stream(&minisig).ok().map(|v| (path, v))) {
+                Some(found) => { found },
+                None => {
+                        log::error!("verify_image_signature(): {:?} was not signed by a trusted key (or uses the legacy non-prehashed format)", signature_path);
+                        return Ok(false);
+                }
+        };
+        println!("Verifying the image's signature {:?} with {:?}...", signature_path, key_path);
+        let mut file = File::open(image)?;
+        let mut buffer = vec![0u8; 1 << 20];
+        loop {
+                let bytes_read = match file.read(&mut buffer) {
+                        Ok(0) => { break; },
+                        Ok(n) => { n },
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { continue; },
+                        Err(e) => { return Err(e); }
+                };
+                verifier.update(&buffer[..bytes_read]);
+        }
+        match verifier.finalize() {
+                Ok(()) => {
+                        println!("The image's signature is valid (trusted comment: {})", minisig.trusted_comment());
+                        Ok(true)
+                },
+                Err(e) => {
+                        log::error!("verify_image_signature(): the signature of {:?} is invalid, cause: {}", image, e);
+                        Ok(false)
+                }
+        }
+}
+
+/// Loads every `.pub` minisign public key in `directory`, or in `DEFAULT_TRUSTED_KEYS_DIRECTORY` when none is given
+/// in which case a missing directory means no key is trusted
+fn load_trusted_keys(directory: Option<&PathBuf>) -> std::io::Result<Vec<(PathBuf, PublicKey)>> {
+        let entries = match directory {
+                Some(path) => { std::fs::read_dir(path)? },
+                None => {
+                        match std::fs::read_dir(DEFAULT_TRUSTED_KEYS_DIRECTORY) {
+                                Ok(entries) => { entries },
+                                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                        log::debug!("load_trusted_keys(): {} does not exist, no key is trusted", DEFAULT_TRUSTED_KEYS_DIRECTORY);
+                                        return Ok(vec![]);
+                                },
+                                Err(e) => { return Err(e); }
+                        }
+                }
+        };
+        let mut keys = vec![];
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if path.extension().is_none_or(|e| e != PUBLIC_KEY_EXTENSION) {
+                        continue;
+                }
+                match PublicKey::from_file(&path) {
+                        Ok(key) => { keys.push((path, key)); },
+                        Err(e) => { log::warning!("load_trusted_keys(): ignoring {:?}, cause: {}", path, e); }
+                }
+        }
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(keys)
+}
@@ -0,0 +1,732 @@
+use rusb as usb;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::log;
+use crate::transport::BulkTransport;
+
+pub const SIMULATED_OUT_ENDPOINT: u8 = 0x01;
+pub const SIMULATED_IN_ENDPOINT: u8 = 0x81;
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const SIMULATED_SERIAL_NUMBER: &[u8] = b"0123456789AB";
+const BULK_ONLY_RESET_REQUEST: u8 = 0xFF;
+const GET_MAX_LUN_REQUEST: u8 = 0xFE;
+
+const STATUS_GOOD: u8 = 0x0;
+const STATUS_FAILED: u8 = 0x1;
+const STATUS_PHASE_ERROR: u8 = 0x2;
+
+const SENSE_NO_SENSE: u8 = 0x0;
+const SENSE_NOT_READY: u8 = 0x2;
+const SENSE_MEDIUM_ERROR: u8 = 0x3;
+const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+const SENSE_UNIT_ATTENTION: u8 = 0x6;
+
+/// A fault that the simulated device injects while processing the n-th command (counting from 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandFault {
+        /// Only the first 7 bytes of the CSW are sent
+        ShortStatus,
+        /// The CSW carries a wrong signature
+        BadSignature,
+        /// The CSW echoes a tag that does not match the CBW
+        BadTag,
+        /// The CSW reports a phase error
+        PhaseError,
+        /// The bulk-in endpoint is halted until the host clears it
+        StallIn,
+        /// The bulk-out endpoint is halted until the host clears it
+        StallOut,
+}
+
+/// The set of faults the simulated device injects, parsed from a comma separated list of
+/// `short-csw:N`, `bad-signature:N`, `bad-tag:N`, `phase-error:N`, `stall-in:N`, `stall-out:N`
+/// (N being the index of the affected command), `medium-error:LBA[-LBA]`, `slow:LBA[-LBA]:MILLISECONDS`,
+/// `drop-writes:LBA[-LBA]` (writes are acknowledged but never reach the medium, like counterfeit drives do),
+/// `unit-attention:LUN` (a power on reset is reported by the first command), `no-medium:LUN`,
+/// `becoming-ready:N` (every unit needs N medium access commands before becoming ready) and
+/// `no-provisioning:LUN` (the unit supports neither UNMAP nor WRITE SAME)
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+        commands: Vec<(u32, CommandFault)>,
+        medium_errors: Vec<RangeInclusive<u64>>,
+        dropped_writes: Vec<RangeInclusive<u64>>,
+        slow_sectors: Vec<(RangeInclusive<u64>, Duration)>,
+        unit_attention: Vec<usize>,
+        no_medium: Vec<usize>,
+        no_provisioning: Vec<usize>,
+        becoming_ready: u32,
+}
+
+impl Faults {
+        pub fn parse(spec: &str) -> Result<Faults, String> {
+                let mut faults = Faults::default();
+                for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                        let (kind, value) = item.split_once(':').ok_or(format!("fault '{item}' is missing its argument"))?;
+                        let command_fault = match kind {
+                                "short-csw" => { Some(CommandFault::ShortStatus) },
+                                "bad-signature" => { Some(CommandFault::BadSignature) },
+                                "bad-tag" => { Some(CommandFault::BadTag) },
+                                "phase-error" => { Some(CommandFault::PhaseError) },
+                                "stall-in" => { Some(CommandFault::StallIn) },
+                                "stall-out" => { Some(CommandFault::StallOut) },
+                                _ => { None }
+                        };
+                        if let Some(fault) = command_fault {
+                                let index = value.parse::<u32>().map_err(|e| format!("invalid command index in '{item}': {e}"))?;
+                                faults.commands.push((index, fault));
+                                continue;
+                        }
+                        match kind {
+                                "medium-error" => {
+                                        faults.medium_errors.push(parse_lba_range(value)?);
+                                },
+                                "drop-writes" => {
+                                        faults.dropped_writes.push(parse_lba_range(value)?);
+                                },
+                                "slow" => {
+                                        let (range, delay) = value.rsplit_once(':').ok_or(format!("fault '{item}' is missing its delay"))?;
+                                        let delay = delay.parse::<u64>().map_err(|e| format!("invalid delay in '{item}': {e}"))?;
+                                        faults.slow_sectors.push((parse_lba_range(range)?, Duration::from_millis(delay)));
+                                },
+                                "unit-attention" => {
+                                        faults.unit_attention.push(value.parse::<usize>().map_err(|e| format!("invalid LUN in '{item}': {e}"))?);
+                                },
+                                "no-medium" => {
+                                        faults.no_medium.push(value.parse::<usize>().map_err(|e| format!("invalid LUN in '{item}': {e}"))?);
+                                },
+                                "no-provisioning" => {
+                                        faults.no_provisioning.push(value.parse::<usize>().map_err(|e| format!("invalid LUN in '{item}': {e}"))?);
+                                },
+                                "becoming-ready" => {
+                                        faults.becoming_ready = value.parse::<u32>().map_err(|e| format!("invalid command count in '{item}': {e}"))?;
+                                },
+                                _ => { return Err(format!("unknown fault '{kind}'")); }
+                        }
+                }
+                Ok(faults)
+        }
+
+        fn command_fault(&self, index: u32) -> Option<CommandFault> {
+                self.commands.iter().find(|(n, _)| *n == index).map(|(_, f)| *f)
+        }
+
+        fn medium_error(&self, lba: u64, count: u64) -> Option<u64> {
+                self.medium_errors.iter().filter_map(|r| {
+                        let first = (*r.start()).max(lba);
+                        if count > 0 && first <= *r.end() && first < lba + count { Some(first) } else { None }
+                }).min()
+        }
+
+        fn write_dropped(&self, lba: u64) -> bool {
+                self.dropped_writes.iter().any(|r| r.contains(&lba))
+        }
+
+        fn delay(&self, lba: u64, count: u64) -> Duration {
+                self.slow_sectors.iter()
+                        .filter(|(r, _)| count > 0 && *r.start() < lba + count && *r.end() >= lba)
+                        .map(|(_, d)| *d)
+                        .sum()
+        }
+}
+
+fn parse_lba_range(value: &str) -> Result<RangeInclusive<u64>, String> {
+        let (start, end) = value.split_once('-').unwrap_or((value, value));
+        let start = start.parse::<u64>().map_err(|e| format!("invalid LBA '{start}': {e}"))?;
+        let end = end.parse::<u64>().map_err(|e| format!("invalid LBA '{end}': {e}"))?;
+        Ok(start..=end)
+}
+
+#[derive(Debug)]
+enum Phase {
+        /// Waiting for a CBW
+        Command,
+        /// Sending data to the host, the CSW is queued right after it
+        DataIn { data: Vec<u8>, position: usize, status: u8 },
+        /// Receiving `expected` bytes from the host for `command`
+        DataOut { command: OutCommand, expected: usize, received: Vec<u8> },
+        /// The CSW is ready to be read by the host
+        Status { status: u8 },
+}
+
+/// A command whose data is sent by the host
+#[derive(Debug, Clone, Copy)]
+enum OutCommand {
+        Write { lba: u64 },
+        /// The data is a single block repeated `count` times
+        WriteSame { lba: u64, count: u64 },
+        /// The data is the UNMAP parameter list
+        Unmap,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sense {
+        key: u8,
+        asc: u8,
+        ascq: u8,
+        information: Option<u64>,
+}
+
+impl Sense {
+        const NONE: Sense = Sense { key: SENSE_NO_SENSE, asc: 0, ascq: 0, information: None };
+
+        fn fixed_format(&self) -> [u8; 18] {
+                let mut data = [0u8; 18];
+                data[0] = 0x70;
+                if let Some(info) = self.information {
+                        data[0] |= 0x80;
+                        data[3..7].copy_from_slice(&(info as u32).to_be_bytes());
+                }
+                data[2] = self.key;
+                data[7] = 10;
+                data[12] = self.asc;
+                data[13] = self.ascq;
+                data
+        }
+}
+
+/// A logical unit of the simulated device and its medium
+#[derive(Debug)]
+struct Unit {
+        backing: File,
+        block_count: u64,
+        sense: Sense,
+        /// A power on reset has not been reported yet
+        unit_attention: bool,
+        medium_present: bool,
+        /// Medium access commands to reject before the unit becomes ready
+        becoming_ready: u32,
+        /// UNMAP and WRITE SAME are supported, deallocated blocks read as zeros
+        provisioning: bool,
+}
+
+#[derive(Debug)]
+struct State {
+        units: Vec<Unit>,
+        lun: usize,
+        block_size: u32,
+        faults: Faults,
+        phase: Phase,
+        command_index: u32,
+        active_fault: Option<CommandFault>,
+        /// Bulk-Only Mass Storage Resets received so far
+        resets: u32,
+        tag: u32,
+        residue: u32,
+        in_halted: bool,
+        out_halted: bool,
+}
+
+/// An in-process Bulk-Only mass storage device whose medium is a regular file, it implements the SCSI
+/// commands issued by `mass_storage::Device` just like a USB flash drive would and can inject `Faults`
+#[derive(Debug)]
+pub struct SimulatedDevice {
+        state: RefCell<State>,
+}
+
+impl SimulatedDevice {
+        /// Opens a simulated device with one logical unit for each of the files in `paths`
+        pub fn open(paths: &[PathBuf], block_size: u32, faults: Faults) -> std::io::Result<SimulatedDevice> {
+                let mut units = vec![];
+                for path in paths {
+                        let backing = OpenOptions::new().read(true).write(true).open(path)?;
+                        let block_count = backing.metadata()?.len() / u64::from(block_size);
+                        log::debug!("SimulatedDevice::open(): LUN {} ({:?}) exposes {} blocks of {} bytes", units.len(), path, block_count, block_size);
+                        let lun = units.len();
+                        units.push(Unit {
+                                backing,
+                                block_count,
+                                sense: Sense::NONE,
+                                unit_attention: faults.unit_attention.contains(&lun),
+                                medium_present: !faults.no_medium.contains(&lun),
+                                becoming_ready: faults.becoming_ready,
+                                provisioning: !faults.no_provisioning.contains(&lun),
+                        });
+                }
+                log::debug!("SimulatedDevice::open(): faults: {:?}", faults);
+                Ok(SimulatedDevice { state: RefCell::new(State {
+                        units,
+                        lun: 0,
+                        block_size,
+                        faults,
+                        phase: Phase::Command,
+                        command_index: 0,
+                        active_fault: None,
+                        resets: 0,
+                        tag: 0,
+                        residue: 0,
+                        in_halted: false,
+                        out_halted: false,
+                })})
+        }
+
+        /// The number of CBWs accepted so far, the index of the last command
+        pub fn commands(&self) -> u32 {
+                self.state.borrow().command_index
+        }
+
+        /// The number of Bulk-Only Mass Storage Resets the host has issued so far
+        pub fn resets(&self) -> u32 {
+                self.state.borrow().resets
+        }
+}
+
+impl State {
+        fn unit(&mut self) -> &mut Unit {
+                &mut self.units[self.lun]
+        }
+
+        fn fail(&mut self, key: u8, asc: u8, ascq: u8, information: Option<u64>) -> Phase {
+                self.unit().sense = Sense { key, asc, ascq, information };
+                Phase::Status { status: STATUS_FAILED }
+        }
+
+        fn data_in(&mut self, mut data: Vec<u8>, allocation_length: usize) -> Phase {
+                data.truncate(allocation_length);
+                Phase::DataIn { data, position: 0, status: STATUS_GOOD }
+        }
+
+        fn read(&mut self, lba: u64, count: u64) -> std::io::Result<Phase> {
+                let block_size = u64::from(self.block_size);
+                if lba + count > self.unit().block_count {
+                        return Ok(self.fail(SENSE_ILLEGAL_REQUEST, 0x21, 0x00, None));
+                }
+                std::thread::sleep(self.faults.delay(lba, count));
+                let mut data = vec![0u8; (count * block_size) as usize];
+                self.unit().backing.seek(SeekFrom::Start(lba * block_size))?;
+                self.unit().backing.read_exact(&mut data)?;
+                if let Some(bad) = self.faults.medium_error(lba, count) {
+                        // the sectors before the faulty one are still transferred
+                        data.truncate(((bad - lba) * block_size) as usize);
+                        self.unit().sense = Sense { key: SENSE_MEDIUM_ERROR, asc: 0x11, ascq: 0x00, information: Some(bad) };
+                        return Ok(Phase::DataIn { data, position: 0, status: STATUS_FAILED });
+                }
+                Ok(Phase::DataIn { data, position: 0, status: STATUS_GOOD })
+        }
+
+        fn write(&mut self, lba: u64, count: u64, length: u32) -> Phase {
+                if lba + count > self.unit().block_count {
+                        return self.fail(SENSE_ILLEGAL_REQUEST, 0x21, 0x00, None);
+                }
+                if u64::from(length) != count * u64::from(self.block_size) {
+                        return Phase::Status { status: STATUS_PHASE_ERROR };
+                }
+                Phase::DataOut { command: OutCommand::Write { lba }, expected: length as usize, received: Vec::with_capacity(length as usize) }
+        }
+
+        fn write_same(&mut self, lba: u64, count: u64, length: u32) -> Phase {
+                if lba + count > self.unit().block_count {
+                        return self.fail(SENSE_ILLEGAL_REQUEST, 0x21, 0x00, None);
+                }
+                if length != self.block_size {
+                        return Phase::Status { status: STATUS_PHASE_ERROR };
+                }
+                Phase::DataOut { command: OutCommand::WriteSame { lba, count }, expected: length as usize, received: Vec::with_capacity(length as usize) }
+        }
+
+        fn unmap(&mut self, parameter_list_length: u16, length: u32) -> Phase {
+                if u32::from(parameter_list_length) != length {
+                        return Phase::Status { status: STATUS_PHASE_ERROR };
+                }
+                Phase::DataOut { command: OutCommand::Unmap, expected: length as usize, received: Vec::with_capacity(length as usize) }
+        }
+
+        fn commit(&mut self, command: OutCommand, data: &[u8]) -> std::io::Result<u8> {
+                match command {
+                        OutCommand::Write { lba } => { self.commit_write(lba, data) },
+                        OutCommand::WriteSame { lba, count } => {
+                                self.fill(lba, count, data)?;
+                                Ok(STATUS_GOOD)
+                        },
+                        OutCommand::Unmap => {
+                                let descriptors_length = data.get(2..4).map_or(0, |b| usize::from(u16::from_be_bytes([b[0], b[1]])));
+                                let descriptors = data.get(8..8 + descriptors_length).unwrap_or(&[]);
+                                let zeros = vec![0u8; self.block_size as usize];
+                                for descriptor in descriptors.chunks_exact(16) {
+                                        let lba = u64::from_be_bytes(descriptor[0..8].try_into().unwrap());
+                                        let count = u64::from(u32::from_be_bytes(descriptor[8..12].try_into().unwrap()));
+                                        if lba + count > self.unit().block_count {
+                                                self.unit().sense = Sense { key: SENSE_ILLEGAL_REQUEST, asc: 0x21, ascq: 0x00, information: None };
+                                                return Ok(STATUS_FAILED);
+                                        }
+                                        self.fill(lba, count, &zeros)?;
+                                }
+                                Ok(STATUS_GOOD)
+                        }
+                }
+        }
+
+        /// Writes `block` over `count` blocks starting at `lba`
+        fn fill(&mut self, lba: u64, count: u64, block: &[u8]) -> std::io::Result<()> {
+                let block_size = u64::from(self.block_size);
+                let chunk = block.repeat((count.min(2048)) as usize);
+                self.unit().backing.seek(SeekFrom::Start(lba * block_size))?;
+                let mut remaining = count * block_size;
+                while remaining > 0 {
+                        let length = remaining.min(chunk.len() as u64) as usize;
+                        self.unit().backing.write_all(&chunk[..length])?;
+                        remaining -= length as u64;
+                }
+                Ok(())
+        }
+
+        fn commit_write(&mut self, lba: u64, data: &[u8]) -> std::io::Result<u8> {
+                let block_size = u64::from(self.block_size);
+                let count = data.len() as u64 / block_size;
+                std::thread::sleep(self.faults.delay(lba, count));
+                let good = match self.faults.medium_error(lba, count) {
+                        Some(bad) => { ((bad - lba) * block_size) as usize },
+                        None => { data.len() }
+                };
+                for (n, sector) in data[..good].chunks(block_size as usize).enumerate() {
+                        let sector_lba = lba + n as u64;
+                        if !self.faults.write_dropped(sector_lba) {
+                                self.unit().backing.seek(SeekFrom::Start(sector_lba * block_size))?;
+                                self.unit().backing.write_all(sector)?;
+                        }
+                }
+                if good < data.len() {
+                        self.unit().sense = Sense { key: SENSE_MEDIUM_ERROR, asc: 0x0C, ascq: 0x00, information: Some(lba + good as u64 / block_size) };
+                        return Ok(STATUS_FAILED);
+                }
+                Ok(STATUS_GOOD)
+        }
+
+        fn inquiry(&self) -> Vec<u8> {
+                let mut data = vec![0u8; 36];
+                // direct access block device, removable medium, SPC-4
+                data[1] = 0x80;
+                data[2] = 0x06;
+                data[3] = 0x02;
+                data[4] = 31;
+                data[8..16].copy_from_slice(b"RMSD    ");
+                data[16..32].copy_from_slice(b"Simulated Disk  ");
+                data[32..36].copy_from_slice(b"0001");
+                data
+        }
+
+        fn vital_product_data(&self, page_code: u8) -> Option<Vec<u8>> {
+                let payload: Vec<u8> = match page_code {
+                        0x00 if self.units[self.lun].provisioning => { vec![0x00, 0x80, 0x83, 0xB0, 0xB1, 0xB2] },
+                        0x00 => { vec![0x00, 0x80, 0x83, 0xB0, 0xB1] },
+                        0x80 => { SIMULATED_SERIAL_NUMBER.to_vec() },
+                        0x83 => {
+                                let mut designators = vec![];
+                                // T10 vendor ID designator (ASCII)
+                                let t10_id = [&b"RMSD    "[..], &b"Simulated Disk  "[..], SIMULATED_SERIAL_NUMBER].concat();
+                                designators.extend_from_slice(&[0x02, 0x01, 0x00, t10_id.len() as u8]);
+                                designators.extend_from_slice(&t10_id);
+                                // NAA locally assigned designator (binary)
+                                designators.extend_from_slice(&[0x01, 0x03, 0x00, 0x08, 0x30]);
+                                designators.extend_from_slice(&SIMULATED_SERIAL_NUMBER[..7]);
+                                designators
+                        },
+                        0xB0 => {
+                                let mut limits = vec![0u8; 0x3C];
+                                limits[4..8].copy_from_slice(&0xFFFFu32.to_be_bytes());
+                                limits[8..12].copy_from_slice(&128u32.to_be_bytes());
+                                limits[16..20].copy_from_slice(&0x400000u32.to_be_bytes());
+                                limits[20..24].copy_from_slice(&1u32.to_be_bytes());
+                                limits[24..28].copy_from_slice(&8u32.to_be_bytes());
+                                limits[32..40].copy_from_slice(&0x400000u64.to_be_bytes());
+                                limits
+                        },
+                        0xB1 => {
+                                let mut characteristics = vec![0u8; 0x3C];
+                                // non-rotating medium, form factor not reported
+                                characteristics[0..2].copy_from_slice(&1u16.to_be_bytes());
+                                characteristics
+                        },
+                        0xB2 if self.units[self.lun].provisioning => {
+                                // LBPU, LBPWS, LBPWS10, deallocated blocks read as zeros, thin provisioned
+                                vec![0x00, 0xE4, 0x00, 0x02]
+                        },
+                        _ => { return None; }
+                };
+                let mut page = vec![0x00, page_code];
+                page.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+                page.extend_from_slice(&payload);
+                Some(page)
+        }
+
+        fn execute(&mut self, cb: &[u8], length: u32) -> std::io::Result<Phase> {
+                let mut cdb = [0u8; 16];
+                cdb[..cb.len()].copy_from_slice(cb);
+                if self.lun >= self.units.len() {
+                        log::debug!("SimulatedDevice: LUN {} does not exist", self.lun);
+                        return Ok(Phase::Status { status: STATUS_FAILED });
+                }
+                let previous_sense = std::mem::replace(&mut self.unit().sense, Sense::NONE);
+                // INQUIRY and REQUEST SENSE are served regardless of the state of the unit
+                if cdb[0] != 0x03 && cdb[0] != 0x12 {
+                        if std::mem::replace(&mut self.unit().unit_attention, false) {
+                                return Ok(self.fail(SENSE_UNIT_ATTENTION, 0x29, 0x00, None));
+                        }
+                        if !self.unit().medium_present {
+                                return Ok(self.fail(SENSE_NOT_READY, 0x3A, 0x00, None));
+                        }
+                        if self.unit().becoming_ready > 0 {
+                                self.unit().becoming_ready -= 1;
+                                return Ok(self.fail(SENSE_NOT_READY, 0x04, 0x01, None));
+                        }
+                }
+                Ok(match cdb[0] {
+                        // TEST UNIT READY
+                        0x00 => { Phase::Status { status: STATUS_GOOD } },
+                        // REQUEST SENSE
+                        0x03 => {
+                                let data = previous_sense.fixed_format().to_vec();
+                                self.data_in(data, usize::from(cdb[4]))
+                        },
+                        // READ(6)
+                        0x08 => {
+                                let lba = u64::from(u32::from_be_bytes([0, cdb[1] & 0x1F, cdb[2], cdb[3]]));
+                                let count = if cdb[4] == 0 { 256 } else { u64::from(cdb[4]) };
+                                self.read(lba, count)?
+                        },
+                        // WRITE(6)
+                        0x0A => {
+                                let lba = u64::from(u32::from_be_bytes([0, cdb[1] & 0x1F, cdb[2], cdb[3]]));
+                                let count = if cdb[4] == 0 { 256 } else { u64::from(cdb[4]) };
+                                self.write(lba, count, length)
+                        },
+                        // INQUIRY
+                        0x12 => {
+                                let allocation_length = usize::from(u16::from_be_bytes([cdb[3], cdb[4]]));
+                                if cdb[1] & 0x01 != 0 {
+                                        match self.vital_product_data(cdb[2]) {
+                                                Some(page) => { self.data_in(page, allocation_length) },
+                                                None => { self.fail(SENSE_ILLEGAL_REQUEST, 0x24, 0x00, None) }
+                                        }
+                                } else {
+                                        let data = self.inquiry();
+                                        self.data_in(data, allocation_length)
+                                }
+                        },
+                        // READ CAPACITY(10)
+                        0x25 => {
+                                let mut data = vec![0u8; 8];
+                                let last_lba = u32::try_from(self.unit().block_count.saturating_sub(1)).unwrap_or(u32::MAX);
+                                data[..4].copy_from_slice(&last_lba.to_be_bytes());
+                                data[4..].copy_from_slice(&self.block_size.to_be_bytes());
+                                self.data_in(data, 8)
+                        },
+                        // READ(10)
+                        0x28 => {
+                                let lba = u64::from(u32::from_be_bytes(cdb[2..6].try_into().unwrap()));
+                                let count = u64::from(u16::from_be_bytes(cdb[7..9].try_into().unwrap()));
+                                self.read(lba, count)?
+                        },
+                        // WRITE(10)
+                        0x2A => {
+                                let lba = u64::from(u32::from_be_bytes(cdb[2..6].try_into().unwrap()));
+                                let count = u64::from(u16::from_be_bytes(cdb[7..9].try_into().unwrap()));
+                                self.write(lba, count, length)
+                        },
+                        // WRITE SAME(10)
+                        0x41 if self.unit().provisioning => {
+                                let lba = u64::from(u32::from_be_bytes(cdb[2..6].try_into().unwrap()));
+                                let count = u64::from(u16::from_be_bytes(cdb[7..9].try_into().unwrap()));
+                                self.write_same(lba, count, length)
+                        },
+                        // UNMAP
+                        0x42 if self.unit().provisioning => {
+                                self.unmap(u16::from_be_bytes(cdb[7..9].try_into().unwrap()), length)
+                        },
+                        // READ(16)
+                        0x88 => {
+                                let lba = u64::from_be_bytes(cdb[2..10].try_into().unwrap());
+                                let count = u64::from(u32::from_be_bytes(cdb[10..14].try_into().unwrap()));
+                                self.read(lba, count)?
+                        },
+                        // WRITE(16)
+                        0x8A => {
+                                let lba = u64::from_be_bytes(cdb[2..10].try_into().unwrap());
+                                let count = u64::from(u32::from_be_bytes(cdb[10..14].try_into().unwrap()));
+                                self.write(lba, count, length)
+                        },
+                        // WRITE SAME(16)
+                        0x93 if self.unit().provisioning => {
+                                let lba = u64::from_be_bytes(cdb[2..10].try_into().unwrap());
+                                let count = u64::from(u32::from_be_bytes(cdb[10..14].try_into().unwrap()));
+                                self.write_same(lba, count, length)
+                        },
+                        // SERVICE ACTION IN(16), only READ CAPACITY(16) is implemented
+                        0x9E if cdb[1] & 0x1F == 0x10 => {
+                                let mut data = vec![0u8; 32];
+                                data[..8].copy_from_slice(&self.unit().block_count.saturating_sub(1).to_be_bytes());
+                                data[8..12].copy_from_slice(&self.block_size.to_be_bytes());
+                                self.data_in(data, u32::from_be_bytes(cdb[10..14].try_into().unwrap()) as usize)
+                        },
+                        op => {
+                                log::debug!("SimulatedDevice: unsupported operation code {:#04x}", op);
+                                self.fail(SENSE_ILLEGAL_REQUEST, 0x20, 0x00, None)
+                        }
+                })
+        }
+
+        fn csw(&self, status: u8) -> [u8; 13] {
+                let mut csw = [0u8; 13];
+                let (signature, tag, status) = match self.active_fault {
+                        Some(CommandFault::BadSignature) => { (CSW_SIGNATURE ^ 0xFFFF, self.tag, status) },
+                        Some(CommandFault::BadTag) => { (CSW_SIGNATURE, self.tag.wrapping_add(1), status) },
+                        Some(CommandFault::PhaseError) => { (CSW_SIGNATURE, self.tag, STATUS_PHASE_ERROR) },
+                        _ => { (CSW_SIGNATURE, self.tag, status) }
+                };
+                csw[..4].copy_from_slice(&signature.to_le_bytes());
+                csw[4..8].copy_from_slice(&tag.to_le_bytes());
+                csw[8..12].copy_from_slice(&self.residue.to_le_bytes());
+                csw[12] = status;
+                csw
+        }
+
+        /// Moves to the status phase after the host has transferred `transferred` bytes of the data phase,
+        /// the untransferred part of the expected length is reported as residue
+        fn end_data_phase(&mut self, expected: u32, transferred: usize, status: u8) -> Phase {
+                self.residue = expected.saturating_sub(transferred as u32);
+                Phase::Status { status }
+        }
+}
+
+impl BulkTransport for SimulatedDevice {
+        fn write_bulk(&self, endpoint: u8, data: &[u8], _timeout: Duration) -> usb::Result<usize> {
+                if endpoint != SIMULATED_OUT_ENDPOINT {
+                        return Err(usb::Error::InvalidParam);
+                }
+                let mut state = self.state.borrow_mut();
+                if state.out_halted {
+                        return Err(usb::Error::Pipe);
+                }
+                match std::mem::replace(&mut state.phase, Phase::Command) {
+                        Phase::Command => {
+                                if data.len() != 31 || u32::from_le_bytes(data[..4].try_into().unwrap()) != CBW_SIGNATURE {
+                                        // an invalid CBW halts both pipes until a reset recovery
+                                        state.in_halted = true;
+                                        state.out_halted = true;
+                                        return Err(usb::Error::Pipe);
+                                }
+                                let length = u32::from_le_bytes(data[8..12].try_into().unwrap());
+                                let command_length = usize::from(data[14]).clamp(1, 16);
+                                state.command_index += 1;
+                                state.active_fault = state.faults.command_fault(state.command_index);
+                                state.tag = u32::from_le_bytes(data[4..8].try_into().unwrap());
+                                state.lun = usize::from(data[13] & 0xF);
+                                state.residue = length;
+                                let phase = state.execute(&data[15..15 + command_length], length).map_err(|_| usb::Error::Io)?;
+                                state.phase = match (phase, state.active_fault) {
+                                        (Phase::DataIn { status, .. }, Some(CommandFault::StallIn)) => {
+                                                state.in_halted = true;
+                                                Phase::Status { status }
+                                        },
+                                        (Phase::DataOut { .. }, Some(CommandFault::StallOut)) => {
+                                                // the data is dropped, which the host can only notice through the residue
+                                                state.out_halted = true;
+                                                Phase::Status { status: STATUS_GOOD }
+                                        },
+                                        (Phase::DataIn { mut data, position, .. }, _) if data.len() > length as usize => {
+                                                // the device has more data than the host expects, only the expected part is sent
+                                                data.truncate(length as usize);
+                                                Phase::DataIn { data, position, status: STATUS_PHASE_ERROR }
+                                        },
+                                        (Phase::Status { status }, _) if length > 0 => {
+                                                // the data phase the host expects is ended by halting its pipe
+                                                if data[12] & 0x80 != 0 {
+                                                        state.in_halted = true;
+                                                } else {
+                                                        state.out_halted = true;
+                                                }
+                                                Phase::Status { status }
+                                        },
+                                        (phase, _) => { phase }
+                                };
+                                Ok(data.len())
+                        },
+                        Phase::DataOut { command, expected, mut received } => {
+                                let accepted = data.len().min(expected - received.len());
+                                received.extend_from_slice(&data[..accepted]);
+                                if received.len() < expected {
+                                        state.phase = Phase::DataOut { command, expected, received };
+                                        return Ok(accepted);
+                                }
+                                let status = state.commit(command, &received).map_err(|_| usb::Error::Io)?;
+                                state.phase = state.end_data_phase(expected as u32, received.len(), status);
+                                Ok(accepted)
+                        },
+                        phase => {
+                                state.phase = phase;
+                                state.out_halted = true;
+                                Err(usb::Error::Pipe)
+                        }
+                }
+        }
+
+        fn read_bulk(&self, endpoint: u8, data: &mut [u8], _timeout: Duration) -> usb::Result<usize> {
+                if endpoint != SIMULATED_IN_ENDPOINT {
+                        return Err(usb::Error::InvalidParam);
+                }
+                let mut state = self.state.borrow_mut();
+                if state.in_halted {
+                        return Err(usb::Error::Pipe);
+                }
+                match std::mem::replace(&mut state.phase, Phase::Command) {
+                        Phase::DataIn { data: payload, position, status } => {
+                                let count = data.len().min(payload.len() - position);
+                                data[..count].copy_from_slice(&payload[position..position + count]);
+                                let expected = state.residue;
+                                state.phase = if position + count < payload.len() {
+                                        Phase::DataIn { data: payload, position: position + count, status }
+                                } else {
+                                        state.end_data_phase(expected, position + count, status)
+                                };
+                                Ok(count)
+                        },
+                        Phase::Status { status } => {
+                                let csw = state.csw(status);
+                                let length = if state.active_fault == Some(CommandFault::ShortStatus) { 7 } else { csw.len() };
+                                let count = data.len().min(length);
+                                data[..count].copy_from_slice(&csw[..count]);
+                                state.active_fault = None;
+                                Ok(count)
+                        },
+                        phase => {
+                                state.phase = phase;
+                                state.in_halted = true;
+                                Err(usb::Error::Pipe)
+                        }
+                }
+        }
+
+        fn write_control(&self, request_type: u8, request: u8, _value: u16, _index: u16, _data: &[u8], _timeout: Duration) -> usb::Result<usize> {
+                if request_type == 0x21 && request == BULK_ONLY_RESET_REQUEST {
+                        let mut state = self.state.borrow_mut();
+                        log::debug!("SimulatedDevice: Bulk-Only Mass Storage Reset");
+                        state.phase = Phase::Command;
+                        state.active_fault = None;
+                        state.resets += 1;
+                        return Ok(0);
+                }
+                Err(usb::Error::Pipe)
+        }
+
+        fn read_control(&self, request_type: u8, request: u8, _value: u16, _index: u16, data: &mut [u8], _timeout: Duration) -> usb::Result<usize> {
+                if request_type == 0xA1 && request == GET_MAX_LUN_REQUEST && !data.is_empty() {
+                        data[0] = (self.state.borrow().units.len() - 1) as u8;
+                        return Ok(1);
+                }
+                Err(usb::Error::Pipe)
+        }
+
+        fn clear_halt(&self, endpoint: u8) -> usb::Result<()> {
+                let mut state = self.state.borrow_mut();
+                match endpoint {
+                        SIMULATED_IN_ENDPOINT => { state.in_halted = false; },
+                        SIMULATED_OUT_ENDPOINT => { state.out_halted = false; },
+                        _ => { return Err(usb::Error::InvalidParam); }
+                }
+                Ok(())
+        }
+}
@@ -0,0 +1,36 @@
+use rusb::{self as usb, GlobalContext};
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// The USB operations needed to drive a Bulk-Only mass storage interface, `Device` talks to the hardware
+/// exclusively through this trait so that it can also be backed by a simulated device
+#[allow(dead_code)]
+pub trait BulkTransport: Debug {
+        fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> usb::Result<usize>;
+        fn read_bulk(&self, endpoint: u8, data: &mut [u8], timeout: Duration) -> usb::Result<usize>;
+        fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8], timeout: Duration) -> usb::Result<usize>;
+        fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &mut [u8], timeout: Duration) -> usb::Result<usize>;
+        fn clear_halt(&self, endpoint: u8) -> usb::Result<()>;
+}
+
+impl BulkTransport for usb::DeviceHandle<GlobalContext> {
+        fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> usb::Result<usize> {
+                usb::DeviceHandle::write_bulk(self, endpoint, data, timeout)
+        }
+
+        fn read_bulk(&self, endpoint: u8, data: &mut [u8], timeout: Duration) -> usb::Result<usize> {
+                usb::DeviceHandle::read_bulk(self, endpoint, data, timeout)
+        }
+
+        fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8], timeout: Duration) -> usb::Result<usize> {
+                usb::DeviceHandle::write_control(self, request_type, request, value, index, data, timeout)
+        }
+
+        fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &mut [u8], timeout: Duration) -> usb::Result<usize> {
+                usb::DeviceHandle::read_control(self, request_type, request, value, index, data, timeout)
+        }
+
+        fn clear_halt(&self, endpoint: u8) -> usb::Result<()> {
+                usb::DeviceHandle::clear_halt(self, endpoint)
+        }
+}
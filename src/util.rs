@@ -1,32 +1,26 @@
 use std::io::Read;
 use crate::log;
 use crate::mass_storage;
+use crate::scsi;
+use crate::hash;
 const BAR_WIDTH: usize = 100;
 
-pub fn filter_devices(list: &mut Vec<mass_storage::Device>, name: Option<String>, bus: Option<u8>, port: Option<u8>) {
-        for i in 0..list.len() {
-                if name.is_some() {
-                        if list[i].name().unwrap_or(String::new()) != *name.as_ref().unwrap() {
-                                list.remove(i);
-                                continue;
-                        }
-                }
-                if bus.is_some() {
-                        if list[i].generic_device.bus_number() != bus.unwrap() {
-                                list.remove(i);
-                                continue;
-                        }
-                }
-                if port.is_some() {
-                        if list[i].generic_device.port_number() != port.unwrap() {
-                                list.remove(i);
-                                continue;
-                        }
-                }
-        }
+pub fn filter_devices(list: &mut Vec<mass_storage::Device>, name: Option<String>, bus: Option<u8>, port: Option<u8>, lun: Option<u8>) {
+        list.retain(|d| {
+                name.as_ref().is_none_or(|n| d.name().unwrap_or_default() == *n)
+                && bus.is_none_or(|b| d.bus_number() == b)
+                && port.is_none_or(|p| d.port_number() == p)
+                && lun.is_none_or(|l| d.lun() == l)
+        });
 }
 
-pub fn do_progress_bar(current: u32, total: u32) -> () {
+/// Prints the progress of a copy, a `total` of 0 means that the size of the image is unknown and only the number
+/// of sectors copied so far is printed
+pub fn do_progress_bar(current: u64, total: u64) {
+        if total == 0 {
+                print!("\r{current} sectors copied");
+                return;
+        }
         let progress = current as f32 / total as f32;
         let progress_len = (progress * BAR_WIDTH as f32) as usize;
         let mut bar = vec!['='; progress_len as usize];
@@ -34,11 +28,18 @@ pub fn do_progress_bar(current: u32, total: u32) -> () {
         print!("\r[{}] - {:.2}% ({current} / {total} sectors copied)", String::from_iter(bar.iter()), progress * 100.0);
 }
 
+/// Finalizes the hashers and prints their digests, one per line
+pub fn print_digests(hashers: Vec<hash::Hasher>) {
+        for hasher in hashers {
+                println!("{}", hasher.finalize());
+        }
+}
+
 fn choose_target_if_dup(list: &mut Vec<mass_storage::Device>) -> &mut mass_storage::Device {
         if list.len() > 1 {
                 println!("Multiple devices fit the specified filter, select which one to use for the operation:");
                 for (n, d) in list.iter().enumerate() {
-                        println!("\t{}. '{}' at bus {}, port {}", n, d.name().unwrap(), d.generic_device.bus_number(), d.generic_device.port_number());
+                        println!("\t{}. '{}' at bus {}, port {}, LUN {}", n, d.name().unwrap(), d.bus_number(), d.port_number(), d.lun());
                 }
                 let mut input: String = String::new();
                 #[allow(unused_labels)]
@@ -57,21 +58,27 @@ fn choose_target_if_dup(list: &mut Vec<mass_storage::Device>) -> &mut mass_stora
         &mut list[0]
 }
 
-pub fn acquire_target(list: &mut Vec<mass_storage::Device>, skip_prompts: bool, buffer_size: usize) -> &mut mass_storage::Device {
+pub fn acquire_target(list: &mut Vec<mass_storage::Device>, skip_prompts: bool) -> &mut mass_storage::Device {
         let target: &mut mass_storage::Device = choose_target_if_dup(list);
         if skip_prompts {
-                println!("Device '{}' (bus {}, port {}) was automatically selected", target.name().unwrap(), target.generic_device.bus_number(), target.generic_device.port_number());
+                println!("Device '{}' (bus {}, port {}, LUN {}) was automatically selected", target.name().unwrap(), target.bus_number(), target.port_number(), target.lun());
         } else {
-                println!("Device '{}' (bus {}, port {}) has been selected, are you sure [Y/N]?", target.name().unwrap(), target.generic_device.bus_number(), target.generic_device.port_number());
+                println!("Device '{}' (bus {}, port {}, LUN {}) has been selected, are you sure [Y/N]?", target.name().unwrap(), target.bus_number(), target.port_number(), target.lun());
                 if !wait_confirm() {
                         std::process::exit(0);
                 }
         }
-        if buffer_size > u16::max_value() as usize {
-                log::error!("buffer size is greater than 65535");
-                std::process::exit(1);
+        match target.open() {
+                Ok(mass_storage::Readiness::Ready) => {},
+                Ok(readiness) => {
+                        println!("Device '{}' (LUN {}) cannot be used: {}", target.name().unwrap(), target.lun(), readiness);
+                        std::process::exit(1);
+                },
+                Err(e) => {
+                        println!("Unable to open device '{}': {}", target.name().unwrap(), e);
+                        std::process::exit(1);
+                }
         }
-        target.open().unwrap();
         target
 }
 
@@ -88,3 +95,85 @@ pub fn wait_confirm() -> bool {
                 }
         }
 }
+
+/// Prints the INQUIRY identification and the capacity of the device on a single line, the capacity is
+/// replaced by the outcome of the readiness handshake when the medium is not ready
+pub fn print_device_summary(device: &mass_storage::Device, readiness: mass_storage::Readiness) {
+        let identification = match device.inquiry() {
+                Ok(Some(inquiry)) => { format!("{} {} {}", inquiry.vendor, inquiry.product, inquiry.revision) },
+                _ => { String::from("unknown") }
+        };
+        let mut sector_count: u64 = 0;
+        let capacity = match readiness {
+                mass_storage::Readiness::Ready => {
+                        match device.query_capacity(Some(&mut sector_count), None) {
+                                Ok(Some(mass_storage::CommandStatus::Success)) => { format!("{} sectors of {} bytes", sector_count, device.block_size()) },
+                                _ => { String::from("unknown capacity") }
+                        }
+                },
+                readiness => { readiness.to_string() }
+        };
+        println!("\t{} ({})", identification, capacity);
+}
+
+pub fn print_device_info(device: &mass_storage::Device, readiness: mass_storage::Readiness) {
+        match device.inquiry() {
+                Ok(Some(inquiry)) => {
+                        println!("\tVendor: {}", inquiry.vendor);
+                        println!("\tProduct: {}", inquiry.product);
+                        println!("\tRevision: {}", inquiry.revision);
+                        println!("\tPeripheral type: {} ({:#04x}, qualifier {})", inquiry.peripheral_type_name(), inquiry.peripheral_type, inquiry.peripheral_qualifier);
+                        println!("\tSCSI version: {:#04x}", inquiry.version);
+                        println!("\tRemovable medium: {}", if inquiry.removable { "yes" } else { "no" });
+                },
+                _ => { println!("\tINQUIRY data is not available"); }
+        }
+        println!("\tStatus: {}", readiness);
+        let mut sector_count: u64 = 0;
+        match device.query_capacity(Some(&mut sector_count), None) {
+                Ok(Some(mass_storage::CommandStatus::Success)) => {
+                        let bytes = sector_count * u64::from(device.block_size());
+                        println!("\tCapacity: {} sectors ({} bytes, {:.2} GiB)", sector_count, bytes, bytes as f64 / (1u64 << 30) as f64);
+                },
+                _ => { println!("\tCapacity: unknown"); }
+        }
+        println!("\tLogical block size: {} bytes", device.block_size());
+        let supported_pages = match device.vital_product_data(scsi::VPD_SUPPORTED_PAGES) {
+                Ok(Some(page)) => { scsi::parse_supported_pages(&page) },
+                _ => {
+                        println!("\tVital Product Data is not available");
+                        return;
+                }
+        };
+        let read_page = |code: u8| -> Option<Vec<u8>> {
+                if supported_pages.contains(&code) {
+                        device.vital_product_data(code).ok().flatten()
+                } else {
+                        None
+                }
+        };
+        println!("\tSupported VPD pages: {}", supported_pages.iter().map(|p| format!("{:#04x}", p)).collect::<Vec<String>>().join(", "));
+        if let Some(serial) = read_page(scsi::VPD_UNIT_SERIAL_NUMBER).and_then(|p| scsi::parse_unit_serial_number(&p)) {
+                println!("\tSerial number: {}", serial);
+        }
+        if let Some(page) = read_page(scsi::VPD_DEVICE_IDENTIFICATION) {
+                for designator in scsi::parse_device_identification(&page) {
+                        println!("\tIdentifier: {}", designator);
+                }
+        }
+        if let Some(limits) = read_page(scsi::VPD_BLOCK_LIMITS).and_then(|p| scsi::BlockLimits::parse(&p)) {
+                println!("\tMaximum transfer length: {} sectors", limits.maximum_transfer_length);
+                println!("\tOptimal transfer length: {} sectors", limits.optimal_transfer_length);
+                println!("\tMaximum UNMAP LBA count: {} ({} descriptors, granularity {} sectors)", limits.maximum_unmap_lba_count, limits.maximum_unmap_descriptor_count, limits.optimal_unmap_granularity);
+                println!("\tMaximum WRITE SAME length: {} sectors", limits.maximum_write_same_length);
+        }
+        if let Some(characteristics) = read_page(scsi::VPD_BLOCK_DEVICE_CHARACTERISTICS).and_then(|p| scsi::BlockDeviceCharacteristics::parse(&p)) {
+                println!("\tRotation rate: {}", characteristics.rotation_rate_description());
+                println!("\tForm factor: {}", characteristics.form_factor_description());
+        }
+        if let Some(provisioning) = read_page(scsi::VPD_LOGICAL_BLOCK_PROVISIONING).and_then(|p| scsi::LogicalBlockProvisioning::parse(&p)) {
+                let commands: Vec<&str> = [(provisioning.unmap, "UNMAP"), (provisioning.write_same_16, "WRITE SAME(16)"), (provisioning.write_same_10, "WRITE SAME(10)")].iter().filter(|(supported, _)| *supported).map(|(_, name)| *name).collect();
+                println!("\tDeallocation commands: {}", if commands.is_empty() { String::from("none") } else { commands.join(", ") });
+                println!("\tDeallocated sectors read as zeros: {}", if provisioning.reads_zeros { "yes" } else { "no" });
+        }
+}
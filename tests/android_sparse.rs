@@ -0,0 +1,122 @@
+mod common;
+
+use std::io::Read;
+use common::TempDir;
+use rmsd::image::{Compression, Destination, Format, Image, OutputOptions};
+use rmsd::mass_storage::{HolePolicy, SparseOptions};
+
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+enum Chunk {
+        Raw(Vec<u8>),
+        Fill([u8; 4], u32),
+        DontCare(u32),
+        /// CRC32 of the expanded image up to the chunk
+        Crc32(u32),
+}
+
+/// Assembles an Android sparse image of `chunks`, as img2simg would
+fn sparse_image(chunks: &[Chunk]) -> Vec<u8> {
+        let blocks: u32 = chunks.iter().map(|c| match c {
+                Chunk::Raw(data) => { (data.len() / SPARSE_BLOCK_SIZE) as u32 },
+                Chunk::Fill(_, n) | Chunk::DontCare(n) => { *n },
+                Chunk::Crc32(_) => { 0 }
+        }).sum();
+        let mut image = vec![];
+        image.extend_from_slice(&0xED26FF3Au32.to_le_bytes());
+        image.extend_from_slice(&[1, 0, 0, 0, 28, 0, 12, 0]);
+        image.extend_from_slice(&(SPARSE_BLOCK_SIZE as u32).to_le_bytes());
+        image.extend_from_slice(&blocks.to_le_bytes());
+        image.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        for chunk in chunks {
+                let (chunk_type, blocks, data) = match chunk {
+                        Chunk::Raw(data) => { (0xCAC1u16, (data.len() / SPARSE_BLOCK_SIZE) as u32, data.clone()) },
+                        Chunk::Fill(value, n) => { (0xCAC2, *n, value.to_vec()) },
+                        Chunk::DontCare(n) => { (0xCAC3, *n, vec![]) },
+                        Chunk::Crc32(crc) => { (0xCAC4, 0, crc.to_le_bytes().to_vec()) }
+                };
+                image.extend_from_slice(&chunk_type.to_le_bytes());
+                image.extend_from_slice(&[0, 0]);
+                image.extend_from_slice(&blocks.to_le_bytes());
+                image.extend_from_slice(&(12 + data.len() as u32).to_le_bytes());
+                image.extend_from_slice(&data);
+        }
+        image
+}
+
+#[test]
+fn dont_care_chunks_are_skipped_and_zero_fills_written_whatever_the_hole_policy() {
+        let dir = TempDir::new("simg-holes");
+        let raw = common::pattern(2 * SPARSE_BLOCK_SIZE, 11);
+        let image = sparse_image(&[Chunk::Raw(raw.clone()), Chunk::Fill([0; 4], 2), Chunk::DontCare(2), Chunk::Fill([1, 2, 3, 4], 1)]);
+        std::fs::write(dir.join("image.simg"), &image).unwrap();
+        for policy in [HolePolicy::WriteZeros, HolePolicy::Discard, HolePolicy::Skip] {
+                let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * SPARSE_BLOCK_SIZE]);
+                let device = common::device(&backing, "");
+                let sparse = SparseOptions { hole_policy: policy, bmap: None };
+                assert!(device.flash_image_from_file(&common::location(&dir.join("image.simg")), 16, None, &sparse, &mut [], common::no_progress).unwrap());
+                assert!(device.verify_image_on_device(&common::location(&dir.join("image.simg")), 16, None, false, &sparse, common::no_progress).unwrap());
+                let content = std::fs::read(&backing).unwrap();
+                let block = |n: usize| &content[n * SPARSE_BLOCK_SIZE..(n + 1) * SPARSE_BLOCK_SIZE];
+                assert_eq!(&content[..2 * SPARSE_BLOCK_SIZE], &raw[..], "{:?}", policy);
+                assert!(block(2).iter().chain(block(3)).all(|&b| b == 0), "{:?}", policy);
+                assert!(block(4).iter().chain(block(5)).all(|&b| b == 0xA5), "{:?}", policy);
+                assert_eq!(&block(6)[..8], &[1, 2, 3, 4, 1, 2, 3, 4]);
+                assert!(content[7 * SPARSE_BLOCK_SIZE..].iter().all(|&b| b == 0xA5));
+        }
+}
+
+#[test]
+fn every_kind_of_chunk_is_expanded() {
+        let dir = TempDir::new("simg-chunks");
+        let raw = common::pattern(3 * SPARSE_BLOCK_SIZE, 12);
+        let mut expanded = raw.clone();
+        expanded.extend([0xDE, 0xAD, 0xBE, 0xEF].repeat(2 * SPARSE_BLOCK_SIZE / 4));
+        let crc = crc32fast::hash(&expanded);
+        expanded.extend(vec![0; 4 * SPARSE_BLOCK_SIZE]);
+        let image = sparse_image(&[Chunk::Raw(raw), Chunk::Fill([0xDE, 0xAD, 0xBE, 0xEF], 2), Chunk::Crc32(crc), Chunk::Fill([0; 4], 1), Chunk::DontCare(3)]);
+        std::fs::write(dir.join("image.simg"), &image).unwrap();
+        let mut decoded = Image::open(&common::location(&dir.join("image.simg"))).unwrap();
+        assert_eq!(decoded.format(), Format::AndroidSparse);
+        assert_eq!(decoded.size(), Some(expanded.len() as u64));
+        assert_eq!(decoded.data_extents(), Some(&[0..6 * SPARSE_BLOCK_SIZE as u64][..]));
+        assert_eq!(decoded.undefined_extents(), &[6 * SPARSE_BLOCK_SIZE as u64..9 * SPARSE_BLOCK_SIZE as u64]);
+        let mut data = vec![];
+        decoded.read_to_end(&mut data).unwrap();
+        assert_eq!(data, expanded);
+}
+
+#[test]
+fn crc32_mismatches_are_reported() {
+        let dir = TempDir::new("simg-crc32");
+        let raw = common::pattern(2 * SPARSE_BLOCK_SIZE, 13);
+        let image = sparse_image(&[Chunk::Raw(raw.clone()), Chunk::Crc32(crc32fast::hash(&raw) ^ 1), Chunk::DontCare(1)]);
+        std::fs::write(dir.join("image.simg"), &image).unwrap();
+        let mut decoded = Image::open(&common::location(&dir.join("image.simg"))).unwrap();
+        assert!(decoded.read_to_end(&mut vec![]).is_err());
+}
+
+#[test]
+fn clones_round_trip_through_android_sparse_images() {
+        let dir = TempDir::new("simg-round-trip");
+        // random blocks, blocks of zeros and blocks repeating a word, for RAW and both kinds of FILL chunks
+        let mut content = common::pattern(48 * SPARSE_BLOCK_SIZE, 14);
+        content[40 * SPARSE_BLOCK_SIZE..].copy_from_slice(&[0x11, 0x22, 0x33, 0x44].repeat(8 * SPARSE_BLOCK_SIZE / 4));
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let device = common::device(&backing, "");
+        let output = OutputOptions { format: Format::AndroidSparse, compression: Compression::None, level: None, threads: 1, split_size: None };
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.simg")), 32, None, &output, &mut [], common::no_progress).unwrap());
+        assert!(std::fs::metadata(dir.join("clone.simg")).unwrap().len() < content.len() as u64 * 3 / 4);
+        let mut decoded = Image::open(&common::location(&dir.join("clone.simg"))).unwrap();
+        assert_eq!(decoded.format(), Format::AndroidSparse);
+        assert_eq!(decoded.data_extents(), Some(&[0..content.len() as u64][..]));
+        let mut data = vec![];
+        decoded.read_to_end(&mut data).unwrap();
+        assert_eq!(data, content);
+
+        let copy = common::backing_file(&dir.join("copy.bin"), &vec![0xA5; content.len()]);
+        let device = common::device(&copy, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("clone.simg")), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        assert_eq!(std::fs::read(&copy).unwrap(), content);
+}
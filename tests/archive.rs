@@ -0,0 +1,215 @@
+mod common;
+
+use std::io::{Read, Write};
+use common::TempDir;
+use rmsd::image::{Image, Location};
+
+enum Method {
+        Stored,
+        Deflated,
+        /// Stored, its sizes and offset only recorded in zip64 extra fields and the directory found from the zip64
+        /// end records
+        Zip64,
+}
+
+/// Assembles a zip archive of `entries`, all stored with `method`
+fn zip_archive(entries: &[(&str, &[u8])], method: Method) -> Vec<u8> {
+        let mut archive = vec![];
+        let mut directory = vec![];
+        for (name, data) in entries {
+                let (method_id, stored) = match method {
+                        Method::Deflated => {
+                                let mut encoder = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+                                encoder.write_all(data).unwrap();
+                                (8u16, encoder.finish().unwrap())
+                        },
+                        Method::Stored | Method::Zip64 => { (0, data.to_vec()) }
+                };
+                let zip64 = matches!(method, Method::Zip64);
+                let field = |value: u64| if zip64 { 0xFFFF_FFFFu32 } else { value as u32 };
+                let mut extra = vec![];
+                if zip64 {
+                        extra.extend_from_slice(&1u16.to_le_bytes());
+                        extra.extend_from_slice(&24u16.to_le_bytes());
+                        for value in [data.len() as u64, stored.len() as u64, archive.len() as u64] {
+                                extra.extend_from_slice(&value.to_le_bytes());
+                        }
+                }
+                let mut common = vec![];
+                common.extend_from_slice(&(if zip64 { 45u16 } else { 20 }).to_le_bytes());
+                common.extend_from_slice(&0u16.to_le_bytes());
+                common.extend_from_slice(&method_id.to_le_bytes());
+                common.extend_from_slice(&[0; 4]);
+                common.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+                common.extend_from_slice(&field(stored.len() as u64).to_le_bytes());
+                common.extend_from_slice(&field(data.len() as u64).to_le_bytes());
+                common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                common.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+
+                directory.extend_from_slice(b"PK\x01\x02");
+                directory.extend_from_slice(&(if zip64 { 45u16 } else { 20 }).to_le_bytes());
+                directory.extend_from_slice(&common);
+                // comment length, disk number and attributes
+                directory.extend_from_slice(&[0; 10]);
+                directory.extend_from_slice(&field(archive.len() as u64).to_le_bytes());
+                directory.extend_from_slice(name.as_bytes());
+                directory.extend_from_slice(&extra);
+
+                archive.extend_from_slice(b"PK\x03\x04");
+                archive.extend_from_slice(&common);
+                archive.extend_from_slice(name.as_bytes());
+                archive.extend_from_slice(&extra);
+                archive.extend_from_slice(&stored);
+        }
+        let offset = archive.len() as u64;
+        archive.extend_from_slice(&directory);
+        if matches!(method, Method::Zip64) {
+                let end_offset = archive.len() as u64;
+                archive.extend_from_slice(b"PK\x06\x06");
+                archive.extend_from_slice(&44u64.to_le_bytes());
+                archive.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                for value in [entries.len() as u64, entries.len() as u64, directory.len() as u64, offset] {
+                        archive.extend_from_slice(&value.to_le_bytes());
+                }
+                archive.extend_from_slice(b"PK\x06\x07");
+                archive.extend_from_slice(&0u32.to_le_bytes());
+                archive.extend_from_slice(&end_offset.to_le_bytes());
+                archive.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let (count, offset) = match method {
+                Method::Zip64 => { (0xFFFFu16, 0xFFFF_FFFFu32) },
+                _ => { (entries.len() as u16, offset as u32) }
+        };
+        archive.extend_from_slice(b"PK\x05\x06");
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&count.to_le_bytes());
+        archive.extend_from_slice(&count.to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&offset.to_le_bytes());
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive
+}
+
+/// A POSIX ustar header, names longer than 100 bytes are split at a slash into the prefix field
+fn tar_header(name: &str, size: usize, kind: u8) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        let (prefix, base) = match name.len() > 100 {
+                true => { name.split_at(name.rfind('/').unwrap()) },
+                false => { ("", name) }
+        };
+        let base = base.trim_start_matches('/');
+        header[..base.len()].copy_from_slice(base.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        header
+}
+
+fn tar_data(archive: &mut Vec<u8>, data: &[u8]) {
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(512), 0);
+}
+
+/// Assembles a tar archive of regular `entries`, those whose name starts with "pax:" have it recorded in a pax
+/// header
+fn tar_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = vec![];
+        for (name, data) in entries {
+                let name = match name.strip_prefix("pax:") {
+                        Some(name) => {
+                                let record = format!("path={}\n", name);
+                                // the length counts its own digits
+                                let length = (1..).map(|digits| digits + 1 + record.len()).find(|l| l.to_string().len() + 1 + record.len() == *l).unwrap();
+                                let records = format!("{} {}", length, record);
+                                archive.extend_from_slice(&tar_header("PaxHeaders/entry", records.len(), b'x'));
+                                tar_data(&mut archive, records.as_bytes());
+                                "truncated-name"
+                        },
+                        None => { name }
+                };
+                archive.extend_from_slice(&tar_header(name, data.len(), b'0'));
+                tar_data(&mut archive, data);
+        }
+        archive.extend_from_slice(&[0; 1024]);
+        archive
+}
+
+/// Reads the image of the archive at `path`, the entry named `entry` or else the one picked
+fn read(path: &std::path::Path, entry: Option<&str>) -> std::io::Result<Vec<u8>> {
+        let mut image = Image::open(&Location { path, entry, input_size: None })?;
+        let mut data = vec![];
+        image.read_to_end(&mut data)?;
+        assert_eq!(image.size(), Some(data.len() as u64));
+        Ok(data)
+}
+
+#[test]
+fn zip_archives_are_read_whatever_their_method() {
+        let dir = TempDir::new("archive-zip");
+        let image = common::pattern(40000, 51);
+        let entries: [(&str, &[u8]); 2] = [("README.txt", b"flash me"), ("images/disk.img", &image)];
+        for (name, method) in [("stored.zip", Method::Stored), ("deflated.zip", Method::Deflated), ("zip64.zip", Method::Zip64)] {
+                std::fs::write(dir.join(name), zip_archive(&entries, method)).unwrap();
+                assert!(read(&dir.join(name), None).unwrap() == image, "{}", name);
+                assert_eq!(read(&dir.join(name), Some("README.txt")).unwrap(), b"flash me", "{}", name);
+        }
+        // the deflated entry is smaller than the image
+        assert!(std::fs::metadata(dir.join("deflated.zip")).unwrap().len() < image.len() as u64);
+}
+
+#[test]
+fn tar_entry_names_are_read_from_the_ustar_prefix_and_pax_headers() {
+        let dir = TempDir::new("archive-tar");
+        let image = common::pattern(40000, 52);
+        let long = format!("{}/disk.img", "long-directory-name".repeat(6));
+        let pax = format!("pax:{}/other.img", "x".repeat(120));
+        let entries: [(&str, &[u8]); 3] = [("notes.txt", b"notes"), (&long, &image), (&pax, b"other")];
+        std::fs::write(dir.join("archive.tar"), tar_archive(&entries)).unwrap();
+        assert!(read(&dir.join("archive.tar"), Some(&long)).unwrap() == image);
+        assert!(read(&dir.join("archive.tar"), Some(&format!("./{}", long))).unwrap() == image);
+        assert_eq!(read(&dir.join("archive.tar"), Some(&pax[4..])).unwrap(), b"other");
+        assert_eq!(read(&dir.join("archive.tar"), Some("notes.txt")).unwrap(), b"notes");
+        // the first disk image is picked
+        assert!(read(&dir.join("archive.tar"), None).unwrap() == image);
+        assert_eq!(read(&dir.join("archive.tar"), Some("missing.img")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn archives_of_a_single_file_flash_it_whatever_its_name() {
+        let dir = TempDir::new("archive-single");
+        let data = common::pattern(3000, 53);
+        let entries: [(&str, &[u8]); 1] = [("firmware", &data)];
+        std::fs::write(dir.join("single.zip"), zip_archive(&entries, Method::Deflated)).unwrap();
+        std::fs::write(dir.join("single.tar"), tar_archive(&entries)).unwrap();
+        assert!(read(&dir.join("single.zip"), None).unwrap() == data);
+        assert!(read(&dir.join("single.tar"), None).unwrap() == data);
+        // with several files, one has to look like a disk image
+        let entries: [(&str, &[u8]); 2] = [("firmware", &data), ("notes.txt", b"notes")];
+        std::fs::write(dir.join("several.zip"), zip_archive(&entries, Method::Stored)).unwrap();
+        std::fs::write(dir.join("several.tar"), tar_archive(&entries)).unwrap();
+        assert!(read(&dir.join("several.zip"), None).is_err());
+        assert!(read(&dir.join("several.tar"), None).is_err());
+}
+
+#[test]
+fn archive_entries_are_flashed() {
+        let dir = TempDir::new("archive-flash");
+        let image = common::pattern(64 * common::BLOCK_SIZE, 54);
+        let entries: [(&str, &[u8]); 2] = [("a.img", &vec![1; 512]), ("b.img", &image)];
+        std::fs::write(dir.join("images.zip"), zip_archive(&entries, Method::Deflated)).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * common::BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        let location = Location { path: &dir.join("images.zip"), entry: Some("b.img"), input_size: None };
+        // several disk images without --entry are refused
+        assert!(Image::open(&Location { entry: None, ..location }).is_err());
+        assert!(device.flash_image_from_file(&location, 16, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        assert!(device.verify_image_on_device(&location, 16, None, false, &common::write_zeros(), common::no_progress).unwrap());
+        assert!(std::fs::read(&backing).unwrap()[..image.len()] == image[..]);
+}
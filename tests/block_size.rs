@@ -0,0 +1,78 @@
+mod common;
+
+use std::io::Read;
+use common::TempDir;
+use rmsd::image::{Compression, Destination, Format, Image, OutputOptions};
+
+/// The block sizes of 4Kn drives and optical drives
+const BLOCK_SIZES: [usize; 2] = [4096, 2048];
+
+#[test]
+fn flash_and_verify_transfer_whole_blocks_of_the_device() {
+        for block_size in BLOCK_SIZES {
+                let dir = TempDir::new(&format!("block-size-flash-{}", block_size));
+                let image = common::pattern(40 * block_size, block_size as u64);
+                std::fs::write(dir.join("image.img"), &image).unwrap();
+                let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 256 * block_size]);
+                let device = common::device_with_block_size(&backing, block_size as u32);
+                assert_eq!(device.block_size(), block_size as u32);
+                let mut sector_count = 0u64;
+                device.query_capacity(Some(&mut sector_count), None).unwrap();
+                assert_eq!(sector_count, 256);
+                // buffers and sector counts are in the device's blocks
+                assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 16, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+                assert!(device.verify_image_on_device(&common::location(&dir.join("image.img")), 16, None, false, &common::write_zeros(), common::no_progress).unwrap());
+                assert!(device.verify_image_on_device(&common::location(&dir.join("image.img")), 16, None, true, &common::write_zeros(), common::no_progress).unwrap());
+                let content = std::fs::read(&backing).unwrap();
+                assert!(content[..image.len()] == image[..], "{}", block_size);
+                assert!(content[image.len()..].iter().all(|&b| b == 0xA5), "{}", block_size);
+
+                // a single byte differing in the last block is reported
+                let mut changed = content.clone();
+                changed[image.len() - 1] ^= 1;
+                std::fs::write(&backing, &changed).unwrap();
+                assert!(!device.verify_image_on_device(&common::location(&dir.join("image.img")), 16, None, false, &common::write_zeros(), common::no_progress).unwrap());
+        }
+}
+
+#[test]
+fn the_last_block_of_unaligned_images_is_padded_with_zeros() {
+        for block_size in BLOCK_SIZES {
+                let dir = TempDir::new(&format!("block-size-unaligned-{}", block_size));
+                // 10 blocks and a half, the half ending with data
+                let image = vec![0x3C; 10 * block_size + block_size / 2];
+                std::fs::write(dir.join("image.img"), &image).unwrap();
+                let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * block_size]);
+                let device = common::device_with_block_size(&backing, block_size as u32);
+                assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 4, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+                assert!(device.verify_image_on_device(&common::location(&dir.join("image.img")), 4, None, false, &common::write_zeros(), common::no_progress).unwrap());
+                let content = std::fs::read(&backing).unwrap();
+                assert!(content[..image.len()] == image[..], "{}", block_size);
+                assert!(content[image.len()..11 * block_size].iter().all(|&b| b == 0), "{}", block_size);
+                assert!(content[11 * block_size..].iter().all(|&b| b == 0xA5), "{}", block_size);
+        }
+}
+
+#[test]
+fn clones_copy_every_block_of_the_device() {
+        for block_size in BLOCK_SIZES {
+                let dir = TempDir::new(&format!("block-size-clone-{}", block_size));
+                let content = common::pattern(100 * block_size, 3 * block_size as u64);
+                let backing = common::backing_file(&dir.join("device.bin"), &content);
+                let device = common::device_with_block_size(&backing, block_size as u32);
+                let output = OutputOptions { format: Format::Raw, compression: Compression::None, level: None, threads: 1, split_size: None };
+                assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.img")), 7, None, &output, &mut [], common::no_progress).unwrap());
+                assert!(std::fs::read(dir.join("clone.img")).unwrap() == content, "{}", block_size);
+                // a preferred size is a number of the device's blocks
+                assert!(device.clone_drive_to_file(Destination::Path(dir.join("part.img")), 7, Some(30), &output, &mut [], common::no_progress).unwrap());
+                assert!(std::fs::read(dir.join("part.img")).unwrap() == content[..30 * block_size], "{}", block_size);
+
+                // virtual disks record the logical block size of the device
+                let output = OutputOptions { format: Format::Vhdx, ..output };
+                assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.vhdx")), 7, None, &output, &mut [], common::no_progress).unwrap());
+                let mut image = Image::open(&common::location(&dir.join("clone.vhdx"))).unwrap();
+                let mut decoded = vec![];
+                image.read_to_end(&mut decoded).unwrap();
+                assert!(decoded == content, "{}", block_size);
+        }
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use std::process::Command;
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::bmap;
+use rmsd::hash::{HashAlgorithm, Hasher};
+use rmsd::mass_storage::{HolePolicy, SparseOptions};
+
+const IMAGE_SIZE: usize = 40 * 1024 + 1000;
+
+fn sha256(data: &[u8]) -> String {
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(data);
+        hasher.finalize().to_hex()
+}
+
+/// Writes an image whose every byte holds data and a bmap mapping its 4KiB blocks 0 and 1, 5 and the last
+/// partial one, with the digests of `content`
+fn image_with_bmap(dir: &TempDir, image: &[u8], content: &[u8]) {
+        std::fs::write(dir.join("image.img"), image).unwrap();
+        let ranges: String = [(0..8192, "0-1"), (20480..24576, "5"), (40960..IMAGE_SIZE, "10")].iter()
+                .map(|(bytes, text)| format!("<Range chksum=\"{}\">{}</Range>", sha256(&content[bytes.clone()]), text)).collect();
+        let bmap = format!("<bmap version=\"2.0\"><ImageSize>{}</ImageSize><BlockSize>4096</BlockSize><ChecksumType>sha256</ChecksumType>\
+                <BmapFileChecksum>{}</BmapFileChecksum><BlockMap>{}</BlockMap></bmap>", IMAGE_SIZE, "0".repeat(64), ranges);
+        let bmap = bmap.replacen(&"0".repeat(64), &sha256(bmap.as_bytes()), 1);
+        std::fs::write(dir.join("image.img.bmap"), bmap).unwrap();
+}
+
+#[test]
+fn only_the_mapped_ranges_are_written() {
+        let dir = TempDir::new("bmap-mapped");
+        let image = common::pattern(IMAGE_SIZE, 101);
+        image_with_bmap(&dir, &image, &image);
+        let bmap = bmap::load_image_bmap(&dir.join("image.img"), None).unwrap().unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        let sparse = SparseOptions { hole_policy: HolePolicy::Skip, bmap: Some(&bmap) };
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 8, None, &sparse, &mut [], common::no_progress).unwrap());
+        let written = std::fs::read(&backing).unwrap();
+        for (bytes, mapped) in [(0..8192, true), (8192..20480, false), (20480..24576, true), (24576..40960, false), (40960..IMAGE_SIZE, true)] {
+                match mapped {
+                        true => { assert!(written[bytes.clone()] == image[bytes.clone()], "{:?}", bytes); },
+                        false => { assert!(written[bytes.clone()].iter().all(|&b| b == 0xA5), "{:?}", bytes); }
+                }
+        }
+        // the last block is padded with zeros
+        assert!(written[IMAGE_SIZE..41 * 1024].iter().all(|&b| b == 0));
+        assert!(written[41 * 1024..].iter().all(|&b| b == 0xA5));
+}
+
+#[test]
+fn ranges_whose_digest_mismatches_are_not_written() {
+        let dir = TempDir::new("bmap-mismatch");
+        let expected = common::pattern(IMAGE_SIZE, 102);
+        let mut image = expected.clone();
+        // unmapped data does not matter
+        image[10000] ^= 1;
+        image[22000] ^= 1;
+        image_with_bmap(&dir, &image, &expected);
+        let bmap = bmap::load_image_bmap(&dir.join("image.img"), None).unwrap().unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        let sparse = SparseOptions { hole_policy: HolePolicy::Skip, bmap: Some(&bmap) };
+        assert!(!device.flash_image_from_file(&common::location(&dir.join("image.img")), 8, None, &sparse, &mut [], common::no_progress).unwrap());
+        let written = std::fs::read(&backing).unwrap();
+        assert!(written[..8192] == image[..8192]);
+        assert!(written[8192..].iter().all(|&b| b == 0xA5));
+}
+
+#[test]
+fn the_flash_command_uses_the_bmap_next_to_the_image() {
+        let dir = TempDir::new("bmap-cli");
+        let image = common::pattern(IMAGE_SIZE, 103);
+        image_with_bmap(&dir, &image, &image);
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * BLOCK_SIZE]);
+        let flash = |args: &[&str]| {
+                Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(&backing)
+                        .args(["flash", "-y", "--skip-checksum", "--allow-unsigned", "-i"]).arg(dir.join("image.img")).args(args)
+                        .output().unwrap()
+        };
+        let output = flash(&[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        let written = std::fs::read(&backing).unwrap();
+        assert!(written[..8192] == image[..8192]);
+        assert!(written[8192..20480].iter().all(|&b| b == 0xA5));
+
+        // unless told not to
+        let output = flash(&["--no-bmap"]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        assert!(std::fs::read(&backing).unwrap()[..IMAGE_SIZE] == image[..]);
+
+        // a bmap that was modified is refused
+        let bmap = std::fs::read_to_string(dir.join("image.img.bmap")).unwrap();
+        std::fs::write(dir.join("image.img.bmap"), bmap.replace(">5<", ">6<")).unwrap();
+        let output = flash(&[]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(1), "{}", stdout);
+        assert!(stdout.contains("Unable to use the image's bmap"), "{}", stdout);
+}
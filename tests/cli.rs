@@ -0,0 +1,101 @@
+mod common;
+
+use std::process::{Command, Output};
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::hash::{HashAlgorithm, Hasher};
+
+/// Runs rmsd on the simulated device stored in `backing`
+fn rmsd(backing: &std::path::Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(backing).args(args).output().unwrap()
+}
+
+fn sha256(data: &[u8]) -> String {
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(data);
+        hasher.finalize().to_hex()
+}
+
+#[test]
+fn buffers_of_more_than_65535_sectors_are_accepted() {
+        let dir = TempDir::new("cli-buffer-size");
+        let content = common::pattern(70000 * BLOCK_SIZE, 61);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let output = rmsd(&backing, &["checksum", "-y", "--buffer-size", "65536"]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains(&format!("sha256: {}", sha256(&content))), "{}", stdout);
+
+        let output = rmsd(&backing, &["checksum", "-y", "--buffer-size", "524289"]);
+        assert!(!output.status.success());
+}
+
+#[test]
+fn sector_counts_are_in_the_simulated_block_size() {
+        let dir = TempDir::new("cli-block-size");
+        let content = common::pattern(64 * 2048, 62);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let clone = dir.join("clone.img");
+        let output = rmsd(&backing, &["--simulate-block-size", "2048", "clone", "-y", "-i", clone.to_str().unwrap(), "--sector-count", "30"]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        assert!(std::fs::read(&clone).unwrap() == content[..30 * 2048]);
+}
+
+#[test]
+fn digests_of_a_range_and_of_transfers_are_printed() {
+        let dir = TempDir::new("cli-hash");
+        let content = common::pattern(256 * BLOCK_SIZE, 63);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let output = rmsd(&backing, &["checksum", "-y", "--start-sector", "10", "--sector-count", "100", "--hash", "sha256", "--hash", "md5"]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains(&format!("sha256: {}", sha256(&content[10 * BLOCK_SIZE..110 * BLOCK_SIZE]))), "{}", stdout);
+        assert!(stdout.contains("md5: "), "{}", stdout);
+
+        // the digest of a clone is the one of the data it holds
+        let clone = dir.join("clone.img");
+        let output = rmsd(&backing, &["clone", "-y", "-i", clone.to_str().unwrap(), "--hash", "sha256"]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains(&format!("sha256: {}", sha256(&std::fs::read(&clone).unwrap()))), "{}", stdout);
+
+        // the digest of a flash is the one of the image, not of the whole device
+        let image = common::pattern(40 * BLOCK_SIZE, 64);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let output = rmsd(&backing, &["flash", "-y", "--skip-checksum", "--allow-unsigned", "-i", dir.join("image.img").to_str().unwrap(), "--hash", "sha256"]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains(&format!("sha256: {}", sha256(&image))), "{}", stdout);
+}
+
+#[test]
+fn unreadable_checksum_files_are_reported() {
+        let dir = TempDir::new("cli-checksum-file");
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * BLOCK_SIZE]);
+        std::fs::write(dir.join("image.img"), common::pattern(8 * BLOCK_SIZE, 65)).unwrap();
+        let output = rmsd(&backing, &["flash", "-y", "--allow-unsigned", "-i", dir.join("image.img").to_str().unwrap(), "--checksum-file", dir.join("missing.sha256").to_str().unwrap()]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("Unable to verify the image's checksum"), "{}", stdout);
+        assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5));
+}
+
+#[test]
+fn compressed_clones_are_written_on_worker_threads() {
+        let dir = TempDir::new("cli-compress");
+        let content = common::pattern(9 << 20, 66);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let clone = dir.join("clone.img.zst");
+        let output = rmsd(&backing, &["clone", "-y", "-i", clone.to_str().unwrap(), "--buffer-size", "2048", "--compress", "zstd", "--compression-level", "5", "--threads", "4"]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        // the frames record their size
+        let mut image = rmsd::image::Image::open(&common::location(&clone)).unwrap();
+        assert_eq!(image.size(), Some(content.len() as u64));
+        let mut decoded = vec![];
+        std::io::Read::read_to_end(&mut image, &mut decoded).unwrap();
+        assert!(decoded == content);
+
+        for args in [&["--compress", "zstd", "--compression-level", "23"][..], &["--compress", "gzip", "--compression-level", "10"], &["--compression-level", "3"], &["--format", "vhdx", "--compress", "xz"]] {
+                let output = rmsd(&backing, &[&["clone", "-y", "-i", dir.join("refused.img").to_str().unwrap()][..], args].concat());
+                assert!(!output.status.success(), "{:?}", args);
+        }
+}
@@ -0,0 +1,93 @@
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use rmsd::image::Location;
+use rmsd::mass_storage::{self, Device, HolePolicy, SparseOptions};
+use rmsd::simulator::{Faults, SimulatedDevice, SIMULATED_IN_ENDPOINT, SIMULATED_OUT_ENDPOINT};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// A directory removed with everything in it when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+                let path = std::env::temp_dir().join(format!("rmsd-{}-{}", name, std::process::id()));
+                let _ = std::fs::remove_dir_all(&path);
+                std::fs::create_dir_all(&path).unwrap();
+                TempDir(path)
+        }
+
+        pub fn join(&self, name: &str) -> PathBuf {
+                self.0.join(name)
+        }
+}
+
+impl Drop for TempDir {
+        fn drop(&mut self) {
+                let _ = std::fs::remove_dir_all(&self.0);
+        }
+}
+
+/// Pseudo-random data with runs of zeros, so that sparse code paths see both
+pub fn pattern(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+        let mut data = vec![0u8; length];
+        for (n, chunk) in data.chunks_mut(4096).enumerate() {
+                if n % 3 == 1 {
+                        continue;
+                }
+                for byte in chunk {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        *byte = state as u8;
+                }
+        }
+        data
+}
+
+/// Creates the backing file of a simulated device holding `content`
+pub fn backing_file(path: &Path, content: &[u8]) -> PathBuf {
+        std::fs::write(path, content).unwrap();
+        path.to_path_buf()
+}
+
+/// Builds a simulated device whose LUN 0 is stored in `backing`, without opening it. Like the USB devices, its
+/// `in_endpoint` is the one commands and data are sent to
+pub fn simulated(backing: &Path, faults: &str) -> Device {
+        simulated_transport(backing, faults).0
+}
+
+/// Like `simulated` but also returns the simulated device so that the commands and resets it saw can be checked
+pub fn simulated_transport(backing: &Path, faults: &str) -> (Device, Rc<SimulatedDevice>) {
+        let simulated = Rc::new(SimulatedDevice::open(&[backing.to_path_buf()], BLOCK_SIZE as u32, Faults::parse(faults).unwrap()).unwrap());
+        (Device::with_transport(simulated.clone(), SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT, 0), simulated)
+}
+
+/// Builds a simulated device whose LUN 0 is stored in `backing` and opens it
+pub fn device(backing: &Path, faults: &str) -> Device {
+        let mut device = simulated(backing, faults);
+        assert_eq!(device.open().unwrap(), mass_storage::Readiness::Ready);
+        device
+}
+
+/// Like `device` but with logical blocks of `block_size` bytes
+pub fn device_with_block_size(backing: &Path, block_size: u32) -> Device {
+        let simulated = Rc::new(SimulatedDevice::open(&[backing.to_path_buf()], block_size, Faults::default()).unwrap());
+        let mut device = Device::with_transport(simulated, SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT, 0);
+        assert_eq!(device.open().unwrap(), mass_storage::Readiness::Ready);
+        device
+}
+
+/// The image stored at `path`, not an archive entry nor the standard input
+pub fn location(path: &Path) -> Location<'_> {
+        Location { path, entry: None, input_size: None }
+}
+
+pub fn no_progress(_current: u64, _total: u64) {}
+
+pub fn write_zeros() -> SparseOptions<'static> {
+        SparseOptions { hole_policy: HolePolicy::WriteZeros, bmap: None }
+}
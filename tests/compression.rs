@@ -0,0 +1,106 @@
+mod common;
+
+use std::cell::RefCell;
+use std::io::Write;
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::image::{Compression, Image};
+
+/// Encodes `data` the way the usual command line tools do
+fn encode(compression: Compression, data: &[u8]) -> Vec<u8> {
+        match compression {
+                Compression::Gzip => {
+                        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                        encoder.write_all(data).unwrap();
+                        encoder.finish().unwrap()
+                },
+                Compression::Xz => {
+                        let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+                        encoder.write_all(data).unwrap();
+                        encoder.finish().unwrap()
+                },
+                Compression::Bzip2 => {
+                        let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+                        encoder.write_all(data).unwrap();
+                        encoder.finish().unwrap()
+                },
+                Compression::Zstd => { zstd::bulk::compress(data, 3).unwrap() },
+                Compression::None => { data.to_vec() }
+        }
+}
+
+thread_local! {
+        static PROGRESS: RefCell<Vec<(u64, u64)>> = const { RefCell::new(vec![]) };
+}
+
+fn record_progress(current: u64, total: u64) {
+        PROGRESS.with(|p| p.borrow_mut().push((current, total)));
+}
+
+#[test]
+fn every_format_is_decoded_while_flashing() {
+        let image = common::pattern(333 * BLOCK_SIZE + 17, 71);
+        for (compression, name, size) in [(Compression::Gzip, "image.img.gz", None), (Compression::Xz, "image.img.xz", Some(image.len() as u64)),
+                (Compression::Bzip2, "image.img.bz2", None), (Compression::Zstd, "image.img.zst", Some(image.len() as u64))] {
+                let dir = TempDir::new(&format!("compression-{}", name));
+                // the format is detected from the content whatever the name of the file
+                for name in [name, "image.img"] {
+                        std::fs::write(dir.join(name), encode(compression, &image)).unwrap();
+                        let opened = Image::open(&common::location(&dir.join(name))).unwrap();
+                        assert_eq!(opened.compression(), compression, "{}", name);
+                        assert_eq!(opened.size(), size, "{}", name);
+
+                        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 512 * BLOCK_SIZE]);
+                        let device = common::device(&backing, "");
+                        assert!(device.flash_image_from_file(&common::location(&dir.join(name)), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap(), "{}", name);
+                        assert!(device.verify_image_on_device(&common::location(&dir.join(name)), 32, None, false, &common::write_zeros(), common::no_progress).unwrap(), "{}", name);
+                        let content = std::fs::read(&backing).unwrap();
+                        assert!(content[..image.len()] == image[..], "{:?}", compression);
+                        assert!(content[image.len()..334 * BLOCK_SIZE].iter().all(|&b| b == 0), "{:?}", compression);
+                        assert!(content[334 * BLOCK_SIZE..].iter().all(|&b| b == 0xA5), "{:?}", compression);
+                }
+        }
+}
+
+#[test]
+fn images_whose_size_is_recorded_are_checked_against_the_capacity_before_writing() {
+        let dir = TempDir::new("compression-capacity");
+        let image = common::pattern(65 * BLOCK_SIZE, 72);
+        for (compression, name) in [(Compression::Xz, "image.img.xz"), (Compression::Zstd, "image.img.zst")] {
+                std::fs::write(dir.join(name), encode(compression, &image)).unwrap();
+                let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * BLOCK_SIZE]);
+                let device = common::device(&backing, "");
+                assert!(!device.flash_image_from_file(&common::location(&dir.join(name)), 8, None, &common::write_zeros(), &mut [], common::no_progress).unwrap(), "{}", name);
+                assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5), "{}", name);
+        }
+}
+
+#[test]
+fn images_of_unknown_size_are_flashed_with_an_estimated_progress() {
+        let dir = TempDir::new("compression-unknown-size");
+        let image = common::pattern(200 * BLOCK_SIZE, 73);
+        let encoded = encode(Compression::Gzip, &image);
+        std::fs::write(dir.join("image.img.gz"), &encoded).unwrap();
+        let opened = Image::open(&common::location(&dir.join("image.img.gz"))).unwrap();
+        assert_eq!(opened.size(), None);
+        // nothing was decoded yet, the stored size is the only estimate
+        assert_eq!(opened.estimated_size(0), Some(encoded.len() as u64));
+
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 256 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img.gz")), 16, None, &common::write_zeros(), &mut [], record_progress).unwrap());
+        assert!(std::fs::read(&backing).unwrap()[..image.len()] == image[..]);
+        let totals = PROGRESS.with(|p| p.take());
+        let (current, total) = *totals.last().unwrap();
+        assert_eq!(current, 200);
+        assert_eq!(total, 200);
+        assert!(totals.iter().all(|&(_, total)| total > 0));
+}
+
+#[test]
+fn images_too_large_for_the_device_are_refused_once_decoded_when_their_size_is_unknown() {
+        let dir = TempDir::new("compression-unknown-too-large");
+        std::fs::write(dir.join("image.img.gz"), encode(Compression::Gzip, &common::pattern(65 * BLOCK_SIZE, 74))).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(!device.flash_image_from_file(&common::location(&dir.join("image.img.gz")), 8, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+}
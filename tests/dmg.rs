@@ -0,0 +1,123 @@
+mod common;
+
+use std::io::{Read, Write};
+use base64::Engine;
+use common::TempDir;
+use rmsd::image::{Format, Image, Location};
+
+const SECTOR_SIZE: usize = 512;
+
+/// The chunks of a UDIF image: kind, first sector, sector count and stored data
+type Chunks = Vec<(u32, u64, u64, Vec<u8>)>;
+
+/// Assembles a UDIF image of a disk of `sectors` sectors, its single partition lists `chunks`
+fn udif_image(sectors: u64, chunks: &Chunks) -> Vec<u8> {
+        let mut image = vec![];
+        let mut table = vec![0u8; 204];
+        table[..4].copy_from_slice(b"mish");
+        table[4..8].copy_from_slice(&1u32.to_be_bytes());
+        table[16..24].copy_from_slice(&sectors.to_be_bytes());
+        table[200..204].copy_from_slice(&(chunks.len() as u32 + 1).to_be_bytes());
+        for (kind, first, count, data) in chunks {
+                let offset = if data.is_empty() { 0 } else { image.len() as u64 };
+                for field in [u64::from(*kind) << 32, *first, *count, offset, data.len() as u64] {
+                        table.extend_from_slice(&field.to_be_bytes());
+                }
+                image.extend_from_slice(data);
+        }
+        for field in [0xFFFF_FFFFu64 << 32, sectors, 0, image.len() as u64, 0] {
+                table.extend_from_slice(&field.to_be_bytes());
+        }
+        let plist = format!(concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+                "<plist version=\"1.0\"><dict><key>resource-fork</key><dict><key>blkx</key><array><dict>",
+                "<key>Data</key><data>\n{}\n</data><key>Name</key><string>disk image (Apple_HFS : 1)</string>",
+                "</dict></array></dict></dict></plist>\n"), base64::engine::general_purpose::STANDARD.encode(&table));
+        let mut trailer = vec![0u8; 512];
+        trailer[..4].copy_from_slice(b"koly");
+        trailer[4..8].copy_from_slice(&4u32.to_be_bytes());
+        trailer[8..12].copy_from_slice(&512u32.to_be_bytes());
+        trailer[32..40].copy_from_slice(&(image.len() as u64).to_be_bytes());
+        trailer[216..224].copy_from_slice(&(image.len() as u64).to_be_bytes());
+        trailer[224..232].copy_from_slice(&(plist.len() as u64).to_be_bytes());
+        trailer[492..500].copy_from_slice(&sectors.to_be_bytes());
+        image.extend_from_slice(plist.as_bytes());
+        image.extend_from_slice(&trailer);
+        image
+}
+
+/// An LZFSE stream of a sector, "abcd" repeated by an LZVN block followed by an uncompressed block
+fn lzfse_sector() -> (Vec<u8>, Vec<u8>) {
+        // "abcd", 10 bytes from 4 back, then 271 and 211 bytes from the same distance
+        let payload = [0xE4, b'a', b'b', b'c', b'd', 0x38, 0x04, 0xF0, 0xFF, 0xF0, 0xC3, 0x06, 0, 0, 0, 0, 0, 0, 0];
+        let mut stream = b"bvxn".to_vec();
+        stream.extend_from_slice(&496u32.to_le_bytes());
+        stream.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        stream.extend_from_slice(&payload);
+        stream.extend_from_slice(b"bvx-");
+        stream.extend_from_slice(&16u32.to_le_bytes());
+        stream.extend_from_slice(b"0123456789abcdef");
+        stream.extend_from_slice(b"bvx$");
+        (stream, [&b"abcd".repeat(124)[..], b"0123456789abcdef"].concat())
+}
+
+/// An ADC chunk of a sector, "wxyz" repeated by matches 4 bytes back
+fn adc_sector() -> (Vec<u8>, Vec<u8>) {
+        let mut chunk = vec![0x83, b'w', b'x', b'y', b'z'];
+        for _ in 0..7 {
+                chunk.extend_from_slice(&[0x7F, 0x00, 0x03]);
+        }
+        chunk.extend_from_slice(&[0x63, 0x00, 0x03]);
+        (chunk, b"wxyz".repeat(128))
+}
+
+#[test]
+fn udif_images_decode_every_kind_of_chunk() {
+        let dir = TempDir::new("udif");
+        let data = common::pattern(11 * SECTOR_SIZE, 21);
+        let sector = |n: usize| data[n * SECTOR_SIZE..(n + 1) * SECTOR_SIZE].to_vec();
+        let mut zlib = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        zlib.write_all(&[sector(1), sector(2)].concat()).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        bzip2.write_all(&[sector(4), sector(5)].concat()).unwrap();
+        let (lzfse, lzfse_data) = lzfse_sector();
+        let (adc, adc_data) = adc_sector();
+        let chunks: Chunks = vec![
+                (0x0000_0001, 0, 1, sector(0)),
+                (0x8000_0005, 1, 2, zlib.finish().unwrap()),
+                (0x0000_0000, 3, 1, vec![]),
+                (0x8000_0006, 4, 2, bzip2.finish().unwrap()),
+                (0x8000_0007, 6, 1, lzfse),
+                (0x0000_0002, 7, 1, vec![]),
+                (0x8000_0004, 8, 1, adc),
+                // sector 9 is listed in no chunk, sector 10 is a raw chunk stored short
+                (0x0000_0001, 10, 1, sector(10)[..100].to_vec()),
+        ];
+        std::fs::write(dir.join("image.dmg"), udif_image(11, &chunks)).unwrap();
+        let mut expected = vec![0u8; 11 * SECTOR_SIZE];
+        expected[..3 * SECTOR_SIZE].copy_from_slice(&data[..3 * SECTOR_SIZE]);
+        expected[4 * SECTOR_SIZE..6 * SECTOR_SIZE].copy_from_slice(&data[4 * SECTOR_SIZE..6 * SECTOR_SIZE]);
+        expected[6 * SECTOR_SIZE..7 * SECTOR_SIZE].copy_from_slice(&lzfse_data);
+        expected[8 * SECTOR_SIZE..9 * SECTOR_SIZE].copy_from_slice(&adc_data);
+        expected[10 * SECTOR_SIZE..10 * SECTOR_SIZE + 100].copy_from_slice(&data[10 * SECTOR_SIZE..10 * SECTOR_SIZE + 100]);
+
+        let mut image = Image::open(&Location { path: &dir.join("image.dmg"), entry: None, input_size: None }).unwrap();
+        assert_eq!(image.format(), Format::Dmg);
+        assert_eq!(image.size(), Some(expected.len() as u64));
+        let extents = [0..3, 4..7, 8..9, 10..11].map(|r| (r.start * SECTOR_SIZE) as u64..(r.end * SECTOR_SIZE) as u64);
+        assert_eq!(image.data_extents(), Some(&extents[..]));
+        let mut decoded = vec![];
+        image.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+}
+
+#[test]
+fn udif_chunks_that_do_not_decode_are_reported() {
+        let dir = TempDir::new("udif-corrupt");
+        let (mut adc, _) = adc_sector();
+        adc.truncate(adc.len() - 3);
+        std::fs::write(dir.join("image.dmg"), udif_image(1, &vec![(0x8000_0004, 0, 1, adc)])).unwrap();
+        let mut image = Image::open(&Location { path: &dir.join("image.dmg"), entry: None, input_size: None }).unwrap();
+        assert!(image.read_to_end(&mut vec![]).is_err());
+}
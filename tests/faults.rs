@@ -0,0 +1,142 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::mass_storage::{CommandStatus, Readiness};
+use rmsd::scsi;
+
+/// A device of 64 sectors holding a known pattern, commands are counted from the first one sent by the test
+fn faulty(dir: &TempDir, faults: &str) -> (rmsd::mass_storage::Device, std::rc::Rc<rmsd::simulator::SimulatedDevice>, Vec<u8>) {
+        let content = common::pattern(64 * BLOCK_SIZE, 7);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let (device, simulated) = common::simulated_transport(&backing, faults);
+        (device, simulated, content)
+}
+
+#[test]
+fn short_csw_triggers_a_reset_recovery() {
+        let dir = TempDir::new("fault-short-csw");
+        let (device, simulated, _) = faulty(&dir, "short-csw:1");
+        assert_eq!(device.test_unit_ready().unwrap(), None);
+        assert_eq!(simulated.resets(), 1);
+        assert_eq!(device.test_unit_ready().unwrap(), Some(CommandStatus::Success));
+}
+
+#[test]
+fn bad_signature_triggers_a_reset_recovery_and_a_retry() {
+        let dir = TempDir::new("fault-bad-signature");
+        let (device, simulated, _) = faulty(&dir, "bad-signature:1");
+        let mut sector_count: u64 = 0;
+        assert_eq!(device.query_capacity(Some(&mut sector_count), None).unwrap(), Some(CommandStatus::Success));
+        assert_eq!(sector_count, 64);
+        assert_eq!(simulated.resets(), 1);
+        assert_eq!(simulated.commands(), 2);
+}
+
+#[test]
+fn bad_tag_triggers_a_reset_recovery() {
+        let dir = TempDir::new("fault-bad-tag");
+        let (device, simulated, _) = faulty(&dir, "bad-tag:1");
+        assert_eq!(device.test_unit_ready().unwrap(), None);
+        assert_eq!(simulated.resets(), 1);
+        assert_eq!(device.test_unit_ready().unwrap(), Some(CommandStatus::Success));
+}
+
+#[test]
+fn phase_error_triggers_a_reset_recovery() {
+        let dir = TempDir::new("fault-phase-error");
+        let (device, simulated, _) = faulty(&dir, "phase-error:1");
+        assert_eq!(device.test_unit_ready().unwrap(), Some(CommandStatus::PhaseError));
+        assert_eq!(simulated.resets(), 1);
+        assert_eq!(device.test_unit_ready().unwrap(), Some(CommandStatus::Success));
+}
+
+#[test]
+fn stall_in_is_cleared_and_the_command_retried() {
+        let dir = TempDir::new("fault-stall-in");
+        let (device, simulated, content) = faulty(&dir, "stall-in:1");
+        let mut data = vec![0u8; 8 * BLOCK_SIZE];
+        let mut data_size: usize = 0;
+        assert_eq!(device.storage_read(&mut data, 4, &mut data_size).unwrap(), Some(CommandStatus::Success));
+        assert_eq!(data_size, data.len());
+        assert_eq!(data, &content[4 * BLOCK_SIZE..12 * BLOCK_SIZE]);
+        assert_eq!(simulated.commands(), 2);
+        assert_eq!(simulated.resets(), 0);
+}
+
+#[test]
+fn stall_out_is_cleared_and_the_write_retried() {
+        let dir = TempDir::new("fault-stall-out");
+        let (device, simulated, _) = faulty(&dir, "stall-out:1");
+        let data = vec![0x5A; 8 * BLOCK_SIZE];
+        assert_eq!(device.storage_write(&data, 16).unwrap(), Some(CommandStatus::Success));
+        assert_eq!(simulated.commands(), 2);
+        assert_eq!(simulated.resets(), 0);
+        let content = std::fs::read(dir.join("device.bin")).unwrap();
+        assert_eq!(&content[16 * BLOCK_SIZE..24 * BLOCK_SIZE], &data[..]);
+}
+
+#[test]
+fn medium_error_returns_the_error_and_its_sense_data() {
+        let dir = TempDir::new("fault-medium-error");
+        let (device, simulated, content) = faulty(&dir, "medium-error:13-14");
+        let mut data = vec![0u8; 8 * BLOCK_SIZE];
+        let mut data_size: usize = 0;
+        assert_eq!(device.storage_read(&mut data, 10, &mut data_size).unwrap(), Some(CommandStatus::Error));
+        // the sectors before the faulty one are transferred, the command is not retried
+        assert_eq!(data_size, 3 * BLOCK_SIZE);
+        assert_eq!(&data[..data_size], &content[10 * BLOCK_SIZE..13 * BLOCK_SIZE]);
+        let sense = device.sense().unwrap();
+        assert_eq!((sense.key, sense.asc, sense.information), (scsi::SENSE_KEY_MEDIUM_ERROR, 0x11, Some(13)));
+        assert_eq!(simulated.commands(), 2);
+        assert_eq!(simulated.resets(), 0);
+}
+
+#[test]
+fn slow_sectors_delay_the_transfer() {
+        let dir = TempDir::new("fault-slow");
+        let (device, _, content) = faulty(&dir, "slow:20-21:150");
+        let mut data = vec![0u8; 4 * BLOCK_SIZE];
+        let mut data_size: usize = 0;
+        let start = Instant::now();
+        assert_eq!(device.storage_read(&mut data, 18, &mut data_size).unwrap(), Some(CommandStatus::Success));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(data, &content[18 * BLOCK_SIZE..22 * BLOCK_SIZE]);
+}
+
+#[test]
+fn unit_attention_is_reported_once_and_retried() {
+        let dir = TempDir::new("fault-unit-attention");
+        let (device, simulated, _) = faulty(&dir, "unit-attention:0");
+        assert_eq!(device.test_unit_ready().unwrap(), Some(CommandStatus::Error));
+        let sense = device.sense().unwrap();
+        assert_eq!((sense.key, sense.asc), (scsi::SENSE_KEY_UNIT_ATTENTION, 0x29));
+        assert_eq!(device.ready().unwrap(), Readiness::Ready);
+        // TEST UNIT READY, REQUEST SENSE, then the TEST UNIT READY of ready()
+        assert_eq!(simulated.commands(), 3);
+}
+
+#[test]
+fn no_medium_is_reported_without_retrying() {
+        let dir = TempDir::new("fault-no-medium");
+        let (mut device, simulated, _) = faulty(&dir, "no-medium:0");
+        assert_eq!(device.open().unwrap(), Readiness::NoMedium);
+        let commands = simulated.commands();
+        let mut data = vec![0u8; BLOCK_SIZE];
+        let mut data_size: usize = 0;
+        assert_eq!(device.storage_read(&mut data, 0, &mut data_size).unwrap(), Some(CommandStatus::Error));
+        let sense = device.sense().unwrap();
+        assert_eq!((sense.key, sense.asc), (scsi::SENSE_KEY_NOT_READY, scsi::ASC_MEDIUM_NOT_PRESENT));
+        // READ and REQUEST SENSE
+        assert_eq!(simulated.commands(), commands + 2);
+}
+
+#[test]
+fn becoming_ready_is_waited_for() {
+        let dir = TempDir::new("fault-becoming-ready");
+        let (device, simulated, _) = faulty(&dir, "becoming-ready:2");
+        assert_eq!(device.ready().unwrap(), Readiness::Ready);
+        // two rejected TEST UNIT READY followed by their REQUEST SENSE, then the successful one
+        assert_eq!(simulated.commands(), 5);
+        assert_eq!(simulated.resets(), 0);
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::image::Image;
+use rmsd::mass_storage::{HolePolicy, SparseOptions};
+
+const MIB: usize = 1 << 20;
+
+/// A sparse image of 4MiB holding data at 1MiB and in its last 64KiB, the rest are holes
+fn sparse_image(dir: &TempDir) -> (std::path::PathBuf, Vec<u8>) {
+        let path = dir.join("image.img");
+        let mut content = vec![0u8; 4 * MIB];
+        let mut file = OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+        file.set_len(content.len() as u64).unwrap();
+        for (offset, length, seed) in [(MIB, 8192, 1), (4 * MIB - 65536, 65536, 2)] {
+                let data = common::pattern(length, seed);
+                file.seek(SeekFrom::Start(offset as u64)).unwrap();
+                file.write_all(&data).unwrap();
+                content[offset..offset + length].copy_from_slice(&data);
+        }
+        (path, content)
+}
+
+#[test]
+fn holes_are_handled_by_the_hole_policy() {
+        let dir = TempDir::new("hole-policy");
+        let (image, content) = sparse_image(&dir);
+        let extents = Image::open(&common::location(&image)).unwrap().data_extents().map(|e| e.to_vec());
+        let extents = match extents {
+                Some(extents) => { extents },
+                // the filesystem of the temporary directory does not report holes
+                None => { return; }
+        };
+        assert!(extents.iter().map(|e| e.end - e.start).sum::<u64>() < MIB as u64, "{:?}", extents);
+
+        for (policy, faults) in [(HolePolicy::WriteZeros, ""), (HolePolicy::Discard, ""), (HolePolicy::Discard, "no-provisioning:0"), (HolePolicy::Skip, "")] {
+                let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 5 * MIB]);
+                let (mut device, simulated) = common::simulated_transport(&backing, faults);
+                device.open().unwrap();
+                let commands = simulated.commands();
+                let sparse = SparseOptions { hole_policy: policy, bmap: None };
+                assert!(device.flash_image_from_file(&common::location(&image), 128, None, &sparse, &mut [], common::no_progress).unwrap());
+                let commands = simulated.commands() - commands;
+                let written = std::fs::read(&backing).unwrap();
+                assert!(written[5 * MIB - BLOCK_SIZE..].iter().all(|&b| b == 0xA5), "{:?}", policy);
+                match policy {
+                        HolePolicy::Skip => {
+                                for extent in &extents {
+                                        let range = extent.start as usize..extent.end as usize;
+                                        assert!(written[range.clone()] == content[range], "{:?}", policy);
+                                }
+                                assert!(written[..MIB].iter().all(|&b| b == 0xA5), "{:?}", policy);
+                        },
+                        _ => { assert!(written[..content.len()] == content[..], "{:?} {}", policy, faults); }
+                }
+                // the 3MiB of holes take 48 writes of 64KiB, a discard takes a few commands
+                match (policy, faults) {
+                        (HolePolicy::WriteZeros, _) | (HolePolicy::Discard, "no-provisioning:0") => { assert!(commands > 48, "{:?} {}: {} commands", policy, faults, commands); },
+                        _ => { assert!(commands < 24, "{:?} {}: {} commands", policy, faults, commands); }
+                }
+        }
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::mass_storage::CommandStatus;
+
+/// A sparse backing file of `sectors` sectors, only the blocks written by the tests take space
+fn sparse_backing(dir: &TempDir, sectors: u64) -> std::path::PathBuf {
+        let path = dir.join("device.bin");
+        OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap().set_len(sectors * BLOCK_SIZE as u64).unwrap();
+        path
+}
+
+#[test]
+fn read_capacity_16_is_only_sent_when_the_capacity_does_not_fit_in_32_bits() {
+        let dir = TempDir::new("lba64-capacity");
+        for (sectors, commands) in [(u64::from(u32::MAX), 1), (u64::from(u32::MAX) + 1, 2), (5u64 << 32, 2)] {
+                let backing = sparse_backing(&dir, sectors);
+                let (device, simulated) = common::simulated_transport(&backing, "");
+                let (mut sector_count, mut sector_size) = (0u64, 0u32);
+                assert_eq!(device.query_capacity(Some(&mut sector_count), Some(&mut sector_size)).unwrap(), Some(CommandStatus::Success));
+                assert_eq!((sector_count, sector_size), (sectors, BLOCK_SIZE as u32));
+                // READ CAPACITY(10) reports 0xFFFFFFFF as its last LBA, then READ CAPACITY(16) is sent
+                assert_eq!(simulated.commands(), commands, "{} sectors", sectors);
+        }
+}
+
+#[test]
+fn sectors_above_2_to_the_32_are_addressed_with_16_byte_commands() {
+        let dir = TempDir::new("lba64-transfers");
+        let backing = sparse_backing(&dir, 5u64 << 32);
+        let device = common::device(&backing, "");
+        // the first transfer straddles the 2^32 boundary, the second is entirely above it
+        for start in [(1u64 << 32) - 2, (4u64 << 32) + 5] {
+                let data = common::pattern(8 * BLOCK_SIZE, start);
+                assert_eq!(device.storage_write(&data, start).unwrap(), Some(CommandStatus::Success));
+                let mut stored = vec![0u8; data.len()];
+                let mut file = std::fs::File::open(&backing).unwrap();
+                file.seek(SeekFrom::Start(start * BLOCK_SIZE as u64)).unwrap();
+                file.read_exact(&mut stored).unwrap();
+                assert!(stored == data, "sector {}", start);
+
+                let mut file = OpenOptions::new().write(true).open(&backing).unwrap();
+                file.seek(SeekFrom::Start(start * BLOCK_SIZE as u64)).unwrap();
+                file.write_all(&vec![0x5A; data.len()]).unwrap();
+                let mut read = vec![0u8; data.len()];
+                let mut data_size: usize = 0;
+                assert_eq!(device.storage_read(&mut read, start, &mut data_size).unwrap(), Some(CommandStatus::Success));
+                assert_eq!(data_size, read.len());
+                assert!(read.iter().all(|&b| b == 0x5A), "sector {}", start);
+        }
+        // nothing was written at the truncated 32 bit addresses
+        let mut low = vec![0u8; 16 * BLOCK_SIZE];
+        std::fs::File::open(&backing).unwrap().read_exact(&mut low).unwrap();
+        assert!(low.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn transfers_too_large_for_a_command_are_refused() {
+        let dir = TempDir::new("lba64-too-large");
+        let backing = sparse_backing(&dir, 1 << 24);
+        let device = common::device(&backing, "");
+        // the length of a CBW is 32 bits, this buffer is 4GiB
+        let mut data = vec![0u8; 1 << 32];
+        let mut data_size: usize = 0;
+        assert!(device.storage_read(&mut data, 0, &mut data_size).is_err());
+        assert!(device.storage_write(&data, 0).is_err());
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use rusb as usb;
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::mass_storage::{self, CommandStatus, Readiness};
+use rmsd::simulator::{Faults, SimulatedDevice, SIMULATED_IN_ENDPOINT, SIMULATED_OUT_ENDPOINT};
+use rmsd::transport::BulkTransport;
+
+/// Forwards everything to a simulated device and records the LUN of each CBW sent to it
+#[derive(Debug)]
+struct Recorder {
+        device: SimulatedDevice,
+        luns: RefCell<Vec<u8>>,
+}
+
+impl BulkTransport for Recorder {
+        fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> usb::Result<usize> {
+                if data.len() == 31 && data[..4] == *b"USBC" {
+                        self.luns.borrow_mut().push(data[13]);
+                }
+                self.device.write_bulk(endpoint, data, timeout)
+        }
+
+        fn read_bulk(&self, endpoint: u8, data: &mut [u8], timeout: Duration) -> usb::Result<usize> {
+                self.device.read_bulk(endpoint, data, timeout)
+        }
+
+        fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8], timeout: Duration) -> usb::Result<usize> {
+                self.device.write_control(request_type, request, value, index, data, timeout)
+        }
+
+        fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &mut [u8], timeout: Duration) -> usb::Result<usize> {
+                self.device.read_control(request_type, request, value, index, data, timeout)
+        }
+
+        fn clear_halt(&self, endpoint: u8) -> usb::Result<()> {
+                self.device.clear_halt(endpoint)
+        }
+}
+
+/// The backing files of three logical units of different sizes, filled with 0xA5
+fn backing_files(dir: &TempDir) -> Vec<std::path::PathBuf> {
+        (0..3).map(|lun| common::backing_file(&dir.join(&format!("lun{}.bin", lun)), &vec![0xA5; (lun + 1) * 64 * BLOCK_SIZE])).collect()
+}
+
+#[test]
+fn get_max_lun_reports_the_highest_logical_unit() {
+        let dir = TempDir::new("multi-lun-max-lun");
+        let simulated = SimulatedDevice::open(&backing_files(&dir), BLOCK_SIZE as u32, Faults::default()).unwrap();
+        let mut max_lun = [0xFFu8; 1];
+        assert_eq!(simulated.read_control(0xA1, 0xFE, 0, 0, &mut max_lun, Duration::from_secs(1)).unwrap(), 1);
+        assert_eq!(max_lun[0], 2);
+        let list = mass_storage::list_transport_devices(Rc::new(simulated), SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT);
+        assert_eq!(list.iter().map(|d| d.lun()).collect::<Vec<_>>(), [0, 1, 2]);
+
+        // a device with a single logical unit lists a single device
+        let simulated = SimulatedDevice::open(&backing_files(&dir)[..1], BLOCK_SIZE as u32, Faults::default()).unwrap();
+        let list = mass_storage::list_transport_devices(Rc::new(simulated), SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT);
+        assert_eq!(list.iter().map(|d| d.lun()).collect::<Vec<_>>(), [0]);
+}
+
+#[test]
+fn each_device_addresses_its_own_logical_unit() {
+        let dir = TempDir::new("multi-lun-cbw");
+        let backing = backing_files(&dir);
+        let simulated = SimulatedDevice::open(&backing, BLOCK_SIZE as u32, Faults::parse("no-medium:2").unwrap()).unwrap();
+        let recorder = Rc::new(Recorder { device: simulated, luns: RefCell::new(vec![]) });
+        let mut list = mass_storage::list_transport_devices(recorder.clone(), SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[2].open().unwrap(), Readiness::NoMedium);
+        assert!(recorder.luns.borrow().iter().all(|&lun| lun == 2));
+
+        for lun in [1, 0] {
+                recorder.luns.borrow_mut().clear();
+                let device = &mut list[lun];
+                assert_eq!(device.open().unwrap(), Readiness::Ready);
+                let mut sector_count = 0u64;
+                device.query_capacity(Some(&mut sector_count), None).unwrap();
+                assert_eq!(sector_count, (lun as u64 + 1) * 64);
+                let data = common::pattern(8 * BLOCK_SIZE, lun as u64);
+                assert_eq!(device.storage_write(&data, 4).unwrap(), Some(CommandStatus::Success));
+                assert!(!recorder.luns.borrow().is_empty());
+                assert!(recorder.luns.borrow().iter().all(|&l| usize::from(l) == lun), "{:?}", recorder.luns.borrow());
+        }
+        // the writes only landed on the logical unit they were sent to
+        for (lun, path) in backing.iter().enumerate() {
+                let content = std::fs::read(path).unwrap();
+                let expected = match lun {
+                        2 => { vec![0xA5; content.len()] },
+                        _ => {
+                                let mut expected = vec![0xA5; content.len()];
+                                expected[4 * BLOCK_SIZE..12 * BLOCK_SIZE].copy_from_slice(&common::pattern(8 * BLOCK_SIZE, lun as u64));
+                                expected
+                        }
+                };
+                assert!(content == expected, "LUN {}", lun);
+        }
+}
+
+#[test]
+fn the_lun_filter_selects_a_single_logical_unit() {
+        let dir = TempDir::new("multi-lun-filter");
+        let backing = backing_files(&dir);
+        let all = || {
+                let simulated = SimulatedDevice::open(&backing, BLOCK_SIZE as u32, Faults::default()).unwrap();
+                mass_storage::list_transport_devices(Rc::new(simulated), SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT)
+        };
+        let mut list = all();
+        rmsd::util::filter_devices(&mut list, None, None, None, Some(1));
+        assert_eq!(list.iter().map(|d| d.lun()).collect::<Vec<_>>(), [1]);
+        let mut list = all();
+        rmsd::util::filter_devices(&mut list, None, None, None, Some(3));
+        assert!(list.is_empty());
+        let mut list = all();
+        rmsd::util::filter_devices(&mut list, Some(String::from("Simulated Device")), Some(0), Some(0), None);
+        assert_eq!(list.len(), 3);
+}
+
+#[test]
+fn the_lun_option_selects_the_logical_unit_to_flash() {
+        let dir = TempDir::new("multi-lun-cli");
+        let backing = backing_files(&dir);
+        let image = common::pattern(16 * BLOCK_SIZE, 81);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rmsd"))
+                .args(backing.iter().flat_map(|path| [std::ffi::OsStr::new("--simulate"), path.as_os_str()]))
+                .args(["flash", "-y", "--skip-checksum", "--allow-unsigned", "--lun", "1", "-i"]).arg(dir.join("image.img"))
+                .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        for (lun, path) in backing.iter().enumerate() {
+                let content = std::fs::read(path).unwrap();
+                match lun {
+                        1 => { assert!(content[..image.len()] == image[..]); },
+                        _ => { assert!(content.iter().all(|&b| b == 0xA5), "LUN {}", lun); }
+                }
+        }
+}
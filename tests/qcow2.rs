@@ -0,0 +1,58 @@
+mod common;
+
+use std::io::Read;
+use common::TempDir;
+use rmsd::image::{Compression, Destination, Format, Image, OutputOptions};
+
+const CLUSTER_SIZE: usize = 65536;
+
+/// A device of 16 clusters: data with runs of zeros, zeros, text and random data that does not compress
+fn content() -> Vec<u8> {
+        let mut content = common::pattern(4 * CLUSTER_SIZE, 31);
+        content.extend(vec![0; 2 * CLUSTER_SIZE]);
+        content.extend(b"The quick brown fox jumps over the lazy dog. ".iter().cycle().take(4 * CLUSTER_SIZE));
+        content.extend((0..16).flat_map(|n| common::pattern(4096, 100 + n)));
+        content.extend(vec![0; 4 * CLUSTER_SIZE]);
+        // a cluster holding a single byte
+        content.extend(vec![0; CLUSTER_SIZE]);
+        content[15 * CLUSTER_SIZE + 1000] = 1;
+        content
+}
+
+#[test]
+fn clones_round_trip_through_qcow2_images() {
+        let dir = TempDir::new("qcow2-round-trip");
+        let content = content();
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let data = [0..4, 6..11, 15..16].map(|r| (r.start * CLUSTER_SIZE) as u64..(r.end * CLUSTER_SIZE) as u64);
+        let mut sizes = vec![];
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+                let device = common::device(&backing, "");
+                let output = OutputOptions { format: Format::Qcow2, compression, level: None, threads: 1, split_size: None };
+                assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.qcow2")), 32, None, &output, &mut [], common::no_progress).unwrap());
+                sizes.push(std::fs::metadata(dir.join("clone.qcow2")).unwrap().len());
+                let mut image = Image::open(&common::location(&dir.join("clone.qcow2"))).unwrap();
+                assert_eq!(image.format(), Format::Qcow2, "{:?}", compression);
+                assert_eq!(image.size(), Some(content.len() as u64), "{:?}", compression);
+                assert_eq!(image.data_extents(), Some(&data[..]), "{:?}", compression);
+                let mut decoded = vec![];
+                image.read_to_end(&mut decoded).unwrap();
+                assert!(decoded == content, "{:?}", compression);
+
+                let copy = common::backing_file(&dir.join("copy.bin"), &vec![0xA5; content.len()]);
+                let device = common::device(&copy, "");
+                assert!(device.flash_image_from_file(&common::location(&dir.join("clone.qcow2")), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+                assert!(std::fs::read(&copy).unwrap() == content, "{:?}", compression);
+        }
+        // the text and the zeros of the first clusters compress, the random cluster is stored as it is
+        assert!(sizes[1] < sizes[0] - 4 * CLUSTER_SIZE as u64, "{:?}", sizes);
+        assert!(sizes[2] < sizes[0] - 4 * CLUSTER_SIZE as u64, "{:?}", sizes);
+}
+
+#[test]
+fn qcow2_clusters_cannot_be_compressed_with_xz() {
+        let dir = TempDir::new("qcow2-xz");
+        let output = OutputOptions { format: Format::Qcow2, compression: Compression::Xz, level: None, threads: 1, split_size: None };
+        assert!(rmsd::image::create(Destination::Path(dir.join("clone.qcow2")), &output, 1 << 20, 512).is_err());
+        assert!(!dir.join("clone.qcow2").exists());
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use std::process::Command;
+use std::rc::Rc;
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::mass_storage::{Device, Readiness};
+use rmsd::simulator::{Faults, SimulatedDevice, SIMULATED_IN_ENDPOINT, SIMULATED_OUT_ENDPOINT};
+
+/// A device of 64 blocks of 4096 bytes, so that whether `open` queried the block size shows
+fn unopened(dir: &TempDir, faults: &str) -> (Device, Rc<SimulatedDevice>) {
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * 4096]);
+        let simulated = Rc::new(SimulatedDevice::open(&[backing], 4096, Faults::parse(faults).unwrap()).unwrap());
+        (Device::with_transport(simulated.clone(), SIMULATED_OUT_ENDPOINT, SIMULATED_IN_ENDPOINT, 0), simulated)
+}
+
+#[test]
+fn open_waits_for_the_unit_before_reporting_it_ready() {
+        let dir = TempDir::new("readiness-open");
+        let (mut device, simulated) = unopened(&dir, "unit-attention:0,becoming-ready:2");
+        assert_eq!(device.open().unwrap(), Readiness::Ready);
+        // INQUIRY, the power on reset, two rejected TEST UNIT READY each followed by REQUEST SENSE, then the
+        // successful TEST UNIT READY and READ CAPACITY
+        assert_eq!(simulated.commands(), 9);
+        assert_eq!(simulated.resets(), 0);
+        assert_eq!(device.block_size(), 4096);
+}
+
+#[test]
+fn a_unit_without_medium_is_not_retried_nor_sized() {
+        let dir = TempDir::new("readiness-no-medium");
+        let (mut device, simulated) = unopened(&dir, "no-medium:0");
+        let readiness = device.open().unwrap();
+        assert_eq!(readiness, Readiness::NoMedium);
+        assert_eq!(readiness.to_string(), "no medium inserted");
+        // INQUIRY, TEST UNIT READY and its REQUEST SENSE
+        assert_eq!(simulated.commands(), 3);
+        assert_eq!(device.block_size(), BLOCK_SIZE as u32);
+}
+
+#[test]
+fn units_that_are_not_ready_are_refused_by_the_commands() {
+        let dir = TempDir::new("readiness-cli");
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * BLOCK_SIZE]);
+        std::fs::write(dir.join("image.img"), common::pattern(16 * BLOCK_SIZE, 91)).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(&backing)
+                .args(["--simulate-faults", "no-medium:0", "flash", "-y", "--skip-checksum", "--allow-unsigned", "-i"]).arg(dir.join("image.img"))
+                .output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(!output.status.success());
+        assert!(stdout.contains("cannot be used: no medium inserted"), "{}", stdout);
+        assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5));
+
+        // listing the devices reports it instead of their capacity
+        let output = Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(&backing)
+                .args(["--simulate-faults", "no-medium:0", "list"])
+                .output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("(no medium inserted)"), "{}", stdout);
+
+        // a unit becoming ready is waited for
+        let output = Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(&backing)
+                .args(["--simulate-faults", "becoming-ready:3", "list"])
+                .output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("(64 sectors of 512 bytes)"), "{}", stdout);
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::path::PathBuf;
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::signature::verify_image_signature;
+
+/// The key the images are signed with
+const TRUSTED_KEY: &str = "untrusted comment: minisign public key 0807060504030201\nRWQBAgMEBQYHCM6Hubyjqb01Hr1dtwAW9rthFVgHMcmC3LputqCpdwMe\n";
+/// Another key with its own key id
+const OTHER_KEY: &str = "untrusted comment: minisign public key 100F0E0D0C0B0A09\nRWQJCgsMDQ4PEM+3hUkwApr0sEQtqZ9QlkPslMmYqk//0ky+njSfBsH8\n";
+/// Another key claiming the key id of the trusted one
+const IMPOSTOR_KEY: &str = "untrusted comment: minisign public key 0807060504030201\nRWQBAgMEBQYHCOcuFBw1R7DVt4h7S7U/M7ogRFxGitl7n7FxpqSgBx+g\n";
+/// The prehashed signature of `content()` made with the trusted key
+const SIGNATURE: &str = "untrusted comment: signature from minisign secret key\nRUQBAgMEBQYHCDhWft2vdo5XYrFnbWTSkYiXkdEl0adnz+skuplrIEwN5fe7lrJCFJen7NeNBaJSRc5Zskjyy3TrS5YnY7iUZAg=\ntrusted comment: timestamp:1700000000\tfile:image.img\thashed\nt4GYWma8GC6MsVkuJ7kCSi0yh9p5KY0xt1v7FYlEF8owO1EHb9tku01g4ZtdAo1cvi33LntGjYNyz3KnCVmDCQ==\n";
+/// The signature of `content()` made with the other key
+const OTHER_SIGNATURE: &str = "untrusted comment: signature from minisign secret key\nRUQJCgsMDQ4PEPAWa6LpIB277C9NSBRaQz6XUf59xuCdzADLRYiioFQIrZtaf9XJMMK4Hqo+eKltY5ttTZEkTqq2K6hK3NC+5gs=\ntrusted comment: timestamp:1700000000\tfile:image.img\thashed\nMvG5DdPJW1tN+9Jxki5SCmicRX7VFD60ElgVeKwGZ+EXOzTp2jJsnxd/K9hctfzr5eDLcBd4rLoUwRfGELpwDw==\n";
+
+fn content() -> Vec<u8> {
+        (0..65536u32).map(|n| (n % 251) as u8).collect()
+}
+
+/// A directory holding the signed image, its signature and a directory of trusted keys holding `keys`
+fn signed(name: &str, keys: &[&str]) -> (TempDir, PathBuf, PathBuf) {
+        let dir = TempDir::new(name);
+        std::fs::write(dir.join("image.img"), content()).unwrap();
+        std::fs::write(dir.join("image.img.minisig"), SIGNATURE).unwrap();
+        std::fs::create_dir(dir.join("keys")).unwrap();
+        for (n, key) in keys.iter().enumerate() {
+                std::fs::write(dir.join("keys").join(format!("{}.pub", n)), key).unwrap();
+        }
+        let (image, keys) = (dir.join("image.img"), dir.join("keys"));
+        (dir, image, keys)
+}
+
+#[test]
+fn valid_signatures_are_accepted() {
+        let (dir, image, keys) = signed("signature-valid", &[OTHER_KEY, TRUSTED_KEY]);
+        // the key id of the signature selects the key among the trusted ones
+        assert!(verify_image_signature(&image, None, None, Some(&keys), false).unwrap());
+        std::fs::write(dir.join("trusted.pub"), TRUSTED_KEY).unwrap();
+        assert!(verify_image_signature(&image, None, Some(&dir.join("trusted.pub")), None, false).unwrap());
+        std::fs::rename(dir.join("image.img.minisig"), dir.join("release.minisig")).unwrap();
+        assert!(verify_image_signature(&image, Some(&dir.join("release.minisig")), None, Some(&keys), false).unwrap());
+}
+
+#[test]
+fn tampered_images_are_refused() {
+        let (_dir, image, keys) = signed("signature-tampered", &[TRUSTED_KEY]);
+        let mut data = std::fs::read(&image).unwrap();
+        data[40000] ^= 1;
+        std::fs::write(&image, &data).unwrap();
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), false).unwrap());
+        // allowing unsigned images does not allow invalid signatures
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+        data.truncate(65535);
+        std::fs::write(&image, &data).unwrap();
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+}
+
+#[test]
+fn signatures_of_other_keys_are_refused() {
+        let (dir, image, keys) = signed("signature-wrong-key", &[OTHER_KEY]);
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+        // a key with the right id but the wrong material
+        std::fs::write(dir.join("impostor.pub"), IMPOSTOR_KEY).unwrap();
+        assert!(!verify_image_signature(&image, None, Some(&dir.join("impostor.pub")), None, true).unwrap());
+        // a signature made by an untrusted key
+        std::fs::write(dir.join("image.img.minisig"), OTHER_SIGNATURE).unwrap();
+        std::fs::write(dir.join("keys").join("0.pub"), TRUSTED_KEY).unwrap();
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+        // a signature without any key to check it
+        std::fs::remove_file(dir.join("keys").join("0.pub")).unwrap();
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+        // a missing or malformed public key
+        assert!(!verify_image_signature(&image, None, Some(&dir.join("missing.pub")), None, true).unwrap());
+        assert!(!verify_image_signature(&image, None, Some(&dir.join("image.img.minisig")), None, true).unwrap());
+}
+
+#[test]
+fn unsigned_images_are_refused_unless_allowed() {
+        let (dir, image, keys) = signed("signature-missing", &[TRUSTED_KEY]);
+        std::fs::remove_file(dir.join("image.img.minisig")).unwrap();
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), false).unwrap());
+        assert!(verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+        // without any trusted key too
+        std::fs::remove_file(dir.join("keys").join("0.pub")).unwrap();
+        assert!(!verify_image_signature(&image, None, None, Some(&keys), false).unwrap());
+        assert!(verify_image_signature(&image, None, None, Some(&keys), true).unwrap());
+        // an explicitly given signature has to exist
+        assert!(!verify_image_signature(&image, Some(&dir.join("missing.minisig")), None, Some(&keys), true).unwrap());
+}
+
+#[test]
+fn unreadable_trusted_key_directories_are_errors() {
+        let (dir, image, _) = signed("signature-unreadable", &[TRUSTED_KEY]);
+        assert!(verify_image_signature(&image, None, None, Some(&dir.join("missing")), true).is_err());
+        assert!(verify_image_signature(&image, None, None, Some(&image), true).is_err());
+}
+
+#[test]
+fn the_flash_command_fails_closed() {
+        let (dir, image, keys) = signed("signature-cli", &[TRUSTED_KEY]);
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 256 * BLOCK_SIZE]);
+        let flash = |args: &[&str]| {
+                std::process::Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(&backing)
+                        .args(["flash", "-y", "-i", image.to_str().unwrap()]).args(args)
+                        .output().unwrap()
+        };
+        let output = flash(&["--trusted-keys", dir.join("missing").to_str().unwrap()]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("Unable to verify the image's signature"), "{}", stdout);
+
+        std::fs::remove_file(dir.join("image.img.minisig")).unwrap();
+        let output = flash(&["--trusted-keys", keys.to_str().unwrap()]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("refusing to flash it"), "{}", stdout);
+        assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5));
+
+        let output = flash(&["--trusted-keys", keys.to_str().unwrap(), "--allow-unsigned"]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        std::fs::write(dir.join("image.img.minisig"), SIGNATURE).unwrap();
+        let output = flash(&["--trusted-keys", keys.to_str().unwrap()]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        assert!(std::fs::read(&backing).unwrap()[..65536] == content()[..]);
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use std::io::{Read, Write};
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::image::{Compression, Destination, Format, Image, OutputOptions};
+
+fn raw_output() -> OutputOptions {
+        OutputOptions { format: Format::Raw, compression: Compression::None, level: None, threads: 1, split_size: None }
+}
+
+#[test]
+fn flash_writes_the_image_and_leaves_the_rest_of_the_device() {
+        let dir = TempDir::new("flash-raw");
+        let image = common::pattern(300 * BLOCK_SIZE, 1);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 2048 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        let content = std::fs::read(&backing).unwrap();
+        assert_eq!(&content[..image.len()], &image[..]);
+        assert!(content[image.len()..].iter().all(|&b| b == 0xA5));
+}
+
+#[test]
+fn flash_pads_the_last_sector_of_unaligned_images() {
+        let dir = TempDir::new("flash-unaligned");
+        let image = common::pattern(10 * BLOCK_SIZE + 100, 2);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xFF; 64 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 4, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        let content = std::fs::read(&backing).unwrap();
+        assert_eq!(&content[..image.len()], &image[..]);
+        assert!(content[image.len()..11 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert!(content[11 * BLOCK_SIZE..].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn flash_refuses_images_larger_than_the_device() {
+        let dir = TempDir::new("flash-too-large");
+        std::fs::write(dir.join("image.img"), common::pattern(65 * BLOCK_SIZE, 3)).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0; 64 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(!device.flash_image_from_file(&common::location(&dir.join("image.img")), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0));
+}
+
+#[test]
+fn flash_decodes_compressed_images() {
+        let dir = TempDir::new("flash-gzip");
+        let image = common::pattern(200 * BLOCK_SIZE, 4);
+        let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(dir.join("image.img.gz")).unwrap(), flate2::Compression::default());
+        encoder.write_all(&image).unwrap();
+        encoder.finish().unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 256 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img.gz")), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        assert_eq!(&std::fs::read(&backing).unwrap()[..image.len()], &image[..]);
+        assert!(device.verify_image_on_device(&common::location(&dir.join("image.img.gz")), 32, None, false, &common::write_zeros(), common::no_progress).unwrap());
+}
+
+#[test]
+fn clone_reads_back_what_was_flashed() {
+        let dir = TempDir::new("clone-raw");
+        let image = common::pattern(300 * BLOCK_SIZE, 5);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0x5A; 512 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 32, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.img")), 32, Some(300), &raw_output(), &mut [], common::no_progress).unwrap());
+        assert_eq!(std::fs::read(dir.join("clone.img")).unwrap(), image);
+        // the whole device by default
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("whole.img")), 32, None, &raw_output(), &mut [], common::no_progress).unwrap());
+        assert_eq!(std::fs::read(dir.join("whole.img")).unwrap(), std::fs::read(&backing).unwrap());
+}
+
+#[test]
+fn compressed_clones_decode_to_the_device_content() {
+        let dir = TempDir::new("clone-compressed");
+        let backing = common::backing_file(&dir.join("device.bin"), &common::pattern(256 * BLOCK_SIZE, 6));
+        let device = common::device(&backing, "");
+        for (compression, name) in [(Compression::Zstd, "clone.img.zst"), (Compression::Xz, "clone.img.xz"), (Compression::Gzip, "clone.img.gz")] {
+                let output = OutputOptions { compression, threads: 2, ..raw_output() };
+                assert!(device.clone_drive_to_file(Destination::Path(dir.join(name)), 32, None, &output, &mut [], common::no_progress).unwrap());
+                let mut decoded = vec![];
+                let path = dir.join(name);
+                let mut clone = Image::open(&common::location(&path)).unwrap();
+                assert_eq!(clone.compression(), compression);
+                clone.read_to_end(&mut decoded).unwrap();
+                assert_eq!(decoded, std::fs::read(&backing).unwrap(), "{}", name);
+        }
+}
+
+#[test]
+fn clone_hashes_the_data_it_copies() {
+        let dir = TempDir::new("clone-hash");
+        let content = common::pattern(64 * BLOCK_SIZE, 7);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let device = common::device(&backing, "");
+        let mut hashers = vec![rmsd::hash::Hasher::new(rmsd::hash::HashAlgorithm::Sha256)];
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.img")), 32, None, &raw_output(), &mut hashers, common::no_progress).unwrap());
+        let mut expected = rmsd::hash::Hasher::new(rmsd::hash::HashAlgorithm::Sha256);
+        expected.update(&content);
+        assert_eq!(hashers.remove(0).finalize(), expected.finalize());
+}
+
+#[test]
+fn images_are_checked_against_the_device_after_flashing() {
+        let dir = TempDir::new("verify");
+        let image = common::pattern(128 * BLOCK_SIZE, 8);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0; 256 * BLOCK_SIZE]);
+        // writes to sectors 40 to 49 are acknowledged but lost
+        let device = common::device(&backing, "drop-writes:40-49");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 16, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        for fast in [false, true] {
+                assert!(!device.verify_image_on_device(&common::location(&dir.join("image.img")), 16, None, fast, &common::write_zeros(), common::no_progress).unwrap());
+                assert!(device.verify_image_on_device(&common::location(&dir.join("image.img")), 16, Some(40), fast, &common::write_zeros(), common::no_progress).unwrap());
+        }
+}
+
+#[test]
+fn split_clone_replaces_the_parts_of_a_larger_image() {
+        let dir = TempDir::new("split-stale");
+        for index in 1..=5 {
+                std::fs::write(dir.join(&format!("clone.img.{index:03}")), vec![0xEE; 16 * BLOCK_SIZE]).unwrap();
+        }
+        let content = common::pattern(40 * BLOCK_SIZE, 9);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let device = common::device(&backing, "");
+        let output = OutputOptions { split_size: Some(16 * BLOCK_SIZE as u64), ..raw_output() };
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.img")), 32, None, &output, &mut [], common::no_progress).unwrap());
+        assert!(dir.join("clone.img.003").exists());
+        assert!(!dir.join("clone.img.004").exists() && !dir.join("clone.img.005").exists());
+        let mut image = Image::open(&common::location(&dir.join("clone.img.001"))).unwrap();
+        assert_eq!(image.size(), Some(content.len() as u64));
+        let mut data = vec![];
+        image.read_to_end(&mut data).unwrap();
+        assert_eq!(data, content);
+}
+
+#[test]
+fn split_sets_with_parts_of_different_sizes_are_rejected() {
+        let dir = TempDir::new("split-sizes");
+        std::fs::write(dir.join("image.001"), vec![1; 16 * BLOCK_SIZE]).unwrap();
+        std::fs::write(dir.join("image.002"), vec![2; 8 * BLOCK_SIZE]).unwrap();
+        std::fs::write(dir.join("image.003"), vec![3; 8 * BLOCK_SIZE]).unwrap();
+        assert!(Image::open(&common::location(&dir.join("image.001"))).is_err());
+        std::fs::remove_file(dir.join("image.003")).unwrap();
+        assert_eq!(Image::open(&common::location(&dir.join("image"))).unwrap().size(), Some(24 * BLOCK_SIZE as u64));
+}
+
+#[test]
+fn buffers_of_more_than_65535_sectors_are_transferred_whole() {
+        let dir = TempDir::new("large-buffer");
+        let image = common::pattern(70000 * BLOCK_SIZE, 9);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 72000 * BLOCK_SIZE]);
+        let (device, simulated) = common::simulated_transport(&backing, "");
+        assert_eq!(device.storage_write(&image, 1000).unwrap(), Some(rmsd::mass_storage::CommandStatus::Success));
+        assert_eq!(simulated.commands(), 1);
+        assert!(std::fs::read(&backing).unwrap()[1000 * BLOCK_SIZE..71000 * BLOCK_SIZE] == image[..]);
+        let mut data = vec![0u8; image.len()];
+        let mut data_size: usize = 0;
+        assert_eq!(device.storage_read(&mut data, 1000, &mut data_size).unwrap(), Some(rmsd::mass_storage::CommandStatus::Success));
+        assert_eq!(data_size, image.len());
+        assert!(data == image);
+        assert_eq!(simulated.commands(), 2);
+}
+
+#[test]
+fn buffer_sizes_are_bounded() {
+        use clap::Parser;
+        for (size, accepted) in [("0", false), ("65536", true), ("524288", true), ("524289", false)] {
+                let args = rmsd::args::Arguments::try_parse_from(["rmsd", "checksum", "--buffer-size", size]);
+                assert_eq!(args.is_ok(), accepted, "{}", size);
+        }
+}
+
+#[test]
+fn verify_compares_the_holes_of_a_bmap_against_the_zeros_written_over_them() {
+        let dir = TempDir::new("verify-bmap-zeros");
+        // the bmap leaves sectors 16 to 47 unmapped although the image holds data there
+        let image = common::pattern(64 * BLOCK_SIZE, 10).iter().map(|&b| b | 1).collect::<Vec<u8>>();
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let ranges = [0..16, 48..64].map(|r| rmsd::bmap::MappedRange { bytes: (r.start * BLOCK_SIZE) as u64..(r.end * BLOCK_SIZE) as u64, digest: None });
+        let bmap = rmsd::bmap::Bmap { path: dir.join("image.bmap"), image_size: image.len() as u64, block_size: BLOCK_SIZE as u64, ranges: ranges.to_vec() };
+        let sparse = rmsd::mass_storage::SparseOptions { hole_policy: rmsd::mass_storage::HolePolicy::WriteZeros, bmap: Some(&bmap) };
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("image.img")), 8, None, &sparse, &mut [], common::no_progress).unwrap());
+        let content = std::fs::read(&backing).unwrap();
+        assert!(content[16 * BLOCK_SIZE..48 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        for fast in [false, true] {
+                assert!(device.verify_image_on_device(&common::location(&dir.join("image.img")), 8, None, fast, &sparse, common::no_progress).unwrap(), "fast: {}", fast);
+        }
+        // a sector of the hole that is no longer zero is reported
+        let mut content = content;
+        content[30 * BLOCK_SIZE] = 1;
+        std::fs::write(&backing, &content).unwrap();
+        assert!(!device.verify_image_on_device(&common::location(&dir.join("image.img")), 8, None, false, &sparse, common::no_progress).unwrap());
+}
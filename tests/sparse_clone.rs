@@ -0,0 +1,70 @@
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+use common::{BLOCK_SIZE, TempDir};
+use rmsd::image::{Compression, Destination, Format, Image, OutputOptions};
+
+const KIB: usize = 1024;
+
+fn raw_output() -> OutputOptions {
+        OutputOptions { format: Format::Raw, compression: Compression::None, level: None, threads: 1, split_size: None }
+}
+
+/// 96KiB of data whose every third 4KiB block is zero, 1MiB of zeros, a block holding a single byte and 64KiB of
+/// zeros at the end of the device, along with the ranges of the 4KiB blocks holding data
+fn content() -> (Vec<u8>, Vec<std::ops::Range<u64>>) {
+        let mut content = common::pattern(96 * KIB, 51);
+        content.extend(vec![0; 1024 * KIB]);
+        content.extend(vec![0; 4 * KIB]);
+        content[1120 * KIB + 100] = 1;
+        content.extend(vec![0; 64 * KIB]);
+        // blocks 0, 2 and 3, 5 and 6... 20 and 21, then 23
+        let mut data: Vec<std::ops::Range<u64>> = vec![0..4096];
+        data.extend((0..7u64).map(|n| (3 * n + 2) * 4096..(3 * n + 4) * 4096));
+        data.push(23 * 4096..24 * 4096);
+        data.push(1120 * KIB as u64..1124 * KIB as u64);
+        (content, data)
+}
+
+#[test]
+fn zero_blocks_of_clones_are_left_as_holes() {
+        let dir = TempDir::new("sparse-clone");
+        let (content, data) = content();
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let device = common::device(&backing, "");
+        // buffers of 3 sectors do not line up with the blocks checked for zeros
+        for buffer_size in [64, 3] {
+                let clone = dir.join(&format!("clone-{}.img", buffer_size));
+                assert!(device.clone_drive_to_file(Destination::Path(clone.clone()), buffer_size, None, &raw_output(), &mut [], common::no_progress).unwrap());
+                // the trailing zeros were never written, the file is still extended to the size of the device
+                assert_eq!(std::fs::metadata(&clone).unwrap().len(), content.len() as u64);
+                assert!(std::fs::read(&clone).unwrap() == content);
+                let image = Image::open(&common::location(&clone)).unwrap();
+                assert_eq!(image.data_extents(), Some(&data[..]), "buffers of {} sectors", buffer_size);
+                let allocated = std::fs::metadata(&clone).unwrap().blocks() * 512;
+                assert!(allocated <= 100 * KIB as u64, "{} bytes allocated", allocated);
+        }
+}
+
+#[test]
+fn clones_of_empty_devices_are_a_single_hole() {
+        let dir = TempDir::new("sparse-clone-empty");
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0; 256 * BLOCK_SIZE]);
+        let device = common::device(&backing, "");
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.img")), 32, None, &raw_output(), &mut [], common::no_progress).unwrap());
+        let metadata = std::fs::metadata(dir.join("clone.img")).unwrap();
+        assert_eq!((metadata.len(), metadata.blocks()), (256 * BLOCK_SIZE as u64, 0));
+        assert!(std::fs::read(dir.join("clone.img")).unwrap().iter().all(|&b| b == 0));
+}
+
+#[test]
+fn existing_files_are_replaced_by_sparse_clones() {
+        let dir = TempDir::new("sparse-clone-replace");
+        let (content, _) = content();
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let device = common::device(&backing, "");
+        // the holes must not show the previous content of the file
+        std::fs::write(dir.join("clone.img"), vec![0xA5; 2 * content.len()]).unwrap();
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.img")), 32, None, &raw_output(), &mut [], common::no_progress).unwrap());
+        assert!(std::fs::read(dir.join("clone.img")).unwrap() == content);
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use common::{BLOCK_SIZE, TempDir};
+
+/// Runs the tool on the simulated device backed by `backing`, `input` being written to its standard input
+fn rmsd(backing: &Path, args: &[&str], input: &[u8]) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rmsd")).arg("--simulate").arg(backing).args(args)
+                .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+                .spawn().unwrap();
+        // the tool may refuse to run before reading its input
+        let _ = child.stdin.take().unwrap().write_all(input);
+        child.wait_with_output().unwrap()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+}
+
+#[test]
+fn images_are_flashed_from_the_standard_input() {
+        let dir = TempDir::new("stdio-flash");
+        let image = common::pattern(100 * BLOCK_SIZE + 7, 111);
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * BLOCK_SIZE]);
+        let output = rmsd(&backing, &["flash", "-y", "--allow-unsigned", "-i", "-", "--hash", "sha256"], &gzip(&image));
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        let written = std::fs::read(&backing).unwrap();
+        assert!(written[..image.len()] == image[..]);
+        assert!(written[image.len()..101 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert!(written[101 * BLOCK_SIZE..].iter().all(|&b| b == 0xA5));
+
+        // the given size is checked against the capacity before anything is written
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 128 * BLOCK_SIZE]);
+        let output = rmsd(&backing, &["flash", "-y", "--allow-unsigned", "-i", "-", "--input-size", &(129 * BLOCK_SIZE).to_string()], &image);
+        assert!(!output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5));
+        let output = rmsd(&backing, &["flash", "-y", "--allow-unsigned", "-i", "-", "--input-size", &image.len().to_string()], &image);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        assert!(std::fs::read(&backing).unwrap()[..image.len()] == image[..]);
+}
+
+#[test]
+fn the_standard_input_is_only_read_when_allowed() {
+        let dir = TempDir::new("stdio-refused");
+        let image = common::pattern(16 * BLOCK_SIZE, 112);
+        std::fs::write(dir.join("image.img"), &image).unwrap();
+        let backing = common::backing_file(&dir.join("device.bin"), &vec![0xA5; 64 * BLOCK_SIZE]);
+        let refused = |args: &[&str], message: &str| {
+                let output = rmsd(&backing, &[&["flash", "--allow-unsigned"][..], args].concat(), &image);
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                assert_eq!(output.status.code(), Some(1), "{:?}: {}", args, stdout);
+                assert!(stdout.contains(message), "{:?}: {}", args, stdout);
+                assert!(std::fs::read(&backing).unwrap().iter().all(|&b| b == 0xA5), "{:?}", args);
+        };
+        // the prompts would read the image
+        refused(&["-i", "-"], "-y is required");
+        // a stream can only be read once
+        refused(&["-y", "-i", "-", "--verify"], "can only be read once");
+        refused(&["-y", "-i", "-", "--signature", dir.join("image.img.minisig").to_str().unwrap()], "can only be read once");
+        refused(&["-y", "-i", "-", "--checksum-file", dir.join("SHA256SUMS").to_str().unwrap()], "can only be read once");
+        // the size of a file is known
+        refused(&["-y", "--skip-checksum", "-i", dir.join("image.img").to_str().unwrap(), "--input-size", "65536"], "--input-size only applies");
+}
+
+#[test]
+fn clones_are_written_to_the_standard_output() {
+        let dir = TempDir::new("stdio-clone");
+        let content = common::pattern(64 * BLOCK_SIZE, 113);
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let output = rmsd(&backing, &["clone", "-y", "-i", "-", "--hash", "sha256"], &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        // the progress and the digests are printed on the standard error
+        assert!(output.stdout == content);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("sha256: "), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let output = rmsd(&backing, &["clone", "-y", "-i", "-", "--compress", "gzip"], &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let mut decoded = vec![];
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&output.stdout[..]), &mut decoded).unwrap();
+        assert!(decoded == content);
+}
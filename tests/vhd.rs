@@ -0,0 +1,83 @@
+mod common;
+
+use std::io::Read;
+use common::TempDir;
+use rmsd::image::{Compression, Destination, Format, Image, OutputOptions};
+
+const MIB: usize = 1 << 20;
+
+/// The footer of a fixed disk of `size` bytes
+fn fixed_footer(size: u64) -> Vec<u8> {
+        let mut footer = vec![0u8; 512];
+        footer[..8].copy_from_slice(b"conectix");
+        footer[8..12].copy_from_slice(&2u32.to_be_bytes());
+        footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        footer[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+        footer[40..48].copy_from_slice(&size.to_be_bytes());
+        footer[48..56].copy_from_slice(&size.to_be_bytes());
+        footer[60..64].copy_from_slice(&2u32.to_be_bytes());
+        let sum = footer.iter().fold(0u32, |sum, &b| sum.wrapping_add(u32::from(b)));
+        footer[64..68].copy_from_slice(&(!sum).to_be_bytes());
+        footer
+}
+
+#[test]
+fn clones_round_trip_through_vhdx_images() {
+        let dir = TempDir::new("vhdx-round-trip");
+        // blocks of 2MiB: data, zeros, data, zeros, then a last block of 1MiB holding a single byte
+        let mut content = common::pattern(9 * MIB, 41);
+        content[2 * MIB..4 * MIB].fill(0);
+        content[6 * MIB..].fill(0);
+        content[8 * MIB + 4096] = 1;
+        let backing = common::backing_file(&dir.join("device.bin"), &content);
+        let device = common::device(&backing, "");
+        let output = OutputOptions { format: Format::Vhdx, compression: Compression::None, level: None, threads: 1, split_size: None };
+        assert!(device.clone_drive_to_file(Destination::Path(dir.join("clone.vhdx")), 64, None, &output, &mut [], common::no_progress).unwrap());
+        let mut image = Image::open(&common::location(&dir.join("clone.vhdx"))).unwrap();
+        assert_eq!(image.format(), Format::Vhdx);
+        assert_eq!(image.size(), Some(content.len() as u64));
+        let data = [0..2, 4..6, 8..9].map(|r| (r.start * MIB) as u64..(r.end * MIB) as u64);
+        assert_eq!(image.data_extents(), Some(&data[..]));
+        let mut decoded = vec![];
+        image.read_to_end(&mut decoded).unwrap();
+        assert!(decoded == content);
+
+        let copy = common::backing_file(&dir.join("copy.bin"), &vec![0xA5; content.len()]);
+        let device = common::device(&copy, "");
+        assert!(device.flash_image_from_file(&common::location(&dir.join("clone.vhdx")), 64, None, &common::write_zeros(), &mut [], common::no_progress).unwrap());
+        assert!(device.verify_image_on_device(&common::location(&dir.join("clone.vhdx")), 64, None, false, &common::write_zeros(), common::no_progress).unwrap());
+        assert!(std::fs::read(&copy).unwrap() == content);
+}
+
+#[test]
+fn fixed_vhds_are_recognized_from_their_footer() {
+        let dir = TempDir::new("vhd-fixed");
+        let mut data = common::pattern(64 * 512, 42);
+        data.extend(fixed_footer(64 * 512));
+        std::fs::write(dir.join("disk.vhd"), &data).unwrap();
+        let mut image = Image::open(&common::location(&dir.join("disk.vhd"))).unwrap();
+        assert_eq!(image.format(), Format::Vhd);
+        assert_eq!(image.size(), Some(64 * 512));
+        let mut decoded = vec![];
+        image.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, &data[..64 * 512]);
+}
+
+#[test]
+fn raw_images_ending_with_the_cookie_are_not_taken_for_vhds() {
+        let dir = TempDir::new("vhd-cookie");
+        // a footer whose checksum does not match, then one describing a disk larger than the file
+        let mut bad_checksum = fixed_footer(63 * 512);
+        bad_checksum[64] ^= 1;
+        for (name, footer) in [("checksum.img", bad_checksum), ("size.img", fixed_footer(64 * 512))] {
+                let mut data = common::pattern(63 * 512, 43);
+                data.extend(footer);
+                std::fs::write(dir.join(name), &data).unwrap();
+                let mut image = Image::open(&common::location(&dir.join(name))).unwrap();
+                assert_eq!(image.format(), Format::Raw, "{}", name);
+                assert_eq!(image.size(), Some(data.len() as u64), "{}", name);
+                let mut decoded = vec![];
+                image.read_to_end(&mut decoded).unwrap();
+                assert!(decoded == data, "{}", name);
+        }
+}